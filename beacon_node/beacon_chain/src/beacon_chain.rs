@@ -1,3 +1,4 @@
+use crate::block_root_by_body_root::BlockRootByBodyRoot;
 use crate::checkpoint::CheckPoint;
 use crate::errors::{BeaconChainError as Error, BlockProductionError};
 use crate::fork_choice::{Error as ForkChoiceError, ForkChoice};
@@ -101,6 +102,12 @@ impl<T: BeaconChainTypes> BeaconChain<T> {
 
         let genesis_block_root = genesis_block.block_header().canonical_root();
         store.put(&genesis_block_root, &genesis_block)?;
+        store.put(
+            &genesis_block.block_header().block_body_root,
+            &BlockRootByBodyRoot {
+                block_root: genesis_block_root,
+            },
+        )?;
 
         // Also store the genesis block under the `ZERO_HASH` key.
         let genesis_block_root = genesis_block.block_header().canonical_root();
@@ -234,6 +241,21 @@ impl<T: BeaconChainTypes> BeaconChain<T> {
         StateRootsIterator::owned(self.store.clone(), self.state.read().clone(), slot)
     }
 
+    /// Returns the block whose body has the given root, if any.
+    ///
+    /// ## Errors
+    ///
+    /// May return a database error.
+    pub fn get_block_by_body_root(
+        &self,
+        body_root: &Hash256,
+    ) -> Result<Option<BeaconBlock>, Error> {
+        match self.store.get::<BlockRootByBodyRoot>(body_root)? {
+            Some(index) => self.get_block(&index.block_root),
+            None => Ok(None),
+        }
+    }
+
     /// Returns the block at the given root, if any.
     ///
     /// ## Errors
@@ -249,6 +271,11 @@ impl<T: BeaconChainTypes> BeaconChain<T> {
         self.state.read()
     }
 
+    /// Returns the root of the genesis block.
+    pub fn genesis_block_root(&self) -> Hash256 {
+        self.genesis_block_root
+    }
+
     /// Returns a read-lock guarded `CheckPoint` struct for reading the head (as chosen by the
     /// fork-choice rule).
     ///
@@ -630,6 +657,10 @@ impl<T: BeaconChainTypes> BeaconChain<T> {
 
         // Store the block and state.
         self.store.put(&block_root, &block)?;
+        self.store.put(
+            &block.block_header().block_body_root,
+            &BlockRootByBodyRoot { block_root },
+        )?;
         self.store.put(&state_root, &state)?;
 
         // Register the new block with the fork choice service.
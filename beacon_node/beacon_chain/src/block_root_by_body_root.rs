@@ -0,0 +1,25 @@
+use ssz::{Decode, Encode};
+use ssz_derive::{Decode, Encode};
+use store::{DBColumn, Error as StoreError, StoreItem};
+use types::Hash256;
+
+/// The root of the block a `BeaconBlockBody` belongs to, stored under the root of the body so
+/// that bodies can be found by their root.
+#[derive(Encode, Decode)]
+pub struct BlockRootByBodyRoot {
+    pub block_root: Hash256,
+}
+
+impl StoreItem for BlockRootByBodyRoot {
+    fn db_column() -> DBColumn {
+        DBColumn::BeaconBlockBodyRoot
+    }
+
+    fn as_store_bytes(&self) -> Vec<u8> {
+        self.as_ssz_bytes()
+    }
+
+    fn from_store_bytes(bytes: &mut [u8]) -> Result<Self, StoreError> {
+        Self::from_ssz_bytes(bytes).map_err(Into::into)
+    }
+}
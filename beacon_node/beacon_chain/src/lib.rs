@@ -1,4 +1,5 @@
 mod beacon_chain;
+mod block_root_by_body_root;
 mod checkpoint;
 mod errors;
 mod fork_choice;
@@ -0,0 +1,133 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::black_box;
+use criterion::{Benchmark, Criterion};
+use eth2_libp2p::rpc::codec::{decode_chunk, encode_chunk, inbound_protocols, InboundFrame};
+use eth2_libp2p::rpc::methods::*;
+use eth2_libp2p::rpc::{RPCEncoding, RPCEvent, RPCSizeLimits, RequestId};
+use types::{BeaconBlock, ChainSpec, Epoch, Hash256, Slot};
+
+/// The RPCs of a sync round with one peer: statuses, metadata and pings, and a range of blocks.
+fn traffic() -> Vec<RPCEvent> {
+    let id = RequestId::from(1);
+    let hello = HelloMessage {
+        network_id: 1,
+        genesis_root: Hash256::zero(),
+        latest_finalized_root: Hash256::from_low_u64_be(1),
+        latest_finalized_epoch: Epoch::new(10),
+        best_root: Hash256::from_low_u64_be(2),
+        best_slot: Slot::new(400),
+    };
+    let request = |body: RPCRequest| RPCEvent::Request {
+        id,
+        method_id: body.method_id(),
+        body,
+    };
+    let response = |result: RPCResponse| RPCEvent::Response {
+        id,
+        method_id: result.method_id(),
+        result,
+    };
+
+    let mut events = vec![
+        request(RPCRequest::Hello(hello.clone())),
+        response(RPCResponse::Hello(hello)),
+        request(RPCRequest::MetaData),
+        response(RPCResponse::MetaData(MetaData::default())),
+        request(RPCRequest::Ping(PingMessage { data: 1 })),
+        response(RPCResponse::Ping(PingMessage { data: 1 })),
+        request(RPCRequest::BeaconBlocksByRange(
+            BeaconBlocksByRangeRequest {
+                start_slot: Slot::new(336),
+                count: 64,
+                step: 1,
+            },
+        )),
+    ];
+    let block = BeaconBlock::empty(&ChainSpec::mainnet());
+    for slot in 336..400 {
+        let mut block = block.clone();
+        block.slot = Slot::new(slot);
+        events.push(response(RPCResponse::BeaconBlocksByRange(Box::new(block))));
+    }
+    events.push(RPCEvent::StreamTermination {
+        id,
+        method_id: RPCMethod::BeaconBlocksByRange.into(),
+    });
+    events
+}
+
+/// Encodes `events` as `ssz_snappy` chunks, storing those smaller than `threshold`.
+fn encode(events: &[RPCEvent], threshold: usize) -> Vec<(u16, Vec<u8>)> {
+    events
+        .iter()
+        .map(|event| {
+            let chunk = encode_chunk(event, RPCEncoding::SSZSnappy, threshold)
+                .expect("should encode event");
+            (event.method_id(), chunk)
+        })
+        .collect()
+}
+
+/// Decodes the chunks of `encode`.
+fn decode(chunks: &[(u16, Vec<u8>)], size_limits: &RPCSizeLimits) {
+    let protocols = inbound_protocols();
+    for (method_id, chunk) in chunks {
+        let protocol = protocols
+            .iter()
+            .find(|p| p.method_id == *method_id && p.encoding == RPCEncoding::SSZSnappy)
+            .expect("method is supported")
+            .clone();
+        match decode_chunk(chunk, protocol, size_limits) {
+            InboundFrame::Event(event) => {
+                black_box(event);
+            }
+            frame => panic!("should decode chunk, got {:?}", frame),
+        }
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let events = traffic();
+    let thresholds = [0, 128];
+
+    for threshold in &thresholds {
+        let bytes: usize = encode(&events, *threshold)
+            .iter()
+            .map(|(_, chunk)| chunk.len())
+            .sum();
+        println!(
+            "rpc traffic with compression threshold {}: {} bytes",
+            threshold, bytes
+        );
+    }
+
+    let (events_0, events_128) = (events.clone(), events.clone());
+    c.bench(
+        "rpc_traffic_encode",
+        Benchmark::new("threshold_0", move |b| {
+            b.iter(|| black_box(encode(&events_0, 0)))
+        })
+        .with_function("threshold_128", move |b| {
+            b.iter(|| black_box(encode(&events_128, 128)))
+        })
+        .sample_size(100),
+    );
+
+    let size_limits = RPCSizeLimits::default();
+    let (chunks_0, chunks_128) = (encode(&events, 0), encode(&events, 128));
+    c.bench(
+        "rpc_traffic_decode",
+        Benchmark::new("threshold_0", move |b| {
+            b.iter(|| decode(&chunks_0, &size_limits))
+        })
+        .with_function("threshold_128", move |b| {
+            b.iter(|| decode(&chunks_128, &size_limits))
+        })
+        .sample_size(100),
+    );
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate eth2_libp2p;
+
+use eth2_libp2p::rpc::codec::{decode_chunk, inbound_protocols, InboundFrame};
+use eth2_libp2p::rpc::{RPCError, RPCErrorCode, RPCSizeLimits};
+
+// Decode arbitrary bytes as an inbound chunk of every RPC protocol
+fuzz_target!(|data: &[u8]| {
+    let size_limits = RPCSizeLimits::default();
+    let invalid_request: u8 = RPCErrorCode::InvalidRequest.into();
+    for protocol in inbound_protocols() {
+        let method_id = protocol.method_id;
+        match decode_chunk(data, protocol, &size_limits) {
+            // Decoded events belong to the method of the substream
+            InboundFrame::Event(event) => assert_eq!(event.method_id(), method_id),
+            InboundFrame::OversizedRequest { method_id: id, size, .. } => {
+                // Only frames of this method beyond its limit are refused for their size
+                assert_eq!(id, method_id);
+                assert!(size > size_limits.limit(method_id).max_request);
+            }
+            InboundFrame::Failed(error) => {
+                // Every failure is answered as an invalid request
+                assert_eq!(RPCError::from(error).code(), invalid_request);
+            }
+        }
+    }
+});
@@ -0,0 +1,20 @@
+//! Writes a valid chunk of every RPC event and encoding to the corpus of
+//! `fuzz_target_rpc_decode`, so fuzzing starts from well-formed frames.
+
+extern crate eth2_libp2p;
+
+use eth2_libp2p::rpc::codec::{encode_chunk, example_events, FRAME_FORMATS};
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let corpus = Path::new("corpus/fuzz_target_rpc_decode");
+    fs::create_dir_all(corpus).expect("should create corpus directory");
+
+    for (i, event) in example_events().iter().enumerate() {
+        for (j, (encoding, threshold)) in FRAME_FORMATS.iter().enumerate() {
+            let chunk = encode_chunk(event, *encoding, *threshold).expect("should encode event");
+            fs::write(corpus.join(format!("seed_{}_{}", i, j)), chunk).expect("should write seed");
+        }
+    }
+}
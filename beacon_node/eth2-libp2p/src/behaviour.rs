@@ -1,24 +1,113 @@
-use crate::discovery::Discovery;
-use crate::rpc::{RPCEvent, RPCMessage, Rpc};
+use crate::config::{
+    attestation_subnet_topic, MessageAuthenticity, MessageId, MessageIdFn, SubnetId,
+    GOSSIP_MAX_SIZE,
+};
+use crate::discovery::{DialErrorKind, Discovery, DiscoveryEvent, PeerSource};
+use crate::gossip::{BoundedGossipsub, GossipEvent};
+use crate::metrics::Metrics;
+use crate::rpc::methods::{
+    BeaconBlocksByRangeRequest, GoodbyeReason, HelloMessage, MetaData, PingMessage,
+    ATTESTATION_SUBNET_COUNT, MAX_CHUNK_SIZE,
+};
+use crate::rpc::{
+    OutboundQueueLimits, RPCError, RPCErrorCode, RPCEvent, RPCMessage, RPCMethod, RPCRequest,
+    RPCResponse, RPCVersion, RequestHandler, RequestId, Rpc,
+};
 use crate::{error, NetworkConfig};
-use crate::{Topic, TopicHash};
+use crate::{Topic, TopicBuilder, TopicHash};
 use futures::prelude::*;
+use futures::sync::{mpsc, oneshot};
+use futures::task;
 use libp2p::{
     core::{
-        identity::Keypair,
-        swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess},
+        identity::{error::SigningError, Keypair, PublicKey},
+        swarm::{toggle::Toggle, NetworkBehaviourAction, NetworkBehaviourEventProcess},
     },
-    discv5::Discv5Event,
-    gossipsub::{Gossipsub, GossipsubEvent},
-    ping::{Ping, PingConfig, PingEvent},
+    enr::Enr,
+    gossipsub::{GossipsubConfig, GossipsubEvent},
+    identify::{Identify, IdentifyEvent, IdentifyInfo},
+    mdns::{Mdns, MdnsEvent},
+    multiaddr::Protocol,
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
     tokio_io::{AsyncRead, AsyncWrite},
-    NetworkBehaviour, PeerId,
+    Multiaddr, NetworkBehaviour, PeerId,
 };
-use slog::{o, trace, warn};
+use prometheus::IntCounter;
+use slog::{debug, info, o, trace, warn};
 use ssz::{ssz_encode, Decode, DecodeError, Encode};
+use ssz_derive::{Decode, Encode};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
 use std::num::NonZeroU32;
-use std::time::Duration;
-use types::{Attestation, BeaconBlock};
+use std::panic;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+use types::{
+    Attestation, AttesterSlashing, BeaconBlock, Bitfield, Epoch, Hash256, ProposerSlashing, Slot,
+    VoluntaryExit,
+};
+
+/// The protocol version advertised to peers via the identify protocol.
+const IDENTIFY_PROTOCOL_VERSION: &str = "lighthouse/libp2p";
+/// The ping round-trip time above which a peer is considered slow.
+const SLOW_PEER_RTT: Duration = Duration::from_secs(2);
+/// The number of consecutive slow pings after which a peer is considered chronically slow.
+const SLOW_PEER_THRESHOLD: u8 = 3;
+/// The number of recently received gossip messages remembered to detect duplicates.
+const SEEN_MESSAGES_CAPACITY: usize = 4096;
+/// The number of consecutive failed Ping RPCs after which a peer is banned.
+const MAX_RPC_PING_FAILURES: u32 = 3;
+/// The number of consecutive status requests a peer may fail to answer before its RPC score is
+/// lowered.
+const MAX_STATUS_FAILURES: u32 = 3;
+/// The RPC score at or below which a peer is banned.
+const MIN_RPC_SCORE: i32 = -20;
+/// The RPC score above which useful responses no longer raise a peer's score, so that a long
+/// useful history cannot excuse an arbitrary amount of later misbehaviour.
+const MAX_RPC_SCORE: i32 = 20;
+/// The time a negative RPC score is kept after its peer disconnects, so that the peer cannot
+/// wipe its penalty by reconnecting.
+const RPC_PENALTY_RETENTION: Duration = Duration::from_secs(60 * 60);
+/// The time the connected peer count must stay on the other side of `min_peers` before a
+/// `LowPeerCount` or `RecoveredPeerCount` event is emitted, so that a count flapping around the
+/// threshold does not flood us with events.
+const PEER_COUNT_DEBOUNCE: Duration = Duration::from_secs(10);
+/// The time after a failed RPC request for which a peer is not chosen to sync from.
+const SYNC_PEER_FAILURE_BACKOFF: Duration = Duration::from_secs(60);
+/// The number of gossip messages published between polls of the behaviour, beyond which
+/// publishes are queued with blocks ahead of attestations.
+const MAX_PUBLISHES_PER_POLL: usize = 64;
+/// The number of publishes that can be queued. Once full, further attestations fail to publish
+/// and blocks displace the oldest queued attestation.
+const MAX_QUEUED_PUBLISHES: usize = 1024;
+/// The time allowed for goodbyes to be sent to our peers when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// The time allowed for our goodbye to be sent to a departing peer before it is disconnected.
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+/// The time for which a peer on another network or chain is not dialed again.
+const INCOMPATIBLE_PEER_DIAL_BACKOFF: Duration = Duration::from_secs(3600);
+/// The largest SSZ encoded block accepted over gossip, as over RPC. A block carrying the maximum
+/// number of each operation is well within this.
+const MAX_BLOCK_GOSSIP_SIZE: usize = MAX_CHUNK_SIZE;
+/// The largest SSZ encoded attestation accepted over gossip: two bitfields of
+/// `max_indices_per_attestation` (4096) bits, the attestation data and the signature, with room to
+/// spare.
+const MAX_ATTESTATION_GOSSIP_SIZE: usize = 2_048;
+/// The largest SSZ encoded light client update accepted over gossip: two block headers, a
+/// finality branch and a sync aggregate, with room to spare.
+const MAX_LIGHT_CLIENT_UPDATE_GOSSIP_SIZE: usize = 2_048;
+/// The largest SSZ encoded voluntary exit accepted over gossip: an epoch, an index and a signature,
+/// with room to spare.
+const MAX_VOLUNTARY_EXIT_GOSSIP_SIZE: usize = 256;
+/// The largest SSZ encoded proposer slashing accepted over gossip: an index and two block headers,
+/// with room to spare.
+const MAX_PROPOSER_SLASHING_GOSSIP_SIZE: usize = 1_024;
+/// The largest SSZ encoded attester slashing accepted over gossip: two indexed attestations, each
+/// listing at most `max_indices_per_attestation` (4096) indices alongside its data and signature.
+const MAX_ATTESTER_SLASHING_GOSSIP_SIZE: usize = 2 * (4_096 * 8 + 1_024);
 
 /// Builds the network behaviour that manages the core protocols of eth2.
 /// This core behaviour is managed by `Behaviour` which adds peer management to all core
@@ -27,27 +116,220 @@ use types::{Attestation, BeaconBlock};
 #[behaviour(out_event = "BehaviourEvent", poll_method = "poll")]
 pub struct Behaviour<TSubstream: AsyncRead + AsyncWrite> {
     /// The routing pub-sub mechanism for eth2.
-    gossipsub: Gossipsub<TSubstream>,
+    gossipsub: BoundedGossipsub<TSubstream>,
     /// The serenity RPC specified in the wire-0 protocol.
     serenity_rpc: Rpc<TSubstream>,
     /// Keep regular connection to peers and disconnect if absent.
     ping: Ping<TSubstream>,
     /// Kademlia for peer discovery.
     discovery: Discovery<TSubstream>,
+    /// Exchanges client and protocol information with connected peers.
+    identify: Identify<TSubstream>,
+    /// Discovers peers on the local network, if `enable_mdns` is set.
+    mdns: Toggle<Mdns<TSubstream>>,
     #[behaviour(ignore)]
     /// The events generated by this behaviour to be consumed in the swarm poll.
     events: Vec<BehaviourEvent>,
+    /// Tracks peers that are slow to respond to pings.
+    #[behaviour(ignore)]
+    slow_peers: SlowPeers,
+    /// The client information of connected peers, learned via the identify protocol.
+    #[behaviour(ignore)]
+    client_info: HashMap<PeerId, ClientInfo>,
+    /// Peers running a client whose agent version contains any of these strings are disconnected.
+    #[behaviour(ignore)]
+    banned_agents: Vec<String>,
+    /// Recently received gossip messages, used to drop duplicates.
+    #[behaviour(ignore)]
+    seen_messages: SeenMessages,
+    /// The gossip messages handed up whose validation result has yet to be reported.
+    #[behaviour(ignore)]
+    awaiting_validation: AwaitingValidation,
+    /// Derives the ids `seen_messages` recognises gossip messages by.
+    #[behaviour(ignore)]
+    message_id_fn: MessageIdFn,
+    /// The time the behaviour was built.
+    #[behaviour(ignore)]
+    started_at: Instant,
+    /// The time the first block was received over gossip, if one has been.
+    #[behaviour(ignore)]
+    first_block_at: Option<Instant>,
+    /// The gossipsub topics we are subscribed to.
+    #[behaviour(ignore)]
+    subscriptions: HashMap<TopicHash, Topic>,
+    /// The attestation subnets subscribed to, set by `update_subnet_subscriptions`.
+    #[behaviour(ignore)]
+    subnet_subscriptions: HashSet<SubnetId>,
+    /// The key published gossip messages are signed with, if messages are signed.
+    #[behaviour(ignore)]
+    signing_key: Option<Keypair>,
+    /// Observes the raw gossip messages received, if set.
+    #[behaviour(ignore)]
+    gossip_observer: Option<Arc<dyn GossipObserver>>,
+    /// The latest ping round-trip time of each peer.
+    #[behaviour(ignore)]
+    peer_rtts: HashMap<PeerId, Duration>,
+    /// The peers subscribed to each gossipsub topic.
+    #[behaviour(ignore)]
+    topic_peers: HashMap<TopicHash, HashSet<PeerId>>,
+    /// The interval at which a `PeerSummary` is emitted.
+    #[behaviour(ignore)]
+    peer_summary_interval: Duration,
+    /// The time at which the next `PeerSummary` is emitted.
+    #[behaviour(ignore)]
+    next_peer_summary: Instant,
+    /// Wakes the behaviour when the next `PeerSummary` is due.
+    #[behaviour(ignore)]
+    peer_summary_delay: Delay,
+    /// The metadata we advertise to our peers.
+    #[behaviour(ignore)]
+    metadata: MetaData,
+    /// The latest metadata received from each peer.
+    #[behaviour(ignore)]
+    peer_metadata: HashMap<PeerId, MetaData>,
+    /// The interval at which connected peers are sent a Ping RPC.
+    #[behaviour(ignore)]
+    rpc_ping_interval: Duration,
+    /// The time at which connected peers are next sent a Ping RPC.
+    #[behaviour(ignore)]
+    next_rpc_ping: Instant,
+    /// Wakes the behaviour when the next Ping RPCs are due.
+    #[behaviour(ignore)]
+    rpc_ping_delay: Delay,
+    /// The number of consecutive Ping RPCs each peer has failed to answer.
+    #[behaviour(ignore)]
+    rpc_ping_failures: HashMap<PeerId, u32>,
+    /// The score of each peer, based on the usefulness of its responses to our RPC requests.
+    #[behaviour(ignore)]
+    rpc_scores: HashMap<PeerId, i32>,
+    /// The time until which the negative RPC score of each disconnected peer is kept.
+    #[behaviour(ignore)]
+    rpc_penalties_kept_until: HashMap<PeerId, Instant>,
+    /// The time each peer last failed one of our RPC requests.
+    #[behaviour(ignore)]
+    rpc_failed_at: HashMap<PeerId, Instant>,
+    /// Cloned into the `NetworkSender`s handed out by the behaviour.
+    #[behaviour(ignore)]
+    command_send: mpsc::UnboundedSender<NetworkCommand>,
+    /// The commands sent through `NetworkSender`s, applied when the behaviour is polled.
+    #[behaviour(ignore)]
+    command_recv: mpsc::UnboundedReceiver<NetworkCommand>,
+    /// The `BeaconBlocksByRange` requests made through `request_blocks_by_range` which have yet to
+    /// complete, keyed by peer and request id.
+    #[behaviour(ignore)]
+    blocks_by_range_requests: HashMap<(PeerId, RequestId), BlocksByRangeRequest>,
+    /// The number of times a failed `BeaconBlocksByRange` request is retried from another peer.
+    #[behaviour(ignore)]
+    range_sync_retries: u32,
+    /// Keep peers on another network or chain rather than disconnecting them.
+    #[behaviour(ignore)]
+    keep_incompatible_peers: bool,
+    /// Whether publishes are dropped, so that we never originate gossip.
+    #[behaviour(ignore)]
+    observer_mode: bool,
+    /// Whether inbound gossip is dropped and publishing refused, set by `pause_gossip`.
+    #[behaviour(ignore)]
+    gossip_paused: bool,
+    /// The peers we have said goodbye to, to be disconnected once the goodbye is sent.
+    #[behaviour(ignore)]
+    departing_peers: HashMap<PeerId, Departure>,
+    /// The time after which a peer sending no traffic beyond keep-alives is disconnected.
+    #[behaviour(ignore)]
+    connection_idle_timeout: Option<Duration>,
+    /// The time each connected peer last sent us gossip or an RPC beyond the keep-alives, or was
+    /// first seen connected.
+    #[behaviour(ignore)]
+    last_active: HashMap<PeerId, Instant>,
+    /// Wakes the behaviour when the longest idle peer reaches the idle timeout.
+    #[behaviour(ignore)]
+    idle_peer_delay: Delay,
+    /// The chain state each peer advertised in its latest status.
+    #[behaviour(ignore)]
+    peer_chain_states: HashMap<PeerId, PeerChainState>,
+    /// The age after which a peer's chain state is stale.
+    #[behaviour(ignore)]
+    peer_status_max_age: Duration,
+    /// Our status, sent to our peers by the periodic status exchange, set by
+    /// `update_local_status`.
+    #[behaviour(ignore)]
+    local_status: Option<HelloMessage>,
+    /// The id of our network, which the statuses of our peers must match.
+    #[behaviour(ignore)]
+    network_id: u8,
+    /// The root of our genesis block, which the statuses of our peers must match.
+    #[behaviour(ignore)]
+    genesis_root: Hash256,
+    /// The interval at which our status is sent again to our peers.
+    #[behaviour(ignore)]
+    status_interval: Duration,
+    /// The time at which our status is next sent to our peers.
+    #[behaviour(ignore)]
+    next_status_exchange: Instant,
+    /// Wakes the behaviour when the next status exchange is due.
+    #[behaviour(ignore)]
+    status_exchange_delay: Delay,
+    /// The status requests sent by the periodic status exchange which have yet to be answered.
+    #[behaviour(ignore)]
+    status_requests: HashSet<(PeerId, RequestId)>,
+    /// The number of consecutive status requests each peer has failed to answer.
+    #[behaviour(ignore)]
+    status_failures: HashMap<PeerId, u32>,
+    /// The peers kept dialed, set by `update_peer_policy`.
+    #[behaviour(ignore)]
+    trusted_peers: HashSet<PeerId>,
+    /// The peers refused connections, set by `update_peer_policy`.
+    #[behaviour(ignore)]
+    banned_peers: HashSet<PeerId>,
+    /// The time for which a misbehaving peer is banned.
+    #[behaviour(ignore)]
+    ban_duration: Duration,
+    /// Wakes the behaviour when the earliest ban of a misbehaving peer ends.
+    #[behaviour(ignore)]
+    ban_expiry_delay: Delay,
+    /// The number of connected peers below which a `LowPeerCount` event is emitted.
+    #[behaviour(ignore)]
+    min_peers: usize,
+    /// Whether the last peer count event was a `LowPeerCount`.
+    #[behaviour(ignore)]
+    low_peer_count: bool,
+    /// The time the connected peer count crossed `min_peers` without an event being emitted yet.
+    #[behaviour(ignore)]
+    peer_count_crossed_at: Option<Instant>,
+    /// Wakes the behaviour when a crossing of `min_peers` has lasted `PEER_COUNT_DEBOUNCE`.
+    #[behaviour(ignore)]
+    peer_count_delay: Delay,
+    /// The publishes waiting for the gossipsub behaviour once `MAX_PUBLISHES_PER_POLL` is reached.
+    #[behaviour(ignore)]
+    publish_queue: PublishQueue,
+    /// The number of gossip messages published since the behaviour was last polled.
+    #[behaviour(ignore)]
+    published_since_poll: usize,
+    /// The progress of the graceful shutdown, once started.
+    #[behaviour(ignore)]
+    shutdown: Option<Shutdown>,
+    /// Network metrics.
+    #[behaviour(ignore)]
+    pub metrics: Metrics,
     /// Logger for behaviour actions.
     #[behaviour(ignore)]
     log: slog::Logger,
 }
 
 impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
+    /// Builds the behaviour of `net_conf`, which is checked up front so a bad config is reported
+    /// rather than panicking later.
     pub fn new(
         local_key: &Keypair,
         net_conf: &NetworkConfig,
         log: &slog::Logger,
-    ) -> error::Result<Self> {
+    ) -> Result<Self, BehaviourError> {
+        validate_config(net_conf)?;
+        let network_id = net_conf
+            .network_id
+            .ok_or(BehaviourError::MissingNetworkId)?;
+        let genesis_root = net_conf
+            .genesis_root
+            .ok_or(BehaviourError::MissingGenesisRoot)?;
         let local_peer_id = local_key.public().clone().into_peer_id();
         let behaviour_log = log.new(o!());
         let ping_config = PingConfig::new()
@@ -55,16 +337,223 @@ impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
             .with_interval(Duration::from_secs(20))
             .with_max_failures(NonZeroU32::new(2).expect("2 != 0"))
             .with_keep_alive(false);
+        let metrics = Metrics::new().map_err(|e| BehaviourError::Metrics(format!("{:?}", e)))?;
+        let (command_send, command_recv) = mpsc::unbounded();
+        let mdns = if net_conf.enable_mdns {
+            info!(log, "Discovering peers on the local network with mDNS");
+            Some(Mdns::new().map_err(|e| BehaviourError::Mdns(e.to_string()))?)
+        } else {
+            None
+        };
 
-        Ok(Behaviour {
-            serenity_rpc: Rpc::new(log),
-            gossipsub: Gossipsub::new(local_peer_id.clone(), net_conf.gs_config.clone()),
-            discovery: Discovery::new(local_key, net_conf, log)?,
+        let mut behaviour = Behaviour {
+            serenity_rpc: Rpc::new(
+                net_conf.rpc_request_timeout,
+                net_conf.rpc_idle_timeout,
+                net_conf.rpc_quotas.clone(),
+                net_conf.rpc_size_limits,
+                net_conf.rpc_max_concurrent_requests,
+                &net_conf.rpc_encodings,
+                net_conf.rpc_compression_threshold,
+                OutboundQueueLimits {
+                    max_requests_in_flight: net_conf.max_rpc_per_peer,
+                    ..net_conf.rpc_outbound_queue_limits
+                },
+                metrics.rpc_outbound_queue_depth.clone(),
+                metrics.rpc_bytes_sent.clone(),
+                metrics.rpc_inbound_failures.clone(),
+                net_conf.rpc_trace,
+                log,
+            ),
+            gossipsub: BoundedGossipsub::new(
+                local_peer_id.clone(),
+                gossipsub_config(net_conf),
+                log,
+            ),
+            discovery: Discovery::new(local_key, net_conf, log)
+                .map_err(|e| BehaviourError::Discovery(e.to_string()))?,
             ping: Ping::new(ping_config),
+            identify: Identify::new(
+                IDENTIFY_PROTOCOL_VERSION.into(),
+                net_conf.client_version.clone(),
+                local_key.public(),
+            ),
+            mdns: Toggle::from(mdns),
             events: Vec::new(),
+            slow_peers: SlowPeers::default(),
+            client_info: HashMap::new(),
+            banned_agents: net_conf.banned_agents.clone(),
+            seen_messages: SeenMessages::new(
+                SEEN_MESSAGES_CAPACITY,
+                metrics.gossip_duplicates_received.clone(),
+            ),
+            awaiting_validation: AwaitingValidation::new(SEEN_MESSAGES_CAPACITY),
+            message_id_fn: net_conf.gossip_message_id,
+            started_at: Instant::now(),
+            first_block_at: None,
+            subscriptions: HashMap::new(),
+            subnet_subscriptions: HashSet::new(),
+            signing_key: match net_conf.message_authenticity {
+                MessageAuthenticity::Signed => Some(local_key.clone()),
+                MessageAuthenticity::Anonymous => None,
+            },
+            gossip_observer: None,
+            peer_rtts: HashMap::new(),
+            topic_peers: HashMap::new(),
+            peer_summary_interval: net_conf.peer_summary_interval,
+            next_peer_summary: Instant::now() + net_conf.peer_summary_interval,
+            peer_summary_delay: Delay::new(Instant::now() + net_conf.peer_summary_interval),
+            metadata: MetaData::default(),
+            peer_metadata: HashMap::new(),
+            rpc_ping_interval: net_conf.rpc_ping_interval,
+            next_rpc_ping: Instant::now() + net_conf.rpc_ping_interval,
+            rpc_ping_delay: Delay::new(Instant::now() + net_conf.rpc_ping_interval),
+            rpc_ping_failures: HashMap::new(),
+            rpc_scores: HashMap::new(),
+            rpc_penalties_kept_until: HashMap::new(),
+            rpc_failed_at: HashMap::new(),
+            command_send,
+            command_recv,
+            blocks_by_range_requests: HashMap::new(),
+            range_sync_retries: net_conf.range_sync_retries,
+            keep_incompatible_peers: net_conf.keep_incompatible_peers,
+            observer_mode: net_conf.observer_mode,
+            gossip_paused: false,
+            departing_peers: HashMap::new(),
+            peer_chain_states: HashMap::new(),
+            peer_status_max_age: net_conf.peer_status_max_age,
+            local_status: None,
+            network_id,
+            genesis_root,
+            status_interval: net_conf.status_interval,
+            next_status_exchange: Instant::now() + net_conf.status_interval,
+            status_exchange_delay: Delay::new(Instant::now() + net_conf.status_interval),
+            status_requests: HashSet::new(),
+            status_failures: HashMap::new(),
+            trusted_peers: HashSet::new(),
+            banned_peers: HashSet::new(),
+            ban_duration: net_conf.ban_duration,
+            ban_expiry_delay: Delay::new(Instant::now()),
+            connection_idle_timeout: net_conf.connection_idle_timeout,
+            last_active: HashMap::new(),
+            idle_peer_delay: Delay::new(Instant::now()),
+            min_peers: net_conf.min_peers,
+            low_peer_count: false,
+            peer_count_crossed_at: None,
+            peer_count_delay: Delay::new(Instant::now() + PEER_COUNT_DEBOUNCE),
+            publish_queue: PublishQueue::default(),
+            published_since_poll: 0,
+            shutdown: None,
+            metrics,
             log: behaviour_log,
-        })
+        };
+
+        // peers cached before a restart keep the RPC scores they had earned
+        let cached_scores = behaviour.discovery.cached_peer_scores();
+        behaviour.rpc_scores.extend(cached_scores);
+
+        // subscribe to the topics of our role and those configured
+        let light_client_topics = if net_conf.serve_light_clients {
+            vec![
+                crate::LIGHT_CLIENT_FINALITY_UPDATE_TOPIC,
+                crate::LIGHT_CLIENT_OPTIMISTIC_UPDATE_TOPIC,
+            ]
+        } else {
+            vec![]
+        };
+        let topics = net_conf
+            .role
+            .default_topics()
+            .into_iter()
+            .chain(light_client_topics)
+            .map(String::from)
+            .chain(net_conf.topics.iter().cloned());
+        let mut subscribed_topics = vec![];
+        for topic in topics {
+            if behaviour.subscribe(TopicBuilder::new(topic.clone()).build()) {
+                trace!(behaviour.log, "Subscribed to topic: {:?}", topic);
+                subscribed_topics.push(topic);
+            } else {
+                warn!(behaviour.log, "Could not subscribe to topic: {:?}", topic)
+            }
+        }
+        info!(
+            behaviour.log,
+            "Subscribed to topics: {:?}", subscribed_topics;
+            "role" => format!("{:?}", net_conf.role)
+        );
+
+        Ok(behaviour)
+    }
+}
+
+/// Returns an error describing the first setting of `net_conf` a behaviour cannot be built with.
+fn validate_config(net_conf: &NetworkConfig) -> Result<(), BehaviourError> {
+    for topic in &net_conf.topics {
+        if topic.is_empty() || topic.contains('/') || topic.contains(char::is_whitespace) {
+            return Err(BehaviourError::InvalidTopic(topic.clone()));
+        }
+    }
+
+    if net_conf.listen_address.is_multicast() {
+        return Err(BehaviourError::InvalidListenAddress(
+            net_conf.listen_address,
+        ));
+    }
+
+    for address in &net_conf.libp2p_nodes {
+        let mut protocols = address.iter();
+        let has_host = match protocols.next() {
+            Some(Protocol::Ip4(_)) | Some(Protocol::Ip6(_)) => true,
+            Some(Protocol::Dns4(_)) | Some(Protocol::Dns6(_)) => true,
+            _ => false,
+        };
+        let has_port = match protocols.next() {
+            Some(Protocol::Tcp(_)) => true,
+            _ => false,
+        };
+        if !has_host || !has_port {
+            return Err(BehaviourError::InvalidBootNodeAddress(address.clone()));
+        }
     }
+
+    if net_conf.target_peers > net_conf.max_peers {
+        return Err(BehaviourError::InvalidPeerCounts {
+            target_peers: net_conf.target_peers,
+            max_peers: net_conf.max_peers,
+        });
+    }
+
+    if net_conf.gossip_fanout_peers == 0 {
+        return Err(BehaviourError::InvalidGossipsubConfig(
+            "gossip_fanout_peers must be at least 1".into(),
+        ));
+    }
+    // the mesh size is set from `gossip_fanout_peers`, so may only be left at its default
+    let mesh_n = net_conf.gs_config.mesh_n;
+    if mesh_n != GossipsubConfig::default().mesh_n && mesh_n != net_conf.gossip_fanout_peers {
+        return Err(BehaviourError::InvalidGossipsubConfig(format!(
+            "mesh_n ({}) conflicts with gossip_fanout_peers ({})",
+            mesh_n, net_conf.gossip_fanout_peers
+        )));
+    }
+    if net_conf.gs_config.heartbeat_interval == Duration::from_secs(0) {
+        return Err(BehaviourError::InvalidGossipsubConfig(
+            "heartbeat_interval must not be zero".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the gossipsub configuration of `net_conf`, with its fan-out limits applied.
+fn gossipsub_config(net_conf: &NetworkConfig) -> GossipsubConfig {
+    let mut gs_config = net_conf.gs_config.clone();
+    gs_config.fanout_ttl = net_conf.gossip_fanout_ttl;
+    // fan-out and mesh share a target size, the mesh bounds are widened to contain it
+    gs_config.mesh_n = net_conf.gossip_fanout_peers;
+    gs_config.mesh_n_low = gs_config.mesh_n_low.min(gs_config.mesh_n);
+    gs_config.mesh_n_high = gs_config.mesh_n_high.max(gs_config.mesh_n);
+    gs_config
 }
 
 // Implement the NetworkBehaviourEventProcess trait so that we can derive NetworkBehaviour for Behaviour
@@ -74,29 +563,124 @@ impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<GossipsubE
     fn inject_event(&mut self, event: GossipsubEvent) {
         match event {
             GossipsubEvent::Message(gs_msg) => {
-                trace!(self.log, "Received GossipEvent"; "msg" => format!("{:?}", gs_msg));
+                let log = self.peer_log(&gs_msg.source);
+                trace!(log, "Received GossipEvent"; "msg" => format!("{:?}", gs_msg));
+                self.metrics.gossip_messages_received.inc();
 
-                let pubsub_message = match PubsubMessage::from_ssz_bytes(&gs_msg.data) {
+                if let Some(observer) = &self.gossip_observer {
+                    observer.observe(&gs_msg.topics, &gs_msg.source, &gs_msg.data);
+                }
+
+                if self.is_shutting_down() {
+                    trace!(log, "Dropping gossip message received while shutting down");
+                    return;
+                }
+                // dropped before it is deduplicated or judged, so the source is not scored for it
+                if self.gossip_paused {
+                    trace!(
+                        log,
+                        "Dropping gossip message received while gossip is paused"
+                    );
+                    return;
+                }
+
+                let id = self.message_id(&gs_msg.topics, &gs_msg.data);
+                if !self.seen_messages.observe(&id) {
+                    trace!(log, "Dropping duplicate gossip message");
+                    return;
+                }
+
+                let data = if self.signing_key.is_some() {
+                    match verify_gossip(&gs_msg.source, &gs_msg.data) {
+                        Ok(data) => data,
+                        // the source is only who the message claims to be from, and the peer which
+                        // relayed it is not known to us, so no one is scored for it
+                        Err(e) => {
+                            debug!(
+                                log,
+                                "Rejecting gossip message with invalid signature";
+                                "error" => format!("{:?}", e)
+                            );
+                            return;
+                        }
+                    }
+                } else {
+                    gs_msg.data
+                };
+
+                let pubsub_message = match PubsubMessage::decode_untrusted(&data) {
+                    // the source is spoofable and the relaying peer is not known to us, so the
+                    // message is dropped without scoring anyone
+                    Err(PubsubDecodeError::Panicked) => {
+                        warn!(log, "Decoding gossip message panicked");
+                        return;
+                    }
                     //TODO: Punish peer on error
                     Err(e) => {
-                        warn!(
-                            self.log,
-                            "Received undecodable message from Peer {:?} error", gs_msg.source;
-                            "error" => format!("{:?}", e)
-                        );
+                        if e.is_likely_version_mismatch() {
+                            debug!(
+                                log,
+                                "Received message with undecodable body";
+                                "error" => format!("{:?}", e)
+                            );
+                        } else {
+                            warn!(
+                                log,
+                                "Received undecodable message";
+                                "error" => format!("{:?}", e)
+                            );
+                        }
                         return;
                     }
                     Ok(msg) => msg,
                 };
 
+                let now = Instant::now();
+                self.record_gossip_activity(&gs_msg.topics, now);
+                self.record_first_block(&pubsub_message, now);
+                self.awaiting_validation
+                    .received(id.clone(), pubsub_message.kind(), now);
                 self.events.push(BehaviourEvent::GossipMessage {
+                    id,
                     source: gs_msg.source,
                     topics: gs_msg.topics,
+                    block: pubsub_message.block_info(),
                     message: Box::new(pubsub_message),
                 });
             }
-            GossipsubEvent::Subscribed { .. } => {}
-            GossipsubEvent::Unsubscribed { .. } => {}
+            GossipsubEvent::Subscribed { peer_id, topic } => {
+                self.topic_peers
+                    .entry(topic)
+                    .or_insert_with(HashSet::new)
+                    .insert(peer_id);
+            }
+            GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                if let Some(peers) = self.topic_peers.get_mut(&topic) {
+                    peers.remove(&peer_id);
+                    if peers.is_empty() {
+                        self.topic_peers.remove(&topic);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<GossipEvent>
+    for Behaviour<TSubstream>
+{
+    fn inject_event(&mut self, event: GossipEvent) {
+        match event {
+            GossipEvent::Gossipsub(event) => self.inject_event(event),
+            GossipEvent::MeshChanged {
+                peer_id,
+                topic,
+                grafted,
+            } => self.events.push(BehaviourEvent::MeshChanged {
+                peer_id,
+                topic,
+                grafted,
+            }),
         }
     }
 }
@@ -107,160 +691,5449 @@ impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<RPCMessage
     fn inject_event(&mut self, event: RPCMessage) {
         match event {
             RPCMessage::PeerDialed(peer_id) => {
+                if self.is_trusted(&peer_id) {
+                    self.serenity_rpc
+                        .set_rate_limit_exempt(peer_id.clone(), true);
+                }
                 self.events.push(BehaviourEvent::PeerDialed(peer_id))
             }
-            RPCMessage::RPC(peer_id, rpc_event) => {
-                self.events.push(BehaviourEvent::RPC(peer_id, rpc_event))
+            RPCMessage::RPC(peer_id, rpc_event) => self.on_rpc(peer_id, rpc_event),
+            RPCMessage::PeerDisconnected(peer_id) => {
+                self.client_info.remove(&peer_id);
+                self.peer_metadata.remove(&peer_id);
+                self.rpc_ping_failures.remove(&peer_id);
+                self.keep_rpc_penalty(&peer_id, Instant::now());
+                self.rpc_failed_at.remove(&peer_id);
+                self.peer_rtts.remove(&peer_id);
+                self.peer_chain_states.remove(&peer_id);
+                self.status_failures.remove(&peer_id);
+                self.last_active.remove(&peer_id);
+                self.slow_peers.remove(&peer_id);
+                self.status_requests
+                    .retain(|(requested_peer, _id)| *requested_peer != peer_id);
+                self.topic_peers.retain(|_, peers| {
+                    peers.remove(&peer_id);
+                    !peers.is_empty()
+                });
+            }
+            RPCMessage::DialPeer(peer_id) => {
+                if self.is_trusted(&peer_id) {
+                    self.discovery.queue_trusted_dial(peer_id.clone());
+                } else {
+                    self.discovery.queue_dial(peer_id.clone());
+                }
+                if !self.discovery.is_dial_pending(&peer_id) {
+                    debug!(
+                        self.peer_log(&peer_id),
+                        "Not dialing peer for queued RPC requests"
+                    );
+                }
+            }
+            RPCMessage::PeerShouldBan(peer_id) => {
+                warn!(
+                    self.peer_log(&peer_id),
+                    "Banning peer for exceeding RPC limits"
+                );
+                self.ban_peer(peer_id);
+            }
+            RPCMessage::InboundRequestIdle(peer_id, id) => {
+                debug!(
+                    self.peer_log(&peer_id),
+                    "Closed idle RPC request";
+                    "request_id" => format!("{:?}", id)
+                );
             }
         }
     }
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<PingEvent>
+impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<IdentifyEvent>
+    for Behaviour<TSubstream>
+{
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        match event {
+            IdentifyEvent::Identified { peer_id, info, .. } => {
+                let client_info = ClientInfo::from(&info);
+                let log = self.peer_log(&peer_id);
+                debug!(log, "Identified peer";
+                    "protocol_version" => &client_info.protocol_version,
+                    "agent_version" => &client_info.agent_version
+                );
+
+                if is_banned_agent(&self.banned_agents, &client_info.agent_version) {
+                    debug!(log, "Disconnecting peer running banned client");
+                    self.ban_peer(peer_id);
+                    return;
+                }
+
+                self.discovery
+                    .add_identified_addresses(peer_id.clone(), info.listen_addrs.clone());
+                self.client_info.insert(peer_id, client_info);
+            }
+            IdentifyEvent::Error { .. } => {}
+            IdentifyEvent::SendBack { .. } => {}
+        }
+    }
+}
+
+impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<MdnsEvent>
     for Behaviour<TSubstream>
 {
-    fn inject_event(&mut self, _event: PingEvent) {
-        // not interested in ping responses at the moment.
+    fn inject_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(discovered) => self.mdns_discovered(discovered),
+            MdnsEvent::Expired(expired) => self.mdns_expired(expired),
+        }
     }
 }
 
 impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
-    /// Consumes the events list when polled.
-    fn poll<TBehaviourIn>(
-        &mut self,
-    ) -> Async<NetworkBehaviourAction<TBehaviourIn, BehaviourEvent>> {
-        if !self.events.is_empty() {
-            return Async::Ready(NetworkBehaviourAction::GenerateEvent(self.events.remove(0)));
+    /// Records the peers mDNS discovered on the local network, and dials them.
+    fn mdns_discovered(&mut self, discovered: impl IntoIterator<Item = (PeerId, Multiaddr)>) {
+        let mut peer_ids = Vec::new();
+        for (peer_id, address) in discovered {
+            debug!(self.peer_log(&peer_id), "Discovered peer via mDNS";
+                "address" => format!("{}", address)
+            );
+            self.discovery.add_mdns_address(peer_id.clone(), address);
+            peer_ids.push(peer_id);
         }
+        // dialed as the peers discv5 finds are
+        self.discovery.peers_found(peer_ids);
+    }
 
-        Async::NotReady
+    /// Forgets the addresses mDNS no longer finds peers on.
+    fn mdns_expired(&mut self, expired: impl IntoIterator<Item = (PeerId, Multiaddr)>) {
+        for (peer_id, address) in expired {
+            self.discovery.remove_mdns_address(&peer_id, &address);
+        }
     }
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<Discv5Event>
+impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<PingEvent>
     for Behaviour<TSubstream>
 {
-    fn inject_event(&mut self, _event: Discv5Event) {
-        // discv5 has no events to inject
+    fn inject_event(&mut self, event: PingEvent) {
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                self.peer_rtts.insert(event.peer.clone(), rtt);
+                if let Some(ping_sent) = Instant::now().checked_sub(rtt) {
+                    self.gossipsub.ping_returned(&event.peer, ping_sent);
+                }
+                if self.slow_peers.record_rtt(&event.peer, rtt) {
+                    warn!(self.peer_log(&event.peer), "Peer is chronically slow";
+                        "rtt" => format!("{:?}", rtt)
+                    );
+                }
+            }
+            Ok(PingSuccess::Pong) => {}
+            Err(_) => {
+                // failing peers are disconnected by the ping protocol, stop tracking them.
+                debug!(self.peer_log(&event.peer), "Ping failed");
+                self.slow_peers.remove(&event.peer);
+                self.peer_rtts.remove(&event.peer);
+            }
+        }
     }
 }
 
-/// Implements the combined behaviour for the libp2p service.
 impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
-    /* Pubsub behaviour functions */
+    /// Returns a logger whose messages are tagged with the peer.
+    fn peer_log(&self, peer_id: &PeerId) -> slog::Logger {
+        self.log.new(o!("peer" => short_peer_id(peer_id)))
+    }
 
-    /// Subscribes to a gossipsub topic.
-    pub fn subscribe(&mut self, topic: Topic) -> bool {
-        self.gossipsub.subscribe(topic)
+    /// Records how long the gossip message `id` took to validate, from its receipt to now, in
+    /// the `gossip_validation_seconds` histogram of its kind. Validation is measured once per
+    /// message; ids of messages not awaiting validation are ignored.
+    pub fn report_gossip_validation(&mut self, id: &[u8]) {
+        self.report_gossip_validation_at(id, Instant::now())
     }
 
-    /// Publishes a message on the pubsub (gossipsub) behaviour.
-    pub fn publish(&mut self, topics: Vec<Topic>, message: PubsubMessage) {
-        let message_bytes = ssz_encode(&message);
-        for topic in topics {
-            self.gossipsub.publish(topic, message_bytes.clone());
+    fn report_gossip_validation_at(&mut self, id: &[u8], now: Instant) {
+        if let Some((kind, received_at)) = self.awaiting_validation.validated(id) {
+            let elapsed = now.duration_since(received_at);
+            trace!(
+                self.log,
+                "Gossip message validated";
+                "kind" => kind,
+                "ms" => elapsed.as_millis() as u64
+            );
+            self.metrics
+                .gossip_validation_seconds
+                .with_label_values(&[kind])
+                .observe(elapsed.as_secs_f64());
         }
     }
 
-    /* Eth2 RPC behaviour functions */
-
-    /// Sends an RPC Request/Response via the RPC protocol.
-    pub fn send_rpc(&mut self, peer_id: PeerId, rpc_event: RPCEvent) {
-        self.serenity_rpc.send_rpc(peer_id, rpc_event);
+    /// Returns the id of a gossip message with `data` published on `topics`, the ids derived for
+    /// each of its topics in turn. A message on a single topic has the id derived for that topic.
+    fn message_id(&self, topics: &[TopicHash], data: &[u8]) -> MessageId {
+        topics
+            .iter()
+            .flat_map(|topic| self.message_id_fn.message_id(topic, data))
+            .collect()
     }
-}
 
-/// The types of events than can be obtained from polling the behaviour.
-pub enum BehaviourEvent {
-    RPC(PeerId, RPCEvent),
-    PeerDialed(PeerId),
-    GossipMessage {
-        source: PeerId,
-        topics: Vec<TopicHash>,
-        message: Box<PubsubMessage>,
-    },
-}
+    /// Handles the RPCs exchanged by the behaviour itself, passing all others to the application.
+    ///
+    /// Requests received while shutting down are dropped.
+    fn on_rpc(&mut self, peer_id: PeerId, event: RPCEvent) {
+        if !is_keep_alive(&event) {
+            self.record_activity(&peer_id, Instant::now());
+        }
+        if let RPCEvent::Request {
+            body: RPCRequest::Goodbye(_),
+            ..
+        } = &event
+        {
+            // a peer leaving of its own accord is not redialed for a while
+            if !self.is_trusted(&peer_id) {
+                self.discovery.cool_down(peer_id.clone());
+            }
+        }
 
-/// Messages that are passed to and from the pubsub (Gossipsub) behaviour.
-#[derive(Debug, Clone, PartialEq)]
-pub enum PubsubMessage {
-    /// Gossipsub message providing notification of a new block.
-    Block(BeaconBlock),
-    /// Gossipsub message providing notification of a new attestation.
-    Attestation(Attestation),
-}
+        if let RPCEvent::Request { .. } = event {
+            if self.is_shutting_down() {
+                debug!(
+                    self.peer_log(&peer_id),
+                    "Dropping RPC request received while shutting down"
+                );
+                return;
+            }
+        }
 
-//TODO: Correctly encode/decode enums. Prefixing with integer for now.
-impl Encode for PubsubMessage {
-    fn is_ssz_fixed_len() -> bool {
-        false
+        match event {
+            RPCEvent::Request {
+                id,
+                body: RPCRequest::MetaData,
+                ..
+            } => {
+                self.send_metadata(peer_id, id);
+            }
+            RPCEvent::Response {
+                result: RPCResponse::MetaData(metadata),
+                ..
+            } => {
+                self.peer_metadata.insert(peer_id, metadata);
+            }
+            RPCEvent::Request {
+                id,
+                body: RPCRequest::Ping(ping),
+                ..
+            } => {
+                self.send_pong(peer_id.clone(), id);
+                self.check_metadata_seq(peer_id, ping.data);
+            }
+            RPCEvent::Response {
+                result: RPCResponse::Ping(ping),
+                ..
+            } => {
+                self.rpc_ping_failures.remove(&peer_id);
+                self.check_metadata_seq(peer_id, ping.data);
+            }
+            event => {
+                if self.on_status_exchange(&peer_id, &event) {
+                    return;
+                }
+                match &event {
+                    RPCEvent::Request {
+                        body: RPCRequest::Hello(hello),
+                        ..
+                    }
+                    | RPCEvent::Response {
+                        result: RPCResponse::Hello(hello),
+                        ..
+                    } => {
+                        if !self.check_status(peer_id.clone(), hello) {
+                            return;
+                        }
+                        self.record_status(peer_id.clone(), hello, Instant::now())
+                    }
+                    _ => {}
+                }
+                if let RPCEvent::Error {
+                    method_id, error, ..
+                } = &event
+                {
+                    if RPCMethod::from(*method_id) == RPCMethod::Ping {
+                        match error {
+                            // the peer is leaving, its pending ping says nothing of its health
+                            RPCError::Disconnected => {}
+                            // the peer does not support pings, which is no sign it is unresponsive
+                            RPCError::NegotiationFailed
+                            | RPCError::Response {
+                                code: RPCErrorCode::InvalidRequest,
+                                ..
+                            } => {
+                                debug!(
+                                    self.peer_log(&peer_id),
+                                    "Peer does not support pings";
+                                    "error" => error.message()
+                                );
+                            }
+                            _ => self.penalise_ping_failure(peer_id, error),
+                        }
+                        return;
+                    }
+                    if RPCMethod::from(*method_id) == RPCMethod::BeaconBlocksByRange
+                        && *error == RPCError::Timeout
+                    {
+                        self.report_rpc_outcome(peer_id.clone(), RPCResponseQuality::TimedOut);
+                    }
+                    if let RPCError::ResponseTooLarge(_) = error {
+                        self.report_rpc_outcome(peer_id.clone(), RPCResponseQuality::Oversized);
+                    }
+                }
+                if let Some(event) = self.resolve_blocks_by_range(&peer_id, event) {
+                    self.events.push(BehaviourEvent::RPC(peer_id, event));
+                }
+            }
+        }
     }
 
-    fn ssz_append(&self, buf: &mut Vec<u8>) {
-        let offset = <u32 as Encode>::ssz_fixed_len() + <Vec<u8> as Encode>::ssz_fixed_len();
+    /// Feeds a response, stream termination or error for a request made through
+    /// `request_blocks_by_range` to the request, resolving it once it completes.
+    ///
+    /// Returns the event if it does not belong to such a request.
+    fn resolve_blocks_by_range(&mut self, peer_id: &PeerId, event: RPCEvent) -> Option<RPCEvent> {
+        // inbound requests carry ids chosen by the peer
+        if let RPCEvent::Request { .. } = event {
+            return Some(event);
+        }
+        let key = (peer_id.clone(), event.id());
+        let request = match self.blocks_by_range_requests.get_mut(&key) {
+            Some(request) => request,
+            None => return Some(event),
+        };
 
-        let mut encoder = ssz::SszEncoder::container(buf, offset);
+        match event {
+            RPCEvent::Response {
+                result: RPCResponse::BeaconBlocksByRange(block),
+                ..
+            } => request.blocks.push(*block),
+            RPCEvent::StreamTermination { .. } => {
+                if let Some(request) = self.blocks_by_range_requests.remove(&key) {
+                    // the caller may have dropped the future
+                    let _ = request.sender.send(Ok(request.blocks));
+                }
+            }
+            RPCEvent::Error { error, .. } => {
+                if let Some(request) = self.blocks_by_range_requests.remove(&key) {
+                    self.retry_blocks_by_range(peer_id.clone(), request, error);
+                }
+            }
+            event => return Some(event),
+        }
+        None
+    }
 
-        match self {
-            PubsubMessage::Block(block_gossip) => {
-                encoder.append(&0_u32);
+    /// Forgets the requests made through `request_blocks_by_range` whose caller has dropped the
+    /// future, so they are neither collected nor retried. Later responses to them are passed to
+    /// the application.
+    fn prune_blocks_by_range_requests(&mut self) {
+        self.blocks_by_range_requests
+            .retain(|_key, request| !request.sender.is_canceled());
+    }
 
-                // Encode the gossip as a Vec<u8>;
-                encoder.append(&block_gossip.as_ssz_bytes());
+    /// Reissues `request`, which failed with `error` from `peer_id`, to the best sync peer yet to
+    /// be tried, up to `range_sync_retries` times.
+    ///
+    /// Once no retries or untried peers remain, the request fails with `error` and a
+    /// `RangeSyncFailed` is emitted.
+    fn retry_blocks_by_range(
+        &mut self,
+        peer_id: PeerId,
+        mut request: BlocksByRangeRequest,
+        error: RPCError,
+    ) {
+        request.tried_peers.insert(peer_id);
+        if request.retries < self.range_sync_retries {
+            let next_peer = self.best_sync_peer_excluding(Instant::now(), &request.tried_peers);
+            if let Some(next_peer) = next_peer {
+                let body = RPCRequest::BeaconBlocksByRange(request.range.clone());
+                let sent = self.send_rpc(
+                    next_peer.clone(),
+                    RPCEvent::Request {
+                        id: RequestId::default(),
+                        method_id: body.method_id(),
+                        body,
+                    },
+                );
+                if let Some(id) = sent {
+                    debug!(
+                        self.peer_log(&next_peer),
+                        "Retrying blocks by range request";
+                        "start_slot" => request.range.start_slot.as_u64(),
+                        "count" => request.range.count,
+                        "error" => format!("{:?}", error)
+                    );
+                    request.retries += 1;
+                    request.blocks.clear();
+                    self.blocks_by_range_requests
+                        .insert((next_peer, id), request);
+                    return;
+                }
             }
-            PubsubMessage::Attestation(attestation_gossip) => {
-                encoder.append(&1_u32);
+        }
 
-                // Encode the gossip as a Vec<u8>;
-                encoder.append(&attestation_gossip.as_ssz_bytes());
-            }
+        let (start_slot, count) = (request.range.start_slot, request.range.count);
+        warn!(
+            self.log,
+            "Blocks by range request failed";
+            "start_slot" => start_slot.as_u64(),
+            "count" => count,
+            "peers_tried" => request.tried_peers.len(),
+            "error" => format!("{:?}", error)
+        );
+        self.events
+            .push(BehaviourEvent::RangeSyncFailed { start_slot, count });
+        // the caller may have dropped the future
+        let _ = request.sender.send(Err(error));
+    }
+
+    /// Requests the metadata of `peer_id` if `seq_number` differs from that of the metadata we
+    /// hold for the peer.
+    fn check_metadata_seq(&mut self, peer_id: PeerId, seq_number: u64) {
+        let known_seq_number = self
+            .peer_metadata
+            .get(&peer_id)
+            .map(|metadata| metadata.seq_number);
+        if known_seq_number != Some(seq_number) {
+            self.request_metadata(peer_id);
         }
+    }
 
-        encoder.finalize();
+    /// Records `now` as the time of the first block gossip if `message` is the first block.
+    fn record_first_block(&mut self, message: &PubsubMessage, now: Instant) {
+        if let PubsubMessage::Block(_) = message {
+            self.first_block_at.get_or_insert(now);
+        }
     }
-}
 
-impl Decode for PubsubMessage {
-    fn is_ssz_fixed_len() -> bool {
-        false
+    /// Records the chain state `peer_id` advertised in `status`, received at `now`.
+    fn record_status(&mut self, peer_id: PeerId, status: &HelloMessage, now: Instant) {
+        self.peer_chain_states.insert(
+            peer_id,
+            PeerChainState {
+                head_slot: status.best_slot,
+                finalized_epoch: status.latest_finalized_epoch,
+                updated_at: now,
+            },
+        );
     }
 
-    fn from_ssz_bytes(bytes: &[u8]) -> Result<Self, ssz::DecodeError> {
-        let mut builder = ssz::SszDecoderBuilder::new(&bytes);
+    /// Returns the chain state of every peer which has sent a status, as of `now`, ordered from
+    /// the highest advertised head slot down and then from the highest finalized epoch down.
+    fn peers_by_head_slot_at(&self, now: Instant) -> Vec<PeerChainInfo> {
+        let mut peers: Vec<PeerChainInfo> = self
+            .peer_chain_states
+            .keys()
+            .filter_map(|peer_id| self.peer_chain_info(peer_id, now))
+            .collect();
+        peers.sort_by(|a, b| {
+            (b.head_slot, b.finalized_epoch).cmp(&(a.head_slot, a.finalized_epoch))
+        });
+        peers
+    }
 
-        builder.register_type::<u32>()?;
-        builder.register_type::<Vec<u8>>()?;
+    /// Returns the chain state `peer_id` advertised in its latest status as of `now`, if it has
+    /// sent one.
+    fn peer_chain_info(&self, peer_id: &PeerId, now: Instant) -> Option<PeerChainInfo> {
+        self.peer_chain_states
+            .get(peer_id)
+            .map(|state| PeerChainInfo {
+                peer_id: peer_id.clone(),
+                head_slot: state.head_slot,
+                finalized_epoch: state.finalized_epoch,
+                updated_at: state.updated_at,
+                stale: now.duration_since(state.updated_at) > self.peer_status_max_age,
+            })
+    }
 
-        let mut decoder = builder.build()?;
+    /// Returns what we know of each connected peer, copied so the behaviour is not borrowed
+    /// beyond the call.
+    pub fn peer_info_snapshot(&self) -> Vec<PeerInfo> {
+        let now = Instant::now();
+        let mut topics: HashMap<&PeerId, Vec<TopicHash>> = HashMap::new();
+        for (topic, peers) in &self.topic_peers {
+            for peer_id in peers {
+                topics.entry(peer_id).or_default().push(topic.clone());
+            }
+        }
 
-        let id: u32 = decoder.decode_next()?;
-        let body: Vec<u8> = decoder.decode_next()?;
+        self.discovery
+            .connected_peer_ids()
+            .into_iter()
+            .map(|peer_id| PeerInfo {
+                address: self.discovery.connected_address(&peer_id).cloned(),
+                listen_addresses: self.discovery.identified_addresses(&peer_id),
+                rtt: self.peer_rtts.get(&peer_id).cloned(),
+                rpc_score: self.rpc_score(&peer_id),
+                client: self.client_info.get(&peer_id).cloned(),
+                topics: topics.remove(&peer_id).unwrap_or_default(),
+                chain: self.peer_chain_info(&peer_id, now),
+                trusted: self.is_trusted(&peer_id),
+                discovered_via_mdns: self.discovery.is_mdns_peer(&peer_id),
+                redial_at: self.discovery.redial_at(&peer_id),
+                peer_id,
+            })
+            .collect()
+    }
 
-        match id {
-            0 => Ok(PubsubMessage::Block(BeaconBlock::from_ssz_bytes(&body)?)),
-            1 => Ok(PubsubMessage::Attestation(Attestation::from_ssz_bytes(
-                &body,
-            )?)),
-            _ => Err(DecodeError::BytesInvalid(
-                "Invalid PubsubMessage id".to_string(),
-            )),
+    /// Handles the answer to a status request sent by the periodic status exchange, returning
+    /// `false` if `event` is not such an answer.
+    fn on_status_exchange(&mut self, peer_id: &PeerId, event: &RPCEvent) -> bool {
+        let id = match event {
+            RPCEvent::Response { id, .. } | RPCEvent::Error { id, .. } => *id,
+            _ => return false,
+        };
+        if !self.status_requests.remove(&(peer_id.clone(), id)) {
+            return false;
         }
+        match event {
+            RPCEvent::Response {
+                result: RPCResponse::Hello(status),
+                ..
+            } => {
+                self.status_failures.remove(peer_id);
+                if self.check_status(peer_id.clone(), status) {
+                    self.record_status(peer_id.clone(), status, Instant::now());
+                }
+            }
+            // the peer is leaving, its pending status says nothing of its health
+            RPCEvent::Error {
+                error: RPCError::Disconnected,
+                ..
+            } => {}
+            RPCEvent::Error { error, .. } => self.penalise_status_failure(peer_id.clone(), error),
+            // responses are decoded by the method of their request
+            _ => {}
+        }
+        true
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use types::*;
+    /// Records a failed status request to `peer_id`, lowering the peer's RPC score once it has
+    /// failed `MAX_STATUS_FAILURES` status requests in a row.
+    fn penalise_status_failure(&mut self, peer_id: PeerId, error: &RPCError) {
+        debug!(self.peer_log(&peer_id), "Status request failed"; "error" => error.message());
 
-    #[test]
-    fn ssz_encoding() {
-        let original = PubsubMessage::Block(BeaconBlock::empty(&MainnetEthSpec::default_spec()));
+        let failures = self.status_failures.entry(peer_id.clone()).or_insert(0);
+        *failures = failures.saturating_add(1);
+        if *failures >= MAX_STATUS_FAILURES {
+            self.status_failures.remove(&peer_id);
+            self.report_rpc_outcome(peer_id, RPCResponseQuality::TimedOut);
+        }
+    }
 
-        let encoded = ssz_encode(&original);
+    /// Records a failed Ping RPC to `peer_id`, banning the peer once it has failed
+    /// `MAX_RPC_PING_FAILURES` pings in a row.
+    fn penalise_ping_failure(&mut self, peer_id: PeerId, error: &RPCError) {
+        let log = self.peer_log(&peer_id);
+        debug!(log, "Ping RPC failed"; "error" => error.message());
 
-        let decoded = PubsubMessage::from_ssz_bytes(&encoded).unwrap();
+        let failures = self.rpc_ping_failures.entry(peer_id.clone()).or_insert(0);
+        *failures = failures.saturating_add(1);
+        if *failures >= MAX_RPC_PING_FAILURES {
+            warn!(log, "Banning peer for failing to answer pings");
+            self.rpc_ping_failures.remove(&peer_id);
+            self.ban_peer(peer_id);
+        }
+    }
 
-        assert_eq!(original, decoded);
+    /// Applies the commands sent through `NetworkSender`s.
+    fn poll_commands(&mut self) {
+        loop {
+            match self.command_recv.poll() {
+                Ok(Async::Ready(Some(command))) => self.apply_command(command),
+                // the behaviour holds a sender, the channel never closes
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) | Err(_) => break,
+            }
+        }
+    }
+
+    /// Applies a command sent through a `NetworkSender`.
+    fn apply_command(&mut self, command: NetworkCommand) {
+        trace!(self.log, "Applying network command"; "command" => format!("{:?}", command));
+        match command {
+            NetworkCommand::SendRpc(peer_id, rpc_event) => {
+                self.send_rpc(peer_id, rpc_event);
+            }
+            NetworkCommand::Publish { topics, message } => {
+                if let Err(e) = self.publish(topics, *message) {
+                    warn!(self.log, "Could not publish gossip message"; "error" => format!("{:?}", e));
+                }
+            }
+            NetworkCommand::Dial(peer_id) => self.discovery.queue_dial(peer_id),
+            NetworkCommand::ReportValidation(id) => self.report_gossip_validation(&id),
+            NetworkCommand::UpdateLocalStatus(status) => self.update_local_status(status),
+        }
+    }
+
+    /// Sends every connected peer a Ping RPC if a round of pings is due at `now`.
+    fn poll_rpc_ping(&mut self, now: Instant) {
+        if now < self.next_rpc_ping || self.is_shutting_down() {
+            return;
+        }
+        self.next_rpc_ping = now + self.rpc_ping_interval;
+        for peer_id in self.discovery.connected_peer_ids() {
+            self.send_ping(peer_id);
+        }
+    }
+
+    /// Sends our status to every peer we have exchanged statuses with if a status exchange is due
+    /// at `now`.
+    fn poll_status_exchange(&mut self, now: Instant) {
+        if now < self.next_status_exchange || self.is_shutting_down() {
+            return;
+        }
+        self.next_status_exchange = now + self.status_interval;
+        let status = match &self.local_status {
+            Some(status) => status.clone(),
+            None => return,
+        };
+        let peer_ids: Vec<PeerId> = self.peer_chain_states.keys().cloned().collect();
+        for peer_id in peer_ids {
+            let request = RPCEvent::Request {
+                id: RequestId::default(),
+                method_id: RPCMethod::Hello.into(),
+                body: RPCRequest::Hello(status.clone()),
+            };
+            if let Some(id) = self.serenity_rpc.send_rpc(peer_id.clone(), request) {
+                self.status_requests.insert((peer_id, id));
+            }
+        }
+    }
+
+    /// Disconnects, or bans, the departing peers our goodbye has been sent to, or which have not
+    /// accepted it by `now`.
+    fn poll_departing_peers(&mut self, now: Instant) {
+        let rpc = &self.serenity_rpc;
+        let events = &mut self.events;
+        self.departing_peers.retain(|peer_id, departure| {
+            if !rpc.is_connected(peer_id) {
+                return false;
+            }
+            if rpc.outbound_queue_len(peer_id) > 0 && now < departure.deadline {
+                return true;
+            }
+            events.push(if departure.ban {
+                BehaviourEvent::BanPeer(peer_id.clone())
+            } else {
+                BehaviourEvent::DisconnectPeer(peer_id.clone())
+            });
+            false
+        });
+    }
+
+    /// Records that `peer_id` sent us traffic beyond keep-alives at `now`, restarting its idle
+    /// timeout.
+    fn record_activity(&mut self, peer_id: &PeerId, now: Instant) {
+        if let Some(last_active) = self.last_active.get_mut(peer_id) {
+            *last_active = now;
+        }
+    }
+
+    /// Records activity at `now` for the peers sharing the subscribed `topics` a gossip message was
+    /// received on. The message's source is only who it claims to be from, and the peer which
+    /// relayed it is not known to us, so each peer which could have relayed it is credited.
+    fn record_gossip_activity(&mut self, topics: &[TopicHash], now: Instant) {
+        let relays: HashSet<PeerId> = topics
+            .iter()
+            .filter(|topic| self.subscriptions.contains_key(topic))
+            .filter_map(|topic| self.topic_peers.get(topic))
+            .flatten()
+            .cloned()
+            .collect();
+        for peer_id in &relays {
+            self.record_activity(peer_id, now);
+        }
+    }
+
+    /// Says goodbye to the peers other than trusted ones which have sent us no traffic beyond
+    /// keep-alives for `connection_idle_timeout` at `now`. Newly connected peers start their idle
+    /// timeout here.
+    fn poll_idle_peers(&mut self, now: Instant) {
+        let timeout = match self.connection_idle_timeout {
+            Some(timeout) if !self.is_shutting_down() => timeout,
+            _ => return,
+        };
+
+        let connected: HashSet<PeerId> = self.discovery.connected_peer_ids().into_iter().collect();
+        self.last_active
+            .retain(|peer_id, _last_active| connected.contains(peer_id));
+        for peer_id in connected {
+            self.last_active.entry(peer_id).or_insert(now);
+        }
+
+        let idle: Vec<PeerId> = self
+            .last_active
+            .iter()
+            .filter(|(peer_id, last_active)| {
+                now >= **last_active + timeout
+                    && !self.is_trusted(peer_id)
+                    && !self.departing_peers.contains_key(peer_id)
+            })
+            .map(|(peer_id, _last_active)| peer_id.clone())
+            .collect();
+        for peer_id in idle {
+            debug!(self.peer_log(&peer_id), "Disconnecting idle peer");
+            self.say_goodbye(peer_id, GoodbyeReason::Idle, false);
+        }
+    }
+
+    /// Returns the time the longest idle peer which may be disconnected reaches the idle
+    /// timeout, if the timeout is set.
+    fn next_idle_deadline(&self) -> Option<Instant> {
+        let timeout = self.connection_idle_timeout?;
+        self.last_active
+            .iter()
+            .filter(|(peer_id, _last_active)| {
+                !self.is_trusted(peer_id) && !self.departing_peers.contains_key(peer_id)
+            })
+            .map(|(_peer_id, last_active)| *last_active + timeout)
+            .min()
+    }
+
+    /// Emits `ShutdownComplete` once the goodbyes sent to our peers have been flushed, or the
+    /// shutdown has timed out at `now`.
+    fn poll_shutdown(&mut self, now: Instant) {
+        let shutdown = match &mut self.shutdown {
+            Some(shutdown) if !shutdown.complete => shutdown,
+            _ => return,
+        };
+
+        let rpc = &self.serenity_rpc;
+        shutdown
+            .unsent_goodbyes
+            .retain(|peer_id| rpc.is_connected(peer_id) && rpc.outbound_queue_len(peer_id) > 0);
+        if !shutdown.unsent_goodbyes.is_empty() && now < shutdown.deadline {
+            return;
+        }
+
+        debug!(
+            self.log,
+            "Network shutdown complete";
+            "unsent_goodbyes" => shutdown.unsent_goodbyes.len()
+        );
+        shutdown.complete = true;
+        self.events.push(BehaviourEvent::ShutdownComplete);
+    }
+
+    /// Emits a `PeerSummary` if one is due at `now`.
+    fn poll_peer_summary(&mut self, now: Instant) {
+        if now < self.next_peer_summary {
+            return;
+        }
+        self.next_peer_summary = now + self.peer_summary_interval;
+        let summary = self.peer_summary();
+        self.events.push(BehaviourEvent::PeerSummary(summary));
+    }
+
+    /// Emits a `LowPeerCount` or `RecoveredPeerCount` event once the connected peer count has
+    /// been on the other side of `min_peers` for `PEER_COUNT_DEBOUNCE` at `now`.
+    fn poll_peer_count(&mut self, now: Instant) {
+        let count = self.discovery.connected_peers();
+        let low = count < self.min_peers;
+        if low == self.low_peer_count {
+            self.peer_count_crossed_at = None;
+            return;
+        }
+        let crossed_at = *self.peer_count_crossed_at.get_or_insert(now);
+        if now < crossed_at + PEER_COUNT_DEBOUNCE {
+            return;
+        }
+
+        self.low_peer_count = low;
+        self.peer_count_crossed_at = None;
+        if low {
+            warn!(
+                self.log,
+                "Low peer count";
+                "connected_peers" => count,
+                "min_peers" => self.min_peers
+            );
+            self.events.push(BehaviourEvent::LowPeerCount(count));
+        } else {
+            debug!(self.log, "Peer count recovered"; "connected_peers" => count);
+            self.events.push(BehaviourEvent::RecoveredPeerCount(count));
+        }
+    }
+
+    /// Builds a summary of our peers.
+    fn peer_summary(&self) -> PeerSummary {
+        let mut clients = HashMap::new();
+        for client_info in self.client_info.values() {
+            *clients.entry(client_info.client_name()).or_insert(0) += 1;
+        }
+
+        let average_rtt = if self.peer_rtts.is_empty() {
+            None
+        } else {
+            let total: Duration = self.peer_rtts.values().sum();
+            Some(total / self.peer_rtts.len() as u32)
+        };
+
+        let topic_peers = self
+            .subscriptions
+            .keys()
+            .map(|topic| {
+                let peers = self.topic_peers.get(topic).map(HashSet::len).unwrap_or(0);
+                (topic.clone(), peers)
+            })
+            .collect();
+
+        PeerSummary {
+            connected_peers: self.discovery.connected_peers(),
+            clients,
+            average_rtt,
+            topic_peers,
+            last_peer_search: self.discovery.last_successful_search(),
+        }
+    }
+
+    /// Consumes the events list when polled.
+    fn poll<TBehaviourIn>(
+        &mut self,
+    ) -> Async<NetworkBehaviourAction<TBehaviourIn, BehaviourEvent>> {
+        self.published_since_poll = 0;
+        self.flush_publishes();
+        self.poll_commands();
+        if !self.publish_queue.is_empty() {
+            // publish the rest of the queue on the next poll
+            task::current().notify();
+        }
+
+        for peer_id in self.discovery.take_refused_peers() {
+            self.events.push(BehaviourEvent::DisconnectPeer(peer_id));
+        }
+        self.dismiss_excess_peers();
+
+        self.poll_peer_summary(Instant::now());
+        // wake up when the next summary is due
+        self.peer_summary_delay.reset(self.next_peer_summary);
+        if let Err(e) = self.peer_summary_delay.poll() {
+            warn!(self.log, "Peer summary timer failed"; "error" => format!("{:?}", e));
+        }
+
+        self.poll_rpc_ping(Instant::now());
+        self.rpc_ping_delay.reset(self.next_rpc_ping);
+        if let Err(e) = self.rpc_ping_delay.poll() {
+            warn!(self.log, "Ping RPC timer failed"; "error" => format!("{:?}", e));
+        }
+
+        self.poll_peer_count(Instant::now());
+        if let Some(crossed_at) = self.peer_count_crossed_at {
+            // wake up once the crossing has lasted long enough to be reported
+            self.peer_count_delay
+                .reset(crossed_at + PEER_COUNT_DEBOUNCE);
+            if let Err(e) = self.peer_count_delay.poll() {
+                warn!(self.log, "Peer count timer failed"; "error" => format!("{:?}", e));
+            }
+        }
+
+        self.poll_status_exchange(Instant::now());
+        self.status_exchange_delay.reset(self.next_status_exchange);
+        if let Err(e) = self.status_exchange_delay.poll() {
+            warn!(self.log, "Status exchange timer failed"; "error" => format!("{:?}", e));
+        }
+
+        self.poll_departing_peers(Instant::now());
+        self.prune_blocks_by_range_requests();
+
+        self.poll_idle_peers(Instant::now());
+        if let Some(deadline) = self.next_idle_deadline() {
+            self.idle_peer_delay.reset(deadline);
+            if let Err(e) = self.idle_peer_delay.poll() {
+                warn!(self.log, "Idle peer timer failed"; "error" => format!("{:?}", e));
+            }
+        }
+
+        self.poll_ban_expiries(Instant::now());
+        self.poll_rpc_penalty_expiries(Instant::now());
+        if let Some(expiry) = self.discovery.next_ban_expiry() {
+            // wake up when the earliest ban ends
+            self.ban_expiry_delay.reset(expiry);
+            if let Err(e) = self.ban_expiry_delay.poll() {
+                warn!(self.log, "Ban expiry timer failed"; "error" => format!("{:?}", e));
+            }
+        }
+
+        self.poll_shutdown(Instant::now());
+        if let Some(shutdown) = &mut self.shutdown {
+            // wake up if the goodbyes are not flushed in time
+            if let Err(e) = shutdown.delay.poll() {
+                warn!(self.log, "Shutdown timer failed"; "error" => format!("{:?}", e));
+            }
+        }
+
+        if !self.events.is_empty() {
+            return Async::Ready(NetworkBehaviourAction::GenerateEvent(self.events.remove(0)));
+        }
+
+        Async::NotReady
+    }
+}
+
+impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<DiscoveryEvent>
+    for Behaviour<TSubstream>
+{
+    fn inject_event(&mut self, event: DiscoveryEvent) {
+        match event {
+            DiscoveryEvent::PeerDiscovered(peer_id) => {
+                trace!(self.log, "Peer discovered"; "peer" => format!("{:?}", peer_id));
+                self.events.push(BehaviourEvent::PeerDiscovered(peer_id));
+            }
+            DiscoveryEvent::DialFailed { addr, kind } => {
+                self.events.push(BehaviourEvent::DialFailed { addr, kind });
+            }
+            DiscoveryEvent::SubnetPeersFound(subnet_id, count) => {
+                self.events
+                    .push(BehaviourEvent::SubnetPeersFound(subnet_id, count));
+            }
+            DiscoveryEvent::SubnetQueryTimedOut(subnet_id, count) => {
+                self.events
+                    .push(BehaviourEvent::SubnetQueryTimedOut(subnet_id, count));
+            }
+        }
+    }
+}
+
+/// Implements the combined behaviour for the libp2p service.
+impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
+    /* Pubsub behaviour functions */
+
+    /// Subscribes to a gossipsub topic.
+    pub fn subscribe(&mut self, topic: Topic) -> bool {
+        let subscribed = self.gossipsub.subscribe(topic.clone());
+        if subscribed {
+            self.subscriptions.insert(topic.hash().clone(), topic);
+        }
+        subscribed
+    }
+
+    /// Unsubscribes from a gossipsub topic.
+    pub fn unsubscribe(&mut self, topic: Topic) -> bool {
+        let topic_hash = topic.hash().clone();
+        let unsubscribed = self.gossipsub.unsubscribe(topic);
+        if unsubscribed {
+            self.subscriptions.remove(&topic_hash);
+        }
+        unsubscribed
+    }
+
+    /// Subscribes to the topics of exactly the attestation `subnets`, such as those our validators
+    /// are assigned to this epoch, and advertises them in our metadata and ENR. Only the subnets
+    /// added since the last update are subscribed to and only those removed are unsubscribed
+    /// from. Subnets beyond `ATTESTATION_SUBNET_COUNT` are ignored.
+    pub fn update_subnet_subscriptions(&mut self, mut subnets: HashSet<SubnetId>) {
+        let log = &self.log;
+        subnets.retain(|subnet_id| {
+            let exists = (*subnet_id as usize) < ATTESTATION_SUBNET_COUNT;
+            if !exists {
+                warn!(log, "Ignoring unknown attestation subnet"; "subnet_id" => subnet_id);
+            }
+            exists
+        });
+        let removed: Vec<SubnetId> = self
+            .subnet_subscriptions
+            .difference(&subnets)
+            .cloned()
+            .collect();
+        let added: Vec<SubnetId> = subnets
+            .difference(&self.subnet_subscriptions)
+            .cloned()
+            .collect();
+        if !removed.is_empty() || !added.is_empty() {
+            debug!(
+                self.log,
+                "Updating subnet subscriptions";
+                "subscribing" => format!("{:?}", added),
+                "unsubscribing" => format!("{:?}", removed)
+            );
+        }
+
+        for subnet_id in removed {
+            self.unsubscribe(TopicBuilder::new(attestation_subnet_topic(subnet_id)).build());
+        }
+        for subnet_id in added {
+            self.subscribe(TopicBuilder::new(attestation_subnet_topic(subnet_id)).build());
+        }
+
+        let mut attnets = Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT);
+        for subnet_id in &subnets {
+            attnets.set(*subnet_id as usize, true);
+        }
+        self.update_metadata(attnets);
+        self.subnet_subscriptions = subnets;
+    }
+
+    /// Returns the attestation subnets subscribed to by `update_subnet_subscriptions`.
+    pub fn subnet_subscriptions(&self) -> &HashSet<SubnetId> {
+        &self.subnet_subscriptions
+    }
+
+    /// Returns `true` if we are subscribed to the gossipsub topic.
+    pub fn is_subscribed(&self, topic: &TopicHash) -> bool {
+        self.subscriptions.contains_key(topic)
+    }
+
+    /// Publishes a message on the pubsub (gossipsub) behaviour.
+    pub fn publish(&mut self, topics: Vec<Topic>, message: PubsubMessage) -> error::Result<()> {
+        self.publish_raw(topics, ssz_encode(&message))
+    }
+
+    /// Publishes pre-encoded bytes on the pubsub (gossipsub) behaviour.
+    ///
+    /// The caller is responsible for `data` being a correctly SSZ encoded `PubsubMessage`.
+    /// Messages are signed if configured. Fails if the message cannot be signed, is then larger
+    /// than `GOSSIP_MAX_SIZE`, gossip is paused or the behaviour is shutting down. The message is
+    /// published on either all of `topics` or, on failure, none of them.
+    ///
+    /// Beyond `MAX_PUBLISHES_PER_POLL` publishes between polls, messages are queued and published
+    /// on the following polls, blocks ahead of attestations.
+    ///
+    /// In observer mode nothing is published, and the publish succeeds.
+    pub fn publish_raw(&mut self, topics: Vec<Topic>, data: Vec<u8>) -> error::Result<()> {
+        if self.observer_mode {
+            debug!(
+                self.log,
+                "Not publishing in observer mode";
+                "topics" => format!("{:?}", topics),
+                "size" => data.len()
+            );
+            return Ok(());
+        }
+        let is_block = is_block_gossip(&data);
+        let data = self.prepare_gossip(data)?;
+
+        if self.publish_queue.is_empty() && self.published_since_poll < MAX_PUBLISHES_PER_POLL {
+            self.send_gossip(topics, data);
+            return Ok(());
+        }
+        let publish = QueuedPublish {
+            is_block,
+            topics,
+            data,
+        };
+        if self.publish_queue.push(publish) {
+            Ok(())
+        } else {
+            Err("Gossip publish queue is full".into())
+        }
+    }
+
+    /// Publishes queued messages, blocks first, until `MAX_PUBLISHES_PER_POLL` is reached.
+    fn flush_publishes(&mut self) {
+        if self.gossip_paused {
+            return;
+        }
+        while self.published_since_poll < MAX_PUBLISHES_PER_POLL {
+            match self.publish_queue.pop() {
+                Some(publish) => self.send_gossip(publish.topics, publish.data),
+                None => break,
+            }
+        }
+    }
+
+    /// Hands a prepared message to the gossipsub behaviour on every topic.
+    fn send_gossip(&mut self, topics: Vec<Topic>, data: Vec<u8>) {
+        self.published_since_poll += 1;
+        for topic in topics {
+            if cfg!(feature = "metrics") {
+                let label = topic.hash().clone().into_string();
+                self.metrics
+                    .gossip_topic_publishes
+                    .with_label_values(&[&label])
+                    .inc();
+                self.metrics
+                    .gossip_published_bytes
+                    .with_label_values(&[&label])
+                    .observe(data.len() as f64);
+            }
+            self.gossipsub.publish(topic, data.clone());
+            self.metrics.gossip_messages_published.inc();
+        }
+    }
+
+    /// Signs and size-checks an encoded message, returning the bytes to publish.
+    ///
+    /// Every check that can fail a publish is made here, before any topic is published on.
+    fn prepare_gossip(&self, data: Vec<u8>) -> error::Result<Vec<u8>> {
+        if self.is_shutting_down() {
+            return Err("Cannot publish while shutting down".into());
+        }
+        if self.gossip_paused {
+            return Err("Cannot publish while gossip is paused".into());
+        }
+
+        let data = match &self.signing_key {
+            Some(key) => sign_gossip(key, data)
+                .map_err(|e| format!("Could not sign gossip message: {:?}", e))?,
+            None => data,
+        };
+
+        if data.len() > GOSSIP_MAX_SIZE {
+            return Err(format!(
+                "Gossip message of {} bytes exceeds the maximum of {}",
+                data.len(),
+                GOSSIP_MAX_SIZE
+            )
+            .into());
+        }
+        Ok(data)
+    }
+
+    /// Starts a graceful shutdown, saying goodbye to every connected peer.
+    ///
+    /// Gossip and RPC requests received from then on are dropped, and publishing and sending RPCs
+    /// fail. `BehaviourEvent::ShutdownComplete` is emitted once the goodbyes have been sent, or
+    /// after `SHUTDOWN_TIMEOUT`, after which the swarm can be dropped.
+    pub fn shutdown(&mut self) {
+        if self.is_shutting_down() {
+            return;
+        }
+
+        let mut unsent_goodbyes = HashSet::new();
+        for peer_id in self.discovery.connected_peer_ids() {
+            let goodbye = RPCRequest::Goodbye(GoodbyeReason::ClientShutdown);
+            let sent = self.serenity_rpc.send_rpc(
+                peer_id.clone(),
+                RPCEvent::Request {
+                    id: RequestId::default(),
+                    method_id: goodbye.method_id(),
+                    body: goodbye,
+                },
+            );
+            if sent.is_some() {
+                unsent_goodbyes.insert(peer_id);
+            }
+        }
+        debug!(self.log, "Shutting down network"; "peers" => unsent_goodbyes.len());
+        self.discovery.save_peer_cache();
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        self.shutdown = Some(Shutdown {
+            unsent_goodbyes,
+            deadline,
+            delay: Delay::new(deadline),
+            complete: false,
+        });
+    }
+
+    /// Resets our view of the network, for when a fork or reorg has invalidated it.
+    ///
+    /// Every connected peer is sent a goodbye for `reason` and disconnected once it is sent or
+    /// after `GOODBYE_TIMEOUT`. What we have learned of our peers and the gossip messages we have
+    /// seen are forgotten, and our topics are subscribed to again. Unlike a shutdown, the
+    /// behaviour keeps running, connecting to new peers as usual.
+    pub fn reset_network(&mut self, reason: u64) {
+        if self.is_shutting_down() {
+            return;
+        }
+        let reason = GoodbyeReason::from(reason);
+        let peer_ids = self.discovery.connected_peer_ids();
+        debug!(self.log, "Resetting network";
+            "peers" => peer_ids.len(),
+            "reason" => format!("{:?}", reason)
+        );
+        for peer_id in peer_ids {
+            if !self.departing_peers.contains_key(&peer_id) {
+                self.say_goodbye(peer_id, reason.clone(), false);
+            }
+        }
+
+        self.seen_messages.clear();
+        self.awaiting_validation.clear();
+        self.peer_rtts.clear();
+        self.slow_peers = SlowPeers::default();
+        self.rpc_scores.clear();
+        self.rpc_penalties_kept_until.clear();
+        self.rpc_failed_at.clear();
+
+        let topics: Vec<Topic> = self.subscriptions.values().cloned().collect();
+        for topic in &topics {
+            self.unsubscribe(topic.clone());
+        }
+        self.topic_peers.clear();
+        for topic in topics {
+            self.subscribe(topic);
+        }
+    }
+
+    /// Stops processing gossip, for instance during heavy database maintenance, without
+    /// disconnecting peers. Until `resume_gossip`, inbound gossip messages are dropped without
+    /// being scored, publishing fails and the publishes already queued are held back. RPC, pings
+    /// and discovery carry on.
+    pub fn pause_gossip(&mut self) {
+        if !self.gossip_paused {
+            info!(self.log, "Pausing gossip");
+            self.gossip_paused = true;
+        }
+    }
+
+    /// Resumes processing gossip after `pause_gossip`. The messages held back are published from
+    /// the next poll.
+    pub fn resume_gossip(&mut self) {
+        if self.gossip_paused {
+            info!(self.log, "Resuming gossip");
+            self.gossip_paused = false;
+        }
+    }
+
+    /// Returns `true` while gossip is paused.
+    pub fn is_gossip_paused(&self) -> bool {
+        self.gossip_paused
+    }
+
+    /// Returns `true` once a shutdown has been started.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_some()
+    }
+
+    /// Returns a handle through which the behaviour can be instructed from outside the swarm
+    /// task.
+    pub fn network_sender(&self) -> NetworkSender {
+        NetworkSender {
+            sender: self.command_send.clone(),
+        }
+    }
+
+    /// Returns `true` if the peer has repeatedly been slow to respond to pings.
+    pub fn is_slow_peer(&self, peer_id: &PeerId) -> bool {
+        self.slow_peers.is_slow(peer_id)
+    }
+
+    /// Returns `true` if gossip messages to the peer are being skipped, as too many are queued
+    /// for it already.
+    pub fn is_gossip_backed_up(&self, peer_id: &PeerId) -> bool {
+        self.gossipsub.is_backed_up(peer_id)
+    }
+
+    /* Eth2 RPC behaviour functions */
+
+    /// Sends an RPC Request/Response via the RPC protocol.
+    ///
+    /// Returns the id of the request the event belongs to, or `None` if the event was dropped.
+    /// Requests are assigned a new id, which is carried on every response or error for them.
+    ///
+    /// Requests to a peer we are not connected to are sent once the peer has been dialed, or
+    /// failed with `RPCError::DialFailed` if it cannot be. All RPCs are dropped once the behaviour
+    /// is shutting down.
+    pub fn send_rpc(&mut self, peer_id: PeerId, rpc_event: RPCEvent) -> Option<RequestId> {
+        if self.is_shutting_down() {
+            debug!(self.log, "Not sending RPC while shutting down");
+            return None;
+        }
+        if self.is_undialable(&peer_id) {
+            return self.serenity_rpc.fail_request(peer_id, rpc_event);
+        }
+        self.serenity_rpc.send_rpc(peer_id, rpc_event)
+    }
+
+    /// Sends an RPC Request/Response via the RPC protocol, overriding the configured request
+    /// timeout. See `Rpc::send_rpc_with_timeout` for the bounds the timeout is clamped to.
+    pub fn send_rpc_with_timeout(
+        &mut self,
+        peer_id: PeerId,
+        rpc_event: RPCEvent,
+        timeout: Duration,
+    ) -> Option<RequestId> {
+        if self.is_shutting_down() {
+            debug!(self.log, "Not sending RPC while shutting down");
+            return None;
+        }
+        if self.is_undialable(&peer_id) {
+            return self.serenity_rpc.fail_request(peer_id, rpc_event);
+        }
+        self.serenity_rpc
+            .send_rpc_with_timeout(peer_id, rpc_event, timeout)
+    }
+
+    /// Requests a range of blocks from `peer_id`, returning a future which resolves to the blocks
+    /// once the peer terminates its response stream, or to the error which ended the request.
+    ///
+    /// The request is sent as with `send_rpc`, but its responses are collected by the behaviour
+    /// rather than emitted as `BehaviourEvent::RPC`. Requests the behaviour drops, for instance
+    /// while shutting down, resolve to `RPCError::NotSent`.
+    pub fn request_blocks_by_range(
+        &mut self,
+        peer_id: PeerId,
+        request: BeaconBlocksByRangeRequest,
+    ) -> impl Future<Item = Vec<BeaconBlock>, Error = RPCError> {
+        let (sender, receiver) = oneshot::channel();
+        let body = RPCRequest::BeaconBlocksByRange(request.clone());
+        let sent = self.send_rpc(
+            peer_id.clone(),
+            RPCEvent::Request {
+                id: RequestId::default(),
+                method_id: body.method_id(),
+                body,
+            },
+        );
+        match sent {
+            Some(id) => {
+                self.blocks_by_range_requests.insert(
+                    (peer_id, id),
+                    BlocksByRangeRequest {
+                        range: request,
+                        blocks: vec![],
+                        sender,
+                        retries: 0,
+                        tried_peers: HashSet::new(),
+                    },
+                );
+            }
+            None => {
+                let _ = sender.send(Err(RPCError::NotSent));
+            }
+        }
+
+        // the sender is only dropped with the behaviour, which takes the connection with it
+        receiver.then(|result| result.unwrap_or(Err(RPCError::Disconnected)))
+    }
+
+    /// Registers `handler` to answer inbound RPC requests of its methods, off the swarm thread.
+    ///
+    /// Requests the handler answers are not emitted as `BehaviourEvent::RPC`.
+    pub fn set_request_handler(&mut self, handler: Arc<dyn RequestHandler>) -> error::Result<()> {
+        self.serenity_rpc
+            .set_request_handler(handler)
+            .map_err(|e| format!("Could not start RPC request handler: {:?}", e).into())
+    }
+
+    /// Registers `observer` to see every gossip message received before it is decoded, replacing
+    /// any observer already set, or removes the observer if `None`.
+    ///
+    /// The observer is called on the swarm thread, so should hand the message off rather than
+    /// block.
+    pub fn set_gossip_observer(&mut self, observer: Option<Arc<dyn GossipObserver>>) {
+        self.gossip_observer = observer;
+    }
+
+    /// Returns `true` if we are not connected to `peer_id` and know no address to dial it on.
+    fn is_undialable(&mut self, peer_id: &PeerId) -> bool {
+        !self.serenity_rpc.is_connected(peer_id)
+            && !self.discovery.has_addresses(peer_id)
+            && !self.discovery.is_mdns_peer(peer_id)
+    }
+
+    /// Fails the inbound RPC request `id` from `peer_id` with the given error code and message.
+    ///
+    /// Returns `None` if the peer is not awaiting a response to the request.
+    pub fn send_rpc_error(
+        &mut self,
+        peer_id: PeerId,
+        id: RequestId,
+        code: RPCErrorCode,
+        message: Option<String>,
+    ) -> Option<RequestId> {
+        self.serenity_rpc.send_error(peer_id, id, code, message)
+    }
+
+    /// Sends `response` as the next chunk of the streamed response to the inbound RPC request
+    /// `id` from `peer_id`. The stream stays open until `terminate_rpc_stream` is called.
+    ///
+    /// Returns `None` if the peer is not awaiting a response to the request or the chunk exceeds
+    /// `MAX_CHUNK_SIZE`.
+    pub fn send_rpc_stream_chunk(
+        &mut self,
+        peer_id: PeerId,
+        id: RequestId,
+        response: RPCResponse,
+    ) -> Option<RequestId> {
+        self.serenity_rpc.send_stream_chunk(peer_id, id, response)
+    }
+
+    /// Terminates the streamed response to the inbound RPC request `id` from `peer_id`.
+    pub fn terminate_rpc_stream(&mut self, peer_id: PeerId, id: RequestId) -> Option<RequestId> {
+        self.serenity_rpc.terminate_stream(peer_id, id)
+    }
+
+    /// Returns the version of the RPC method `method_id` negotiated with the peer, if known.
+    pub fn peer_rpc_version(&self, peer_id: &PeerId, method_id: u16) -> Option<RPCVersion> {
+        self.serenity_rpc.peer_version(peer_id, method_id)
+    }
+
+    /// Overrides the number of RPC requests which may be in flight to `peer_id` at once, or
+    /// restores the configured `max_requests_in_flight` if `cap` is `None`.
+    pub fn set_rpc_request_cap(&mut self, peer_id: PeerId, cap: Option<usize>) {
+        self.serenity_rpc.set_request_cap(peer_id, cap)
+    }
+
+    /// Returns the version of each RPC method negotiated with each peer, keyed by method id.
+    ///
+    /// This allows preferring peers which support newer versions of a method.
+    pub fn rpc_capabilities(&self) -> &HashMap<PeerId, HashMap<u16, RPCVersion>> {
+        self.serenity_rpc.peer_capabilities()
+    }
+
+    /// Requests the metadata of `peer_id`. The response is available from `peer_metadata` once
+    /// it arrives.
+    pub fn request_metadata(&mut self, peer_id: PeerId) -> Option<RequestId> {
+        self.serenity_rpc.send_rpc(
+            peer_id,
+            RPCEvent::Request {
+                id: RequestId::default(),
+                method_id: RPCMethod::MetaData.into(),
+                body: RPCRequest::MetaData,
+            },
+        )
+    }
+
+    /// Answers the metadata request `id` from `peer_id` with our current metadata.
+    fn send_metadata(&mut self, peer_id: PeerId, id: RequestId) -> Option<RequestId> {
+        let response = self.metadata_response(id);
+        self.serenity_rpc.send_rpc(peer_id, response)
+    }
+
+    /// Builds the response to the metadata request `id`.
+    fn metadata_response(&self, id: RequestId) -> RPCEvent {
+        RPCEvent::Response {
+            id,
+            method_id: RPCMethod::MetaData.into(),
+            result: RPCResponse::MetaData(self.metadata.clone()),
+        }
+    }
+
+    /// Sends `peer_id` a Ping RPC carrying our metadata sequence number.
+    fn send_ping(&mut self, peer_id: PeerId) -> Option<RequestId> {
+        let ping = PingMessage {
+            data: self.metadata.seq_number,
+        };
+        self.serenity_rpc.send_rpc(
+            peer_id,
+            RPCEvent::Request {
+                id: RequestId::default(),
+                method_id: RPCMethod::Ping.into(),
+                body: RPCRequest::Ping(ping),
+            },
+        )
+    }
+
+    /// Answers the Ping RPC `id` from `peer_id` with our metadata sequence number.
+    fn send_pong(&mut self, peer_id: PeerId, id: RequestId) -> Option<RequestId> {
+        let pong = PingMessage {
+            data: self.metadata.seq_number,
+        };
+        self.serenity_rpc.send_rpc(
+            peer_id,
+            RPCEvent::Response {
+                id,
+                method_id: RPCMethod::Ping.into(),
+                result: RPCResponse::Ping(pong),
+            },
+        )
+    }
+
+    /// Returns our ENR, the record peers discover us by.
+    pub fn local_enr(&self) -> Enr {
+        self.discovery.local_enr().clone()
+    }
+
+    /// Updates the IP and TCP port peers are told to reach us on in our ENR, bumping its sequence
+    /// number so the change propagates.
+    pub fn update_local_address(&mut self, ip: IpAddr, tcp_port: u16) -> error::Result<()> {
+        self.discovery.update_local_address(ip, tcp_port)
+    }
+
+    /// Returns the metadata we advertise to our peers.
+    pub fn metadata(&self) -> &MetaData {
+        &self.metadata
+    }
+
+    /// Sets the attestation subnets we advertise, in our metadata and our ENR, incrementing the
+    /// sequence number of each if they have changed.
+    pub fn update_metadata(&mut self, attnets: Bitfield) {
+        if self.metadata.attnets != attnets {
+            if let Err(e) = self.discovery.update_attnets(&attnets) {
+                warn!(self.log, "Could not advertise subnets in ENR"; "error" => format!("{}", e));
+            }
+            self.metadata.attnets = attnets;
+            self.metadata.seq_number += 1;
+        }
+    }
+
+    /// Sets the status sent to our peers by the periodic status exchange. A change of our
+    /// finalized checkpoint is sent to our peers immediately.
+    ///
+    /// The network id and genesis root of the status are replaced with those configured.
+    pub fn update_local_status(&mut self, mut status: HelloMessage) {
+        status.network_id = self.network_id;
+        status.genesis_root = self.genesis_root;
+        let finalized_changed = self.local_status.as_ref().map_or(false, |local| {
+            local.latest_finalized_epoch != status.latest_finalized_epoch
+                || local.latest_finalized_root != status.latest_finalized_root
+        });
+        self.local_status = Some(status);
+        if finalized_changed {
+            self.next_status_exchange = Instant::now();
+        }
+    }
+
+    /// Adjusts the RPC score of `peer_id` by the usefulness of its response to one of our
+    /// requests, banning the peer once its score falls to `MIN_RPC_SCORE`.
+    ///
+    /// Timed out `BeaconBlocksByRange` requests are reported by the behaviour itself.
+    pub fn report_rpc_outcome(&mut self, peer_id: PeerId, quality: RPCResponseQuality) {
+        if quality.is_failure() {
+            self.rpc_failed_at.insert(peer_id.clone(), Instant::now());
+        }
+        let score = self.rpc_scores.entry(peer_id.clone()).or_insert(0);
+        *score = (*score + quality.score_delta()).min(MAX_RPC_SCORE);
+        let score = *score;
+        self.discovery.cache_peer_score(&peer_id, score);
+        if score <= MIN_RPC_SCORE {
+            warn!(
+                self.peer_log(&peer_id),
+                "Banning peer for unhelpful RPC responses"
+            );
+            self.rpc_scores.remove(&peer_id);
+            self.ban_peer(peer_id);
+        }
+    }
+
+    /// Forgets the RPC score of the disconnected `peer_id`, unless it is negative, in which case
+    /// it is kept for `RPC_PENALTY_RETENTION` after `now` in case the peer reconnects.
+    fn keep_rpc_penalty(&mut self, peer_id: &PeerId, now: Instant) {
+        if self.rpc_score(peer_id) < 0 {
+            self.rpc_penalties_kept_until
+                .insert(peer_id.clone(), now + RPC_PENALTY_RETENTION);
+        } else {
+            self.rpc_scores.remove(peer_id);
+        }
+    }
+
+    /// Forgets the negative RPC scores of the disconnected peers which have been kept until `now`.
+    /// The scores of peers which have reconnected are kept for as long as they stay connected.
+    fn poll_rpc_penalty_expiries(&mut self, now: Instant) {
+        let discovery = &self.discovery;
+        let rpc_scores = &mut self.rpc_scores;
+        self.rpc_penalties_kept_until.retain(|peer_id, kept_until| {
+            if discovery.connected_address(peer_id).is_some() {
+                return false;
+            }
+            if *kept_until <= now {
+                rpc_scores.remove(peer_id);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Returns the RPC score of `peer_id`. Peers start with a score of zero.
+    pub fn rpc_score(&self, peer_id: &PeerId) -> i32 {
+        self.rpc_scores.get(peer_id).cloned().unwrap_or(0)
+    }
+
+    /// Returns the best peer to request the next range of blocks from, if any peer is suitable.
+    ///
+    /// Suitable peers have sent a status that is not stale and, once our own status is known, is
+    /// ahead of it, are not being disconnected or banned, are not chronically slow, and have not
+    /// failed one of our RPC requests within `SYNC_PEER_FAILURE_BACKOFF`. Of these the peer with
+    /// the lowest ping round-trip time is chosen, peers yet to be pinged last, then the peer with
+    /// the highest head slot.
+    pub fn best_sync_peer(&self) -> Option<PeerId> {
+        self.best_sync_peer_at(Instant::now())
+    }
+
+    /// Returns the best peer to sync from as of `now`. See `best_sync_peer`.
+    fn best_sync_peer_at(&self, now: Instant) -> Option<PeerId> {
+        self.best_sync_peer_excluding(now, &HashSet::new())
+    }
+
+    /// Returns the best peer to sync from as of `now` which is not in `excluded`.
+    fn best_sync_peer_excluding(&self, now: Instant, excluded: &HashSet<PeerId>) -> Option<PeerId> {
+        let local_head_slot = self.local_status.as_ref().map(|status| status.best_slot);
+        self.peer_chain_states
+            .iter()
+            .filter(|&(peer_id, state)| {
+                !excluded.contains(peer_id)
+                    && now.duration_since(state.updated_at) <= self.peer_status_max_age
+                    && local_head_slot.map_or(true, |head_slot| state.head_slot > head_slot)
+                    && !self.banned_peers.contains(peer_id)
+                    && !self.departing_peers.contains_key(peer_id)
+                    && !self.slow_peers.is_slow(peer_id)
+                    && self.rpc_failed_at.get(peer_id).map_or(true, |failed_at| {
+                        now.duration_since(*failed_at) >= SYNC_PEER_FAILURE_BACKOFF
+                    })
+            })
+            .min_by_key(|&(peer_id, state)| {
+                let rtt = self.peer_rtts.get(peer_id);
+                (rtt.is_none(), rtt.cloned(), Reverse(state.head_slot))
+            })
+            .map(|(peer_id, _state)| peer_id.clone())
+    }
+
+    /// Checks the status a peer sent in the handshake against our `local` status, returning `true`
+    /// if the peer is on our network and finalized chain.
+    ///
+    /// Incompatible peers are sent a goodbye, disconnected once it is sent, and not dialed again
+    /// for `INCOMPATIBLE_PEER_DIAL_BACKOFF`, unless `keep_incompatible_peers` is configured.
+    pub fn verify_status(
+        &mut self,
+        peer_id: PeerId,
+        local: &HelloMessage,
+        remote: &HelloMessage,
+    ) -> bool {
+        match status_mismatch(local, remote) {
+            Some(reason) => self.reject_incompatible_peer(peer_id, reason),
+            None => true,
+        }
+    }
+
+    /// Checks the status a peer sent against our configured network id and genesis root,
+    /// returning `true` if the peer is on our network. Peers on another network are treated as by
+    /// `verify_status`, and their status ignored.
+    fn verify_network(&mut self, peer_id: PeerId, remote: &HelloMessage) -> bool {
+        let reason = if remote.network_id != self.network_id {
+            "network_id"
+        } else if remote.genesis_root != self.genesis_root {
+            "genesis_root"
+        } else {
+            return true;
+        };
+        self.reject_incompatible_peer(peer_id, reason)
+    }
+
+    /// Checks the status a peer sent against our network and, once it is known through
+    /// `update_local_status`, our finalized chain, returning `true` if the peer is compatible.
+    /// Incompatible peers are treated as by `verify_status`.
+    fn check_status(&mut self, peer_id: PeerId, remote: &HelloMessage) -> bool {
+        if !self.verify_network(peer_id.clone(), remote) {
+            return false;
+        }
+        match self.local_status.clone() {
+            Some(local) => self.verify_status(peer_id, &local, remote),
+            None => true,
+        }
+    }
+
+    /// Says goodbye to a peer found incompatible for `reason`, unless `keep_incompatible_peers` is
+    /// configured. Returns `false`, the peer being incompatible either way.
+    fn reject_incompatible_peer(&mut self, peer_id: PeerId, reason: &'static str) -> bool {
+        let log = self.peer_log(&peer_id);
+        if self.keep_incompatible_peers {
+            debug!(log, "Keeping incompatible peer"; "reason" => reason);
+            return false;
+        }
+        debug!(log, "Disconnecting incompatible peer"; "reason" => reason);
+
+        self.discovery.block_dials(
+            peer_id.clone(),
+            Some(Instant::now() + INCOMPATIBLE_PEER_DIAL_BACKOFF),
+        );
+        self.say_goodbye(peer_id, GoodbyeReason::IrreleventNetwork, false);
+        false
+    }
+
+    /// Reconciles the trusted and banned peers with the given lists. Newly trusted peers are
+    /// dialed. Newly banned peers are sent a goodbye and banned once it is sent, and are not
+    /// dialed again until unbanned. Applying the current lists again has no effect.
+    pub fn update_peer_policy(&mut self, trusted: Vec<PeerId>, banned: Vec<PeerId>) {
+        let trusted: HashSet<PeerId> = trusted.into_iter().collect();
+        let banned: HashSet<PeerId> = banned.into_iter().collect();
+        let unbanned: Vec<PeerId> = self.banned_peers.difference(&banned).cloned().collect();
+        let newly_banned: Vec<PeerId> = banned.difference(&self.banned_peers).cloned().collect();
+        // banned peers are not dialed, even if trusted
+        let newly_trusted: Vec<PeerId> = trusted
+            .difference(&self.trusted_peers)
+            .filter(|peer_id| !banned.contains(peer_id))
+            .cloned()
+            .collect();
+        self.trusted_peers = trusted;
+        self.banned_peers = banned;
+
+        for peer_id in unbanned {
+            debug!(self.peer_log(&peer_id), "Unbanning peer");
+            self.discovery.unban_peer(&peer_id);
+            self.departing_peers.remove(&peer_id);
+            self.events.push(BehaviourEvent::UnbanPeer(peer_id));
+        }
+        for peer_id in newly_banned {
+            debug!(self.peer_log(&peer_id), "Banning peer by policy");
+            self.discovery.ban_peer(peer_id.clone(), None);
+            if self.serenity_rpc.is_connected(&peer_id) {
+                self.say_goodbye(peer_id, GoodbyeReason::Fault, true);
+            } else {
+                self.events.push(BehaviourEvent::BanPeer(peer_id));
+            }
+        }
+        for peer_id in newly_trusted {
+            self.discovery.queue_trusted_dial(peer_id);
+        }
+    }
+
+    /// Keeps the peer on `address` connected, as the `trusted_peers` of the config are. The peer
+    /// is dialed now and redialed whenever it disconnects, and is never banned for misbehaving,
+    /// rate limited or dismissed for exceeding `max_peers`.
+    pub fn add_trusted_peer(&mut self, address: Multiaddr) {
+        self.discovery.add_trusted_peer(address);
+    }
+
+    /// Returns `true` if `peer_id` is trusted, by address or by policy.
+    pub fn is_trusted(&self, peer_id: &PeerId) -> bool {
+        self.trusted_peers.contains(peer_id) || self.discovery.is_trusted(peer_id)
+    }
+
+    /// Returns what discovery knows of the network, for debugging.
+    pub fn discovery_info(&mut self) -> DiscoveryInfo {
+        DiscoveryInfo {
+            known_peers: self.discovery.known_peers(),
+            bucket_occupancy: self.discovery.bucket_occupancy(),
+            pending_queries: self.discovery.pending_queries(),
+            pending_subnet_queries: self.discovery.pending_subnet_queries(),
+        }
+    }
+
+    /// Returns the number of consecutive dials of `peer_id` which have failed, backing off its
+    /// redials. Connecting resets it.
+    pub fn dial_failures(&self, peer_id: &PeerId) -> u32 {
+        self.discovery.dial_failures(peer_id)
+    }
+
+    /// Bans `peer_id` for `ban_duration` for misbehaving. A connected peer is first sent a goodbye,
+    /// so it can tell the ban from a lost connection, and banned once it is sent or after
+    /// `GOODBYE_TIMEOUT`. Peers banned by policy stay banned until the policy lifts the ban.
+    /// Trusted peers are not banned.
+    fn ban_peer(&mut self, peer_id: PeerId) {
+        if self.banned_peers.contains(&peer_id) {
+            return;
+        }
+        if self.is_trusted(&peer_id) {
+            debug!(self.peer_log(&peer_id), "Not banning trusted peer");
+            return;
+        }
+        self.discovery
+            .ban_peer(peer_id.clone(), Some(Instant::now() + self.ban_duration));
+        if let Some(departure) = self.departing_peers.get_mut(&peer_id) {
+            // our goodbye is already on its way
+            departure.ban = true;
+            return;
+        }
+        if self.serenity_rpc.is_connected(&peer_id) {
+            self.say_goodbye(peer_id, GoodbyeReason::Fault, true);
+        } else {
+            self.events.push(BehaviourEvent::BanPeer(peer_id));
+        }
+    }
+
+    /// Lifts the bans of misbehaving peers which have ended by `now`.
+    fn poll_ban_expiries(&mut self, now: Instant) {
+        for peer_id in self.discovery.take_expired_bans(now) {
+            debug!(self.peer_log(&peer_id), "Ban of peer ended");
+            self.events.push(BehaviourEvent::UnbanPeer(peer_id));
+        }
+    }
+
+    /// Says goodbye to the peers which connected while we had `max_peers`, without banning them.
+    fn dismiss_excess_peers(&mut self) {
+        for peer_id in self.discovery.take_excess_peers() {
+            self.say_goodbye(peer_id, GoodbyeReason::TooManyPeers, false);
+        }
+    }
+
+    /// Sends `peer_id` a goodbye for `reason`, disconnecting the peer once it is sent or after
+    /// `GOODBYE_TIMEOUT`. The peer is banned if `ban` is set.
+    fn say_goodbye(&mut self, peer_id: PeerId, reason: GoodbyeReason, ban: bool) {
+        let goodbye = RPCRequest::Goodbye(reason);
+        self.serenity_rpc.send_rpc(
+            peer_id.clone(),
+            RPCEvent::Request {
+                id: RequestId::default(),
+                method_id: goodbye.method_id(),
+                body: goodbye,
+            },
+        );
+        self.departing_peers.insert(
+            peer_id,
+            Departure {
+                deadline: Instant::now() + GOODBYE_TIMEOUT,
+                ban,
+            },
+        );
+    }
+
+    /// Returns the time from building the behaviour to receiving the first block over gossip, if
+    /// one has been received.
+    pub fn time_to_first_block(&self) -> Option<Duration> {
+        self.first_block_at
+            .map(|first_block_at| first_block_at.duration_since(self.started_at))
+    }
+
+    /// Returns the chain state advertised by every peer which has sent a status, ordered from the
+    /// highest head slot down. Peers whose latest status is older than `peer_status_max_age` are
+    /// flagged stale.
+    pub fn peers_by_head_slot(&self) -> Vec<PeerChainInfo> {
+        self.peers_by_head_slot_at(Instant::now())
+    }
+
+    /// Returns the latest metadata received from the peer, if any.
+    pub fn peer_metadata(&self, peer_id: &PeerId) -> Option<MetaData> {
+        self.peer_metadata.get(peer_id).cloned()
+    }
+
+    /// Returns the peers whose latest metadata advertises the attestation subnet `subnet_id`.
+    pub fn peers_on_subnet(&self, subnet_id: usize) -> Vec<PeerId> {
+        self.peer_metadata
+            .iter()
+            .filter(|(_peer_id, metadata)| metadata.attnets.get(subnet_id) == Ok(true))
+            .map(|(peer_id, _metadata)| peer_id.clone())
+            .collect()
+    }
+
+    /// Returns the discovered peers whose ENR advertises the attestation subnet `subnet_id`. They
+    /// need not be connected, nor have sent their metadata.
+    pub fn discovered_peers_on_subnet(&self, subnet_id: u64) -> Vec<PeerId> {
+        self.discovery.peers_for_subnet(subnet_id)
+    }
+
+    /// Searches for `min_peers` peers on the attestation subnet `subnet_id`, dialing those found,
+    /// so a validator has peers on the subnet by the time its duties there begin. The outcome is
+    /// reported by a `SubnetPeersFound` or `SubnetQueryTimedOut` event.
+    pub fn discover_subnet_peers(&mut self, subnet_id: u64, min_peers: usize) {
+        self.discovery.discover_subnet_peers(subnet_id, min_peers);
+    }
+
+    /* Identify behaviour functions */
+
+    /// Returns the client information the peer reported via the identify protocol, if known.
+    pub fn peer_client_info(&self, peer_id: &PeerId) -> Option<ClientInfo> {
+        self.client_info.get(peer_id).cloned()
+    }
+}
+
+/// Counts the consecutive slow pings of each peer, so chronically slow peers are not chosen to
+/// sync from.
+///
+/// Only peers whose latest ping was slow are stored, so the map is bounded by the number of
+/// connected peers.
+#[derive(Default)]
+struct SlowPeers {
+    slow_pings: HashMap<PeerId, u8>,
+}
+
+impl SlowPeers {
+    /// Records a ping round-trip time for `peer_id`. Returns `true` if the peer has just become
+    /// chronically slow.
+    fn record_rtt(&mut self, peer_id: &PeerId, rtt: Duration) -> bool {
+        if rtt <= SLOW_PEER_RTT {
+            self.slow_pings.remove(peer_id);
+            return false;
+        }
+
+        let slow_pings = self.slow_pings.entry(peer_id.clone()).or_insert(0);
+        *slow_pings = slow_pings.saturating_add(1);
+        *slow_pings == SLOW_PEER_THRESHOLD
+    }
+
+    /// Returns `true` if the peer is chronically slow.
+    fn is_slow(&self, peer_id: &PeerId) -> bool {
+        self.slow_pings
+            .get(peer_id)
+            .map(|slow_pings| *slow_pings >= SLOW_PEER_THRESHOLD)
+            .unwrap_or(false)
+    }
+
+    /// Stops tracking a peer.
+    fn remove(&mut self, peer_id: &PeerId) {
+        self.slow_pings.remove(peer_id);
+    }
+}
+
+/// The client implementation a peer reported via the identify protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    /// The libp2p protocol version of the peer, e.g. `lighthouse/libp2p`.
+    pub protocol_version: String,
+    /// The client name and version of the peer, e.g. `Lighthouse/v0.1.0`.
+    pub agent_version: String,
+}
+
+impl ClientInfo {
+    /// The name of the client, e.g. `Lighthouse`, taken from the agent version.
+    pub fn client_name(&self) -> String {
+        self.agent_version
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+impl From<&IdentifyInfo> for ClientInfo {
+    fn from(info: &IdentifyInfo) -> Self {
+        ClientInfo {
+            protocol_version: info.protocol_version.clone(),
+            agent_version: info.agent_version.clone(),
+        }
+    }
+}
+
+/// Formats a peer id compactly for logging, keeping the start and end of its base58 form.
+pub fn short_peer_id(peer_id: &PeerId) -> String {
+    let peer_id = peer_id.to_base58();
+    if peer_id.len() <= 12 {
+        return peer_id;
+    }
+    format!("{}...{}", &peer_id[..4], &peer_id[peer_id.len() - 6..])
+}
+
+/// Returns `true` if `agent_version` contains any of the `banned_agents`.
+fn is_banned_agent(banned_agents: &[String], agent_version: &str) -> bool {
+    banned_agents
+        .iter()
+        .any(|banned| agent_version.contains(banned.as_str()))
+}
+
+/// Returns `true` if `event` only keeps the connection alive: the pings, metadata and statuses
+/// exchanged periodically with every peer, a goodbye or a failed request. Other RPCs reset the
+/// idle timeout.
+fn is_keep_alive(event: &RPCEvent) -> bool {
+    match event {
+        RPCEvent::Request { body, .. } => match body {
+            RPCRequest::Hello(_)
+            | RPCRequest::Goodbye(_)
+            | RPCRequest::MetaData
+            | RPCRequest::Ping(_) => true,
+            _ => false,
+        },
+        RPCEvent::Response { result, .. } => match result {
+            RPCResponse::Hello(_) | RPCResponse::MetaData(_) | RPCResponse::Ping(_) => true,
+            _ => false,
+        },
+        RPCEvent::StreamTermination { .. } => false,
+        RPCEvent::Error { .. } => true,
+    }
+}
+
+/// A gossip message signed by its source.
+#[derive(Encode, Decode)]
+struct SignedGossip {
+    /// The SSZ encoded `PubsubMessage`.
+    data: Vec<u8>,
+    /// The protobuf encoded public key of the source.
+    public_key: Vec<u8>,
+    /// The signature of the source over `data`.
+    signature: Vec<u8>,
+}
+
+/// The reasons a signed gossip message is rejected.
+#[derive(Debug, PartialEq)]
+enum GossipSignatureError {
+    /// The message is not a signed message.
+    Unsigned,
+    /// The public key could not be decoded.
+    InvalidPublicKey,
+    /// The public key does not belong to the source of the message.
+    SourceMismatch,
+    /// The signature does not match the data.
+    InvalidSignature,
+}
+
+/// Wraps `data` in a `SignedGossip` signed with `key`.
+fn sign_gossip(key: &Keypair, data: Vec<u8>) -> Result<Vec<u8>, SigningError> {
+    let signature = key.sign(&data)?;
+    Ok(SignedGossip {
+        data,
+        public_key: key.public().into_protobuf_encoding(),
+        signature,
+    }
+    .as_ssz_bytes())
+}
+
+/// Returns the data of a `SignedGossip` if it is validly signed by `source`.
+fn verify_gossip(source: &PeerId, signed: &[u8]) -> Result<Vec<u8>, GossipSignatureError> {
+    let signed =
+        SignedGossip::from_ssz_bytes(signed).map_err(|_| GossipSignatureError::Unsigned)?;
+    let public_key = PublicKey::from_protobuf_encoding(&signed.public_key)
+        .map_err(|_| GossipSignatureError::InvalidPublicKey)?;
+
+    if public_key.clone().into_peer_id() != *source {
+        return Err(GossipSignatureError::SourceMismatch);
+    }
+    if !public_key.verify(&signed.data, &signed.signature) {
+        return Err(GossipSignatureError::InvalidSignature);
+    }
+    Ok(signed.data)
+}
+
+/// A bounded cache of recently received gossip messages.
+///
+/// Messages are identified by their full message id, so distinct messages are never mistaken for
+/// one another. Duplicates are counted in the given counter.
+struct SeenMessages {
+    seen: HashSet<MessageId>,
+    order: VecDeque<MessageId>,
+    capacity: usize,
+    duplicates: IntCounter,
+}
+
+impl SeenMessages {
+    fn new(capacity: usize, duplicates: IntCounter) -> Self {
+        SeenMessages {
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+            duplicates,
+        }
+    }
+
+    /// Records a received message by its id. Returns `true` if the message has not been seen
+    /// recently.
+    fn observe(&mut self, id: &[u8]) -> bool {
+        if self.seen.contains(id) {
+            self.duplicates.inc();
+            return false;
+        }
+
+        self.seen.insert(id.to_vec());
+        self.order.push_back(id.to_vec());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Forgets every message seen.
+    fn clear(&mut self) {
+        self.seen.clear();
+        self.order.clear();
+    }
+}
+
+/// The gossip messages handed to the application which have yet to have their validation result
+/// reported, with the kind of each and the time it was received.
+///
+/// At most `capacity` messages are tracked; the oldest are forgotten, their result never reported.
+struct AwaitingValidation {
+    received: HashMap<MessageId, (&'static str, Instant)>,
+    order: VecDeque<MessageId>,
+    capacity: usize,
+}
+
+impl AwaitingValidation {
+    fn new(capacity: usize) -> Self {
+        AwaitingValidation {
+            received: HashMap::new(),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records the message `id` of `kind` as received at `now`.
+    fn received(&mut self, id: MessageId, kind: &'static str, now: Instant) {
+        self.received.insert(id.clone(), (kind, now));
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.received.remove(&oldest);
+            }
+        }
+    }
+
+    /// Stops tracking the message `id`, returning its kind and the time it was received if it
+    /// was awaiting validation.
+    fn validated(&mut self, id: &[u8]) -> Option<(&'static str, Instant)> {
+        self.received.remove(id)
+    }
+
+    /// Forgets every message awaiting validation.
+    fn clear(&mut self) {
+        self.received.clear();
+        self.order.clear();
+    }
+}
+
+/// Observes the raw gossip messages received, for instance to record them for replay.
+pub trait GossipObserver: Send + Sync {
+    /// Called with every gossip message received from gossipsub, before it is deduplicated,
+    /// verified or decoded. `data` is the message as sent by `source`, including its signature if
+    /// messages are signed.
+    fn observe(&self, topics: &[TopicHash], source: &PeerId, data: &[u8]);
+}
+
+/// A gossip message waiting to be published.
+struct QueuedPublish {
+    topics: Vec<Topic>,
+    /// The signed, encoded message.
+    data: Vec<u8>,
+    is_block: bool,
+}
+
+/// The publishes waiting for the gossipsub behaviour, blocks taking priority over attestations.
+#[derive(Default)]
+struct PublishQueue {
+    blocks: VecDeque<QueuedPublish>,
+    others: VecDeque<QueuedPublish>,
+}
+
+impl PublishQueue {
+    fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.others.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len() + self.others.len()
+    }
+
+    /// Queues a publish, returning `false` if the queue is full and the publish was dropped. A
+    /// block is only dropped if the queue holds nothing but blocks.
+    fn push(&mut self, publish: QueuedPublish) -> bool {
+        if self.len() >= MAX_QUEUED_PUBLISHES {
+            if !publish.is_block || self.others.pop_front().is_none() {
+                return false;
+            }
+        }
+        if publish.is_block {
+            self.blocks.push_back(publish);
+        } else {
+            self.others.push_back(publish);
+        }
+        true
+    }
+
+    /// Returns the next publish, the oldest block if any are queued.
+    fn pop(&mut self) -> Option<QueuedPublish> {
+        self.blocks.pop_front().or_else(|| self.others.pop_front())
+    }
+}
+
+/// Returns `true` if `data` is an encoded `PubsubMessage::Block`, read from the message id
+/// without decoding the block.
+fn is_block_gossip(data: &[u8]) -> bool {
+    data.starts_with(&0_u32.to_le_bytes())
+}
+
+/// The reasons a `Behaviour` cannot be built from a `NetworkConfig`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BehaviourError {
+    /// The config has no network id.
+    MissingNetworkId,
+    /// The config has no genesis root.
+    MissingGenesisRoot,
+    /// A topic to subscribe to is empty, or contains a slash or whitespace.
+    InvalidTopic(String),
+    /// The address to listen on cannot be listened on.
+    InvalidListenAddress(IpAddr),
+    /// A boot node address does not start with a host and a TCP port to dial.
+    InvalidBootNodeAddress(Multiaddr),
+    /// More peers are sought than are accepted.
+    InvalidPeerCounts {
+        target_peers: usize,
+        max_peers: usize,
+    },
+    /// The gossipsub parameters cannot be used, for the given reason.
+    InvalidGossipsubConfig(String),
+    /// The network metrics could not be created.
+    Metrics(String),
+    /// The discovery service could not be started.
+    Discovery(String),
+    /// mDNS discovery is enabled but could not be started.
+    Mdns(String),
+}
+
+impl fmt::Display for BehaviourError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BehaviourError::MissingNetworkId => write!(f, "Network config has no network id"),
+            BehaviourError::MissingGenesisRoot => write!(f, "Network config has no genesis root"),
+            BehaviourError::InvalidTopic(topic) => write!(f, "Invalid topic: {:?}", topic),
+            BehaviourError::InvalidListenAddress(address) => {
+                write!(f, "Cannot listen on address: {}", address)
+            }
+            BehaviourError::InvalidBootNodeAddress(address) => {
+                write!(f, "Boot node address has no host and TCP port: {}", address)
+            }
+            BehaviourError::InvalidPeerCounts {
+                target_peers,
+                max_peers,
+            } => write!(
+                f,
+                "Target peers ({}) exceeds max peers ({})",
+                target_peers, max_peers
+            ),
+            BehaviourError::InvalidGossipsubConfig(reason) => {
+                write!(f, "Invalid gossipsub config: {}", reason)
+            }
+            BehaviourError::Metrics(e) => write!(f, "Failed to create network metrics: {}", e),
+            BehaviourError::Discovery(e) => write!(f, "Failed to start discovery: {}", e),
+            BehaviourError::Mdns(e) => write!(f, "Failed to start mDNS discovery: {}", e),
+        }
+    }
+}
+
+/// A periodic snapshot of our peers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerSummary {
+    /// The number of connected peers.
+    pub connected_peers: usize,
+    /// The number of identified peers running each client, keyed by client name.
+    pub clients: HashMap<String, usize>,
+    /// The average of the latest ping round-trip times of our peers, if any have been pinged.
+    pub average_rtt: Option<Duration>,
+    /// The number of peers subscribed to each topic we are subscribed to, from which our meshes
+    /// are formed.
+    pub topic_peers: HashMap<TopicHash, usize>,
+    /// The time of the last discovery search which found peers, if any has.
+    pub last_peer_search: Option<Instant>,
+}
+
+/// The chain state a peer advertised in its latest status.
+struct PeerChainState {
+    head_slot: Slot,
+    finalized_epoch: Epoch,
+    /// The time the status was received.
+    updated_at: Instant,
+}
+
+/// The chain state a peer advertised in its latest status, as returned by `peers_by_head_slot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerChainInfo {
+    pub peer_id: PeerId,
+    /// The head slot of the peer.
+    pub head_slot: Slot,
+    /// The finalized epoch of the peer.
+    pub finalized_epoch: Epoch,
+    /// The time the status was received.
+    pub updated_at: Instant,
+    /// Whether the status is older than the configured `peer_status_max_age`.
+    pub stale: bool,
+}
+
+/// Where a block received over gossip sits in the chain, for sync to decide whether to process
+/// the block or queue it until its parent is known.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GossipBlockInfo {
+    /// The slot of the block.
+    pub slot: Slot,
+    /// The root of the block's parent.
+    pub parent_root: Hash256,
+}
+
+/// What discovery knows of the network, as returned by `Behaviour::discovery_info`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryInfo {
+    /// The peers we know of, with their addresses and how we came to know of them.
+    pub known_peers: Vec<(PeerId, Vec<Multiaddr>, PeerSource)>,
+    /// The number of peers in each non-empty bucket of the discv5 routing table, by the log2
+    /// distance of the bucket from us.
+    pub bucket_occupancy: Vec<(u32, usize)>,
+    /// The number of discv5 searches in progress.
+    pub pending_queries: usize,
+    /// The number of searches for peers on attestation subnets in progress.
+    pub pending_subnet_queries: usize,
+}
+
+/// What we know of a connected peer, as returned by `Behaviour::peer_info_snapshot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    /// The address the peer is connected on.
+    pub address: Option<Multiaddr>,
+    /// The addresses the peer told us it listens on via the identify protocol.
+    pub listen_addresses: Vec<Multiaddr>,
+    /// The latest ping round-trip time of the peer, if it has been pinged.
+    pub rtt: Option<Duration>,
+    /// The RPC score of the peer.
+    pub rpc_score: i32,
+    /// The client the peer reported via the identify protocol, if it has.
+    pub client: Option<ClientInfo>,
+    /// The gossipsub topics the peer is subscribed to.
+    pub topics: Vec<TopicHash>,
+    /// The chain state the peer advertised in its latest status, if it has sent one.
+    pub chain: Option<PeerChainInfo>,
+    /// Whether the peer is trusted, kept connected whatever its score.
+    pub trusted: bool,
+    /// Whether mDNS has discovered the peer on the local network.
+    pub discovered_via_mdns: bool,
+    /// The time before which the peer is not redialed, should it disconnect, by a dial backoff,
+    /// a cooldown after it said goodbye, or a ban.
+    pub redial_at: Option<Instant>,
+}
+
+/// A command sent to the behaviour through a `NetworkSender`.
+#[derive(Debug)]
+pub enum NetworkCommand {
+    /// Send an RPC, as with `Behaviour::send_rpc`.
+    SendRpc(PeerId, RPCEvent),
+    /// Publish a gossipsub message, as with `Behaviour::publish`.
+    Publish {
+        topics: Vec<Topic>,
+        message: Box<PubsubMessage>,
+    },
+    /// Queue a peer to be dialed.
+    Dial(PeerId),
+    /// Report a gossip message validated, as with `Behaviour::report_gossip_validation`.
+    ReportValidation(MessageId),
+    /// Set the status sent to our peers, as with `Behaviour::update_local_status`.
+    UpdateLocalStatus(HelloMessage),
+}
+
+/// A cloneable handle to the behaviour, for components without access to the swarm.
+///
+/// Commands are queued and applied the next time the behaviour is polled. The ids assigned to
+/// requests sent this way are not returned; they are carried on the responses and errors
+/// received for the requests.
+#[derive(Clone)]
+pub struct NetworkSender {
+    sender: mpsc::UnboundedSender<NetworkCommand>,
+}
+
+impl NetworkSender {
+    /// Sends an RPC request or response to `peer_id`.
+    pub fn send_rpc(&self, peer_id: PeerId, rpc_event: RPCEvent) -> error::Result<()> {
+        self.send(NetworkCommand::SendRpc(peer_id, rpc_event))
+    }
+
+    /// Publishes `message` on the gossipsub `topics`.
+    pub fn publish(&self, topics: Vec<Topic>, message: PubsubMessage) -> error::Result<()> {
+        self.send(NetworkCommand::Publish {
+            topics,
+            message: Box::new(message),
+        })
+    }
+
+    /// Dials `peer_id`, if it is not already connected.
+    pub fn dial(&self, peer_id: PeerId) -> error::Result<()> {
+        self.send(NetworkCommand::Dial(peer_id))
+    }
+
+    /// Reports the gossip message `id` validated, measuring its validation latency.
+    pub fn report_validation(&self, id: MessageId) -> error::Result<()> {
+        self.send(NetworkCommand::ReportValidation(id))
+    }
+
+    /// Sets the status sent to our peers and checked against theirs.
+    pub fn update_local_status(&self, status: HelloMessage) -> error::Result<()> {
+        self.send(NetworkCommand::UpdateLocalStatus(status))
+    }
+
+    /// Queues `command` for the behaviour, failing if the behaviour has been dropped.
+    fn send(&self, command: NetworkCommand) -> error::Result<()> {
+        self.sender
+            .unbounded_send(command)
+            .map_err(|_| "Network behaviour has shut down".into())
+    }
+}
+
+/// The types of events than can be obtained from polling the behaviour.
+pub enum BehaviourEvent {
+    RPC(PeerId, RPCEvent),
+    PeerDialed(PeerId),
+    /// A discovery search found a peer, which will be dialed if we need more peers.
+    PeerDiscovered(PeerId),
+    /// Dialing an address failed. The peer is not redialed for a time depending on `kind`.
+    DialFailed {
+        addr: Multiaddr,
+        kind: DialErrorKind,
+    },
+    /// A `discover_subnet_peers` query found the given number of peers on the attestation
+    /// subnet, at least the number sought.
+    SubnetPeersFound(u64, usize),
+    /// A `discover_subnet_peers` query gave up, having found only the given number of peers on
+    /// the attestation subnet.
+    SubnetQueryTimedOut(u64, usize),
+    /// The peer should be disconnected and refused future connections.
+    BanPeer(PeerId),
+    /// The peer should be disconnected, but may connect again later.
+    DisconnectPeer(PeerId),
+    /// The peer's ban is lifted, it may connect again.
+    UnbanPeer(PeerId),
+    /// A gossip message was received. Its `id` is reported to `report_gossip_validation` once
+    /// the message is validated. The slot and parent of a block are given in `block`.
+    GossipMessage {
+        id: MessageId,
+        source: PeerId,
+        topics: Vec<TopicHash>,
+        block: Option<GossipBlockInfo>,
+        message: Box<PubsubMessage>,
+    },
+    /// A peer has joined, or left, our mesh of a topic, as it was grafted or pruned.
+    MeshChanged {
+        peer_id: PeerId,
+        topic: TopicHash,
+        grafted: bool,
+    },
+    /// A periodic summary of our peers.
+    PeerSummary(PeerSummary),
+    /// The number of connected peers, given, has fallen below the configured `min_peers`.
+    LowPeerCount(usize),
+    /// The number of connected peers, given, has recovered to the configured `min_peers` after a
+    /// `LowPeerCount`.
+    RecoveredPeerCount(usize),
+    /// The goodbyes sent by `Behaviour::shutdown` have been flushed or timed out. The swarm can
+    /// now be dropped.
+    ShutdownComplete,
+    /// A `BeaconBlocksByRange` request made through `request_blocks_by_range` failed on every
+    /// peer it was retried from.
+    RangeSyncFailed {
+        start_slot: Slot,
+        count: u64,
+    },
+}
+
+/// How useful a peer's response to one of our RPC requests was.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RPCResponseQuality {
+    /// The response contained the requested items.
+    Useful,
+    /// The response contained some, but fewer than all, of the requested items.
+    Short,
+    /// The response contained none of the requested items.
+    Empty,
+    /// The response contained items that were not requested.
+    WrongRange,
+    /// The peer did not respond in time.
+    TimedOut,
+    /// The response exceeded the size limit of its method.
+    Oversized,
+}
+
+impl RPCResponseQuality {
+    /// Judges a `BeaconBlocksByRange` response from the slots of the blocks it contained.
+    pub fn of_blocks_by_range(request: &BeaconBlocksByRangeRequest, slots: &[Slot]) -> Self {
+        let requested = |slot: &Slot| {
+            slot.as_u64()
+                .checked_sub(request.start_slot.as_u64())
+                .map(|offset| {
+                    request.step > 0
+                        && offset % request.step == 0
+                        && offset / request.step < request.count
+                })
+                .unwrap_or(false)
+        };
+
+        if !slots.iter().all(requested) {
+            RPCResponseQuality::WrongRange
+        } else if slots.is_empty() {
+            RPCResponseQuality::Empty
+        } else if (slots.len() as u64) < request.count {
+            RPCResponseQuality::Short
+        } else {
+            RPCResponseQuality::Useful
+        }
+    }
+
+    /// Returns `true` if the request failed, rather than being answered in part or in full.
+    pub fn is_failure(self) -> bool {
+        match self {
+            RPCResponseQuality::Useful | RPCResponseQuality::Short => false,
+            RPCResponseQuality::Empty
+            | RPCResponseQuality::WrongRange
+            | RPCResponseQuality::TimedOut
+            | RPCResponseQuality::Oversized => true,
+        }
+    }
+
+    /// The change in the responder's RPC score.
+    pub fn score_delta(self) -> i32 {
+        match self {
+            RPCResponseQuality::Useful => 1,
+            RPCResponseQuality::Short => -1,
+            RPCResponseQuality::Empty => -2,
+            RPCResponseQuality::TimedOut => -3,
+            RPCResponseQuality::WrongRange | RPCResponseQuality::Oversized => -5,
+        }
+    }
+}
+
+/// Returns the reason the `remote` status is incompatible with our `local` status, if it is.
+///
+/// Finalized roots can only be compared at the same finalized epoch; the chains of peers at other
+/// epochs are checked by sync against our block history.
+fn status_mismatch(local: &HelloMessage, remote: &HelloMessage) -> Option<&'static str> {
+    if local.network_id != remote.network_id {
+        return Some("network_id");
+    }
+    if local.genesis_root != remote.genesis_root {
+        return Some("genesis_root");
+    }
+    let zero_root = Hash256::zero();
+    if local.latest_finalized_epoch == remote.latest_finalized_epoch
+        && local.latest_finalized_root != zero_root
+        && remote.latest_finalized_root != zero_root
+        && local.latest_finalized_root != remote.latest_finalized_root
+    {
+        return Some("finalized_root");
+    }
+    None
+}
+
+/// A `BeaconBlocksByRange` request made through `Behaviour::request_blocks_by_range`.
+struct BlocksByRangeRequest {
+    /// The range of blocks requested.
+    range: BeaconBlocksByRangeRequest,
+    /// The blocks received so far from the peer currently asked.
+    blocks: Vec<BeaconBlock>,
+    /// Resolves the caller's future once the request completes.
+    sender: oneshot::Sender<Result<Vec<BeaconBlock>, RPCError>>,
+    /// The number of times the request has been reissued to another peer.
+    retries: u32,
+    /// The peers which have failed the request.
+    tried_peers: HashSet<PeerId>,
+}
+
+/// A peer we have said goodbye to.
+struct Departure {
+    /// The time after which the peer is disconnected, whether or not the goodbye was sent.
+    deadline: Instant,
+    /// Whether the peer is banned rather than disconnected.
+    ban: bool,
+}
+
+/// The progress of a graceful shutdown.
+struct Shutdown {
+    /// The peers our goodbye has yet to be sent to.
+    unsent_goodbyes: HashSet<PeerId>,
+    /// The time after which the shutdown completes, whether or not the goodbyes were sent.
+    deadline: Instant,
+    /// Wakes the behaviour at the deadline.
+    delay: Delay,
+    /// Whether `ShutdownComplete` has been emitted.
+    complete: bool,
+}
+
+/// Messages that are passed to and from the pubsub (Gossipsub) behaviour.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PubsubMessage {
+    /// Gossipsub message providing notification of a new block.
+    Block(BeaconBlock),
+    /// Gossipsub message providing notification of a new attestation.
+    Attestation(Attestation),
+    /// Gossipsub message carrying the SSZ encoded `LightClientFinalityUpdate` light clients
+    /// follow the finalized chain by. We do not produce light client updates, so they are relayed
+    /// without being decoded.
+    LightClientFinalityUpdate(Vec<u8>),
+    /// Gossipsub message carrying the SSZ encoded `LightClientOptimisticUpdate` light clients
+    /// follow the head of the chain by, relayed like `LightClientFinalityUpdate`.
+    LightClientOptimisticUpdate(Vec<u8>),
+    /// Gossipsub message carrying a validator's voluntary exit.
+    VoluntaryExit(VoluntaryExit),
+    /// Gossipsub message carrying a slashing of a validator which proposed two blocks in a slot.
+    ProposerSlashing(ProposerSlashing),
+    /// Gossipsub message carrying a slashing of validators which made conflicting attestations.
+    AttesterSlashing(AttesterSlashing),
+}
+
+//TODO: Correctly encode/decode enums. Prefixing with integer for now.
+impl Encode for PubsubMessage {
+    fn is_ssz_fixed_len() -> bool {
+        false
+    }
+
+    fn ssz_append(&self, buf: &mut Vec<u8>) {
+        let offset = <u32 as Encode>::ssz_fixed_len() + <Vec<u8> as Encode>::ssz_fixed_len();
+
+        let mut encoder = ssz::SszEncoder::container(buf, offset);
+
+        match self {
+            PubsubMessage::Block(block_gossip) => {
+                encoder.append(&0_u32);
+
+                // Encode the gossip as a Vec<u8>;
+                encoder.append(&block_gossip.as_ssz_bytes());
+            }
+            PubsubMessage::Attestation(attestation_gossip) => {
+                encoder.append(&1_u32);
+
+                // Encode the gossip as a Vec<u8>;
+                encoder.append(&attestation_gossip.as_ssz_bytes());
+            }
+            PubsubMessage::LightClientFinalityUpdate(update) => {
+                encoder.append(&2_u32);
+                encoder.append(update);
+            }
+            PubsubMessage::LightClientOptimisticUpdate(update) => {
+                encoder.append(&3_u32);
+                encoder.append(update);
+            }
+            PubsubMessage::VoluntaryExit(exit) => {
+                encoder.append(&4_u32);
+                encoder.append(&exit.as_ssz_bytes());
+            }
+            PubsubMessage::ProposerSlashing(slashing) => {
+                encoder.append(&5_u32);
+                encoder.append(&slashing.as_ssz_bytes());
+            }
+            PubsubMessage::AttesterSlashing(slashing) => {
+                encoder.append(&6_u32);
+                encoder.append(&slashing.as_ssz_bytes());
+            }
+        }
+
+        encoder.finalize();
+    }
+}
+
+impl Decode for PubsubMessage {
+    fn is_ssz_fixed_len() -> bool {
+        false
+    }
+
+    fn from_ssz_bytes(bytes: &[u8]) -> Result<Self, ssz::DecodeError> {
+        PubsubMessage::decode(bytes).map_err(Into::into)
+    }
+}
+
+impl PubsubMessage {
+    /// Returns the kind of the message, as labels metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PubsubMessage::Block(_) => "block",
+            PubsubMessage::Attestation(_) => "attestation",
+            PubsubMessage::LightClientFinalityUpdate(_) => "light_client_finality_update",
+            PubsubMessage::LightClientOptimisticUpdate(_) => "light_client_optimistic_update",
+            PubsubMessage::VoluntaryExit(_) => "voluntary_exit",
+            PubsubMessage::ProposerSlashing(_) => "proposer_slashing",
+            PubsubMessage::AttesterSlashing(_) => "attester_slashing",
+        }
+    }
+
+    /// Returns the slot and parent of a block message, read from the decoded block.
+    pub fn block_info(&self) -> Option<GossipBlockInfo> {
+        match self {
+            PubsubMessage::Block(block) => Some(GossipBlockInfo {
+                slot: block.slot,
+                parent_root: block.previous_block_root,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Decodes a `PubsubMessage` received from an untrusted peer.
+    ///
+    /// A panic raised while decoding adversarial bytes is caught and returned as
+    /// `PubsubDecodeError::Panicked` rather than unwinding through the networking task.
+    pub fn decode_untrusted(bytes: &[u8]) -> Result<Self, PubsubDecodeError> {
+        panic::catch_unwind(|| PubsubMessage::decode(bytes))
+            .unwrap_or(Err(PubsubDecodeError::Panicked))
+    }
+
+    /// Decodes a `PubsubMessage`, distinguishing an unknown message id from a known id with an
+    /// undecodable body.
+    pub fn decode(bytes: &[u8]) -> Result<Self, PubsubDecodeError> {
+        let mut builder = ssz::SszDecoderBuilder::new(&bytes);
+
+        builder.register_type::<u32>()?;
+        builder.register_type::<Vec<u8>>()?;
+
+        let mut decoder = builder.build()?;
+
+        let id: u32 = decoder.decode_next()?;
+        let body: Vec<u8> = decoder.decode_next()?;
+
+        let invalid_body = |error| PubsubDecodeError::InvalidBody { id, error };
+
+        // bodies larger than any valid message are not decoded, however their length fields read
+        let max_size = match id {
+            0 => MAX_BLOCK_GOSSIP_SIZE,
+            1 => MAX_ATTESTATION_GOSSIP_SIZE,
+            2 | 3 => MAX_LIGHT_CLIENT_UPDATE_GOSSIP_SIZE,
+            4 => MAX_VOLUNTARY_EXIT_GOSSIP_SIZE,
+            5 => MAX_PROPOSER_SLASHING_GOSSIP_SIZE,
+            6 => MAX_ATTESTER_SLASHING_GOSSIP_SIZE,
+            _ => return Err(PubsubDecodeError::UnknownId(id)),
+        };
+        if body.len() > max_size {
+            return Err(PubsubDecodeError::Oversized {
+                id,
+                size: body.len(),
+            });
+        }
+
+        match id {
+            0 => Ok(PubsubMessage::Block(
+                BeaconBlock::from_ssz_bytes(&body).map_err(invalid_body)?,
+            )),
+            1 => Ok(PubsubMessage::Attestation(
+                Attestation::from_ssz_bytes(&body).map_err(invalid_body)?,
+            )),
+            2 => Ok(PubsubMessage::LightClientFinalityUpdate(body)),
+            3 => Ok(PubsubMessage::LightClientOptimisticUpdate(body)),
+            4 => Ok(PubsubMessage::VoluntaryExit(
+                VoluntaryExit::from_ssz_bytes(&body).map_err(invalid_body)?,
+            )),
+            5 => Ok(PubsubMessage::ProposerSlashing(
+                ProposerSlashing::from_ssz_bytes(&body).map_err(invalid_body)?,
+            )),
+            6 => Ok(PubsubMessage::AttesterSlashing(
+                AttesterSlashing::from_ssz_bytes(&body).map_err(invalid_body)?,
+            )),
+            _ => Err(PubsubDecodeError::UnknownId(id)),
+        }
+    }
+}
+
+/// Errors that can occur when decoding a `PubsubMessage`.
+#[derive(Debug, PartialEq)]
+pub enum PubsubDecodeError {
+    /// The message id and body could not be read from the bytes.
+    InvalidContainer(DecodeError),
+    /// The message id does not correspond to a known message type.
+    UnknownId(u32),
+    /// The message id is known but the body could not be decoded.
+    InvalidBody { id: u32, error: DecodeError },
+    /// The body is larger than any valid message of its id.
+    Oversized { id: u32, size: usize },
+    /// Decoding the message panicked.
+    Panicked,
+}
+
+impl PubsubDecodeError {
+    /// Returns `true` if the message was well-formed apart from its body, which is more likely
+    /// caused by the peer running a different version than by malice.
+    pub fn is_likely_version_mismatch(&self) -> bool {
+        match self {
+            PubsubDecodeError::InvalidBody { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<DecodeError> for PubsubDecodeError {
+    fn from(error: DecodeError) -> Self {
+        PubsubDecodeError::InvalidContainer(error)
+    }
+}
+
+impl Into<DecodeError> for PubsubDecodeError {
+    fn into(self) -> DecodeError {
+        match self {
+            PubsubDecodeError::InvalidContainer(error) => error,
+            PubsubDecodeError::UnknownId(_) => {
+                DecodeError::BytesInvalid("Invalid PubsubMessage id".to_string())
+            }
+            PubsubDecodeError::InvalidBody { error, .. } => error,
+            PubsubDecodeError::Oversized { .. } => {
+                DecodeError::BytesInvalid("PubsubMessage body too large".to_string())
+            }
+            PubsubDecodeError::Panicked => {
+                DecodeError::BytesInvalid("PubsubMessage decoding panicked".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod harness;
+
+#[cfg(test)]
+mod test {
+    use super::harness::{ConnectedPair, LoopbackNetwork, Node};
+    use super::*;
+    use crate::rpc::{OneShotEvent, ProtocolId, RPCEncoding};
+    use crate::NodeRole;
+    use futures::future;
+    use libp2p::core::swarm::{ConnectedPoint, NetworkBehaviour};
+    use libp2p::core::{muxing::StreamMuxerBox, nodes::Substream};
+    use libp2p::gossipsub::protocol::GossipsubControlAction;
+    use libp2p::gossipsub::{GossipsubMessage, GossipsubRpc};
+    use std::ops::{Deref, DerefMut};
+    use std::sync::Mutex;
+    use tempfile::{tempdir, TempDir};
+    use types::test_utils::{RngCore, SeedableRng, TestRandom, XorShiftRng};
+    use types::*;
+
+    const NETWORK_ID: u8 = 1;
+
+    /// Returns the default config, on network `NETWORK_ID` with a zero genesis root. Behaviours
+    /// start subscribed to no topics, leaving tests to choose them.
+    fn test_config() -> NetworkConfig {
+        let mut config = NetworkConfig::default();
+        config.network_id = Some(NETWORK_ID);
+        config.genesis_root = Some(Hash256::zero());
+        config.role = NodeRole::BootstrapOnly;
+        // signing is opted into, so that it is covered
+        config.message_authenticity = MessageAuthenticity::Signed;
+        config
+    }
+
+    /// A behaviour built by a test, with the directory it stores its network files in, which is
+    /// removed as the behaviour is dropped.
+    struct TestBehaviour {
+        behaviour: Behaviour<Substream<StreamMuxerBox>>,
+        _network_dir: TempDir,
+    }
+
+    impl Deref for TestBehaviour {
+        type Target = Behaviour<Substream<StreamMuxerBox>>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.behaviour
+        }
+    }
+
+    impl DerefMut for TestBehaviour {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.behaviour
+        }
+    }
+
+    /// Builds a behaviour listening for discovery on an ephemeral port.
+    fn build_behaviour() -> TestBehaviour {
+        build_behaviour_with_config(test_config())
+    }
+
+    fn build_behaviour_with_config(mut config: NetworkConfig) -> TestBehaviour {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let local_key = Keypair::generate_secp256k1();
+        let network_dir = tempdir().expect("should create network directory");
+        config.network_dir = network_dir.path().to_path_buf();
+        config.discovery_port = 0;
+        TestBehaviour {
+            behaviour: Behaviour::new(&local_key, &config, &log).expect("should build behaviour"),
+            _network_dir: network_dir,
+        }
+    }
+
+    #[test]
+    fn bootnode_introduces_peers() {
+        let mut network = LoopbackNetwork::new();
+        let boot_node = network.add_node(|_| {});
+        let (boot_enr, boot_address) = (network.enr(boot_node), network.address(boot_node));
+        let configure = |config: &mut NetworkConfig| {
+            config.boot_nodes = vec![boot_enr.clone()];
+            config.libp2p_nodes = vec![boot_address.clone()];
+        };
+
+        // the first node dials the boot node, and searching from it makes itself known
+        let first = network.add_node(configure);
+        network.drive_until(|swarms| swarms[first].discovery.connected_peers() == 1);
+
+        // the second node learns of the first through the boot node, and connects to it
+        let second = network.add_node(configure);
+        let first_id = network.peer_id(first);
+        network.drive_until(|swarms| {
+            swarms[second]
+                .discovery
+                .connected_peer_ids()
+                .contains(&first_id)
+        });
+    }
+
+    #[test]
+    fn role_topics_subscribed() {
+        let subscribed = |role: NodeRole, extra: &[&str]| {
+            let mut config = test_config();
+            config.role = role;
+            config.topics = extra.iter().map(|topic| topic.to_string()).collect();
+            let behaviour = build_behaviour_with_config(config);
+            let mut topics: Vec<String> = behaviour
+                .subscriptions
+                .keys()
+                .map(|hash| hash.clone().into_string())
+                .collect();
+            topics.sort();
+            topics
+        };
+        let hashes = |topics: &[&str]| {
+            let mut hashes: Vec<String> = topics
+                .iter()
+                .map(|topic| {
+                    TopicBuilder::new(*topic)
+                        .build()
+                        .hash()
+                        .clone()
+                        .into_string()
+                })
+                .collect();
+            hashes.sort();
+            hashes
+        };
+
+        assert_eq!(
+            subscribed(NodeRole::FullNode, &[]),
+            hashes(&[crate::BEACON_PUBSUB_TOPIC, crate::BEACON_ATTESTATION_TOPIC])
+        );
+        assert_eq!(
+            subscribed(NodeRole::Validator, &[]),
+            hashes(&[
+                crate::BEACON_PUBSUB_TOPIC,
+                crate::BEACON_ATTESTATION_TOPIC,
+                crate::VOLUNTARY_EXIT_TOPIC,
+                crate::PROPOSER_SLASHING_TOPIC,
+                crate::ATTESTER_SLASHING_TOPIC,
+            ])
+        );
+        assert!(subscribed(NodeRole::BootstrapOnly, &[]).is_empty());
+
+        // configured topics are subscribed to beyond those of the role
+        assert_eq!(
+            subscribed(NodeRole::BootstrapOnly, &["shard0"]),
+            hashes(&["shard0"])
+        );
+        assert_eq!(
+            subscribed(NodeRole::FullNode, &[crate::BEACON_PUBSUB_TOPIC]),
+            hashes(&[crate::BEACON_PUBSUB_TOPIC, crate::BEACON_ATTESTATION_TOPIC])
+        );
+    }
+
+    #[test]
+    fn fanout_limits_applied() {
+        let mut config = NetworkConfig::default();
+        config.gossip_fanout_ttl = Duration::from_secs(5);
+        config.gossip_fanout_peers = 3;
+
+        let gs_config = gossipsub_config(&config);
+        assert_eq!(gs_config.fanout_ttl, Duration::from_secs(5));
+        assert_eq!(gs_config.mesh_n, 3);
+        assert!(gs_config.mesh_n_low <= 3 && gs_config.mesh_n_high >= 3);
+        assert_eq!(gs_config.heartbeat_interval, Duration::from_secs(20));
+
+        // a fan-out beyond the default mesh bounds widens them
+        config.gossip_fanout_peers = 20;
+        let gs_config = gossipsub_config(&config);
+        assert_eq!(gs_config.mesh_n, 20);
+        assert!(gs_config.mesh_n_low <= 20 && gs_config.mesh_n_high >= 20);
+    }
+
+    #[test]
+    fn subscription_tracking() {
+        let mut behaviour = build_behaviour();
+        let topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        let other_topic = TopicBuilder::new("other_topic").build();
+
+        assert!(!behaviour.is_subscribed(topic.hash()));
+        assert!(behaviour.subscribe(topic.clone()));
+        assert!(behaviour.is_subscribed(topic.hash()));
+        assert!(!behaviour.is_subscribed(other_topic.hash()));
+
+        assert!(behaviour.unsubscribe(topic.clone()));
+        assert!(!behaviour.is_subscribed(topic.hash()));
+    }
+
+    #[test]
+    fn ssz_encoding() {
+        let original = PubsubMessage::Block(BeaconBlock::empty(&MainnetEthSpec::default_spec()));
+
+        let encoded = ssz_encode(&original);
+
+        let decoded = PubsubMessage::from_ssz_bytes(&encoded).unwrap();
+
+        assert_eq!(original, decoded);
+    }
+
+    #[test]
+    fn light_client_updates_round_trip() {
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        let mut update = vec![0; 400];
+        rng.fill_bytes(&mut update);
+
+        for original in &[
+            PubsubMessage::LightClientFinalityUpdate(update.clone()),
+            PubsubMessage::LightClientOptimisticUpdate(update.clone()),
+        ] {
+            let decoded = PubsubMessage::decode(&ssz_encode(original)).expect("decodes");
+            assert_eq!(*original, decoded);
+        }
+
+        // updates larger than any valid update are refused
+        let oversized = vec![0; MAX_LIGHT_CLIENT_UPDATE_GOSSIP_SIZE + 1];
+        let encoded = ssz_encode(&PubsubMessage::LightClientOptimisticUpdate(oversized));
+        assert_eq!(
+            PubsubMessage::decode(&encoded),
+            Err(PubsubDecodeError::Oversized {
+                id: 3,
+                size: MAX_LIGHT_CLIENT_UPDATE_GOSSIP_SIZE + 1
+            })
+        );
+    }
+
+    #[test]
+    fn operations_round_trip() {
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        for original in &[
+            PubsubMessage::VoluntaryExit(VoluntaryExit::random_for_test(&mut rng)),
+            PubsubMessage::ProposerSlashing(ProposerSlashing::random_for_test(&mut rng)),
+            PubsubMessage::AttesterSlashing(AttesterSlashing::random_for_test(&mut rng)),
+        ] {
+            let decoded = PubsubMessage::decode(&ssz_encode(original)).expect("decodes");
+            assert_eq!(*original, decoded);
+        }
+
+        // a slashing of attestations by every index they may hold is within the limit
+        let mut slashing = AttesterSlashing::random_for_test(&mut rng);
+        for attestation in &mut [&mut slashing.attestation_1, &mut slashing.attestation_2] {
+            attestation.custody_bit_0_indices = (0..4_096).collect();
+            attestation.custody_bit_1_indices = vec![];
+        }
+        assert!(slashing.as_ssz_bytes().len() <= MAX_ATTESTER_SLASHING_GOSSIP_SIZE);
+    }
+
+    #[test]
+    fn light_client_topics_subscribed_if_served() {
+        let light_client_topics = |serve_light_clients| {
+            let mut config = test_config();
+            config.serve_light_clients = serve_light_clients;
+            let behaviour = build_behaviour_with_config(config);
+            [
+                crate::LIGHT_CLIENT_FINALITY_UPDATE_TOPIC,
+                crate::LIGHT_CLIENT_OPTIMISTIC_UPDATE_TOPIC,
+            ]
+            .iter()
+            .filter(|topic| {
+                let hash = TopicBuilder::new(**topic).build().hash().clone();
+                behaviour.subscriptions.contains_key(&hash)
+            })
+            .count()
+        };
+
+        assert_eq!(light_client_topics(false), 0);
+        assert_eq!(light_client_topics(true), 2);
+    }
+
+    #[test]
+    fn subnet_subscriptions_updated_by_difference() {
+        let mut behaviour = build_behaviour();
+        let subnet_topic = |subnet_id| {
+            TopicBuilder::new(attestation_subnet_topic(subnet_id))
+                .build()
+                .hash()
+                .clone()
+        };
+        let subscribed = |behaviour: &Behaviour<_>| {
+            let mut subnets: Vec<SubnetId> = (0..ATTESTATION_SUBNET_COUNT as u64)
+                .filter(|subnet_id| {
+                    behaviour
+                        .subscriptions
+                        .contains_key(&subnet_topic(*subnet_id))
+                })
+                .collect();
+            subnets.sort();
+            subnets
+        };
+        assert_eq!(
+            attestation_subnet_topic(3),
+            "committee_index3_beacon_attestation"
+        );
+
+        behaviour.update_subnet_subscriptions([1, 2].iter().cloned().collect());
+        assert_eq!(subscribed(&behaviour), vec![1, 2]);
+
+        // a peer in the mesh of a subnet we stay on is left undisturbed
+        let peer_id = PeerId::random();
+        behaviour.gossipsub.inject_node_event(
+            peer_id.clone(),
+            GossipsubRpc {
+                messages: vec![],
+                subscriptions: vec![],
+                control_msgs: vec![GossipsubControlAction::Graft {
+                    topic_hash: subnet_topic(2),
+                }],
+            },
+        );
+        assert_eq!(behaviour.gossipsub.take_mesh_changes().len(), 1);
+
+        behaviour.update_subnet_subscriptions([2, 3].iter().cloned().collect());
+        assert_eq!(subscribed(&behaviour), vec![2, 3]);
+        assert!(behaviour.gossipsub.take_mesh_changes().is_empty());
+        let expected: HashSet<SubnetId> = [2, 3].iter().cloned().collect();
+        assert_eq!(*behaviour.subnet_subscriptions(), expected);
+
+        // subnets which do not exist are ignored
+        behaviour.update_subnet_subscriptions(
+            [3, ATTESTATION_SUBNET_COUNT as u64]
+                .iter()
+                .cloned()
+                .collect(),
+        );
+        assert_eq!(subscribed(&behaviour), vec![3]);
+        assert_eq!(
+            behaviour.gossipsub.take_mesh_changes(),
+            vec![(peer_id, subnet_topic(2), false)]
+        );
+    }
+
+    #[test]
+    fn subnet_subscriptions_advertised() {
+        let mut behaviour = build_behaviour();
+        let enr_seq = behaviour.local_enr().seq();
+        let mut attnets = Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT);
+        attnets.set(1, true);
+        attnets.set(4, true);
+
+        behaviour.update_subnet_subscriptions([1, 4].iter().cloned().collect());
+        assert_eq!(behaviour.metadata().attnets, attnets);
+        assert_eq!(behaviour.metadata().seq_number, 1);
+        assert_eq!(behaviour.local_enr().seq(), enr_seq + 1);
+        assert_eq!(
+            crate::discovery::enr_attnets(&behaviour.local_enr()),
+            Some(attnets)
+        );
+
+        // the same subnets again are not a change
+        behaviour.update_subnet_subscriptions([4, 1].iter().cloned().collect());
+        assert_eq!(behaviour.metadata().seq_number, 1);
+        assert_eq!(behaviour.local_enr().seq(), enr_seq + 1);
+
+        behaviour.update_subnet_subscriptions(HashSet::new());
+        assert_eq!(
+            behaviour.metadata().attnets,
+            Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT)
+        );
+        assert_eq!(behaviour.local_enr().seq(), enr_seq + 2);
+    }
+
+    #[test]
+    fn published_block_received() {
+        let mut pair = ConnectedPair::new();
+        let topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        pair.subscribe(topic.clone());
+
+        let block = PubsubMessage::Block(BeaconBlock::empty(&MainnetEthSpec::default_spec()));
+        pair.publish(Node::First, vec![topic.clone()], block.clone());
+
+        let (source, topics, message) = pair.next_gossip(Node::Second);
+        assert_eq!(source, pair.peer_id(Node::First));
+        assert_eq!(topics, vec![topic.hash().clone()]);
+        assert_eq!(message, block);
+    }
+
+    #[test]
+    fn pre_encoded_bytes_match_publish() {
+        let mut pair = ConnectedPair::new();
+        let topic = TopicBuilder::new(crate::BEACON_ATTESTATION_TOPIC).build();
+        pair.subscribe(topic.clone());
+        let observer = Arc::new(RecordingObserver::default());
+        pair.behaviour(Node::Second)
+            .set_gossip_observer(Some(observer.clone()));
+
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        let published = PubsubMessage::Attestation(Attestation::random_for_test(&mut rng));
+        pair.publish(Node::First, vec![topic.clone()], published.clone());
+        pair.next_gossip(Node::Second);
+
+        let pre_encoded = PubsubMessage::Attestation(Attestation::random_for_test(&mut rng));
+        pair.behaviour(Node::First)
+            .publish_raw(vec![topic], pre_encoded.as_ssz_bytes())
+            .expect("should publish");
+        pair.next_gossip(Node::Second);
+
+        // the bytes gossipsub delivered, without the signature they may be wrapped in
+        let signed = pair.behaviour(Node::Second).signing_key.is_some();
+        let received: Vec<Vec<u8>> = observer
+            .observed
+            .lock()
+            .expect("lock should not be poisoned")
+            .iter()
+            .map(|(_topics, source, data)| {
+                if signed {
+                    verify_gossip(source, data).expect("signature should be valid")
+                } else {
+                    data.clone()
+                }
+            })
+            .collect();
+        assert_eq!(
+            received,
+            vec![published.as_ssz_bytes(), pre_encoded.as_ssz_bytes()]
+        );
+    }
+
+    #[test]
+    fn oversized_message_published_on_no_topics() {
+        let mut behaviour = build_behaviour();
+        let topics = vec![
+            TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build(),
+            TopicBuilder::new(crate::BEACON_ATTESTATION_TOPIC).build(),
+        ];
+        let published =
+            |behaviour: &Behaviour<_>| behaviour.metrics.gossip_messages_published.get();
+
+        assert!(behaviour
+            .publish_raw(topics.clone(), vec![0; GOSSIP_MAX_SIZE + 1])
+            .is_err());
+        assert_eq!(published(&behaviour), 0);
+
+        assert!(behaviour.publish_raw(topics, vec![0; 32]).is_ok());
+        assert_eq!(published(&behaviour), 2);
+    }
+
+    #[test]
+    fn observer_publishes_nothing() {
+        let mut config = test_config();
+        config.observer_mode = true;
+        let mut behaviour = build_behaviour_with_config(config);
+        let topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        behaviour.subscribe(topic.clone());
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        let block = PubsubMessage::Block(BeaconBlock::random_for_test(&mut rng));
+
+        assert!(behaviour.publish(vec![topic], block).is_ok());
+        assert_eq!(behaviour.metrics.gossip_messages_published.get(), 0);
+        assert!(behaviour.publish_queue.is_empty());
+        assert_eq!(behaviour.published_since_poll, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn publishes_counted_per_topic() {
+        // unsigned, so the published sizes are those of the data
+        let mut config = test_config();
+        config.message_authenticity = MessageAuthenticity::Anonymous;
+        let mut behaviour = build_behaviour_with_config(config);
+        let block_topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        let attestation_topic = TopicBuilder::new(crate::BEACON_ATTESTATION_TOPIC).build();
+        let publishes = |behaviour: &Behaviour<_>, topic: &Topic| {
+            let label = topic.hash().clone().into_string();
+            let metrics = &behaviour.metrics;
+            (
+                metrics
+                    .gossip_topic_publishes
+                    .with_label_values(&[&label])
+                    .get(),
+                metrics
+                    .gossip_published_bytes
+                    .with_label_values(&[&label])
+                    .get_sample_sum(),
+            )
+        };
+
+        let topics = vec![block_topic.clone(), attestation_topic.clone()];
+        assert!(behaviour.publish_raw(topics, vec![0; 100]).is_ok());
+        assert!(behaviour
+            .publish_raw(vec![block_topic.clone()], vec![0; 1000])
+            .is_ok());
+
+        assert_eq!(publishes(&behaviour, &block_topic), (2, 1100.0));
+        assert_eq!(publishes(&behaviour, &attestation_topic), (1, 100.0));
+    }
+
+    #[test]
+    fn block_published_before_queued_attestations() {
+        let mut behaviour = build_behaviour();
+        let topics = vec![TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build()];
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        let block = PubsubMessage::Block(BeaconBlock::random_for_test(&mut rng));
+        let attestation = PubsubMessage::Attestation(Attestation::random_for_test(&mut rng));
+        let published =
+            |behaviour: &Behaviour<_>| behaviour.metrics.gossip_messages_published.get();
+
+        // a backlog of attestations fills this poll's budget and starts the queue
+        for _ in 0..MAX_PUBLISHES_PER_POLL + 2 {
+            assert!(behaviour
+                .publish(topics.clone(), attestation.clone())
+                .is_ok());
+        }
+        assert_eq!(published(&behaviour), MAX_PUBLISHES_PER_POLL as i64);
+        assert!(behaviour.publish(topics.clone(), block).is_ok());
+        assert_eq!(behaviour.publish_queue.len(), 3);
+
+        let mut queued = Vec::new();
+        while let Some(publish) = behaviour.publish_queue.pop() {
+            queued.push(publish.is_block);
+        }
+        assert_eq!(queued, vec![true, false, false]);
+    }
+
+    #[test]
+    fn queued_publishes_flushed_on_poll() {
+        let mut behaviour = build_behaviour();
+        let topics = vec![TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build()];
+        let published =
+            |behaviour: &Behaviour<_>| behaviour.metrics.gossip_messages_published.get();
+
+        behaviour.published_since_poll = MAX_PUBLISHES_PER_POLL;
+        for _ in 0..3 {
+            assert!(behaviour.publish_raw(topics.clone(), vec![1; 32]).is_ok());
+        }
+        assert_eq!(published(&behaviour), 0);
+
+        behaviour.published_since_poll = 0;
+        behaviour.flush_publishes();
+        assert!(behaviour.publish_queue.is_empty());
+        assert_eq!(published(&behaviour), 3);
+    }
+
+    #[test]
+    fn gossip_dropped_while_paused() {
+        let local = build_behaviour();
+        let mut remote = build_behaviour();
+        let local_id = local
+            .signing_key
+            .as_ref()
+            .expect("messages should be signed")
+            .public()
+            .into_peer_id();
+        let block_topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        let message = PubsubMessage::Block(BeaconBlock::empty(&MainnetEthSpec::default_spec()));
+        let published = local
+            .prepare_gossip(ssz_encode(&message))
+            .expect("should prepare gossip");
+        let receive = |remote: &mut Behaviour<_>, data: Vec<u8>| {
+            remote.inject_event(GossipsubEvent::Message(GossipsubMessage {
+                source: local_id.clone(),
+                data,
+                sequence_number: vec![],
+                topics: vec![block_topic.hash().clone()],
+            }))
+        };
+
+        // a publish queued before pausing is held back
+        remote.published_since_poll = MAX_PUBLISHES_PER_POLL;
+        assert!(remote
+            .publish(vec![block_topic.clone()], message.clone())
+            .is_ok());
+        remote.pause_gossip();
+        assert!(remote.is_gossip_paused());
+        remote.published_since_poll = 0;
+        remote.flush_publishes();
+        assert_eq!(remote.publish_queue.len(), 1);
+        assert!(remote
+            .publish(vec![block_topic.clone()], message.clone())
+            .is_err());
+
+        // messages are neither handled nor scored
+        receive(&mut remote, published.clone());
+        receive(&mut remote, vec![1, 2, 3]);
+        assert!(remote.events.is_empty());
+
+        remote.resume_gossip();
+        assert!(!remote.is_gossip_paused());
+        remote.flush_publishes();
+        assert!(remote.publish_queue.is_empty());
+        receive(&mut remote, published);
+        match remote.events.as_slice() {
+            [BehaviourEvent::GossipMessage {
+                message: received, ..
+            }] => assert_eq!(**received, message),
+            _ => panic!("expected the message once gossip is resumed"),
+        }
+    }
+
+    #[test]
+    fn full_publish_queue_keeps_blocks() {
+        let mut queue = PublishQueue::default();
+        let publish = |is_block: bool| QueuedPublish {
+            topics: Vec::new(),
+            data: Vec::new(),
+            is_block,
+        };
+
+        for _ in 0..MAX_QUEUED_PUBLISHES {
+            assert!(queue.push(publish(false)));
+        }
+        assert!(!queue.push(publish(false)));
+        // a block displaces the oldest attestation
+        assert!(queue.push(publish(true)));
+        assert_eq!(queue.len(), MAX_QUEUED_PUBLISHES);
+        assert_eq!(queue.blocks.len(), 1);
+    }
+
+    /// Returns the peer summaries the behaviour has emitted.
+    fn peer_summaries(behaviour: &Behaviour<Substream<StreamMuxerBox>>) -> Vec<PeerSummary> {
+        behaviour
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                BehaviourEvent::PeerSummary(summary) => Some(summary.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn peer_summary_at_interval() {
+        let mut behaviour = build_behaviour();
+        let interval = behaviour.peer_summary_interval;
+        let start = Instant::now();
+        behaviour.next_peer_summary = start + interval;
+
+        let lighthouse_peer = PeerId::random();
+        behaviour.client_info.insert(
+            lighthouse_peer.clone(),
+            ClientInfo {
+                protocol_version: IDENTIFY_PROTOCOL_VERSION.to_string(),
+                agent_version: "Lighthouse/v0.1.0-unstable".to_string(),
+            },
+        );
+        behaviour
+            .peer_rtts
+            .insert(lighthouse_peer.clone(), Duration::from_millis(100));
+        behaviour
+            .peer_rtts
+            .insert(PeerId::random(), Duration::from_millis(300));
+        let topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        behaviour.subscribe(topic.clone());
+        behaviour
+            .topic_peers
+            .entry(topic.hash().clone())
+            .or_insert_with(HashSet::new)
+            .insert(lighthouse_peer);
+
+        behaviour.poll_peer_summary(start + interval / 2);
+        assert!(peer_summaries(&behaviour).is_empty());
+
+        behaviour.poll_peer_summary(start + interval);
+        let summaries = peer_summaries(&behaviour);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].clients.get("Lighthouse"), Some(&1));
+        assert_eq!(summaries[0].average_rtt, Some(Duration::from_millis(200)));
+        assert_eq!(summaries[0].topic_peers.get(topic.hash()), Some(&1));
+        assert_eq!(summaries[0].connected_peers, 0);
+        assert_eq!(summaries[0].last_peer_search, None);
+
+        // the next summary is a full interval later
+        behaviour.poll_peer_summary(start + interval * 3 / 2);
+        assert_eq!(peer_summaries(&behaviour).len(), 1);
+        behaviour.poll_peer_summary(start + interval * 2);
+        assert_eq!(peer_summaries(&behaviour).len(), 2);
+    }
+
+    #[test]
+    fn unsigned_gossip_rejected() {
+        let key = Keypair::generate_secp256k1();
+        let source = key.public().into_peer_id();
+        let data = ssz_encode(&PubsubMessage::Block(BeaconBlock::empty(
+            &MainnetEthSpec::default_spec(),
+        )));
+
+        assert!(verify_gossip(&source, &data).is_err());
+
+        let signed = sign_gossip(&key, data.clone()).expect("should sign");
+        assert_eq!(verify_gossip(&source, &signed), Ok(data));
+        assert_eq!(
+            verify_gossip(&PeerId::random(), &signed),
+            Err(GossipSignatureError::SourceMismatch)
+        );
+
+        let mut tampered = SignedGossip::from_ssz_bytes(&signed).expect("should decode");
+        tampered.data.push(0);
+        assert_eq!(
+            verify_gossip(&source, &tampered.as_ssz_bytes()),
+            Err(GossipSignatureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn invalid_signature_not_scored() {
+        let mut behaviour = build_behaviour();
+        let source = PeerId::random();
+        connect(&mut behaviour, &source);
+        let data = ssz_encode(&PubsubMessage::Block(BeaconBlock::empty(
+            &MainnetEthSpec::default_spec(),
+        )));
+
+        for _ in 0..5 {
+            behaviour.inject_event(GossipsubEvent::Message(GossipsubMessage {
+                source: source.clone(),
+                data: data.clone(),
+                sequence_number: vec![],
+                topics: vec![TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC)
+                    .build()
+                    .hash()
+                    .clone()],
+            }));
+        }
+        assert!(behaviour.events.is_empty());
+        assert!(!behaviour.discovery.is_banned(&source));
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&source), 0);
+    }
+
+    #[test]
+    fn gossip_anonymous_by_default() {
+        assert_eq!(
+            NetworkConfig::default().message_authenticity,
+            MessageAuthenticity::Anonymous
+        );
+        let mut config = test_config();
+        config.message_authenticity = MessageAuthenticity::Anonymous;
+        assert!(build_behaviour_with_config(config).signing_key.is_none());
+    }
+
+    /// Records the gossip messages it observes.
+    #[derive(Default)]
+    struct RecordingObserver {
+        observed: Mutex<Vec<(Vec<TopicHash>, PeerId, Vec<u8>)>>,
+    }
+
+    impl GossipObserver for RecordingObserver {
+        fn observe(&self, topics: &[TopicHash], source: &PeerId, data: &[u8]) {
+            self.observed
+                .lock()
+                .expect("lock should not be poisoned")
+                .push((topics.to_vec(), source.clone(), data.to_vec()));
+        }
+    }
+
+    #[test]
+    fn observer_sees_raw_gossip() {
+        let local = build_behaviour();
+        let mut remote = build_behaviour();
+        let local_id = local
+            .signing_key
+            .as_ref()
+            .expect("messages should be signed")
+            .public()
+            .into_peer_id();
+        let topics = vec![TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC)
+            .build()
+            .hash()
+            .clone()];
+        let message = PubsubMessage::Block(BeaconBlock::empty(&MainnetEthSpec::default_spec()));
+        let receive = |remote: &mut Behaviour<_>, data: Vec<u8>| {
+            remote.inject_event(GossipsubEvent::Message(GossipsubMessage {
+                source: local_id.clone(),
+                data,
+                sequence_number: vec![],
+                topics: topics.clone(),
+            }))
+        };
+
+        // without an observer the message is handled as usual
+        let mut block = BeaconBlock::empty(&MainnetEthSpec::default_spec());
+        block.slot = Slot::new(1);
+        let unobserved = local
+            .prepare_gossip(ssz_encode(&PubsubMessage::Block(block)))
+            .expect("should prepare gossip");
+        receive(&mut remote, unobserved);
+        assert_eq!(remote.events.len(), 1);
+        remote.events.clear();
+
+        let observer = Arc::new(RecordingObserver::default());
+        remote.set_gossip_observer(Some(observer.clone()));
+
+        // the observer sees the bytes as published, signature included
+        let published = local
+            .prepare_gossip(ssz_encode(&message))
+            .expect("should prepare gossip");
+        receive(&mut remote, published.clone());
+        assert_eq!(
+            *observer
+                .observed
+                .lock()
+                .expect("lock should not be poisoned"),
+            vec![(topics.clone(), local_id.clone(), published.clone())]
+        );
+        match remote.events.as_slice() {
+            [BehaviourEvent::GossipMessage {
+                message: decoded, ..
+            }] => {
+                assert_eq!(**decoded, message)
+            }
+            _ => panic!("expected the decoded message"),
+        }
+
+        // duplicates and undecodable messages are observed before being dropped
+        receive(&mut remote, published);
+        receive(&mut remote, vec![1, 2, 3]);
+        assert_eq!(
+            observer
+                .observed
+                .lock()
+                .expect("lock should not be poisoned")
+                .len(),
+            3
+        );
+
+        remote.set_gossip_observer(None);
+        receive(&mut remote, vec![4, 5, 6]);
+        assert_eq!(
+            observer
+                .observed
+                .lock()
+                .expect("lock should not be poisoned")
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn misbehaving_peer_said_goodbye_then_banned() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        behaviour.ban_peer(peer_id.clone());
+        assert!(behaviour.events.is_empty());
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&peer_id), 1);
+        assert!(behaviour.discovery.is_banned(&peer_id));
+        assert!(!behaviour.discovery.has_addresses(&peer_id));
+
+        // the peer is banned once our goodbye is sent
+        ack_sent(&mut behaviour, &peer_id);
+        behaviour.poll_departing_peers(Instant::now());
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::BanPeer(banned)] => assert_eq!(*banned, peer_id),
+            _ => panic!("expected the peer to be banned"),
+        }
+        behaviour.events.clear();
+
+        // the peer dials us again
+        let endpoint = ConnectedPoint::Listener {
+            listen_addr: "/ip4/0.0.0.0/tcp/9000".parse().expect("valid address"),
+            send_back_addr: "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address"),
+        };
+        behaviour
+            .discovery
+            .inject_disconnected(&peer_id, endpoint.clone());
+        behaviour
+            .discovery
+            .inject_connected(peer_id.clone(), endpoint);
+        assert_eq!(
+            behaviour.discovery.take_refused_peers(),
+            vec![peer_id.clone()]
+        );
+
+        // the ban ends
+        behaviour.poll_ban_expiries(Instant::now());
+        assert!(behaviour.events.is_empty());
+        behaviour.poll_ban_expiries(Instant::now() + behaviour.ban_duration);
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::UnbanPeer(unbanned)] => assert_eq!(*unbanned, peer_id),
+            _ => panic!("expected the peer to be unbanned"),
+        }
+        assert!(!behaviour.discovery.is_banned(&peer_id));
+    }
+
+    #[test]
+    fn metadata_seq_increments_on_change() {
+        let mut behaviour = build_behaviour();
+        assert_eq!(behaviour.metadata().seq_number, 0);
+        let enr_seq = behaviour.local_enr().seq();
+
+        // the subnets are advertised in our ENR too
+        let mut attnets = Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT);
+        attnets.set(3, true);
+        behaviour.update_metadata(attnets.clone());
+        assert_eq!(behaviour.metadata().seq_number, 1);
+        assert_eq!(behaviour.metadata().attnets, attnets);
+        assert_eq!(behaviour.local_enr().seq(), enr_seq + 1);
+
+        // re-applying the same subnets is not a change
+        behaviour.update_metadata(attnets);
+        assert_eq!(behaviour.metadata().seq_number, 1);
+        assert_eq!(behaviour.local_enr().seq(), enr_seq + 1);
+    }
+
+    #[test]
+    fn peers_selected_by_subnet() {
+        let mut behaviour = build_behaviour();
+        let metadata = |subnets: &[usize]| {
+            let mut metadata = MetaData::default();
+            for subnet in subnets {
+                metadata.attnets.set(*subnet, true);
+            }
+            RPCEvent::Response {
+                id: RequestId::from(1),
+                method_id: RPCMethod::MetaData.into(),
+                result: RPCResponse::MetaData(metadata),
+            }
+        };
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+
+        behaviour.inject_event(RPCMessage::RPC(peer_id.clone(), metadata(&[1, 2])));
+        behaviour.inject_event(RPCMessage::RPC(other_peer_id.clone(), metadata(&[2])));
+        assert_eq!(behaviour.peers_on_subnet(1), vec![peer_id.clone()]);
+        assert_eq!(behaviour.peers_on_subnet(2).len(), 2);
+        assert!(behaviour.peers_on_subnet(3).is_empty());
+        // subnets beyond the bitfield are covered by no one
+        assert!(behaviour
+            .peers_on_subnet(ATTESTATION_SUBNET_COUNT)
+            .is_empty());
+
+        // newer metadata replaces the old, and is forgotten on disconnection
+        behaviour.inject_event(RPCMessage::RPC(peer_id.clone(), metadata(&[3])));
+        assert!(behaviour.peers_on_subnet(1).is_empty());
+        assert_eq!(behaviour.peers_on_subnet(3), vec![peer_id.clone()]);
+        behaviour.inject_event(RPCMessage::PeerDisconnected(peer_id));
+        assert!(behaviour.peers_on_subnet(3).is_empty());
+    }
+
+    #[test]
+    fn peer_sees_updated_metadata() {
+        let mut local = build_behaviour();
+        let mut remote = build_behaviour();
+        let local_id = PeerId::random();
+        let remote_id = PeerId::random();
+        let request = |id: u64| RPCEvent::Request {
+            id: RequestId::from(id),
+            method_id: RPCMethod::MetaData.into(),
+            body: RPCRequest::MetaData,
+        };
+
+        // requests are answered without reaching the application
+        local.inject_event(RPCMessage::RPC(remote_id.clone(), request(1)));
+        assert!(local.events.is_empty());
+
+        remote.inject_event(RPCMessage::RPC(
+            local_id.clone(),
+            local.metadata_response(RequestId::from(1)),
+        ));
+        assert_eq!(remote.peer_metadata(&local_id), Some(MetaData::default()));
+        assert!(remote.events.is_empty());
+
+        let mut attnets = Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT);
+        attnets.set(7, true);
+        local.update_metadata(attnets.clone());
+
+        local.inject_event(RPCMessage::RPC(remote_id, request(2)));
+        remote.inject_event(RPCMessage::RPC(
+            local_id.clone(),
+            local.metadata_response(RequestId::from(2)),
+        ));
+        let metadata = remote
+            .peer_metadata(&local_id)
+            .expect("metadata should be stored");
+        assert_eq!(metadata.seq_number, 1);
+        assert_eq!(metadata.attnets, attnets);
+    }
+
+    #[test]
+    fn ping_with_new_seq_fetches_metadata() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        let pong = |seq: u64| RPCEvent::Response {
+            id: RequestId::from(1),
+            method_id: RPCMethod::Ping.into(),
+            result: RPCResponse::Ping(PingMessage { data: seq }),
+        };
+        let metadata_requests =
+            |behaviour: &Behaviour<_>| behaviour.serenity_rpc.pending_requests(&peer_id);
+
+        // the peer's metadata is unknown, so the first pong triggers a fetch
+        behaviour.inject_event(RPCMessage::RPC(peer_id.clone(), pong(0)));
+        assert_eq!(metadata_requests(&behaviour), 1);
+        assert!(behaviour.events.is_empty());
+
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Response {
+                id: RequestId::from(1),
+                method_id: RPCMethod::MetaData.into(),
+                result: RPCResponse::MetaData(MetaData::default()),
+            },
+        ));
+
+        // an unchanged sequence number needs no fetch
+        behaviour.inject_event(RPCMessage::RPC(peer_id.clone(), pong(0)));
+        assert_eq!(metadata_requests(&behaviour), 1);
+
+        // the peer bumped its metadata
+        behaviour.inject_event(RPCMessage::RPC(peer_id.clone(), pong(1)));
+        assert_eq!(metadata_requests(&behaviour), 2);
+
+        // so did a peer pinging us, which is also answered
+        let other_id = PeerId::random();
+        behaviour.inject_event(RPCMessage::RPC(
+            other_id.clone(),
+            RPCEvent::Request {
+                id: RequestId::from(1),
+                method_id: RPCMethod::Ping.into(),
+                body: RPCRequest::Ping(PingMessage { data: 3 }),
+            },
+        ));
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&other_id), 1);
+        assert!(behaviour.events.is_empty());
+    }
+
+    #[test]
+    fn failed_pings_ban_peer() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        let failure = |error: RPCError| RPCEvent::Error {
+            id: RequestId::from(1),
+            method_id: RPCMethod::Ping.into(),
+            error,
+        };
+
+        // disconnections are not the peer's fault
+        for _ in 0..MAX_RPC_PING_FAILURES {
+            behaviour.inject_event(RPCMessage::RPC(
+                peer_id.clone(),
+                failure(RPCError::Disconnected),
+            ));
+        }
+        assert!(behaviour.events.is_empty());
+
+        // nor are pings the peer does not support
+        for _ in 0..MAX_RPC_PING_FAILURES {
+            behaviour.inject_event(RPCMessage::RPC(
+                peer_id.clone(),
+                failure(RPCError::NegotiationFailed),
+            ));
+            behaviour.inject_event(RPCMessage::RPC(
+                peer_id.clone(),
+                failure(RPCError::new(RPCErrorCode::InvalidRequest, None)),
+            ));
+        }
+        assert!(behaviour.events.is_empty());
+
+        for _ in 0..MAX_RPC_PING_FAILURES - 1 {
+            behaviour.inject_event(RPCMessage::RPC(peer_id.clone(), failure(RPCError::Timeout)));
+        }
+        assert!(behaviour.events.is_empty());
+
+        // a successful ping resets the count
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Response {
+                id: RequestId::from(1),
+                method_id: RPCMethod::Ping.into(),
+                result: RPCResponse::Ping(PingMessage { data: 0 }),
+            },
+        ));
+        for _ in 0..MAX_RPC_PING_FAILURES - 1 {
+            behaviour.inject_event(RPCMessage::RPC(peer_id.clone(), failure(RPCError::Timeout)));
+        }
+        assert!(behaviour.events.is_empty());
+
+        behaviour.inject_event(RPCMessage::RPC(peer_id.clone(), failure(RPCError::Timeout)));
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::BanPeer(banned)] => assert_eq!(*banned, peer_id),
+            _ => panic!("expected the peer to be banned"),
+        }
+    }
+
+    #[test]
+    fn rpc_to_unknown_peer_not_queued() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+
+        // discovery knows no address to dial the peer on, the request fails immediately
+        let id = behaviour.send_rpc(
+            peer_id.clone(),
+            RPCEvent::Request {
+                id: RequestId::default(),
+                method_id: RPCMethod::MetaData.into(),
+                body: RPCRequest::MetaData,
+            },
+        );
+        assert!(id.is_some());
+        assert_eq!(behaviour.serenity_rpc.queued_requests(&peer_id), 0);
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&peer_id), 0);
+    }
+
+    /// Connects `peer_id` to the behaviour, as the swarm would.
+    fn connect(behaviour: &mut Behaviour<Substream<StreamMuxerBox>>, peer_id: &PeerId) {
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/9000".parse().expect("valid address"),
+        };
+        behaviour
+            .discovery
+            .inject_connected(peer_id.clone(), endpoint.clone());
+        behaviour
+            .serenity_rpc
+            .inject_connected(peer_id.clone(), endpoint);
+        // the handshake is sent on the latest version, so requests of any version may follow
+        let hello = ProtocolId::new(RPCMethod::Hello, RPCVersion::V3, RPCEncoding::SSZSnappy);
+        behaviour
+            .serenity_rpc
+            .inject_node_event(peer_id.clone(), OneShotEvent::Sent(hello));
+    }
+
+    /// Disconnects `peer_id` from the behaviour, as the swarm would.
+    fn disconnect(behaviour: &mut Behaviour<Substream<StreamMuxerBox>>, peer_id: &PeerId) {
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/9000".parse().expect("valid address"),
+        };
+        behaviour.discovery.inject_disconnected(peer_id, endpoint);
+        behaviour.inject_event(RPCMessage::PeerDisconnected(peer_id.clone()));
+    }
+
+    /// Acknowledges the oldest RPC queued for `peer_id` as sent.
+    fn ack_sent(behaviour: &mut Behaviour<Substream<StreamMuxerBox>>, peer_id: &PeerId) {
+        let protocol = ProtocolId::new(RPCMethod::Goodbye, RPCVersion::V1, RPCEncoding::SSZSnappy);
+        behaviour
+            .serenity_rpc
+            .inject_node_event(peer_id.clone(), OneShotEvent::Sent(protocol));
+    }
+
+    fn is_shutdown_complete(behaviour: &Behaviour<Substream<StreamMuxerBox>>) -> bool {
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::ShutdownComplete] => true,
+            [] => false,
+            _ => panic!("unexpected behaviour events"),
+        }
+    }
+
+    #[test]
+    fn shutdown_says_goodbye_to_peers() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+        connect(&mut behaviour, &other_peer_id);
+
+        behaviour.shutdown();
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&peer_id), 1);
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&other_peer_id), 1);
+
+        // no new work is accepted
+        let topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        let message = PubsubMessage::Attestation(Attestation::random_for_test(
+            &mut XorShiftRng::from_seed([42; 16]),
+        ));
+        assert!(behaviour.publish(vec![topic], message).is_err());
+        assert!(behaviour
+            .send_rpc(
+                peer_id.clone(),
+                RPCEvent::Request {
+                    id: RequestId::default(),
+                    method_id: RPCMethod::MetaData.into(),
+                    body: RPCRequest::MetaData,
+                },
+            )
+            .is_none());
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Request {
+                id: RequestId::from(1),
+                method_id: RPCMethod::MetaData.into(),
+                body: RPCRequest::MetaData,
+            },
+        ));
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&peer_id), 1);
+
+        ack_sent(&mut behaviour, &peer_id);
+        behaviour.poll_shutdown(Instant::now());
+        assert!(!is_shutdown_complete(&behaviour));
+
+        ack_sent(&mut behaviour, &other_peer_id);
+        behaviour.poll_shutdown(Instant::now());
+        assert!(is_shutdown_complete(&behaviour));
+
+        // completion is reported once
+        behaviour.events.clear();
+        behaviour.poll_shutdown(Instant::now());
+        assert!(!is_shutdown_complete(&behaviour));
+    }
+
+    #[test]
+    fn reset_network_forgets_peers() {
+        let mut behaviour = build_behaviour();
+        let (peer_id, other_peer_id) = (PeerId::random(), PeerId::random());
+        connect(&mut behaviour, &peer_id);
+        connect(&mut behaviour, &other_peer_id);
+        let topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        behaviour.subscribe(topic.clone());
+
+        // what we learn of our peers
+        assert!(behaviour.seen_messages.observe(&[1]));
+        behaviour
+            .peer_rtts
+            .insert(peer_id.clone(), Duration::from_millis(50));
+        behaviour
+            .slow_peers
+            .record_rtt(&peer_id, SLOW_PEER_RTT + Duration::from_secs(1));
+        behaviour.report_rpc_outcome(peer_id.clone(), RPCResponseQuality::TimedOut);
+        behaviour.inject_event(GossipsubEvent::Subscribed {
+            peer_id: peer_id.clone(),
+            topic: topic.hash().clone(),
+        });
+        behaviour.events.clear();
+
+        behaviour.reset_network(GoodbyeReason::IrreleventNetwork.into());
+        for peer in &[&peer_id, &other_peer_id] {
+            assert_eq!(behaviour.serenity_rpc.outbound_queue_len(peer), 1);
+            assert!(behaviour.departing_peers.contains_key(*peer));
+        }
+        assert!(behaviour.seen_messages.observe(&[1]));
+        assert!(behaviour.peer_rtts.is_empty());
+        assert!(behaviour.slow_peers.slow_pings.is_empty());
+        assert!(behaviour.rpc_scores.is_empty());
+        assert!(behaviour.rpc_failed_at.is_empty());
+        assert!(behaviour.topic_peers.is_empty());
+        assert!(behaviour.is_subscribed(topic.hash()));
+
+        // the peers are disconnected once our goodbyes are sent, the behaviour keeps running
+        ack_sent(&mut behaviour, &peer_id);
+        ack_sent(&mut behaviour, &other_peer_id);
+        behaviour.poll_departing_peers(Instant::now());
+        let disconnected: Vec<&PeerId> = behaviour
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                BehaviourEvent::DisconnectPeer(peer) => Some(peer),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(disconnected.len(), 2);
+        assert!(!behaviour.is_shutting_down());
+    }
+
+    #[test]
+    fn shutdown_completes_when_peer_hangs() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        behaviour.shutdown();
+        let now = Instant::now();
+        behaviour.poll_shutdown(now);
+        assert!(!is_shutdown_complete(&behaviour));
+
+        // the peer never reads our goodbye
+        behaviour.poll_shutdown(now + SHUTDOWN_TIMEOUT);
+        assert!(is_shutdown_complete(&behaviour));
+    }
+
+    fn status(network_id: u8, finalized_root: u64) -> HelloMessage {
+        HelloMessage {
+            network_id,
+            genesis_root: Hash256::zero(),
+            latest_finalized_root: Hash256::from(finalized_root),
+            latest_finalized_epoch: Epoch::new(4),
+            best_root: Hash256::zero(),
+            best_slot: Slot::new(0),
+        }
+    }
+
+    #[test]
+    fn compatible_status_accepted() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        assert!(behaviour.verify_status(peer_id.clone(), &status(1, 7), &status(1, 7)));
+        // peers yet to finalize anything are on every chain
+        assert!(behaviour.verify_status(peer_id.clone(), &status(1, 7), &status(1, 0)));
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&peer_id), 0);
+        assert!(behaviour.departing_peers.is_empty());
+    }
+
+    #[test]
+    fn peer_on_other_network_disconnected() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        assert!(!behaviour.verify_status(peer_id.clone(), &status(1, 7), &status(2, 7)));
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&peer_id), 1);
+        assert!(behaviour.discovery.is_dial_blocked(&peer_id));
+
+        // the peer is disconnected once our goodbye is sent
+        behaviour.poll_departing_peers(Instant::now());
+        assert!(behaviour.events.is_empty());
+        ack_sent(&mut behaviour, &peer_id);
+        behaviour.poll_departing_peers(Instant::now());
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::DisconnectPeer(disconnected)] => assert_eq!(*disconnected, peer_id),
+            _ => panic!("expected the peer to be disconnected"),
+        }
+        assert!(behaviour.departing_peers.is_empty());
+    }
+
+    #[test]
+    fn nodes_on_other_networks_refuse_each_other() {
+        let mut config = test_config();
+        config.network_id = Some(NETWORK_ID + 1);
+        let mut ours = build_behaviour();
+        let mut theirs = build_behaviour_with_config(config);
+        let (our_id, their_id) = (PeerId::random(), PeerId::random());
+        connect(&mut ours, &their_id);
+        connect(&mut theirs, &our_id);
+
+        // each node sends its configured network id, whatever the status it is given
+        ours.update_local_status(status(7, 7));
+        theirs.update_local_status(status(7, 7));
+        let our_status = ours.local_status.clone().expect("status should be set");
+        let their_status = theirs.local_status.clone().expect("status should be set");
+        assert_eq!(our_status.network_id, NETWORK_ID);
+        assert_eq!(their_status.network_id, NETWORK_ID + 1);
+
+        // each node refuses the other's status, neither recording nor passing it on
+        for (behaviour, peer_id, remote) in vec![
+            (&mut ours, &their_id, their_status),
+            (&mut theirs, &our_id, our_status),
+        ] {
+            behaviour.inject_event(RPCMessage::RPC(
+                peer_id.clone(),
+                RPCEvent::Request {
+                    id: RequestId::from(1),
+                    method_id: RPCMethod::Hello.into(),
+                    body: RPCRequest::Hello(remote),
+                },
+            ));
+            assert!(behaviour.events.is_empty());
+            assert!(behaviour.peer_chain_states.is_empty());
+            assert!(behaviour.departing_peers.contains_key(peer_id));
+            assert_eq!(behaviour.serenity_rpc.outbound_queue_len(peer_id), 1);
+            assert!(behaviour.discovery.is_dial_blocked(peer_id));
+        }
+
+        // a peer on our network with another genesis is refused as well
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+        let mut other_genesis = status(NETWORK_ID, 7);
+        other_genesis.genesis_root = Hash256::from_low_u64_be(1);
+        assert!(!behaviour.verify_network(peer_id.clone(), &other_genesis));
+        assert!(behaviour.departing_peers.contains_key(&peer_id));
+        assert!(behaviour.verify_network(PeerId::random(), &status(NETWORK_ID, 7)));
+    }
+
+    #[test]
+    fn network_must_be_configured() {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let local_key = Keypair::generate_secp256k1();
+        let mut config = test_config();
+        config.network_id = None;
+        assert_eq!(
+            Behaviour::<Substream<StreamMuxerBox>>::new(&local_key, &config, &log).err(),
+            Some(BehaviourError::MissingNetworkId)
+        );
+
+        let mut config = test_config();
+        config.genesis_root = None;
+        assert_eq!(
+            Behaviour::<Substream<StreamMuxerBox>>::new(&local_key, &config, &log).err(),
+            Some(BehaviourError::MissingGenesisRoot)
+        );
+    }
+
+    #[test]
+    fn invalid_config_refused() {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let local_key = Keypair::generate_secp256k1();
+        let build_error = |config: NetworkConfig| {
+            Behaviour::<Substream<StreamMuxerBox>>::new(&local_key, &config, &log).err()
+        };
+
+        let mut config = test_config();
+        config.topics = vec!["beacon block".into()];
+        assert_eq!(
+            build_error(config),
+            Some(BehaviourError::InvalidTopic("beacon block".into()))
+        );
+
+        let mut config = test_config();
+        let address: Multiaddr = "/tcp/9000".parse().expect("valid address");
+        config.libp2p_nodes = vec![address.clone()];
+        assert_eq!(
+            build_error(config),
+            Some(BehaviourError::InvalidBootNodeAddress(address))
+        );
+
+        let mut config = test_config();
+        config.target_peers = 20;
+        config.max_peers = 10;
+        assert_eq!(
+            build_error(config),
+            Some(BehaviourError::InvalidPeerCounts {
+                target_peers: 20,
+                max_peers: 10
+            })
+        );
+
+        let mut config = test_config();
+        config.gossip_fanout_peers = 0;
+        match build_error(config) {
+            Some(BehaviourError::InvalidGossipsubConfig(_)) => {}
+            error => panic!("expected an invalid gossipsub config, got {:?}", error),
+        }
+
+        // a mesh size set apart from the fan-out would be overwritten by it
+        let mut config = test_config();
+        config.gossip_fanout_peers = 4;
+        config.gs_config.mesh_n = 8;
+        match build_error(config) {
+            Some(BehaviourError::InvalidGossipsubConfig(_)) => {}
+            error => panic!("expected an invalid gossipsub config, got {:?}", error),
+        }
+        let mut config = test_config();
+        config.gossip_fanout_peers = 8;
+        config.gs_config.mesh_n = 8;
+        assert_eq!(validate_config(&config), Ok(()));
+
+        let mut config = test_config();
+        config.listen_address = "224.0.0.1".parse().expect("valid address");
+        assert_eq!(
+            build_error(config),
+            Some(BehaviourError::InvalidListenAddress(
+                "224.0.0.1".parse().expect("valid address")
+            ))
+        );
+    }
+
+    #[test]
+    fn excess_inbound_peer_said_goodbye() {
+        let mut config = test_config();
+        config.max_peers = 1;
+        config.target_peers = 1;
+        let mut behaviour = build_behaviour_with_config(config);
+        let listener = |address: &str| ConnectedPoint::Listener {
+            listen_addr: "/ip4/0.0.0.0/tcp/9000".parse().expect("valid address"),
+            send_back_addr: address.parse().expect("valid address"),
+        };
+        let (first, excess) = (PeerId::random(), PeerId::random());
+        behaviour
+            .discovery
+            .inject_connected(first.clone(), listener("/ip4/10.0.1.1/tcp/9000"));
+        behaviour
+            .discovery
+            .inject_connected(excess.clone(), listener("/ip4/10.0.2.1/tcp/9000"));
+
+        behaviour.dismiss_excess_peers();
+        assert!(!behaviour.departing_peers.contains_key(&first));
+        let departure = behaviour
+            .departing_peers
+            .get(&excess)
+            .expect("the excess peer is said goodbye to");
+        assert!(!departure.ban);
+    }
+
+    #[test]
+    fn peer_on_other_chain_disconnected() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        assert!(!behaviour.verify_status(peer_id.clone(), &status(1, 7), &status(1, 8)));
+        assert!(behaviour.discovery.is_dial_blocked(&peer_id));
+
+        // the peer never reads our goodbye
+        behaviour.poll_departing_peers(Instant::now() + GOODBYE_TIMEOUT);
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::DisconnectPeer(disconnected)] => assert_eq!(*disconnected, peer_id),
+            _ => panic!("expected the peer to be disconnected"),
+        }
+    }
+
+    #[test]
+    fn incompatible_peer_kept_when_configured() {
+        let mut behaviour = build_behaviour();
+        behaviour.keep_incompatible_peers = true;
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        assert!(!behaviour.verify_status(peer_id.clone(), &status(1, 7), &status(2, 7)));
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&peer_id), 0);
+        assert!(!behaviour.discovery.is_dial_blocked(&peer_id));
+        assert!(behaviour.departing_peers.is_empty());
+    }
+
+    #[test]
+    fn first_block_gossip_timed() {
+        let mut behaviour = build_behaviour();
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        let block = PubsubMessage::Block(BeaconBlock::random_for_test(&mut rng));
+        let attestation = PubsubMessage::Attestation(Attestation::random_for_test(&mut rng));
+        let start = behaviour.started_at;
+
+        behaviour.record_first_block(&attestation, start + Duration::from_secs(1));
+        assert_eq!(behaviour.time_to_first_block(), None);
+
+        behaviour.record_first_block(&block, start + Duration::from_secs(5));
+        assert_eq!(
+            behaviour.time_to_first_block(),
+            Some(Duration::from_secs(5))
+        );
+
+        // later blocks do not move the time
+        behaviour.record_first_block(&block, start + Duration::from_secs(9));
+        assert_eq!(
+            behaviour.time_to_first_block(),
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    fn chain_status(head_slot: u64, finalized_epoch: u64) -> HelloMessage {
+        HelloMessage {
+            latest_finalized_epoch: Epoch::new(finalized_epoch),
+            best_slot: Slot::new(head_slot),
+            ..status(1, 7)
+        }
+    }
+
+    #[test]
+    fn mdns_peers_dialed_until_expired() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        let address: Multiaddr = "/ip4/192.168.0.2/tcp/9000".parse().expect("valid address");
+        let other: Multiaddr = "/ip4/192.168.0.3/tcp/9000".parse().expect("valid address");
+
+        behaviour.mdns_discovered(vec![
+            (peer_id.clone(), address.clone()),
+            (peer_id.clone(), other.clone()),
+        ]);
+        assert!(behaviour.discovery.is_dial_pending(&peer_id));
+        connect(&mut behaviour, &peer_id);
+        let discovered_via_mdns = |behaviour: &Behaviour<Substream<StreamMuxerBox>>| {
+            behaviour.peer_info_snapshot()[0].discovered_via_mdns
+        };
+        assert!(discovered_via_mdns(&behaviour));
+
+        // the peer is known from mDNS until every address has expired
+        behaviour.mdns_expired(vec![(peer_id.clone(), address)]);
+        assert!(discovered_via_mdns(&behaviour));
+        behaviour.mdns_expired(vec![(peer_id, other)]);
+        assert!(!discovered_via_mdns(&behaviour));
+    }
+
+    #[test]
+    fn peer_info_snapshot_of_connected_peers() {
+        let mut behaviour = build_behaviour();
+        let (known, unknown) = (PeerId::random(), PeerId::random());
+        connect(&mut behaviour, &known);
+        connect(&mut behaviour, &unknown);
+
+        let client = ClientInfo {
+            protocol_version: IDENTIFY_PROTOCOL_VERSION.to_string(),
+            agent_version: "Lighthouse/v0.1.0-unstable".to_string(),
+        };
+        behaviour.client_info.insert(known.clone(), client.clone());
+        behaviour
+            .peer_rtts
+            .insert(known.clone(), Duration::from_millis(100));
+        behaviour.report_rpc_outcome(known.clone(), RPCResponseQuality::Useful);
+        let topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        behaviour
+            .topic_peers
+            .entry(topic.hash().clone())
+            .or_insert_with(HashSet::new)
+            .insert(known.clone());
+        behaviour.record_status(known.clone(), &chain_status(50, 1), Instant::now());
+
+        let mut snapshot = behaviour.peer_info_snapshot();
+        assert_eq!(snapshot.len(), 2);
+        snapshot.sort_by_key(|info| info.peer_id != known);
+
+        let info = &snapshot[0];
+        assert_eq!(info.peer_id, known);
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/9000".parse().expect("valid address");
+        assert_eq!(info.address, Some(address));
+        assert_eq!(info.rtt, Some(Duration::from_millis(100)));
+        assert_eq!(info.rpc_score, 1);
+        assert_eq!(info.client, Some(client));
+        assert_eq!(info.topics, vec![topic.hash().clone()]);
+        let chain = info.chain.as_ref().expect("status was recorded");
+        assert_eq!(chain.head_slot, Slot::new(50));
+
+        // peers we know little of are still listed
+        let info = &snapshot[1];
+        assert_eq!(info.peer_id, unknown);
+        assert!(info.address.is_some());
+        assert_eq!((info.rtt, info.rpc_score), (None, 0));
+        assert!(info.client.is_none() && info.topics.is_empty() && info.chain.is_none());
+    }
+
+    #[test]
+    fn peers_ordered_by_head_slot() {
+        let mut behaviour = build_behaviour();
+        let (behind, ahead, level) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let start = Instant::now();
+
+        behaviour.record_status(behind.clone(), &chain_status(10, 1), start);
+        behaviour.record_status(ahead.clone(), &chain_status(50, 1), start);
+        behaviour.record_status(level.clone(), &chain_status(50, 2), start);
+        // a later status replaces the earlier one
+        behaviour.record_status(
+            behind.clone(),
+            &chain_status(20, 1),
+            start + Duration::from_secs(1),
+        );
+
+        let peers = behaviour.peers_by_head_slot_at(start + Duration::from_secs(1));
+        let order: Vec<PeerId> = peers.iter().map(|info| info.peer_id.clone()).collect();
+        assert_eq!(order, vec![level, ahead, behind]);
+        assert_eq!(peers[2].head_slot, Slot::new(20));
+        assert_eq!(peers[2].updated_at, start + Duration::from_secs(1));
+        assert!(peers.iter().all(|info| !info.stale));
+    }
+
+    #[test]
+    fn peer_chain_state_tracked_from_status() {
+        let mut behaviour = build_behaviour();
+        let (requester, responder) = (PeerId::random(), PeerId::random());
+        connect(&mut behaviour, &requester);
+        connect(&mut behaviour, &responder);
+
+        behaviour.inject_event(RPCMessage::RPC(
+            requester.clone(),
+            RPCEvent::Request {
+                id: RequestId::from(1),
+                method_id: RPCMethod::Hello.into(),
+                body: RPCRequest::Hello(chain_status(30, 2)),
+            },
+        ));
+        behaviour.inject_event(RPCMessage::RPC(
+            responder.clone(),
+            RPCEvent::Response {
+                id: RequestId::from(1),
+                method_id: RPCMethod::Hello.into(),
+                result: RPCResponse::Hello(chain_status(40, 3)),
+            },
+        ));
+        let head_slots: Vec<(PeerId, Slot)> = behaviour
+            .peers_by_head_slot()
+            .into_iter()
+            .map(|info| (info.peer_id, info.head_slot))
+            .collect();
+        assert_eq!(
+            head_slots,
+            vec![
+                (responder.clone(), Slot::new(40)),
+                (requester, Slot::new(30))
+            ]
+        );
+
+        // disconnected peers are forgotten
+        behaviour.inject_event(RPCMessage::PeerDisconnected(responder));
+        assert_eq!(behaviour.peers_by_head_slot().len(), 1);
+    }
+
+    #[test]
+    fn old_peer_status_flagged_stale() {
+        let mut behaviour = build_behaviour();
+        let (old, recent) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        let max_age = behaviour.peer_status_max_age;
+
+        behaviour.record_status(old.clone(), &chain_status(50, 1), start);
+        behaviour.record_status(recent.clone(), &chain_status(10, 1), start + max_age);
+
+        let peers = behaviour.peers_by_head_slot_at(start + max_age);
+        assert!(peers.iter().all(|info| !info.stale));
+
+        // stale peers are still listed by head slot, for sync to decide on
+        let peers = behaviour.peers_by_head_slot_at(start + max_age + Duration::from_secs(1));
+        match peers.as_slice() {
+            [first, second] => {
+                assert_eq!((&first.peer_id, first.stale), (&old, true));
+                assert_eq!((&second.peer_id, second.stale), (&recent, false));
+            }
+            _ => panic!("expected both peers"),
+        }
+    }
+
+    /// Connects `peer_id` as a peer we have exchanged statuses with.
+    fn exchange_status(behaviour: &mut Behaviour<Substream<StreamMuxerBox>>, peer_id: &PeerId) {
+        connect(behaviour, peer_id);
+        behaviour.record_status(peer_id.clone(), &chain_status(10, 1), Instant::now());
+    }
+
+    /// Returns the id of the single pending status request to `peer_id`.
+    fn status_request_id(
+        behaviour: &Behaviour<Substream<StreamMuxerBox>>,
+        peer_id: &PeerId,
+    ) -> RequestId {
+        match behaviour
+            .status_requests
+            .iter()
+            .filter(|(requested_peer, _id)| requested_peer == peer_id)
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            [(_peer_id, id)] => *id,
+            _ => panic!("expected a single status request"),
+        }
+    }
+
+    #[test]
+    fn status_exchanged_periodically() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        exchange_status(&mut behaviour, &peer_id);
+        let start = Instant::now();
+        behaviour.next_status_exchange = start + behaviour.status_interval;
+
+        // nothing is sent before our status is known
+        behaviour.poll_status_exchange(start + behaviour.status_interval);
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&peer_id), 0);
+
+        behaviour.update_local_status(chain_status(20, 1));
+        let first = behaviour.next_status_exchange;
+        behaviour.poll_status_exchange(first - Duration::from_secs(1));
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&peer_id), 0);
+        behaviour.poll_status_exchange(first);
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&peer_id), 1);
+        assert_eq!(
+            behaviour.next_status_exchange,
+            first + behaviour.status_interval
+        );
+
+        // the reply updates the peer's chain state and is not passed on
+        let id = status_request_id(&behaviour, &peer_id);
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Response {
+                id,
+                method_id: RPCMethod::Hello.into(),
+                result: RPCResponse::Hello(chain_status(30, 1)),
+            },
+        ));
+        assert!(behaviour.events.is_empty());
+        assert!(behaviour.status_requests.is_empty());
+        assert_eq!(behaviour.peers_by_head_slot()[0].head_slot, Slot::new(30));
+
+        behaviour.poll_status_exchange(first + behaviour.status_interval);
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&peer_id), 2);
+    }
+
+    #[test]
+    fn finalized_status_change_sent_immediately() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        exchange_status(&mut behaviour, &peer_id);
+        behaviour.update_local_status(chain_status(20, 1));
+        let next = behaviour.next_status_exchange;
+
+        // a new head waits for the next exchange
+        behaviour.update_local_status(chain_status(21, 1));
+        assert_eq!(behaviour.next_status_exchange, next);
+
+        behaviour.update_local_status(chain_status(40, 2));
+        behaviour.poll_status_exchange(Instant::now());
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&peer_id), 1);
+    }
+
+    #[test]
+    fn failed_status_requests_lower_score() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        exchange_status(&mut behaviour, &peer_id);
+        behaviour.update_local_status(chain_status(20, 1));
+        let mut now = behaviour.next_status_exchange;
+        let mut fail = |behaviour: &mut Behaviour<_>, error: RPCError| {
+            behaviour.poll_status_exchange(now);
+            now += behaviour.status_interval;
+            let id = status_request_id(behaviour, &peer_id);
+            behaviour.inject_event(RPCMessage::RPC(
+                peer_id.clone(),
+                RPCEvent::Error {
+                    id,
+                    method_id: RPCMethod::Hello.into(),
+                    error,
+                },
+            ));
+        };
+
+        // disconnections are not the peer's fault
+        for _ in 0..MAX_STATUS_FAILURES {
+            fail(&mut behaviour, RPCError::Disconnected);
+        }
+        assert_eq!(behaviour.rpc_score(&peer_id), 0);
+
+        for _ in 0..MAX_STATUS_FAILURES - 1 {
+            fail(&mut behaviour, RPCError::Timeout);
+        }
+        assert_eq!(behaviour.rpc_score(&peer_id), 0);
+        fail(&mut behaviour, RPCError::Timeout);
+        assert_eq!(
+            behaviour.rpc_score(&peer_id),
+            RPCResponseQuality::TimedOut.score_delta()
+        );
+        // the failures are passed on to no one
+        assert!(behaviour.events.is_empty());
+    }
+
+    #[test]
+    fn rpc_penalty_survives_reconnection() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+        behaviour.report_rpc_outcome(peer_id.clone(), RPCResponseQuality::TimedOut);
+        let penalty = RPCResponseQuality::TimedOut.score_delta();
+
+        let disconnected_at = Instant::now();
+        disconnect(&mut behaviour, &peer_id);
+        behaviour.poll_rpc_penalty_expiries(disconnected_at);
+        assert_eq!(behaviour.rpc_score(&peer_id), penalty);
+        connect(&mut behaviour, &peer_id);
+        assert_eq!(behaviour.rpc_score(&peer_id), penalty);
+
+        // the penalty does not expire while the peer is connected
+        behaviour.poll_rpc_penalty_expiries(disconnected_at + RPC_PENALTY_RETENTION * 2);
+        assert_eq!(behaviour.rpc_score(&peer_id), penalty);
+
+        // but is forgotten once the peer has been gone for long enough
+        disconnect(&mut behaviour, &peer_id);
+        let disconnected_at = Instant::now();
+        behaviour.poll_rpc_penalty_expiries(disconnected_at + RPC_PENALTY_RETENTION / 2);
+        assert_eq!(behaviour.rpc_score(&peer_id), penalty);
+        behaviour.poll_rpc_penalty_expiries(disconnected_at + RPC_PENALTY_RETENTION * 2);
+        assert_eq!(behaviour.rpc_score(&peer_id), 0);
+    }
+
+    #[test]
+    fn oversized_response_lowers_score() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Error {
+                id: RequestId::from(1),
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+                error: RPCError::ResponseTooLarge(1_000_000),
+            },
+        ));
+        assert_eq!(
+            behaviour.rpc_score(&peer_id),
+            RPCResponseQuality::Oversized.score_delta()
+        );
+    }
+
+    #[test]
+    fn best_sync_peer_chosen() {
+        let mut behaviour = build_behaviour();
+        let start = Instant::now();
+        assert_eq!(behaviour.best_sync_peer_at(start), None);
+
+        behaviour.update_local_status(chain_status(10, 1));
+        let peer = |behaviour: &mut Behaviour<_>, head_slot: u64, rtt: Option<u64>| {
+            let peer_id = PeerId::random();
+            behaviour.record_status(peer_id.clone(), &chain_status(head_slot, 1), start);
+            if let Some(rtt) = rtt {
+                behaviour
+                    .peer_rtts
+                    .insert(peer_id.clone(), Duration::from_millis(rtt));
+            }
+            peer_id
+        };
+        let fast = peer(&mut behaviour, 50, Some(10));
+        let slower = peer(&mut behaviour, 60, Some(100));
+        let unpinged = peer(&mut behaviour, 70, None);
+        // level with us, so never chosen despite its round-trip time
+        let _level = peer(&mut behaviour, 10, Some(1));
+        let failed = peer(&mut behaviour, 50, Some(1));
+        let banned = peer(&mut behaviour, 50, Some(1));
+        behaviour.report_rpc_outcome(failed, RPCResponseQuality::TimedOut);
+        behaviour.banned_peers.insert(banned);
+        assert_eq!(behaviour.best_sync_peer_at(start), Some(fast.clone()));
+
+        // a short response is no failure
+        behaviour.report_rpc_outcome(fast.clone(), RPCResponseQuality::Short);
+        assert_eq!(behaviour.best_sync_peer_at(start), Some(fast.clone()));
+        behaviour.report_rpc_outcome(fast.clone(), RPCResponseQuality::Empty);
+        assert_eq!(behaviour.best_sync_peer_at(start), Some(slower.clone()));
+        behaviour.report_rpc_outcome(slower, RPCResponseQuality::WrongRange);
+        assert_eq!(behaviour.best_sync_peer_at(start), Some(unpinged.clone()));
+
+        // failures are forgiven after a while
+        let later = Instant::now() + SYNC_PEER_FAILURE_BACKOFF;
+        assert_eq!(behaviour.best_sync_peer_at(later), Some(fast));
+
+        // no peer is ahead of us any more
+        behaviour.update_local_status(chain_status(70, 1));
+        assert_eq!(behaviour.best_sync_peer_at(later), None);
+    }
+
+    /// Returns the peer count events the behaviour has emitted.
+    fn peer_count_events(behaviour: &Behaviour<Substream<StreamMuxerBox>>) -> Vec<(bool, usize)> {
+        behaviour
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                BehaviourEvent::LowPeerCount(count) => Some((true, *count)),
+                BehaviourEvent::RecoveredPeerCount(count) => Some((false, *count)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn peer_count_crossing_min_peers_reported() {
+        let mut behaviour = build_behaviour();
+        behaviour.min_peers = 3;
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/9000".parse().expect("valid address"),
+        };
+        let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        for peer_id in &peers {
+            connect(&mut behaviour, peer_id);
+        }
+        let start = Instant::now();
+        behaviour.poll_peer_count(start);
+
+        // a peer briefly dropping out is not reported
+        behaviour
+            .discovery
+            .inject_disconnected(&peers[0], endpoint.clone());
+        behaviour.poll_peer_count(start);
+        behaviour.poll_peer_count(start + PEER_COUNT_DEBOUNCE - Duration::from_secs(1));
+        connect(&mut behaviour, &peers[0]);
+        behaviour.poll_peer_count(start + PEER_COUNT_DEBOUNCE - Duration::from_secs(1));
+        behaviour.poll_peer_count(start + PEER_COUNT_DEBOUNCE);
+        assert!(peer_count_events(&behaviour).is_empty());
+
+        behaviour
+            .discovery
+            .inject_disconnected(&peers[0], endpoint.clone());
+        let dropped = start + PEER_COUNT_DEBOUNCE;
+        behaviour.poll_peer_count(dropped);
+        behaviour.poll_peer_count(dropped + PEER_COUNT_DEBOUNCE);
+        behaviour.poll_peer_count(dropped + PEER_COUNT_DEBOUNCE * 2);
+        assert_eq!(peer_count_events(&behaviour), vec![(true, 2)]);
+
+        connect(&mut behaviour, &peers[0]);
+        let recovered = dropped + PEER_COUNT_DEBOUNCE * 2;
+        behaviour.poll_peer_count(recovered);
+        assert_eq!(peer_count_events(&behaviour).len(), 1);
+        behaviour.poll_peer_count(recovered + PEER_COUNT_DEBOUNCE);
+        assert_eq!(peer_count_events(&behaviour), vec![(true, 2), (false, 3)]);
+    }
+
+    #[test]
+    fn rpc_dials_queued_by_discovery() {
+        let mut behaviour = build_behaviour();
+        let (peer_id, banned) = (PeerId::random(), PeerId::random());
+        behaviour.discovery.block_dials(banned.clone(), None);
+
+        behaviour.inject_event(RPCMessage::DialPeer(peer_id.clone()));
+        behaviour.inject_event(RPCMessage::DialPeer(banned.clone()));
+        assert!(behaviour.discovery.is_dial_pending(&peer_id));
+        assert!(!behaviour.discovery.is_dial_pending(&banned));
+    }
+
+    #[test]
+    fn peer_policy_reconciled() {
+        let mut behaviour = build_behaviour();
+        let connected = PeerId::random();
+        let trusted = PeerId::random();
+        connect(&mut behaviour, &connected);
+
+        behaviour.update_peer_policy(vec![trusted.clone()], vec![connected.clone()]);
+        assert!(behaviour.discovery.is_dial_pending(&trusted));
+        assert!(behaviour.discovery.is_dial_blocked(&connected));
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&connected), 1);
+
+        // the banned peer is banned once our goodbye is sent
+        ack_sent(&mut behaviour, &connected);
+        behaviour.poll_departing_peers(Instant::now());
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::BanPeer(banned)] => assert_eq!(*banned, connected),
+            _ => panic!("expected the peer to be banned"),
+        }
+        behaviour.events.clear();
+
+        // applying the same lists again changes nothing
+        behaviour.update_peer_policy(vec![trusted.clone()], vec![connected.clone()]);
+        assert_eq!(behaviour.serenity_rpc.outbound_queue_len(&connected), 0);
+        behaviour.poll_departing_peers(Instant::now());
+        assert!(behaviour.events.is_empty());
+
+        // lifting the ban allows the peer to be dialed and to connect again
+        behaviour.update_peer_policy(vec![trusted], vec![]);
+        assert!(!behaviour.discovery.is_dial_blocked(&connected));
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::UnbanPeer(unbanned)] => assert_eq!(*unbanned, connected),
+            _ => panic!("expected the peer to be unbanned"),
+        }
+    }
+
+    #[test]
+    fn blocks_by_range_responses_judged() {
+        let request = BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(10),
+            count: 4,
+            step: 2,
+        };
+        let judge = |slots: &[u64]| {
+            let slots: Vec<Slot> = slots.iter().map(|slot| Slot::new(*slot)).collect();
+            RPCResponseQuality::of_blocks_by_range(&request, &slots)
+        };
+
+        assert_eq!(judge(&[10, 12, 14, 16]), RPCResponseQuality::Useful);
+        assert_eq!(judge(&[12, 16]), RPCResponseQuality::Short);
+        assert_eq!(judge(&[]), RPCResponseQuality::Empty);
+        // before the start, between steps and beyond the end
+        assert_eq!(judge(&[8, 10]), RPCResponseQuality::WrongRange);
+        assert_eq!(judge(&[10, 11]), RPCResponseQuality::WrongRange);
+        assert_eq!(judge(&[10, 18]), RPCResponseQuality::WrongRange);
+    }
+
+    #[test]
+    fn rpc_outcomes_adjust_score() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        let outcomes = [
+            (RPCResponseQuality::Useful, 1),
+            (RPCResponseQuality::Short, 0),
+            (RPCResponseQuality::Empty, -2),
+            (RPCResponseQuality::WrongRange, -7),
+        ];
+        for (quality, score) in outcomes.iter() {
+            behaviour.report_rpc_outcome(peer_id.clone(), *quality);
+            assert_eq!(behaviour.rpc_score(&peer_id), *score);
+        }
+
+        // timeouts of range requests are reported by the behaviour
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Error {
+                id: RequestId::from(1),
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+                error: RPCError::Timeout,
+            },
+        ));
+        assert_eq!(behaviour.rpc_score(&peer_id), -10);
+        behaviour.events.clear();
+
+        // a long useful history only goes so far
+        let other_peer_id = PeerId::random();
+        for _ in 0..100 {
+            behaviour.report_rpc_outcome(other_peer_id.clone(), RPCResponseQuality::Useful);
+        }
+        assert_eq!(behaviour.rpc_score(&other_peer_id), MAX_RPC_SCORE);
+
+        for _ in 0..2 {
+            behaviour.report_rpc_outcome(peer_id.clone(), RPCResponseQuality::WrongRange);
+        }
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::BanPeer(banned)] => assert_eq!(*banned, peer_id),
+            _ => panic!("expected the peer to be banned"),
+        }
+        assert_eq!(behaviour.rpc_score(&peer_id), 0);
+    }
+
+    #[test]
+    fn trusted_peers_never_banned() {
+        let mut behaviour = build_behaviour();
+        let (trusted, untrusted) = (PeerId::random(), PeerId::random());
+        let mut address: Multiaddr = "/ip4/10.0.0.2/tcp/9000".parse().expect("valid address");
+        address.push(Protocol::P2p(trusted.clone().into()));
+        behaviour.add_trusted_peer(address);
+        connect(&mut behaviour, &trusted);
+        connect(&mut behaviour, &untrusted);
+        assert!(behaviour.is_trusted(&trusted));
+        assert!(!behaviour.is_trusted(&untrusted));
+
+        // the worst scored peers are pruned, but not a trusted one
+        for peer_id in &[&trusted, &untrusted] {
+            for _ in 0..4 {
+                behaviour.report_rpc_outcome((*peer_id).clone(), RPCResponseQuality::WrongRange);
+            }
+        }
+        assert!(behaviour.departing_peers.contains_key(&untrusted));
+        assert!(behaviour.discovery.is_banned(&untrusted));
+        assert!(!behaviour.departing_peers.contains_key(&trusted));
+        assert!(!behaviour.discovery.is_banned(&trusted));
+
+        let snapshot = behaviour.peer_info_snapshot();
+        let trusted_peers: Vec<&PeerId> = snapshot
+            .iter()
+            .filter(|info| info.trusted)
+            .map(|info| &info.peer_id)
+            .collect();
+        assert_eq!(trusted_peers, vec![&trusted]);
+    }
+
+    /// Requests blocks from `peer_id` through the future API, returning the future and the id
+    /// assigned to the request.
+    fn request_blocks(
+        behaviour: &mut Behaviour<Substream<StreamMuxerBox>>,
+        peer_id: &PeerId,
+    ) -> (
+        impl Future<Item = Vec<BeaconBlock>, Error = RPCError>,
+        RequestId,
+    ) {
+        let request = BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(0),
+            count: 2,
+            step: 1,
+        };
+        let future = behaviour.request_blocks_by_range(peer_id.clone(), request);
+        let id = behaviour
+            .blocks_by_range_requests
+            .keys()
+            .map(|(_peer_id, id)| *id)
+            .next()
+            .expect("request should be pending");
+        (future, id)
+    }
+
+    #[test]
+    fn blocks_by_range_future_resolves_to_blocks() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        let (future, id) = request_blocks(&mut behaviour, &peer_id);
+        let block = BeaconBlock::empty(&MainnetEthSpec::default_spec());
+        for _ in 0..2 {
+            behaviour.inject_event(RPCMessage::RPC(
+                peer_id.clone(),
+                RPCEvent::Response {
+                    id,
+                    method_id: RPCMethod::BeaconBlocksByRange.into(),
+                    result: RPCResponse::BeaconBlocksByRange(Box::new(block.clone())),
+                },
+            ));
+        }
+        assert_eq!(
+            behaviour.blocks_by_range_requests[&(peer_id.clone(), id)]
+                .blocks
+                .len(),
+            2
+        );
+
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::StreamTermination {
+                id,
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+            },
+        ));
+        assert_eq!(future.wait(), Ok(vec![block.clone(), block]));
+        // the responses are consumed by the future
+        assert!(behaviour.events.is_empty());
+        assert!(behaviour.blocks_by_range_requests.is_empty());
+    }
+
+    #[test]
+    fn dropped_blocks_by_range_future_pruned() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        let (future, _id) = request_blocks(&mut behaviour, &peer_id);
+        behaviour.prune_blocks_by_range_requests();
+        assert_eq!(behaviour.blocks_by_range_requests.len(), 1);
+
+        drop(future);
+        behaviour.prune_blocks_by_range_requests();
+        assert!(behaviour.blocks_by_range_requests.is_empty());
+    }
+
+    #[test]
+    fn blocks_by_range_future_fails() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        let (future, id) = request_blocks(&mut behaviour, &peer_id);
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Error {
+                id,
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+                error: RPCError::Timeout,
+            },
+        ));
+        assert_eq!(future.wait(), Err(RPCError::Timeout));
+
+        let (future, id) = request_blocks(&mut behaviour, &peer_id);
+        let error = RPCError::new(
+            RPCErrorCode::ResourceUnavailable,
+            "Blocks pruned".to_string(),
+        );
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Error {
+                id,
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+                error: error.clone(),
+            },
+        ));
+        assert_eq!(future.wait(), Err(error));
+
+        // requests dropped by the behaviour fail immediately
+        behaviour.shutdown();
+        let request = BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(0),
+            count: 2,
+            step: 1,
+        };
+        assert_eq!(
+            behaviour
+                .request_blocks_by_range(peer_id.clone(), request)
+                .wait(),
+            Err(RPCError::NotSent)
+        );
+    }
+
+    #[test]
+    fn failed_range_retried_from_next_peer() {
+        let mut behaviour = build_behaviour();
+        let (slow, backup) = (PeerId::random(), PeerId::random());
+        for peer_id in &[&slow, &backup] {
+            connect(&mut behaviour, peer_id);
+            behaviour.record_status((*peer_id).clone(), &chain_status(50, 1), Instant::now());
+        }
+        let timeout = |behaviour: &mut Behaviour<_>, peer_id: &PeerId, id| {
+            behaviour.inject_event(RPCMessage::RPC(
+                peer_id.clone(),
+                RPCEvent::Error {
+                    id,
+                    method_id: RPCMethod::BeaconBlocksByRange.into(),
+                    error: RPCError::Timeout,
+                },
+            ));
+        };
+
+        // the first peer times out, and the range is requested from the other
+        let (future, id) = request_blocks(&mut behaviour, &slow);
+        timeout(&mut behaviour, &slow, id);
+        let (retried_peer, retried_id) = behaviour
+            .blocks_by_range_requests
+            .keys()
+            .next()
+            .cloned()
+            .expect("request should be retried");
+        assert_eq!(retried_peer, backup);
+
+        let block = BeaconBlock::empty(&MainnetEthSpec::default_spec());
+        behaviour.inject_event(RPCMessage::RPC(
+            backup.clone(),
+            RPCEvent::Response {
+                id: retried_id,
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+                result: RPCResponse::BeaconBlocksByRange(Box::new(block.clone())),
+            },
+        ));
+        behaviour.inject_event(RPCMessage::RPC(
+            backup.clone(),
+            RPCEvent::StreamTermination {
+                id: retried_id,
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+            },
+        ));
+        assert_eq!(future.wait(), Ok(vec![block]));
+        assert!(behaviour.events.is_empty());
+
+        // the first peer failed too recently to be retried, so no peer is left
+        let (future, id) = request_blocks(&mut behaviour, &backup);
+        timeout(&mut behaviour, &backup, id);
+        assert_eq!(future.wait(), Err(RPCError::Timeout));
+        assert!(behaviour.blocks_by_range_requests.is_empty());
+        match behaviour.events.as_slice() {
+            [BehaviourEvent::RangeSyncFailed { start_slot, count }] => {
+                assert_eq!((*start_slot, *count), (Slot::new(0), 2));
+            }
+            _ => panic!("expected the range sync to fail"),
+        }
+    }
+
+    #[test]
+    fn network_sender_commands_applied_on_poll() {
+        let mut behaviour = build_behaviour();
+        let sender = behaviour.network_sender();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        sender
+            .send_rpc(
+                peer_id.clone(),
+                RPCEvent::Request {
+                    id: RequestId::default(),
+                    method_id: RPCMethod::MetaData.into(),
+                    body: RPCRequest::MetaData,
+                },
+            )
+            .expect("behaviour is alive");
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&peer_id), 0);
+
+        // the receiver registers the polling task once drained
+        future::lazy(|| {
+            behaviour.poll_commands();
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .expect("polling commands does not fail");
+        assert_eq!(behaviour.serenity_rpc.pending_requests(&peer_id), 1);
+
+        drop(behaviour);
+        assert!(sender.dial(peer_id).is_err());
+    }
+
+    #[test]
+    fn local_status_updated_by_command() {
+        let mut behaviour = build_behaviour();
+        let sender = behaviour.network_sender();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        sender
+            .update_local_status(status(NETWORK_ID, 7))
+            .expect("behaviour is alive");
+        future::lazy(|| {
+            behaviour.poll_commands();
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .expect("polling commands does not fail");
+        assert!(behaviour.local_status.is_some());
+
+        // a peer on another finalized chain is disconnected once it says hello
+        behaviour.inject_event(RPCMessage::RPC(
+            peer_id.clone(),
+            RPCEvent::Request {
+                id: RequestId::from(1),
+                method_id: RPCMethod::Hello.into(),
+                body: RPCRequest::Hello(status(NETWORK_ID, 8)),
+            },
+        ));
+        assert!(behaviour.events.is_empty());
+        assert!(behaviour.departing_peers.contains_key(&peer_id));
+        assert!(behaviour.discovery.is_dial_blocked(&peer_id));
+    }
+
+    #[test]
+    fn client_info_from_identify() {
+        let info = IdentifyInfo {
+            public_key: Keypair::generate_secp256k1().public(),
+            protocol_version: IDENTIFY_PROTOCOL_VERSION.to_string(),
+            agent_version: "Lighthouse/v0.1.0-unstable".to_string(),
+            listen_addrs: vec![],
+            protocols: vec![],
+        };
+
+        let client_info = ClientInfo::from(&info);
+        assert_eq!(client_info.agent_version, "Lighthouse/v0.1.0-unstable");
+        assert_eq!(client_info.protocol_version, IDENTIFY_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn banned_agent_matching() {
+        let banned_agents = vec!["BuggyClient/v0.1".to_string()];
+
+        assert!(is_banned_agent(&banned_agents, "BuggyClient/v0.1.2/linux"));
+        assert!(!is_banned_agent(&banned_agents, "BuggyClient/v0.2.0/linux"));
+        assert!(!is_banned_agent(&banned_agents, "Lighthouse/v0.1.0"));
+        assert!(!is_banned_agent(&[], "BuggyClient/v0.1.2/linux"));
+    }
+
+    #[test]
+    fn short_peer_id_format() {
+        let peer_id: PeerId = "QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N"
+            .parse()
+            .expect("valid peer id");
+        assert_eq!(short_peer_id(&peer_id), "QmYy...Wjhx5N");
+    }
+
+    #[test]
+    fn truncated_body() {
+        let original = PubsubMessage::Block(BeaconBlock::empty(&MainnetEthSpec::default_spec()));
+
+        // the body is the trailing field, removing the last byte shortens it by one
+        let mut encoded = ssz_encode(&original);
+        encoded.pop();
+
+        match PubsubMessage::decode(&encoded) {
+            Err(e @ PubsubDecodeError::InvalidBody { id: 0, .. }) => {
+                assert!(e.is_likely_version_mismatch())
+            }
+            other => panic!("expected an invalid body, got {:?}", other),
+        }
+        assert!(PubsubMessage::from_ssz_bytes(&encoded).is_err());
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        let attestation = ssz_encode(&PubsubMessage::Attestation(Attestation::random_for_test(
+            &mut rng,
+        )));
+
+        for i in 0..10_000 {
+            let bytes = if i % 2 == 0 {
+                // arbitrary bytes
+                let mut bytes = vec![0; (rng.next_u32() % 512) as usize];
+                rng.fill_bytes(&mut bytes);
+                bytes
+            } else {
+                // an attestation with corrupted bytes, including its length offsets
+                let mut bytes = attestation.clone();
+                for _ in 0..=rng.next_u32() % 4 {
+                    let index = rng.next_u32() as usize % bytes.len();
+                    bytes[index] = rng.next_u32() as u8;
+                }
+                bytes.truncate(rng.next_u32() as usize % (attestation.len() + 1));
+                bytes
+            };
+
+            assert_ne!(
+                PubsubMessage::decode_untrusted(&bytes),
+                Err(PubsubDecodeError::Panicked),
+                "decoding {:?} panicked",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn absurd_length_fields_rejected() {
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        let attestation = Attestation::random_for_test(&mut rng);
+        let mut block = BeaconBlock::empty(&MainnetEthSpec::default_spec());
+        block.body.attestations = vec![attestation.clone()];
+        let mut encoded = ssz_encode(&PubsubMessage::Block(block));
+
+        // the first offset of the attestation list claims about a billion attestations
+        let list = ssz_encode(&vec![attestation]);
+        let start = encoded
+            .windows(list.len())
+            .position(|window| window == &list[..])
+            .expect("the block contains the list");
+        encoded[start..start + 4].copy_from_slice(&0xffff_fffc_u32.to_le_bytes());
+        match PubsubMessage::decode(&encoded) {
+            Err(PubsubDecodeError::InvalidBody { id: 0, .. }) => {}
+            other => panic!("expected an invalid body, got {:?}", other),
+        }
+
+        // bodies larger than any valid attestation are not decoded
+        let mut oversized = vec![];
+        oversized.extend_from_slice(&1_u32.to_le_bytes());
+        oversized.extend_from_slice(&8_u32.to_le_bytes());
+        oversized.resize(8 + MAX_ATTESTATION_GOSSIP_SIZE + 1, 0);
+        let error = PubsubMessage::decode(&oversized).unwrap_err();
+        assert_eq!(
+            error,
+            PubsubDecodeError::Oversized {
+                id: 1,
+                size: MAX_ATTESTATION_GOSSIP_SIZE + 1
+            }
+        );
+        assert!(!error.is_likely_version_mismatch());
+    }
+
+    #[test]
+    fn unknown_id() {
+        let original = PubsubMessage::Block(BeaconBlock::empty(&MainnetEthSpec::default_spec()));
+
+        // the id is the leading `u32`
+        let mut encoded = ssz_encode(&original);
+        encoded[..4].copy_from_slice(&7_u32.to_le_bytes());
+
+        let error = PubsubMessage::decode(&encoded).unwrap_err();
+        assert_eq!(error, PubsubDecodeError::UnknownId(7));
+        assert!(!error.is_likely_version_mismatch());
+    }
+
+    #[test]
+    fn duplicate_gossip_counted() {
+        let metrics = Metrics::new().unwrap();
+        let mut seen_messages = SeenMessages::new(2, metrics.gossip_duplicates_received.clone());
+
+        assert!(seen_messages.observe(&[1]));
+        assert!(!seen_messages.observe(&[1]));
+        assert_eq!(metrics.gossip_duplicates_received.get(), 1);
+
+        // the oldest message is forgotten once the capacity is exceeded
+        assert!(seen_messages.observe(&[2]));
+        assert!(seen_messages.observe(&[3]));
+        assert!(seen_messages.observe(&[1]));
+        assert_eq!(metrics.gossip_duplicates_received.get(), 1);
+    }
+
+    #[test]
+    fn gossip_validation_latency_recorded() {
+        let mut behaviour = build_behaviour();
+        let latency = |behaviour: &Behaviour<_>, kind: &str| {
+            let histogram = behaviour
+                .metrics
+                .gossip_validation_seconds
+                .with_label_values(&[kind]);
+            (histogram.get_sample_count(), histogram.get_sample_sum())
+        };
+
+        let received_at = Instant::now();
+        behaviour
+            .awaiting_validation
+            .received(vec![1], "block", received_at);
+        behaviour
+            .awaiting_validation
+            .received(vec![2], "attestation", received_at);
+        behaviour.report_gossip_validation_at(&[1], received_at + Duration::from_millis(250));
+        assert_eq!(latency(&behaviour, "block"), (1, 0.25));
+        assert_eq!(latency(&behaviour, "attestation"), (0, 0.0));
+
+        // a message is measured once, and unknown messages not at all
+        behaviour.report_gossip_validation_at(&[1], received_at + Duration::from_secs(1));
+        behaviour.report_gossip_validation_at(&[3], received_at + Duration::from_secs(1));
+        assert_eq!(latency(&behaviour, "block"), (1, 0.25));
+
+        // the oldest messages are forgotten once the capacity is exceeded
+        let mut awaiting = AwaitingValidation::new(1);
+        awaiting.received(vec![1], "block", received_at);
+        awaiting.received(vec![2], "block", received_at);
+        assert_eq!(awaiting.validated(&[1]), None);
+        assert_eq!(awaiting.validated(&[2]), Some(("block", received_at)));
+    }
+
+    #[test]
+    fn message_ids_derived_from_topic_and_data() {
+        let blocks = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        let attestations = TopicBuilder::new(crate::BEACON_ATTESTATION_TOPIC).build();
+        let data = [1, 2, 3];
+
+        // identical payloads are the same message on the same topic, and distinct across topics
+        let id = crate::eth2_message_id(blocks.hash(), &data);
+        assert_eq!(id.len(), 32);
+        assert_eq!(id, crate::eth2_message_id(blocks.hash(), &data));
+        assert_ne!(id, crate::eth2_message_id(attestations.hash(), &data));
+        assert_ne!(id, crate::eth2_message_id(blocks.hash(), &[1, 2, 4]));
+
+        let mut preimage = blocks.hash().clone().into_string().into_bytes();
+        preimage.extend_from_slice(&data);
+        assert_eq!(id, hashing::hash(&preimage));
+
+        // the behaviour derives ids as configured, by default as the specification does
+        let behaviour = build_behaviour();
+        assert_eq!(behaviour.message_id(&[blocks.hash().clone()], &data), id);
+
+        let mut config = test_config();
+        config.gossip_message_id = MessageIdFn(|_topic, data| data.to_vec());
+        let behaviour = build_behaviour_with_config(config);
+        assert_eq!(
+            behaviour.message_id(&[blocks.hash().clone()], &data),
+            behaviour.message_id(&[attestations.hash().clone()], &data)
+        );
+    }
+
+    #[test]
+    fn slow_peer_tracking() {
+        let mut slow_peers = SlowPeers::default();
+        let slow_peer = PeerId::random();
+        let fast_peer = PeerId::random();
+        let slow_rtt = SLOW_PEER_RTT + Duration::from_millis(1);
+
+        for _ in 1..SLOW_PEER_THRESHOLD {
+            assert!(!slow_peers.record_rtt(&slow_peer, slow_rtt));
+        }
+        assert!(slow_peers.record_rtt(&slow_peer, slow_rtt));
+        assert!(slow_peers.is_slow(&slow_peer));
+
+        // continued slow pings are reported once and do not grow the tracked state
+        for _ in 0..100 {
+            assert!(!slow_peers.record_rtt(&slow_peer, slow_rtt));
+            slow_peers.record_rtt(&fast_peer, Duration::from_millis(10));
+        }
+        assert_eq!(slow_peers.slow_pings.len(), 1);
+        assert!(!slow_peers.is_slow(&fast_peer));
+
+        // a fast ping clears the peer
+        slow_peers.record_rtt(&slow_peer, Duration::from_millis(10));
+        assert!(!slow_peers.is_slow(&slow_peer));
+        assert!(slow_peers.slow_pings.is_empty());
+    }
+
+    #[test]
+    fn idle_peers_disconnected_after_timeout() {
+        let mut config = test_config();
+        let timeout = Duration::from_secs(600);
+        config.connection_idle_timeout = Some(timeout);
+        let mut behaviour = build_behaviour_with_config(config);
+        let (idle, active, trusted) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut address: Multiaddr = "/ip4/203.0.113.2/tcp/9000".parse().expect("valid address");
+        address.push(Protocol::P2p(trusted.clone().into()));
+        behaviour.add_trusted_peer(address);
+        for peer_id in &[&idle, &active, &trusted] {
+            connect(&mut behaviour, peer_id);
+        }
+
+        let start = Instant::now();
+        behaviour.poll_idle_peers(start);
+        assert_eq!(behaviour.next_idle_deadline(), Some(start + timeout));
+        behaviour.record_activity(&active, start + timeout / 2);
+
+        // just short of the timeout every peer is kept
+        behaviour.poll_idle_peers(start + timeout - Duration::from_secs(1));
+        assert!(behaviour.departing_peers.is_empty());
+
+        behaviour.poll_idle_peers(start + timeout);
+        assert!(behaviour.departing_peers.contains_key(&idle));
+        assert!(!behaviour.departing_peers.contains_key(&active));
+        assert!(!behaviour.departing_peers.contains_key(&trusted));
+        assert_eq!(
+            behaviour.next_idle_deadline(),
+            Some(start + timeout / 2 + timeout)
+        );
+    }
+
+    #[test]
+    fn gossip_relays_kept_past_idle_timeout() {
+        let mut config = test_config();
+        let timeout = Duration::from_secs(600);
+        config.connection_idle_timeout = Some(timeout);
+        let mut behaviour = build_behaviour_with_config(config);
+        let topic = TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC).build();
+        behaviour.subscribe(topic.clone());
+        let (relay, source, unsubscribed) = (PeerId::random(), PeerId::random(), PeerId::random());
+        for peer_id in &[&relay, &source, &unsubscribed] {
+            connect(&mut behaviour, peer_id);
+        }
+        behaviour
+            .topic_peers
+            .entry(topic.hash().clone())
+            .or_insert_with(HashSet::new)
+            .insert(relay.clone());
+        let other_topic = TopicBuilder::new("other").build().hash().clone();
+        behaviour
+            .topic_peers
+            .entry(other_topic.clone())
+            .or_insert_with(HashSet::new)
+            .insert(unsubscribed.clone());
+
+        // a message claiming to be from `source` is relayed by a peer sharing the topic
+        let start = Instant::now();
+        behaviour.poll_idle_peers(start);
+        behaviour.record_gossip_activity(&[topic.hash().clone(), other_topic], start + timeout / 2);
+
+        behaviour.poll_idle_peers(start + timeout);
+        assert!(!behaviour.departing_peers.contains_key(&relay));
+        assert!(behaviour.departing_peers.contains_key(&source));
+        assert!(behaviour.departing_peers.contains_key(&unsubscribed));
+    }
+
+    #[test]
+    fn goodbye_cools_down_redials() {
+        let mut behaviour = build_behaviour();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, &peer_id);
+
+        let now = Instant::now();
+        let goodbye = RPCRequest::Goodbye(GoodbyeReason::ClientShutdown);
+        behaviour.on_rpc(
+            peer_id.clone(),
+            RPCEvent::Request {
+                id: RequestId::default(),
+                method_id: goodbye.method_id(),
+                body: goodbye,
+            },
+        );
+        assert!(behaviour.discovery.is_dial_blocked(&peer_id));
+        assert_eq!(behaviour.dial_failures(&peer_id), 0);
+        match behaviour.peer_info_snapshot().as_slice() {
+            [info] => assert!(info.redial_at.expect("cooling down") > now),
+            _ => panic!("expected the peer to be connected"),
+        }
+    }
+
+    #[test]
+    fn gossip_block_info_matches_block() {
+        let local = build_behaviour();
+        let mut remote = build_behaviour();
+        let local_id = local
+            .signing_key
+            .as_ref()
+            .expect("messages should be signed")
+            .public()
+            .into_peer_id();
+        let mut rng = XorShiftRng::from_seed([42; 16]);
+        let block = BeaconBlock::random_for_test(&mut rng);
+        let attestation = PubsubMessage::Attestation(Attestation::random_for_test(&mut rng));
+
+        for message in vec![PubsubMessage::Block(block.clone()), attestation] {
+            let data = local
+                .prepare_gossip(ssz_encode(&message))
+                .expect("should prepare gossip");
+            remote.inject_event(GossipsubEvent::Message(GossipsubMessage {
+                source: local_id.clone(),
+                data,
+                sequence_number: vec![],
+                topics: vec![TopicBuilder::new(crate::BEACON_PUBSUB_TOPIC)
+                    .build()
+                    .hash()
+                    .clone()],
+            }));
+        }
+        match remote.events.as_slice() {
+            [BehaviourEvent::GossipMessage {
+                block: Some(info),
+                message: received_block,
+                ..
+            }, BehaviourEvent::GossipMessage { block: None, .. }] => {
+                assert_eq!(**received_block, PubsubMessage::Block(block.clone()));
+                assert_eq!(info.slot, block.slot);
+                assert_eq!(info.parent_root, block.previous_block_root);
+            }
+            _ => panic!("expected the block with its info, then the attestation without"),
+        }
     }
 }
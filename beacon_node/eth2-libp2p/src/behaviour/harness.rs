@@ -0,0 +1,335 @@
+//! Connects two behaviours over an in-memory transport for end-to-end tests.
+//!
+//! Both swarms are polled on the test thread until a condition on them holds, rather than for a
+//! fixed time, so each run of a test observes the same sequence of events.
+
+use super::{Behaviour, BehaviourEvent, PubsubMessage};
+use crate::multiaddr::Protocol;
+use crate::{service, NetworkConfig, NodeRole, Topic, TopicHash};
+use futures::future;
+use futures::prelude::*;
+use libp2p::core::{identity::Keypair, muxing::StreamMuxerBox, nodes::Substream};
+use libp2p::enr::Enr;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use slog::o;
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tempfile::{tempdir, TempDir};
+use tokio::runtime::current_thread::Runtime;
+use tokio_timer::Delay;
+use types::Hash256;
+
+/// How long a condition may take to hold before the test fails.
+const DRIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The next in-memory port to listen on. Ports are shared by every test of the binary.
+static NEXT_PORT: AtomicU64 = AtomicU64::new(1);
+
+pub type TestSwarm = Swarm<service::Libp2pTransport, Behaviour<Substream<StreamMuxerBox>>>;
+
+/// One of the two nodes of a `ConnectedPair`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Node {
+    First,
+    Second,
+}
+
+impl Node {
+    fn index(self) -> usize {
+        match self {
+            Node::First => 0,
+            Node::Second => 1,
+        }
+    }
+
+    /// Returns the node at the other end of the connection.
+    pub fn other(self) -> Node {
+        match self {
+            Node::First => Node::Second,
+            Node::Second => Node::First,
+        }
+    }
+}
+
+/// Two behaviours connected to each other, and the events each has emitted but a test has not
+/// yet taken.
+pub struct ConnectedPair {
+    swarms: [TestSwarm; 2],
+    events: [Vec<BehaviourEvent>; 2],
+    runtime: Runtime,
+    /// The network directories of the nodes, removed when the pair is dropped.
+    _network_dirs: [TempDir; 2],
+}
+
+impl ConnectedPair {
+    /// Builds two behaviours and drives them until they are connected.
+    pub fn new() -> Self {
+        let (first, first_dir) = build_swarm();
+        let (second, second_dir) = build_swarm();
+        let mut pair = ConnectedPair {
+            swarms: [first, second],
+            events: [Vec::new(), Vec::new()],
+            runtime: Runtime::new().expect("should build runtime"),
+            _network_dirs: [first_dir, second_dir],
+        };
+
+        let address: Multiaddr = Protocol::Memory(NEXT_PORT.fetch_add(1, Ordering::Relaxed)).into();
+        Swarm::listen_on(&mut pair.swarms[1], address.clone()).expect("should listen");
+        Swarm::dial_addr(&mut pair.swarms[0], address).expect("should dial");
+
+        pair.drive_until(|swarms, _| {
+            swarms
+                .iter()
+                .all(|swarm| swarm.discovery.connected_peers() == 1)
+        });
+        pair
+    }
+
+    /// Returns the peer id of `node`.
+    pub fn peer_id(&self, node: Node) -> PeerId {
+        Swarm::local_peer_id(&self.swarms[node.index()]).clone()
+    }
+
+    /// Returns the behaviour of `node`.
+    pub fn behaviour(&mut self, node: Node) -> &mut Behaviour<Substream<StreamMuxerBox>> {
+        &mut self.swarms[node.index()]
+    }
+
+    /// Removes and returns the events `node` has emitted so far.
+    pub fn take_events(&mut self, node: Node) -> Vec<BehaviourEvent> {
+        std::mem::replace(&mut self.events[node.index()], Vec::new())
+    }
+
+    /// Subscribes both nodes to `topic`, driving them until each knows the other is subscribed.
+    ///
+    /// The nodes subscribe one after the other, so the second to subscribe adds the first to its
+    /// mesh straight away rather than at a later heartbeat.
+    pub fn subscribe(&mut self, topic: Topic) {
+        for node in &[Node::Second, Node::First] {
+            self.behaviour(*node).subscribe(topic.clone());
+            let (subscriber, peer) = (self.peer_id(*node), node.other().index());
+            let hash = topic.hash().clone();
+            self.drive_until(|swarms, _| {
+                swarms[peer]
+                    .topic_peers
+                    .get(&hash)
+                    .map_or(false, |peers| peers.contains(&subscriber))
+            });
+        }
+    }
+
+    /// Publishes `message` on `topics` from `node`.
+    pub fn publish(&mut self, node: Node, topics: Vec<Topic>, message: PubsubMessage) {
+        self.behaviour(node)
+            .publish(topics, message)
+            .expect("should publish");
+    }
+
+    /// Drives both nodes until `node` receives a gossip message, which is returned along with its
+    /// source and topics. Events `node` emitted before the message are left to be taken.
+    pub fn next_gossip(&mut self, node: Node) -> (PeerId, Vec<TopicHash>, PubsubMessage) {
+        let index = node.index();
+        let is_gossip = |event: &BehaviourEvent| match event {
+            BehaviourEvent::GossipMessage { .. } => true,
+            _ => false,
+        };
+        self.drive_until(|_, events| events[index].iter().any(is_gossip));
+
+        let position = self.events[index]
+            .iter()
+            .position(is_gossip)
+            .expect("a gossip message was received");
+        match self.events[index].remove(position) {
+            BehaviourEvent::GossipMessage {
+                source,
+                topics,
+                message,
+                ..
+            } => (source, topics, *message),
+            _ => unreachable!("the event is a gossip message"),
+        }
+    }
+
+    /// Polls both nodes, collecting their events, until `condition` holds.
+    ///
+    /// Panics if the condition does not hold within `DRIVE_TIMEOUT`.
+    pub fn drive_until<F>(&mut self, mut condition: F)
+    where
+        F: FnMut(&[TestSwarm; 2], &[Vec<BehaviourEvent>; 2]) -> bool,
+    {
+        let ConnectedPair {
+            swarms,
+            events,
+            runtime,
+        } = self;
+        let mut deadline = Delay::new(Instant::now() + DRIVE_TIMEOUT);
+
+        let driven = runtime.block_on(future::poll_fn(|| -> Poll<(), ()> {
+            for (swarm, events) in swarms.iter_mut().zip(events.iter_mut()) {
+                poll_swarm(swarm, events);
+            }
+            if condition(swarms, events) {
+                return Ok(Async::Ready(()));
+            }
+            match deadline.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Err(()),
+            }
+        }));
+        driven.expect("condition should hold before the timeout");
+    }
+}
+
+/// Behaviours on the loopback interface, connected by TCP and discovering each other over UDP as
+/// they would on a real network.
+pub struct LoopbackNetwork {
+    swarms: Vec<TestSwarm>,
+    runtime: Runtime,
+    /// The network directories of the nodes, removed when the network is dropped.
+    _network_dirs: Vec<TempDir>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        LoopbackNetwork {
+            swarms: Vec::new(),
+            runtime: Runtime::new().expect("should build runtime"),
+            _network_dirs: Vec::new(),
+        }
+    }
+
+    /// Adds a node with a config adjusted by `configure`, returning its index.
+    pub fn add_node<F>(&mut self, configure: F) -> usize
+    where
+        F: FnOnce(&mut NetworkConfig),
+    {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let local_key = Keypair::generate_secp256k1();
+        let local_peer_id = local_key.public().into_peer_id();
+        let network_dir = tempdir().expect("should create network directory");
+        let mut config = test_config(&network_dir);
+        config.listen_address = Ipv4Addr::LOCALHOST.into();
+        config.discovery_address = Ipv4Addr::LOCALHOST.into();
+        // the ports are advertised in the node's ENR, so must be known before it is built
+        config.libp2p_port = unused_tcp_port();
+        config.discovery_port = unused_udp_port();
+        configure(&mut config);
+
+        let behaviour = Behaviour::new(&local_key, &config, &log).expect("should build behaviour");
+        let mut swarm = Swarm::new(
+            service::build_transport(local_key),
+            behaviour,
+            local_peer_id,
+        );
+        Swarm::listen_on(&mut swarm, tcp_address(&config)).expect("should listen");
+        self.swarms.push(swarm);
+        self._network_dirs.push(network_dir);
+        self.swarms.len() - 1
+    }
+
+    /// Returns the peer id of the node at `index`.
+    pub fn peer_id(&self, index: usize) -> PeerId {
+        Swarm::local_peer_id(&self.swarms[index]).clone()
+    }
+
+    /// Returns the ENR of the node at `index`.
+    pub fn enr(&self, index: usize) -> Enr {
+        self.swarms[index].discovery.local_enr().clone()
+    }
+
+    /// Returns the TCP address the node at `index` listens on.
+    pub fn address(&self, index: usize) -> Multiaddr {
+        let enr = self.enr(index);
+        let mut address = Multiaddr::from(enr.ip().expect("ENR has an IP"));
+        address.push(Protocol::Tcp(enr.tcp().expect("ENR has a TCP port")));
+        address
+    }
+
+    /// Polls every node until `condition` holds, discarding their events.
+    ///
+    /// Panics if the condition does not hold within `DRIVE_TIMEOUT`.
+    pub fn drive_until<F>(&mut self, mut condition: F)
+    where
+        F: FnMut(&[TestSwarm]) -> bool,
+    {
+        let LoopbackNetwork {
+            swarms, runtime, ..
+        } = self;
+        let mut deadline = Delay::new(Instant::now() + DRIVE_TIMEOUT);
+
+        let driven = runtime.block_on(future::poll_fn(|| -> Poll<(), ()> {
+            for swarm in swarms.iter_mut() {
+                poll_swarm(swarm, &mut Vec::new());
+            }
+            if condition(swarms) {
+                return Ok(Async::Ready(()));
+            }
+            match deadline.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Err(()),
+            }
+        }));
+        driven.expect("condition should hold before the timeout");
+    }
+}
+
+/// Returns a loopback TCP port which was free when asked for.
+fn unused_tcp_port() -> u16 {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("should bind a port");
+    listener.local_addr().expect("has an address").port()
+}
+
+/// Returns a loopback UDP port which was free when asked for.
+fn unused_udp_port() -> u16 {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("should bind a port");
+    socket.local_addr().expect("has an address").port()
+}
+
+/// Returns the TCP address a node with `config` listens on.
+fn tcp_address(config: &NetworkConfig) -> Multiaddr {
+    let mut address = Multiaddr::from(config.listen_address);
+    address.push(Protocol::Tcp(config.libp2p_port));
+    address
+}
+
+/// Polls `swarm` until it has no more events to emit.
+fn poll_swarm(swarm: &mut TestSwarm, events: &mut Vec<BehaviourEvent>) {
+    loop {
+        match swarm.poll().expect("swarm should not fail") {
+            Async::Ready(Some(event)) => events.push(event),
+            Async::Ready(None) => unreachable!("Swarm stream shouldn't end"),
+            Async::NotReady => return,
+        }
+    }
+}
+
+/// Builds a swarm with a fresh identity, whose discovery listens on an ephemeral port, and the
+/// directory it stores its network files in.
+fn build_swarm() -> (TestSwarm, TempDir) {
+    let log = slog::Logger::root(slog::Discard, o!());
+    let local_key = Keypair::generate_secp256k1();
+    let local_peer_id = local_key.public().into_peer_id();
+    let network_dir = tempdir().expect("should create network directory");
+    let mut config = test_config(&network_dir);
+    config.discovery_port = 0;
+
+    let behaviour = Behaviour::new(&local_key, &config, &log).expect("should build behaviour");
+    let swarm = Swarm::new(
+        service::build_memory_transport(local_key),
+        behaviour,
+        local_peer_id,
+    );
+    (swarm, network_dir)
+}
+
+/// Returns the config of a test node, storing its network files in `network_dir`.
+fn test_config(network_dir: &TempDir) -> NetworkConfig {
+    let mut config = NetworkConfig::default();
+    config.network_dir = network_dir.path().to_path_buf();
+    config.network_id = Some(1);
+    config.genesis_root = Some(Hash256::zero());
+    // the nodes subscribe to the topics chosen by each test
+    config.role = NodeRole::BootstrapOnly;
+    config
+}
@@ -1,14 +1,32 @@
+use crate::discovery::parse_enr;
+use crate::rpc::{OutboundQueueLimits, RPCEncoding, RPCQuotas, RPCSizeLimits};
 use clap::ArgMatches;
 use enr::Enr;
+use libp2p::floodsub::TopicHash;
 use libp2p::gossipsub::{GossipsubConfig, GossipsubConfigBuilder};
+use libp2p::Multiaddr;
 use serde_derive::{Deserialize, Serialize};
+use std::fmt;
 use std::path::PathBuf;
 use std::time::Duration;
+use types::Hash256;
 
 /// The beacon node topic string to subscribe to.
 pub const BEACON_PUBSUB_TOPIC: &str = "beacon_block";
 pub const BEACON_ATTESTATION_TOPIC: &str = "beacon_attestation";
+pub const VOLUNTARY_EXIT_TOPIC: &str = "voluntary_exit";
+pub const PROPOSER_SLASHING_TOPIC: &str = "proposer_slashing";
+pub const ATTESTER_SLASHING_TOPIC: &str = "attester_slashing";
 pub const SHARD_TOPIC_PREFIX: &str = "shard";
+/// The topic of an attestation subnet is the prefix, the subnet id, then the postfix, such as
+/// `committee_index3_beacon_attestation`.
+pub const ATTESTATION_SUBNET_TOPIC_PREFIX: &str = "committee_index";
+pub const ATTESTATION_SUBNET_TOPIC_POSTFIX: &str = "_beacon_attestation";
+/// The topics light client updates are gossiped on, subscribed to if `serve_light_clients` is set.
+pub const LIGHT_CLIENT_FINALITY_UPDATE_TOPIC: &str = "light_client_finality_update";
+pub const LIGHT_CLIENT_OPTIMISTIC_UPDATE_TOPIC: &str = "light_client_optimistic_update";
+/// The maximum size of a gossipsub message.
+pub const GOSSIP_MAX_SIZE: usize = 4_000_000;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -29,21 +47,296 @@ pub struct Config {
     /// UDP port that discovery listens on.
     pub discovery_port: u16,
 
-    /// Target number of connected peers.
+    /// The IP version of the addresses we dial peers on first, when they have addresses of both.
+    pub ip_preference: IpPreference,
+
+    /// The number of connected peers we seek. Discovered peers are dialed until we have this
+    /// many connected, or being dialed.
+    pub target_peers: usize,
+
+    /// The most peers we stay connected to. Inbound connections beyond this are said goodbye to and
+    /// closed. Must be at least `target_peers`, which `--maxpeers` lowers to it unless
+    /// `--target-peers` is also given.
     pub max_peers: usize,
 
+    /// The number of connected peers below which we are in danger of being isolated, reported by
+    /// a `LowPeerCount` event.
+    pub min_peers: usize,
+
+    /// The interval between random discovery searches for peers while we have between
+    /// `min_peers` and `target_peers`. Searches are made more often below `min_peers`, and less
+    /// often once we have `target_peers`.
+    pub discovery_interval: Duration,
+
+    /// The maximum number of peers dialed at once. Further dials are queued until an outstanding
+    /// dial connects or fails.
+    pub max_concurrent_dials: usize,
+
+    /// The maximum number of peers connected at once from a single public /24 IPv4 or /64 IPv6
+    /// subnet. Further connections from the subnet are refused. Loopback, private and link-local
+    /// addresses are not limited.
+    pub max_peers_per_subnet: usize,
+
+    /// The addresses a peer identifies itself on are forgotten unless it identifies on them again
+    /// within this time.
+    pub identified_address_ttl: Duration,
+
+    /// Keep and advertise loopback, private and link-local addresses, which only peers on the
+    /// same host or network can dial. For local testnets.
+    pub allow_private_addresses: bool,
+
+    /// Discover peers on the local network with mDNS, announcing ourselves to them. For devnets
+    /// on a single LAN, which then need no boot nodes; public nodes should leave it off.
+    pub enable_mdns: bool,
+
+    /// The rate at which inbound connections are accepted. Connections beyond the rate are
+    /// dropped before they are negotiated; dials we make are exempt.
+    pub inbound_rate_limit: InboundRateLimit,
+
+    /// Keep peers whose status shows them on another network or finalized chain, rather than
+    /// disconnecting them. For debugging only.
+    pub keep_incompatible_peers: bool,
+
     /// Gossipsub configuration parameters.
     #[serde(skip)]
     pub gs_config: GossipsubConfig,
 
+    /// How long fan-out peers are kept for a topic we publish on but are not subscribed to, after
+    /// our last publish on it. Keeping them longer spares choosing new peers for infrequent
+    /// publishes, at the cost of tracking peers we may not publish to again.
+    pub gossip_fanout_ttl: Duration,
+
+    /// The number of peers a message is published to on a topic we are not subscribed to. More
+    /// peers make it more likely the message reaches the network, at the cost of the bandwidth of
+    /// sending it to each. Gossipsub uses the same number as the target size of a topic mesh, which
+    /// this also sets. A `gs_config` whose `mesh_n` is changed to a different number is rejected.
+    pub gossip_fanout_peers: usize,
+
     /// List of nodes to initially connect to.
     pub boot_nodes: Vec<Enr>,
 
+    /// Addresses of boot nodes to dial at startup, for nodes whose ENR is not known. Each is
+    /// redialed with a backoff until it is reached, and discovery searches for peers once the first
+    /// is.
+    pub libp2p_nodes: Vec<Multiaddr>,
+
+    /// Addresses of peers to stay connected to, such as our other nodes. Each is dialed at startup
+    /// and redialed with a backoff whenever it disconnects, and is exempt from banning, rate
+    /// limits and `max_peers`.
+    pub trusted_peers: Vec<Multiaddr>,
+
     /// Client version
     pub client_version: String,
 
-    /// List of extra topics to initially subscribe to as strings.
+    /// The role of the node, which sets the topics subscribed to initially.
+    pub role: NodeRole,
+
+    /// List of extra topics to initially subscribe to as strings, beyond those of the role.
     pub topics: Vec<String>,
+
+    /// The time to wait for a response to an outbound RPC request.
+    pub rpc_request_timeout: Duration,
+
+    /// The time an inbound RPC request may go without a response before it is closed.
+    pub rpc_idle_timeout: Duration,
+
+    /// The rate at which each peer may make RPC requests.
+    pub rpc_quotas: RPCQuotas,
+
+    /// The maximum sizes of the RPC requests and responses received for each method. Larger
+    /// requests are refused and larger responses fail their request.
+    pub rpc_size_limits: RPCSizeLimits,
+
+    /// The maximum number of a peer's RPC requests we respond to at once. Further requests are
+    /// refused until a response completes.
+    pub rpc_max_concurrent_requests: usize,
+
+    /// The RPC encodings proposed to peers, in order of preference. Encodings missing from the
+    /// list are still supported, at the lowest preference.
+    pub rpc_encodings: Vec<RPCEncoding>,
+
+    /// The size in bytes below which RPC frames sent with `ssz_snappy` are stored uncompressed
+    /// within the encoding, as compressing small frames costs more than it saves. Zero compresses
+    /// every frame.
+    pub rpc_compression_threshold: usize,
+
+    /// The limits on the RPCs waiting to be sent to each peer. Further RPCs to the peer are
+    /// refused until the queue drains. Its `max_requests_in_flight` is set by
+    /// `max_rpc_per_peer`.
+    pub rpc_outbound_queue_limits: OutboundQueueLimits,
+
+    /// The maximum number of our RPC requests awaiting a response from a peer at once. Further
+    /// requests wait for one to complete, up to the `max_waiting_requests` of
+    /// `rpc_outbound_queue_limits`, beyond which they fail.
+    pub max_rpc_per_peer: usize,
+
+    /// Logs each step in the lifecycle of every RPC at info rather than debug, with its request
+    /// id, peer and method, to follow RPCs without enabling debug logging throughout the node.
+    pub rpc_trace: bool,
+
+    /// Peers whose identify agent version contains any of these strings are disconnected.
+    pub banned_agents: Vec<String>,
+
+    /// The time for which a misbehaving peer is banned. It is neither dialed nor accepted until
+    /// the ban ends.
+    pub ban_duration: Duration,
+
+    /// The id of the network we are on, sent in our status. Peers with another network id are
+    /// disconnected. Must be set.
+    pub network_id: Option<u8>,
+
+    /// The root of our genesis block, sent in our status. Peers with another genesis are
+    /// disconnected. Must be set.
+    pub genesis_root: Option<Hash256>,
+
+    /// Whether gossip messages are signed by their source and verified on receipt. Messages are
+    /// anonymous unless signing is opted into.
+    pub message_authenticity: MessageAuthenticity,
+
+    /// Receive gossip and answer RPC requests, but never publish gossip of our own. For nodes
+    /// which observe the network, such as for analytics, without influencing it.
+    pub observer_mode: bool,
+
+    /// Subscribe to the light client update topics, relaying the updates to light clients.
+    pub serve_light_clients: bool,
+
+    /// The file the peers we have been connected to are cached in across restarts. Defaults to a
+    /// file in `network_dir`.
+    pub peer_cache_file: Option<PathBuf>,
+
+    /// Cached peers not connected to for longer than this are not dialed on startup.
+    pub peer_cache_max_age: Duration,
+
+    /// Derives the id by which gossip messages received again are recognised as duplicates.
+    #[serde(skip)]
+    pub gossip_message_id: MessageIdFn,
+
+    /// The interval at which a summary of our peers is emitted.
+    pub peer_summary_interval: Duration,
+
+    /// The interval at which connected peers are sent a Ping RPC carrying our metadata sequence
+    /// number.
+    pub rpc_ping_interval: Duration,
+
+    /// The interval at which our status is sent again to the peers we have exchanged statuses
+    /// with, to keep track of their chain state.
+    pub status_interval: Duration,
+
+    /// The age after which the chain state a peer advertised in its latest status is stale.
+    pub peer_status_max_age: Duration,
+
+    /// The time after which a peer which has sent us no gossip or RPCs, beyond the pings,
+    /// metadata and statuses keeping the connection alive, is disconnected. Trusted peers are
+    /// kept. Idle peers are kept connected if unset.
+    pub connection_idle_timeout: Option<Duration>,
+
+    /// The number of times a `BeaconBlocksByRange` request made through the behaviour is retried
+    /// from another peer after failing.
+    pub range_sync_retries: u32,
+}
+
+/// The IP version of the addresses peers are dialed on first.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    /// IPv4 addresses are dialed before IPv6 addresses.
+    V4,
+    /// IPv6 addresses are dialed before IPv4 addresses, for IPv6-only hosts.
+    V6,
+    /// Addresses are dialed in the order they were discovered.
+    Dual,
+}
+
+/// The role of a node on the network, which decides the gossip topics it subscribes to initially.
+///
+/// | Role            | Topics                                                                 |
+/// |-----------------|------------------------------------------------------------------------|
+/// | `FullNode`      | blocks, attestations                                                   |
+/// | `Validator`     | blocks, attestations, voluntary exits, proposer and attester slashings |
+/// | `BootstrapOnly` | none                                                                   |
+///
+/// A full node follows the chain. A validator also collects the operations it includes in the
+/// blocks it proposes. A bootstrap node only serves discovery and spends no bandwidth on gossip.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeRole {
+    FullNode,
+    Validator,
+    BootstrapOnly,
+}
+
+impl NodeRole {
+    /// Returns the topics the role subscribes to initially.
+    pub fn default_topics(self) -> Vec<&'static str> {
+        match self {
+            NodeRole::FullNode => vec![BEACON_PUBSUB_TOPIC, BEACON_ATTESTATION_TOPIC],
+            NodeRole::Validator => vec![
+                BEACON_PUBSUB_TOPIC,
+                BEACON_ATTESTATION_TOPIC,
+                VOLUNTARY_EXIT_TOPIC,
+                PROPOSER_SLASHING_TOPIC,
+                ATTESTER_SLASHING_TOPIC,
+            ],
+            NodeRole::BootstrapOnly => vec![],
+        }
+    }
+}
+
+/// How gossip messages are authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageAuthenticity {
+    /// Published messages are signed with our network key. Received messages which are not
+    /// validly signed by their source are rejected.
+    Signed,
+    /// Messages are neither signed nor verified.
+    Anonymous,
+}
+
+/// The id of a gossip message.
+pub type MessageId = Vec<u8>;
+
+/// The id of an attestation subnet, below `ATTESTATION_SUBNET_COUNT`.
+pub type SubnetId = u64;
+
+/// Returns the name of the gossipsub topic of the attestation subnet `subnet_id`.
+pub fn attestation_subnet_topic(subnet_id: SubnetId) -> String {
+    format!(
+        "{}{}{}",
+        ATTESTATION_SUBNET_TOPIC_PREFIX, subnet_id, ATTESTATION_SUBNET_TOPIC_POSTFIX
+    )
+}
+
+/// Derives the id of a gossip message from a topic it was published on and its data.
+#[derive(Clone, Copy)]
+pub struct MessageIdFn(pub fn(&TopicHash, &[u8]) -> MessageId);
+
+impl MessageIdFn {
+    /// Returns the id of a message with `data` published on `topic`.
+    pub fn message_id(&self, topic: &TopicHash, data: &[u8]) -> MessageId {
+        (self.0)(topic, data)
+    }
+}
+
+impl Default for MessageIdFn {
+    /// Derives ids as the eth2 specification does.
+    fn default() -> Self {
+        MessageIdFn(eth2_message_id)
+    }
+}
+
+impl fmt::Debug for MessageIdFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MessageIdFn")
+    }
+}
+
+/// The id of a gossip message in the eth2 specification: the SHA256 hash of its topic followed by
+/// its data. The same data published on two topics is two messages.
+pub fn eth2_message_id(topic: &TopicHash, data: &[u8]) -> MessageId {
+    let mut preimage = topic.clone().into_string().into_bytes();
+    preimage.extend_from_slice(data);
+    hashing::hash(&preimage)
 }
 
 impl Default for Config {
@@ -58,16 +351,77 @@ impl Default for Config {
             libp2p_port: 9000,
             discovery_address: "127.0.0.1".parse().expect("valid ip address"),
             discovery_port: 9000,
-            max_peers: 10,
+            ip_preference: IpPreference::Dual,
+            target_peers: 10,
+            max_peers: 15,
+            min_peers: 3,
+            discovery_interval: Duration::from_secs(60),
+            max_concurrent_dials: 8,
+            max_peers_per_subnet: 4,
+            identified_address_ttl: Duration::from_secs(60 * 60),
+            allow_private_addresses: false,
+            enable_mdns: false,
+            inbound_rate_limit: InboundRateLimit::default(),
+            keep_incompatible_peers: false,
             //TODO: Set realistic values for production
             gs_config: GossipsubConfigBuilder::new()
-                .max_gossip_size(4_000_000)
+                .max_gossip_size(GOSSIP_MAX_SIZE)
                 .inactivity_timeout(Duration::from_secs(90))
                 .heartbeat_interval(Duration::from_secs(20))
                 .build(),
+            gossip_fanout_ttl: Duration::from_secs(60),
+            gossip_fanout_peers: 6,
             boot_nodes: vec![],
+            libp2p_nodes: vec![],
+            trusted_peers: vec![],
             client_version: version::version(),
+            role: NodeRole::FullNode,
             topics: Vec::new(),
+            rpc_request_timeout: Duration::from_secs(30),
+            rpc_idle_timeout: Duration::from_secs(60),
+            rpc_quotas: RPCQuotas::default(),
+            rpc_size_limits: RPCSizeLimits::default(),
+            rpc_max_concurrent_requests: 16,
+            rpc_encodings: vec![RPCEncoding::SSZSnappy, RPCEncoding::SSZ],
+            rpc_compression_threshold: 128,
+            rpc_outbound_queue_limits: OutboundQueueLimits::default(),
+            max_rpc_per_peer: 8,
+            rpc_trace: false,
+            banned_agents: Vec::new(),
+            ban_duration: Duration::from_secs(1800),
+            network_id: None,
+            genesis_root: None,
+            message_authenticity: MessageAuthenticity::Anonymous,
+            observer_mode: false,
+            serve_light_clients: false,
+            peer_cache_file: None,
+            peer_cache_max_age: Duration::from_secs(24 * 60 * 60),
+            gossip_message_id: MessageIdFn::default(),
+            peer_summary_interval: Duration::from_secs(60),
+            rpc_ping_interval: Duration::from_secs(30),
+            status_interval: Duration::from_secs(60),
+            peer_status_max_age: Duration::from_secs(300),
+            connection_idle_timeout: None,
+            range_sync_retries: 3,
+        }
+    }
+}
+
+/// The rate at which inbound connections are accepted.
+///
+/// Up to `max_connections` connections may be accepted in a burst, which are replenished evenly
+/// over `period`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct InboundRateLimit {
+    pub max_connections: u32,
+    pub period: Duration,
+}
+
+impl Default for InboundRateLimit {
+    fn default() -> Self {
+        InboundRateLimit {
+            max_connections: 10,
+            period: Duration::from_secs(1),
         }
     }
 }
@@ -91,10 +445,21 @@ impl Config {
             self.discovery_address = listen_address;
         }
 
+        if let Some(target_peers_str) = args.value_of("target-peers") {
+            self.target_peers = target_peers_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid number of target peers: {}", target_peers_str))?;
+        }
+
         if let Some(max_peers_str) = args.value_of("maxpeers") {
             self.max_peers = max_peers_str
                 .parse::<usize>()
                 .map_err(|_| format!("Invalid number of max peers: {}", max_peers_str))?;
+            // a maximum below the default target lowers the target with it, while a target given
+            // alongside is left to be refused if it exceeds the maximum
+            if args.value_of("target-peers").is_none() {
+                self.target_peers = self.target_peers.min(self.max_peers);
+            }
         }
 
         if let Some(port_str) = args.value_of("port") {
@@ -108,10 +473,49 @@ impl Config {
         if let Some(boot_enr_str) = args.value_of("boot-nodes") {
             self.boot_nodes = boot_enr_str
                 .split(',')
-                .map(|enr| enr.parse().map_err(|_| format!("Invalid ENR: {}", enr)))
+                .map(parse_enr)
                 .collect::<Result<Vec<Enr>, _>>()?;
         }
 
+        if let Some(libp2p_addresses_str) = args.value_of("libp2p-addresses") {
+            self.libp2p_nodes = libp2p_addresses_str
+                .split(',')
+                .map(|multiaddr| {
+                    multiaddr
+                        .parse()
+                        .map_err(|_| format!("Invalid Multiaddr: {}", multiaddr))
+                })
+                .collect::<Result<Vec<Multiaddr>, _>>()?;
+        }
+
+        if let Some(trusted_peers_str) = args.value_of("trusted-peers") {
+            self.trusted_peers = trusted_peers_str
+                .split(',')
+                .map(|multiaddr| {
+                    multiaddr
+                        .parse()
+                        .map_err(|_| format!("Invalid Multiaddr: {}", multiaddr))
+                })
+                .collect::<Result<Vec<Multiaddr>, _>>()?;
+        }
+
+        if let Some(timeout_str) = args.value_of("connection-idle-timeout") {
+            let secs = timeout_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid connection idle timeout: {}", timeout_str))?;
+            self.connection_idle_timeout = Some(Duration::from_secs(secs));
+        }
+
+        if let Some(max_rpc_str) = args.value_of("max-rpc-per-peer") {
+            self.max_rpc_per_peer = max_rpc_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid max RPC requests per peer: {}", max_rpc_str))?;
+        }
+
+        if args.is_present("enable-mdns") {
+            self.enable_mdns = true;
+        }
+
         if let Some(discovery_address_str) = args.value_of("discovery-address") {
             self.discovery_address = discovery_address_str
                 .parse()
@@ -127,3 +531,57 @@ impl Config {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::{App, Arg};
+
+    /// Returns the config given the peer count and RPC flags `args`.
+    fn config_from(args: &[&str]) -> Result<Config, String> {
+        let matches = App::new("test")
+            .arg(
+                Arg::with_name("maxpeers")
+                    .long("maxpeers")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("target-peers")
+                    .long("target-peers")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("max-rpc-per-peer")
+                    .long("max-rpc-per-peer")
+                    .takes_value(true),
+            )
+            .get_matches_from(std::iter::once("test").chain(args.iter().cloned()));
+        let mut config = Config::default();
+        config.apply_cli_args(&matches).map(|()| config)
+    }
+
+    #[test]
+    fn max_peers_lowers_default_target() {
+        let config = config_from(&["--maxpeers", "5"]).expect("valid args");
+        assert_eq!((config.target_peers, config.max_peers), (5, 5));
+
+        // a maximum above the default target leaves it be
+        let config = config_from(&["--maxpeers", "50"]).expect("valid args");
+        assert_eq!((config.target_peers, config.max_peers), (10, 50));
+
+        // an explicit target is kept, to be refused when the behaviour is built
+        let config = config_from(&["--maxpeers", "5", "--target-peers", "8"]).expect("valid args");
+        assert_eq!((config.target_peers, config.max_peers), (8, 5));
+    }
+
+    #[test]
+    fn max_rpc_per_peer_parsed() {
+        let config = config_from(&[]).expect("valid args");
+        assert_eq!(config.max_rpc_per_peer, 8);
+
+        let config = config_from(&["--max-rpc-per-peer", "2"]).expect("valid args");
+        assert_eq!(config.max_rpc_per_peer, 2);
+
+        assert!(config_from(&["--max-rpc-per-peer", "two"]).is_err());
+    }
+}
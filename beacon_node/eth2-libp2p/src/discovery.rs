@@ -1,7 +1,11 @@
+use crate::config::IpPreference;
+use crate::peer_cache::PeerCache;
+use crate::rpc::methods::ATTESTATION_SUBNET_COUNT;
 use crate::{error, NetworkConfig};
 /// This manages the discovery and management of peers.
 ///
-/// Currently using discv5 for peer discovery.
+/// Peers are discovered with discv5, which runs over UDP on the configured discovery port and
+/// shares ENRs with the discovery services of other eth2 clients.
 ///
 use futures::prelude::*;
 use libp2p::core::swarm::{
@@ -12,36 +16,197 @@ use libp2p::discv5::{Discv5, Discv5Event};
 use libp2p::enr::{Enr, EnrBuilder, NodeId};
 use libp2p::multiaddr::Protocol;
 use slog::{debug, info, o, warn};
-use std::collections::HashSet;
+use ssz::{Decode, Encode};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_timer::Delay;
+use types::Bitfield;
 
-/// Maximum seconds before searching for extra peers.
-const MAX_TIME_BETWEEN_PEER_SEARCHES: u64 = 60;
-/// Initial delay between peer searches.
-const INITIAL_SEARCH_DELAY: u64 = 5;
+/// The file the peer cache is stored in within the network directory, unless configured otherwise.
+const PEER_CACHE_FILENAME: &str = "peers";
+/// The interval at which the peer cache is written to disk, besides on shutdown.
+const PEER_CACHE_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How long the boot nodes are not dialed for on startup while cached peers are dialed.
+const CACHED_PEER_GRACE: Duration = Duration::from_secs(10);
+/// The most peers kept in the peer cache. Beyond it the peers seen longest ago are forgotten.
+const MAX_CACHED_PEERS: usize = 1000;
+
+/// The most addresses a peer may identify itself on that we keep. Beyond it the addresses
+/// confirmed longest ago are forgotten.
+const MAX_IDENTIFIED_ADDRESSES: usize = 16;
+
+/// How long a search for peers on an attestation subnet runs before giving up.
+const SUBNET_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The ENR key of the bitfield of attestation subnets a node is subscribed to.
+const ATTNETS_ENR_KEY: &str = "attnets";
+
+/// The shortest interval between peer searches, reached while we are short of peers.
+const MIN_SEARCH_INTERVAL: Duration = Duration::from_secs(5);
+/// The multiple of the configured interval the interval between peer searches may grow to while
+/// we have all the peers we want.
+const MAX_SEARCH_INTERVAL_MULTIPLE: u32 = 8;
+/// Initial seconds before redialing a boot node that could not be reached.
+const INITIAL_BOOT_NODE_BACKOFF: u64 = 5;
+/// Maximum seconds between attempts to reach a boot node.
+const MAX_BOOT_NODE_BACKOFF: u64 = 300;
+/// Initial seconds before redialing a trusted peer that disconnected or could not be reached.
+const INITIAL_TRUSTED_PEER_BACKOFF: u64 = 1;
+/// Maximum seconds between attempts to reach a trusted peer.
+const MAX_TRUSTED_PEER_BACKOFF: u64 = 60;
+/// The longest a peer whose dials keep failing is not redialed for.
+const MAX_DIAL_BACKOFF: Duration = Duration::from_secs(3600);
+/// How long a peer which said goodbye to us, or whose ban ended, is not redialed for.
+const DIAL_COOLDOWN: Duration = Duration::from_secs(3600);
+/// The dials of an IP address failing by protocol mismatch after which the address is banned.
+const IP_BAN_THRESHOLD: u32 = 3;
+/// How long an IP address whose dials keep failing by protocol mismatch is banned for.
+const IP_BAN_DURATION: Duration = Duration::from_secs(24 * 3600);
+/// The prefix of the text form of an ENR.
+const ENR_PREFIX: &str = "enr:";
 /// Local ENR storage filename.
 const ENR_FILENAME: &str = "enr.dat";
 
 /// Lighthouse discovery behaviour. This provides peer management and discovery using the Discv5
 /// libp2p protocol.
 pub struct Discovery<TSubstream> {
+    /// The peer id of this node, which is never dialed.
+    local_peer_id: PeerId,
+
     /// The peers currently connected to libp2p streams.
     connected_peers: HashSet<PeerId>,
 
-    /// The target number of connected peers on the libp2p interface.
+    /// The number of connected peers we dial discovered peers until reaching.
+    target_peers: usize,
+
+    /// The most peers we accept connections from.
     max_peers: usize,
 
+    /// The number of connected peers below which we search for peers more often.
+    min_peers: usize,
+
+    /// Discovered peers waiting to be dialed.
+    dial_queue: VecDeque<PeerId>,
+
+    /// The peers we have dialed which have neither connected nor failed.
+    dialing: HashSet<PeerId>,
+
+    /// The maximum number of peers dialed at once.
+    max_concurrent_dials: usize,
+
+    /// Peers which are not to be dialed again before the given time, or at all if there is none.
+    dial_blocked_until: HashMap<PeerId, Option<Instant>>,
+
+    /// The failed dials of each peer since it last connected.
+    dial_backoffs: HashMap<PeerId, DialBackoff>,
+
+    /// Peers banned until the given time, or until `unban_peer` if there is none. Their addresses
+    /// are not handed out and their connections are refused.
+    banned_until: HashMap<PeerId, Option<Instant>>,
+
+    /// The dials of each public IP address which have failed by protocol mismatch since a peer
+    /// was last reached on it.
+    ip_dial_failures: HashMap<IpAddr, u32>,
+
+    /// IP addresses banned until the given time, for dials of them failing by protocol mismatch
+    /// `IP_BAN_THRESHOLD` times. They are not dialed and their connections are refused.
+    banned_ips: HashMap<IpAddr, Instant>,
+
+    /// The attestation subnets advertised in the ENRs of discovered peers.
+    peer_attnets: HashMap<PeerId, Bitfield>,
+
+    /// The address each connected peer is connected on.
+    connected_addresses: HashMap<PeerId, Multiaddr>,
+
+    /// The subnet each connected peer connected from, if known.
+    peer_subnets: HashMap<PeerId, IpSubnet>,
+
+    /// The maximum number of peers connected at once from a single subnet.
+    max_peers_per_subnet: usize,
+
+    /// The addresses peers have told us they listen on, in the order first told, kept until a
+    /// dial of the peer fails or they are not confirmed again within `identified_address_ttl`.
+    identified_addresses: HashMap<PeerId, Vec<IdentifiedAddress>>,
+
+    /// How long an identified address is kept without being confirmed again.
+    identified_address_ttl: Duration,
+
+    /// Whether addresses which are not `AddressClass::Public` are kept and advertised.
+    allow_private_addresses: bool,
+
+    /// The IP version of the addresses peers are dialed on first.
+    ip_preference: IpPreference,
+
+    /// Peers refused for being banned or connecting from a subnet that is already at its limit,
+    /// to be disconnected.
+    refused_peers: Vec<PeerId>,
+
+    /// Peers refused for connecting while we had `max_peers`, to be said goodbye to.
+    excess_peers: Vec<PeerId>,
+
     /// The delay between peer discovery searches.
     peer_discovery_delay: Delay,
 
-    /// Tracks the last discovery delay. The delay is doubled each round until the max
-    /// time is reached.
-    past_discovery_delay: u64,
+    /// The interval between searches with between `min_peers` and `target_peers` connected.
+    discovery_interval: Duration,
+
+    /// The current interval between searches. It is halved after each search while we have fewer
+    /// than `min_peers`, and doubled while we have `target_peers`.
+    search_interval: Duration,
+
+    /// The time of the last search which found peers.
+    last_successful_search: Option<Instant>,
+
+    /// The boot nodes dialed by address, until each is first reached.
+    boot_nodes: Vec<BootNode>,
+
+    /// The boot nodes whose peer id is known, from their ENR or address or from reaching them,
+    /// with the addresses they are dialed on.
+    boot_node_peers: HashMap<PeerId, Vec<Multiaddr>>,
+
+    /// The addresses of the peers mDNS has discovered on the local network, until they expire.
+    mdns_addresses: HashMap<PeerId, HashSet<Multiaddr>>,
+
+    /// Fires when the next unreachable boot node is due to be redialed.
+    boot_node_redial: Delay,
+
+    /// The peers kept connected by address, whatever their score or our limits on peers.
+    trusted_peers: Vec<TrustedPeer>,
+
+    /// Fires when the next disconnected trusted peer is due to be redialed.
+    trusted_peer_redial: Delay,
+
+    /// Whether a boot node has been reached, starting the initial search for peers.
+    bootstrapped: bool,
+
+    /// The peers we have been connected to, persisted so they can be dialed after a restart.
+    peer_cache: PeerCache,
+
+    /// The file the peer cache is persisted in.
+    peer_cache_file: PathBuf,
+
+    /// How long a peer is kept in the peer cache after it was last connected.
+    peer_cache_max_age: Duration,
+
+    /// Fires when the peer cache is next due to be written to disk.
+    peer_cache_save: Delay,
+
+    /// The key our ENR is signed with.
+    local_key: Keypair,
+
+    /// The directory our ENR is persisted in, so its sequence number survives restarts.
+    network_dir: PathBuf,
+
+    /// The routable IP address last advertised in our ENR, restored should discv5 replace it with
+    /// one other peers could not dial.
+    advertised_ip: Option<IpAddr>,
 
     /// The TCP port for libp2p. Used to convert an updated IP address to a multiaddr. Note: This
     /// assumes that the external TCP port is the same as the internal TCP port if behind a NAT.
@@ -51,6 +216,18 @@ pub struct Discovery<TSubstream> {
     /// The discovery behaviour used to discover new peers.
     discovery: Discv5<TSubstream>,
 
+    /// The number of discv5 searches started which have yet to return.
+    searches_in_progress: usize,
+
+    /// The searches for peers on attestation subnets yet to find enough peers.
+    subnet_queries: Vec<SubnetQuery>,
+
+    /// Fires when the next subnet query gives up.
+    subnet_query_timeout: Delay,
+
+    /// Events waiting to be emitted.
+    events: VecDeque<DiscoveryEvent>,
+
     /// Logger for the discovery behaviour.
     log: slog::Logger,
 }
@@ -82,232 +259,2967 @@ impl<TSubstream> Discovery<TSubstream> {
             discovery.add_enr(bootnode_enr);
         }
 
-        Ok(Self {
+        // the peers we were connected to before a restart are dialed first
+        let peer_cache_file = config
+            .peer_cache_file
+            .clone()
+            .unwrap_or_else(|| config.network_dir.join(PEER_CACHE_FILENAME));
+        let peer_cache = PeerCache::load(
+            &peer_cache_file,
+            config.peer_cache_max_age,
+            SystemTime::now(),
+            &log,
+        );
+        let cached_peers = peer_cache.freshest(config.target_peers);
+
+        // Dial the boot nodes without a known ENR as soon as the swarm is polled, or once cached
+        // peers have had the chance to connect
+        let boot_nodes_due = if cached_peers.is_empty() {
+            Instant::now()
+        } else {
+            Instant::now() + CACHED_PEER_GRACE
+        };
+        let boot_nodes = config
+            .libp2p_nodes
+            .iter()
+            .map(|address| BootNode::new(address, boot_nodes_due))
+            .collect();
+        let mut boot_node_peers = HashMap::new();
+        for enr in &config.boot_nodes {
+            boot_node_peers.insert(enr.peer_id(), enr_tcp_address(enr).into_iter().collect());
+        }
+        for address in &config.libp2p_nodes {
+            if let Some(peer_id) = peer_id_of(address) {
+                boot_node_peers.insert(peer_id, vec![without_peer_id(address)]);
+            }
+        }
+
+        let advertised_ip = discovery.local_enr().ip().filter(|ip| {
+            config.allow_private_addresses || AddressClass::of_ip(*ip) == AddressClass::Public
+        });
+
+        let mut service = Self {
+            local_peer_id: local_key.public().into_peer_id(),
             connected_peers: HashSet::new(),
+            target_peers: config.target_peers,
             max_peers: config.max_peers,
+            min_peers: config.min_peers,
+            dial_queue: VecDeque::new(),
+            dialing: HashSet::new(),
+            max_concurrent_dials: config.max_concurrent_dials,
+            dial_blocked_until: HashMap::new(),
+            dial_backoffs: HashMap::new(),
+            banned_until: HashMap::new(),
+            ip_dial_failures: HashMap::new(),
+            banned_ips: HashMap::new(),
+            peer_attnets: HashMap::new(),
+            connected_addresses: HashMap::new(),
+            peer_subnets: HashMap::new(),
+            max_peers_per_subnet: config.max_peers_per_subnet,
+            identified_addresses: HashMap::new(),
+            identified_address_ttl: config.identified_address_ttl,
+            allow_private_addresses: config.allow_private_addresses,
+            ip_preference: config.ip_preference,
+            refused_peers: Vec::new(),
+            excess_peers: Vec::new(),
             peer_discovery_delay: Delay::new(Instant::now()),
-            past_discovery_delay: INITIAL_SEARCH_DELAY,
+            discovery_interval: config.discovery_interval,
+            search_interval: MIN_SEARCH_INTERVAL,
+            last_successful_search: None,
+            boot_nodes,
+            boot_node_peers,
+            mdns_addresses: HashMap::new(),
+            boot_node_redial: Delay::new(Instant::now()),
+            trusted_peers: config
+                .trusted_peers
+                .iter()
+                .map(|address| TrustedPeer::new(address, Instant::now()))
+                .collect(),
+            trusted_peer_redial: Delay::new(Instant::now()),
+            bootstrapped: false,
+            peer_cache,
+            peer_cache_file,
+            peer_cache_max_age: config.peer_cache_max_age,
+            peer_cache_save: Delay::new(Instant::now() + PEER_CACHE_SAVE_INTERVAL),
+            local_key: local_key.clone(),
+            network_dir: config.network_dir.clone(),
+            advertised_ip,
             tcp_port: config.libp2p_port,
             discovery,
+            searches_in_progress: 0,
+            subnet_queries: Vec::new(),
+            subnet_query_timeout: Delay::new(Instant::now()),
+            events: VecDeque::new(),
             log,
-        })
+        };
+
+        // we start subscribed to no subnets, whatever a record on disk advertises
+        service.update_attnets(&Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT))?;
+
+        if !cached_peers.is_empty() {
+            info!(service.log, "Dialing cached peers"; "peers" => cached_peers.len());
+        }
+        for (peer_id, peer) in cached_peers {
+            if let Some(enr) = peer.enr {
+                service.discovery.add_enr(enr);
+            }
+            service.add_identified_addresses(peer_id.clone(), peer.addresses);
+            service.queue_dial(peer_id);
+        }
+        Ok(service)
     }
 
-    /// Manually search for peers. This restarts the discovery round, sparking multiple rapid
-    /// queries.
+    /// Manually search for peers now, rather than at the next interval.
     pub fn discover_peers(&mut self) {
-        self.past_discovery_delay = INITIAL_SEARCH_DELAY;
-        self.find_peers();
+        self.find_peers(Instant::now());
     }
 
-    /// Add an Enr to the routing table of the discovery mechanism.
-    pub fn add_enr(&mut self, enr: Enr) {
-        self.discovery.add_enr(enr);
+    /// Returns the time of the last search which found peers, if any has.
+    pub fn last_successful_search(&self) -> Option<Instant> {
+        self.last_successful_search
     }
 
-    /// Search for new peers using the underlying discovery mechanism.
-    fn find_peers(&mut self) {
-        // pick a random NodeId
-        let random_node = NodeId::random();
-        debug!(self.log, "Searching for peers...");
-        self.discovery.find_node(random_node);
+    /// Returns the number of peers currently connected.
+    pub fn connected_peers(&self) -> usize {
+        self.connected_peers.len()
+    }
 
-        // update the time until next discovery
-        let delay = {
-            if self.past_discovery_delay < MAX_TIME_BETWEEN_PEER_SEARCHES {
-                self.past_discovery_delay *= 2;
-                self.past_discovery_delay
-            } else {
-                MAX_TIME_BETWEEN_PEER_SEARCHES
-            }
-        };
-        self.peer_discovery_delay
-            .reset(Instant::now() + Duration::from_secs(delay));
+    /// Returns the ids of the peers currently connected.
+    pub fn connected_peer_ids(&self) -> Vec<PeerId> {
+        self.connected_peers.iter().cloned().collect()
     }
-}
 
-// Redirect all behaviour events to underlying discovery behaviour.
-impl<TSubstream> NetworkBehaviour for Discovery<TSubstream>
-where
-    TSubstream: AsyncRead + AsyncWrite,
-{
-    type ProtocolsHandler = <Discv5<TSubstream> as NetworkBehaviour>::ProtocolsHandler;
-    type OutEvent = <Discv5<TSubstream> as NetworkBehaviour>::OutEvent;
+    /// Returns the address `peer_id` is connected on, if it is connected.
+    pub fn connected_address(&self, peer_id: &PeerId) -> Option<&Multiaddr> {
+        self.connected_addresses.get(peer_id)
+    }
 
-    fn new_handler(&mut self) -> Self::ProtocolsHandler {
-        NetworkBehaviour::new_handler(&mut self.discovery)
+    /// Returns the addresses `peer_id` has told us it listens on, which have not expired.
+    pub fn identified_addresses(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.identified_addresses_at(peer_id, Instant::now())
     }
 
-    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
-        // Let discovery track possible known peers.
-        self.discovery.addresses_of_peer(peer_id)
+    fn identified_addresses_at(&self, peer_id: &PeerId, now: Instant) -> Vec<Multiaddr> {
+        self.identified_addresses
+            .get(peer_id)
+            .into_iter()
+            .flatten()
+            .filter(|identified| {
+                now.duration_since(identified.confirmed_at) <= self.identified_address_ttl
+            })
+            .map(|identified| identified.address.clone())
+            .collect()
     }
 
-    fn inject_connected(&mut self, peer_id: PeerId, _endpoint: ConnectedPoint) {
-        self.connected_peers.insert(peer_id);
+    /// Returns the number of peers being dialed, which have neither connected nor failed.
+    pub fn pending_dials(&self) -> usize {
+        self.dialing.len()
     }
 
-    fn inject_disconnected(&mut self, peer_id: &PeerId, _endpoint: ConnectedPoint) {
-        self.connected_peers.remove(peer_id);
+    /// Returns the peers refused since the last call, which should be disconnected.
+    pub fn take_refused_peers(&mut self) -> Vec<PeerId> {
+        std::mem::replace(&mut self.refused_peers, Vec::new())
     }
 
-    fn inject_replaced(
-        &mut self,
-        _peer_id: PeerId,
-        _closed: ConnectedPoint,
-        _opened: ConnectedPoint,
-    ) {
-        // discv5 doesn't implement
+    /// Keeps the peer on `address` connected, dialing it now and redialing it whenever it
+    /// disconnects. A trusted peer is exempt from our limits on inbound connections and peers.
+    /// Adding an address already trusted has no effect.
+    pub fn add_trusted_peer(&mut self, address: Multiaddr) {
+        let trusted_peer = TrustedPeer::new(&address, Instant::now());
+        if self
+            .trusted_peers
+            .iter()
+            .any(|trusted| trusted.address == trusted_peer.address)
+        {
+            return;
+        }
+        info!(self.log, "Trusting peer"; "address" => format!("{}", address));
+        self.trusted_peers.push(trusted_peer);
     }
 
-    fn inject_node_event(
+    /// Returns `true` if `peer_id` has connected on the address of a trusted peer, or is the peer
+    /// id a trusted address names.
+    pub fn is_trusted(&self, peer_id: &PeerId) -> bool {
+        self.trusted_peers
+            .iter()
+            .any(|trusted| trusted.peer_id.as_ref() == Some(peer_id))
+    }
+
+    /// Returns the peers which connected since the last call while we had `max_peers`, which
+    /// should be said goodbye to and disconnected.
+    pub fn take_excess_peers(&mut self) -> Vec<PeerId> {
+        std::mem::replace(&mut self.excess_peers, Vec::new())
+    }
+
+    /// Returns `true` if an address to dial `peer_id` on is known.
+    pub fn has_addresses(&mut self, peer_id: &PeerId) -> bool
+    where
+        TSubstream: AsyncRead + AsyncWrite,
+    {
+        !self.addresses_of_peer(peer_id).is_empty()
+    }
+
+    /// Records the addresses `peer_id` has told us it listens on, to dial it on alongside those
+    /// discovered. Unspecified addresses, which cannot be dialed, are dropped, as are those other
+    /// peers could not dial unless `allow_private_addresses` is set.
+    pub fn add_identified_addresses(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+        self.add_identified_addresses_at(peer_id, addresses, Instant::now())
+    }
+
+    /// Records the addresses `peer_id` has told us it listens on as confirmed at `now`. The
+    /// addresses it no longer confirms expire, and of more than `MAX_IDENTIFIED_ADDRESSES` those
+    /// confirmed longest ago are forgotten.
+    fn add_identified_addresses_at(
         &mut self,
-        _peer_id: PeerId,
-        _event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+        now: Instant,
     ) {
-        // discv5 doesn't implement
+        let addresses: Vec<Multiaddr> = addresses
+            .into_iter()
+            .filter(|address| match address.iter().next() {
+                Some(Protocol::Ip4(ip)) => !ip.is_unspecified(),
+                Some(Protocol::Ip6(ip)) => !ip.is_unspecified(),
+                _ => true,
+            })
+            .filter(|address| self.is_routable(address))
+            .take(MAX_IDENTIFIED_ADDRESSES)
+            .collect();
+        self.peer_cache.add_addresses(&peer_id, &addresses);
+
+        let ttl = self.identified_address_ttl;
+        let identified = self
+            .identified_addresses
+            .entry(peer_id.clone())
+            .or_default();
+        identified.retain(|identified| now.duration_since(identified.confirmed_at) <= ttl);
+        for address in addresses {
+            match identified
+                .iter_mut()
+                .find(|identified| identified.address == address)
+            {
+                Some(identified) => identified.confirmed_at = now,
+                None => identified.push(IdentifiedAddress {
+                    address,
+                    confirmed_at: now,
+                }),
+            }
+        }
+        while identified.len() > MAX_IDENTIFIED_ADDRESSES {
+            let oldest = identified
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, identified)| identified.confirmed_at)
+                .map(|(position, _)| position)
+                .expect("more than the maximum are identified");
+            identified.remove(oldest);
+        }
+        if identified.is_empty() {
+            self.identified_addresses.remove(&peer_id);
+        }
     }
 
-    fn poll(
-        &mut self,
-        params: &mut impl PollParameters,
-    ) -> Async<
-        NetworkBehaviourAction<
-            <Self::ProtocolsHandler as ProtocolsHandler>::InEvent,
-            Self::OutEvent,
-        >,
-    > {
-        // search for peers if it is time
-        loop {
-            match self.peer_discovery_delay.poll() {
-                Ok(Async::Ready(_)) => {
-                    if self.connected_peers.len() < self.max_peers {
-                        self.find_peers();
-                    }
-                }
-                Ok(Async::NotReady) => break,
-                Err(e) => {
-                    warn!(self.log, "Discovery peer search failed: {:?}", e);
-                }
+    /// Forgets the identified addresses not confirmed within `identified_address_ttl` of `now`,
+    /// such as those of peers which have left, whose addresses are otherwise only expired when
+    /// they identify again.
+    fn prune_identified_addresses(&mut self, now: Instant) {
+        let ttl = self.identified_address_ttl;
+        self.identified_addresses.retain(|_peer_id, identified| {
+            identified.retain(|identified| now.duration_since(identified.confirmed_at) <= ttl);
+            !identified.is_empty()
+        });
+    }
+
+    /// Returns `true` if `address` may be stored and advertised: it is public, is not an IP
+    /// address, or `allow_private_addresses` is set.
+    fn is_routable(&self, address: &Multiaddr) -> bool {
+        self.allow_private_addresses
+            || AddressClass::of(address).map_or(true, |class| class == AddressClass::Public)
+    }
+
+    /// Returns the ENR of this node.
+    pub fn local_enr(&self) -> &Enr {
+        self.discovery.local_enr()
+    }
+
+    /// Updates the IP and TCP port in our ENR, bumping its sequence number if either changed, and
+    /// persists the record. An IP address other peers could not dial is refused unless
+    /// `allow_private_addresses` is set.
+    pub fn update_local_address(&mut self, ip: IpAddr, tcp_port: u16) -> error::Result<()> {
+        let class = AddressClass::of_ip(ip);
+        if class != AddressClass::Public && !self.allow_private_addresses {
+            return Err(format!("Not advertising {:?} address {} in our ENR", class, ip).into());
+        }
+        let enr = self.discovery.local_enr_mut();
+        if enr.ip() != Some(ip) {
+            enr.set_ip(ip, &self.local_key)
+                .map_err(|e| format!("Could not update ENR IP: {:?}", e))?;
+        }
+        if enr.tcp() != Some(tcp_port) {
+            enr.set_tcp(tcp_port, &self.local_key)
+                .map_err(|e| format!("Could not update ENR TCP port: {:?}", e))?;
+        }
+        self.advertised_ip = Some(ip);
+        self.tcp_port = tcp_port;
+        self.persist_local_enr();
+        Ok(())
+    }
+
+    /// Handles discv5 having updated the IP of our ENR to `ip`, the address peers see us on,
+    /// returning the address to report to the swarm as observed. An IP other peers could not
+    /// dial is neither persisted nor reported, and the routable IP it replaced is restored.
+    fn socket_updated(&mut self, ip: IpAddr) -> Option<Multiaddr> {
+        let mut address = Multiaddr::from(ip);
+        address.push(Protocol::Tcp(self.tcp_port));
+        if self.is_routable(&address) {
+            self.advertised_ip = Some(ip);
+            self.persist_local_enr();
+            return Some(address);
+        }
+
+        debug!(
+            self.log,
+            "Not advertising unroutable observed address";
+            "address" => format!("{}", address)
+        );
+        if let Some(advertised_ip) = self.advertised_ip {
+            if let Err(e) = self
+                .discovery
+                .local_enr_mut()
+                .set_ip(advertised_ip, &self.local_key)
+            {
+                warn!(self.log, "Could not restore ENR IP"; "error" => format!("{:?}", e));
+                return None;
             }
+            self.persist_local_enr();
         }
+        None
+    }
 
-        // Poll discovery
-        loop {
-            match self.discovery.poll(params) {
-                Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
-                    match event {
-                        Discv5Event::Discovered(_enr) => {
-                            // not concerned about FINDNODE results, rather the result of an entire
-                            // query.
-                        }
-                        Discv5Event::SocketUpdated(socket) => {
-                            info!(self.log, "Address updated"; "IP" => format!("{}",socket.ip()));
-                            let mut address = Multiaddr::from(socket.ip());
-                            address.push(Protocol::Tcp(self.tcp_port));
-                            return Async::Ready(NetworkBehaviourAction::ReportObservedAddr {
-                                address,
-                            });
-                        }
-                        Discv5Event::FindNodeResult { closer_peers, .. } => {
-                            debug!(self.log, "Discv5 query found {} peers", closer_peers.len());
-                            if closer_peers.is_empty() {
-                                debug!(self.log, "Discv5 random query yielded empty results");
-                            }
-                            for peer_id in closer_peers {
-                                // if we need more peers, attempt a connection
-                                if self.connected_peers.len() < self.max_peers
-                                    && self.connected_peers.get(&peer_id).is_none()
-                                {
-                                    debug!(self.log, "Discv5: Peer discovered"; "Peer"=> format!("{:?}", peer_id));
-                                    return Async::Ready(NetworkBehaviourAction::DialPeer {
-                                        peer_id,
-                                    });
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                // discv5 does not output any other NetworkBehaviourAction
-                Async::Ready(_) => {}
-                Async::NotReady => break,
+    /// Advertises the attestation subnets set in `attnets` in our ENR, bumping its sequence number
+    /// and persisting the record if they have changed.
+    pub fn update_attnets(&mut self, attnets: &Bitfield) -> error::Result<()> {
+        if enr_attnets(self.discovery.local_enr()).as_ref() == Some(attnets) {
+            return Ok(());
+        }
+        self.discovery
+            .local_enr_mut()
+            .insert(ATTNETS_ENR_KEY, attnets.as_ssz_bytes(), &self.local_key)
+            .map_err(|e| format!("Could not update ENR attnets: {:?}", e))?;
+        self.persist_local_enr();
+        Ok(())
+    }
+
+    /// Records the attestation subnets advertised in the ENR of a discovered peer.
+    fn enr_discovered(&mut self, enr: &Enr) {
+        let peer_id = enr.peer_id();
+        self.peer_cache.set_enr(&peer_id, enr);
+        let attnets = match enr_attnets(enr) {
+            Some(attnets) => attnets,
+            None => {
+                self.peer_attnets.remove(&peer_id);
+                return;
+            }
+        };
+
+        let mut sought = false;
+        for query in &mut self.subnet_queries {
+            if attnets.get(query.subnet_id as usize) == Ok(true) {
+                sought |= query.found.insert(peer_id.clone());
             }
         }
-        Async::NotReady
+        self.peer_attnets.insert(peer_id.clone(), attnets);
+        if sought {
+            self.queue_dial(peer_id);
+            self.complete_subnet_queries(Instant::now());
+        }
     }
-}
 
-/// Loads an ENR from file if it exists and matches the current NodeId and sequence number. If none
-/// exists, generates a new one.
-///
-/// If an ENR exists, with the same NodeId and IP address, we use the disk-generated one as its
-/// ENR sequence will be equal or higher than a newly generated one.
-fn load_enr(
-    local_key: &Keypair,
-    config: &NetworkConfig,
-    log: &slog::Logger,
-) -> Result<Enr, String> {
-    // Build the local ENR.
-    // Note: Discovery should update the ENR record's IP to the external IP as seen by the
-    // majority of our peers.
-    let mut local_enr = EnrBuilder::new()
-        .ip(config.discovery_address.into())
-        .tcp(config.libp2p_port)
-        .udp(config.discovery_port)
-        .build(&local_key)
-        .map_err(|e| format!("Could not build Local ENR: {:?}", e))?;
+    /// Searches for `min_peers` peers whose ENR advertises the attestation subnet `subnet_id`,
+    /// dialing those found while we have room for them. A `SubnetPeersFound` event is emitted
+    /// once enough are found, or `SubnetQueryTimedOut` if they are not within
+    /// `SUBNET_QUERY_TIMEOUT`. The peers already discovered on the subnet count towards the
+    /// query.
+    pub fn discover_subnet_peers(&mut self, subnet_id: u64, min_peers: usize) {
+        self.discover_subnet_peers_at(subnet_id, min_peers, Instant::now())
+    }
 
-    let enr_f = config.network_dir.join(ENR_FILENAME);
-    if let Ok(mut enr_file) = File::open(enr_f.clone()) {
-        let mut enr_string = String::new();
-        match enr_file.read_to_string(&mut enr_string) {
-            Err(_) => debug!(log, "Could not read ENR from file"),
-            Ok(_) => {
-                match Enr::from_str(&enr_string) {
-                    Ok(enr) => {
-                        debug!(log, "ENR found in file: {:?}", enr_f);
+    fn discover_subnet_peers_at(&mut self, subnet_id: u64, min_peers: usize, now: Instant) {
+        let found: HashSet<PeerId> = self.peers_for_subnet(subnet_id).into_iter().collect();
+        for peer_id in &found {
+            self.queue_dial(peer_id.clone());
+        }
+        debug!(
+            self.log,
+            "Searching for subnet peers";
+            "subnet_id" => subnet_id,
+            "min_peers" => min_peers,
+            "known" => found.len()
+        );
+        let searching = found.len() < min_peers;
+        self.subnet_queries.push(SubnetQuery {
+            subnet_id,
+            min_peers,
+            found,
+            deadline: now + SUBNET_QUERY_TIMEOUT,
+        });
+        if searching {
+            self.start_search(NodeId::random());
+        } else {
+            self.complete_subnet_queries(now);
+        }
+    }
 
-                        if enr.node_id() == local_enr.node_id() {
-                            if enr.ip() == config.discovery_address.into()
-                                && enr.tcp() == Some(config.libp2p_port)
-                                && enr.udp() == Some(config.discovery_port)
-                            {
-                                debug!(log, "ENR loaded from file");
-                                // the stored ENR has the same configuration, use it
-                                return Ok(enr);
-                            }
+    /// Ends the subnet queries which have found enough peers, or which give up by `now`.
+    fn complete_subnet_queries(&mut self, now: Instant) {
+        let (complete, pending) = std::mem::replace(&mut self.subnet_queries, Vec::new())
+            .into_iter()
+            .partition(|query: &SubnetQuery| {
+                query.found.len() >= query.min_peers || query.deadline <= now
+            });
+        self.subnet_queries = pending;
 
-                            // same node id, different configuration - update the sequence number
-                            let new_seq_no = enr.seq().checked_add(1).ok_or_else(|| "ENR sequence number on file is too large. Remove it to generate a new NodeId")?;
-                            local_enr.set_seq(new_seq_no, local_key).map_err(|e| {
-                                format!("Could not update ENR sequence number: {:?}", e)
-                            })?;
-                            debug!(log, "ENR sequence number increased to: {}", new_seq_no);
-                        }
-                    }
-                    Err(e) => {
-                        warn!(log, "ENR from file could not be decoded: {:?}", e);
-                    }
-                }
+        for query in complete {
+            let (subnet_id, count) = (query.subnet_id, query.found.len());
+            if count >= query.min_peers {
+                debug!(self.log, "Found subnet peers"; "subnet_id" => subnet_id, "count" => count);
+                self.events
+                    .push_back(DiscoveryEvent::SubnetPeersFound(subnet_id, count));
+            } else {
+                warn!(
+                    self.log,
+                    "Could not find enough subnet peers";
+                    "subnet_id" => subnet_id,
+                    "count" => count,
+                    "min_peers" => query.min_peers
+                );
+                self.events
+                    .push_back(DiscoveryEvent::SubnetQueryTimedOut(subnet_id, count));
             }
         }
     }
 
-    // write ENR to disk
-    let _ = std::fs::create_dir_all(&config.network_dir);
-    match File::create(enr_f.clone())
-        .and_then(|mut f| f.write_all(&local_enr.to_base64().as_bytes()))
-    {
-        Ok(_) => {
-            debug!(log, "ENR written to disk");
+    /// Returns the discovered peers whose ENR advertises the attestation subnet `subnet_id`.
+    pub fn peers_for_subnet(&self, subnet_id: u64) -> Vec<PeerId> {
+        self.peer_attnets
+            .iter()
+            .filter(|(_peer_id, attnets)| attnets.get(subnet_id as usize) == Ok(true))
+            .map(|(peer_id, _attnets)| peer_id.clone())
+            .collect()
+    }
+
+    /// Records the RPC score of `peer_id` in the peer cache, to be restored after a restart.
+    pub fn cache_peer_score(&mut self, peer_id: &PeerId, score: i32) {
+        self.peer_cache.set_score(peer_id, score);
+    }
+
+    /// Returns the non-zero RPC scores of the peers in the peer cache.
+    pub fn cached_peer_scores(&self) -> Vec<(PeerId, i32)> {
+        self.peer_cache.scores()
+    }
+
+    /// Writes the peer cache to disk, the connected peers seen now, after forgetting the peers
+    /// not seen within the configured age and all but the `MAX_CACHED_PEERS` seen most recently.
+    pub fn save_peer_cache(&mut self) {
+        let now = SystemTime::now();
+        for peer_id in &self.connected_peers {
+            self.peer_cache.seen(peer_id.clone(), now);
         }
-        Err(e) => {
-            warn!(
-                log,
-                "Could not write ENR to file: {:?}. Error: {}", enr_f, e
-            );
+        self.peer_cache.prune(self.peer_cache_max_age, now);
+        self.peer_cache.truncate(MAX_CACHED_PEERS);
+        self.peer_cache.save(&self.peer_cache_file, &self.log);
+    }
+
+    /// Writes our ENR to disk, logging the record if it has changed.
+    fn persist_local_enr(&self) {
+        let enr = self.discovery.local_enr();
+        info!(self.log, "Local ENR: {}", enr.to_base64(); "seq" => enr.seq());
+        save_enr(enr, &self.network_dir, &self.log);
+    }
+
+    /// Add an Enr to the routing table of the discovery mechanism.
+    pub fn add_enr(&mut self, enr: Enr) {
+        self.discovery.add_enr(enr);
+    }
+
+    /// Queues a peer to be dialed, unless it is ourselves, already connected, being dialed, queued
+    /// or blocked from being dialed. At most `target_peers` peers are queued; further peers are
+    /// dropped and may be rediscovered later.
+    pub fn queue_dial(&mut self, peer_id: PeerId) {
+        if peer_id == self.local_peer_id
+            || self.connected_peers.contains(&peer_id)
+            || self.dialing.contains(&peer_id)
+            || self.dial_queue.contains(&peer_id)
+            || self.dial_queue.len() >= self.target_peers
+            || self.is_dial_blocked(&peer_id)
+        {
+            return;
         }
+        self.dial_blocked_until.remove(&peer_id);
+        self.dial_queue.push_back(peer_id);
+    }
+
+    /// Queues a trusted peer to be dialed ahead of the peers already queued, unless it is
+    /// ourselves, already connected, being dialed or blocked from being dialed. The peer is
+    /// admitted even when `target_peers` peers are queued, by dropping the last peer queued.
+    /// Untrusted peers are queued behind trusted ones, so are dropped first.
+    pub fn queue_trusted_dial(&mut self, peer_id: PeerId) {
+        if peer_id == self.local_peer_id
+            || self.connected_peers.contains(&peer_id)
+            || self.dialing.contains(&peer_id)
+            || self.is_dial_blocked(&peer_id)
+        {
+            return;
+        }
+        self.dial_queue.retain(|queued| *queued != peer_id);
+        if self.dial_queue.len() >= self.target_peers {
+            if let Some(dropped) = self.dial_queue.pop_back() {
+                debug!(
+                    self.log,
+                    "Dropping queued dial for a trusted peer";
+                    "dropped" => format!("{:?}", dropped),
+                    "trusted" => format!("{:?}", peer_id)
+                );
+            }
+        }
+        self.dial_blocked_until.remove(&peer_id);
+        self.dial_queue.push_front(peer_id);
+    }
+
+    /// Returns `true` if `peer_id` is waiting to be dialed or being dialed.
+    pub fn is_dial_pending(&self, peer_id: &PeerId) -> bool {
+        self.dial_queue.contains(peer_id) || self.dialing.contains(peer_id)
+    }
+
+    /// Prevents `peer_id` from being dialed until `until`, or until `unblock_dials` if `None`.
+    pub fn block_dials(&mut self, peer_id: PeerId, until: Option<Instant>) {
+        self.dial_queue.retain(|queued| *queued != peer_id);
+        self.dial_blocked_until.insert(peer_id, until);
+    }
+
+    /// Allows `peer_id` to be dialed again.
+    pub fn unblock_dials(&mut self, peer_id: &PeerId) {
+        self.dial_blocked_until.remove(peer_id);
+    }
+
+    /// Prevents `peer_id` from being dialed until `until`, unless it is already blocked for
+    /// longer, by a ban or an earlier failure.
+    fn delay_redial(&mut self, peer_id: PeerId, until: Instant) {
+        let blocked_longer = match self.dial_blocked_until.get(&peer_id) {
+            Some(None) => true,
+            Some(Some(blocked_until)) => *blocked_until >= until,
+            None => false,
+        };
+        if !blocked_longer {
+            self.block_dials(peer_id, Some(until));
+        }
+    }
+
+    /// Prevents `peer_id` from being dialed for `DIAL_COOLDOWN`, as it said goodbye to us. A
+    /// longer block is kept.
+    pub fn cool_down(&mut self, peer_id: PeerId) {
+        self.delay_redial(peer_id, Instant::now() + DIAL_COOLDOWN);
+    }
+
+    /// Backs off from dialing `peer_id` after a dial of one of its addresses failed at `now`
+    /// with `kind` of error. The delay doubles with each consecutive failed dial of the peer.
+    fn back_off_dial(&mut self, peer_id: PeerId, kind: DialErrorKind, now: Instant) {
+        let backoff = self.dial_backoffs.entry(peer_id).or_insert(DialBackoff {
+            failures: 0,
+            until: now,
+        });
+        let until = now + kind.redial_backoff(backoff.failures);
+        // a later, shorter delay does not cut an earlier one short
+        if until > backoff.until {
+            backoff.until = until;
+        }
+    }
+
+    /// Counts a dial of `addr` which failed at `now` with `kind` of error against its IP address,
+    /// banning the address once `IP_BAN_THRESHOLD` dials of it have failed by protocol mismatch.
+    /// Other kinds of failure may pass, and addresses which are not public may be shared by
+    /// peers we want, so neither counts.
+    fn count_ip_failure(&mut self, addr: &Multiaddr, kind: DialErrorKind, now: Instant) {
+        if kind != DialErrorKind::ProtocolMismatch
+            || AddressClass::of(addr) != Some(AddressClass::Public)
+        {
+            return;
+        }
+        let ip = match ip_of(addr) {
+            Some(ip) => ip,
+            None => return,
+        };
+        let failures = self.ip_dial_failures.entry(ip).or_insert(0);
+        *failures += 1;
+        if *failures >= IP_BAN_THRESHOLD {
+            self.ip_dial_failures.remove(&ip);
+            debug!(
+                self.log,
+                "Banning address which does not speak our protocols";
+                "ip" => format!("{}", ip)
+            );
+            self.banned_ips.insert(ip, now + IP_BAN_DURATION);
+        }
+    }
+
+    /// Returns `true` if the IP address `address` starts with is banned at `now`.
+    fn is_ip_banned(&self, address: &Multiaddr, now: Instant) -> bool {
+        ip_of(address)
+            .and_then(|ip| self.banned_ips.get(&ip))
+            .map_or(false, |until| *until > now)
+    }
+
+    /// Forgets the failed dials of the peers whose backoff ended `MAX_DIAL_BACKOFF` before `now`.
+    /// A backoff outlives its delay so that a peer failing again once redialed backs off for
+    /// longer, but a peer which is not redialed, nor ever connects, is not remembered forever.
+    fn prune_dial_backoffs(&mut self, now: Instant) {
+        self.dial_backoffs
+            .retain(|_peer_id, backoff| backoff.until + MAX_DIAL_BACKOFF > now);
+    }
+
+    /// Returns `true` if `peer_id` may not currently be dialed.
+    pub fn is_dial_blocked(&self, peer_id: &PeerId) -> bool {
+        let now = Instant::now();
+        is_blocked_at(self.dial_blocked_until.get(peer_id), now)
+            || self
+                .dial_backoffs
+                .get(peer_id)
+                .map_or(false, |backoff| backoff.until > now)
+    }
+
+    /// Returns the number of consecutive dials of `peer_id` which have failed since it last
+    /// connected.
+    pub fn dial_failures(&self, peer_id: &PeerId) -> u32 {
+        self.dial_backoffs
+            .get(peer_id)
+            .map_or(0, |backoff| backoff.failures)
+    }
+
+    /// Returns the time before which `peer_id` is not dialed, by a backoff, cooldown or timed
+    /// ban, if it is later than now.
+    pub fn redial_at(&self, peer_id: &PeerId) -> Option<Instant> {
+        let blocked_until = self
+            .dial_blocked_until
+            .get(peer_id)
+            .and_then(|until| *until);
+        let backoff_until = self.dial_backoffs.get(peer_id).map(|backoff| backoff.until);
+        blocked_until
+            .into_iter()
+            .chain(backoff_until)
+            .max()
+            .filter(|until| *until > Instant::now())
+    }
+
+    /// Bans `peer_id` until `until`, or until `unban_peer` if `None`. The peer is not dialed, its
+    /// addresses are not handed out and its connections are refused.
+    pub fn ban_peer(&mut self, peer_id: PeerId, until: Option<Instant>) {
+        self.block_dials(peer_id.clone(), until);
+        self.banned_until.insert(peer_id, until);
+    }
+
+    /// Lifts the ban of `peer_id`, allowing it to be dialed and to connect again.
+    pub fn unban_peer(&mut self, peer_id: &PeerId) {
+        self.unblock_dials(peer_id);
+        self.banned_until.remove(peer_id);
+    }
+
+    /// Returns `true` if `peer_id` is currently banned.
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        is_blocked_at(self.banned_until.get(peer_id), Instant::now())
+    }
+
+    /// Returns the time the earliest ban with an expiry ends, if any.
+    pub fn next_ban_expiry(&self) -> Option<Instant> {
+        self.banned_until.values().filter_map(|until| *until).min()
+    }
+
+    /// Forgets the bans of peers and IP addresses which have ended by `now`, returning the peers
+    /// they banned.
+    pub fn take_expired_bans(&mut self, now: Instant) -> Vec<PeerId> {
+        let expired: Vec<PeerId> = self
+            .banned_until
+            .iter()
+            .filter(|(_peer_id, until)| until.map_or(false, |until| until <= now))
+            .map(|(peer_id, _until)| peer_id.clone())
+            .collect();
+        for peer_id in &expired {
+            self.unban_peer(peer_id);
+            self.delay_redial(peer_id.clone(), now + DIAL_COOLDOWN);
+        }
+        self.banned_ips.retain(|_ip, until| *until > now);
+        expired
+    }
+
+    /// Returns the next queued peer to dial, if we need more peers and fewer than
+    /// `max_concurrent_dials` dials are outstanding.
+    fn next_dial(&mut self) -> Option<PeerId> {
+        while self.dialing.len() < self.max_concurrent_dials
+            && self.connected_peers.len() + self.dialing.len() < self.target_peers
+        {
+            let peer_id = self.dial_queue.pop_front()?;
+            if !self.connected_peers.contains(&peer_id) {
+                self.dialing.insert(peer_id.clone());
+                return Some(peer_id);
+            }
+        }
+        None
+    }
+
+    /// Queues the peers a search, or mDNS, found to be dialed, reporting each which was not
+    /// already connected, pending or blocked.
+    pub fn peers_found(&mut self, peer_ids: Vec<PeerId>) {
+        for peer_id in peer_ids {
+            let was_pending = self.is_dial_pending(&peer_id);
+            self.queue_dial(peer_id.clone());
+            if !was_pending && self.is_dial_pending(&peer_id) {
+                self.events
+                    .push_back(DiscoveryEvent::PeerDiscovered(peer_id));
+            }
+        }
+    }
+
+    /// Returns the address of the next boot node due to be dialed at `now`, marking it as being
+    /// dialed.
+    fn next_boot_node_dial(&mut self, now: Instant) -> Option<Multiaddr> {
+        let boot_node = self
+            .boot_nodes
+            .iter_mut()
+            .find(|boot_node| match boot_node.state {
+                BootNodeState::Waiting(due) => due <= now,
+                _ => false,
+            })?;
+        boot_node.state = BootNodeState::Dialing;
+        Some(boot_node.address.clone())
+    }
+
+    /// Returns the time the next unreachable boot node is due to be redialed, if any.
+    fn next_boot_node_redial(&self) -> Option<Instant> {
+        self.boot_nodes
+            .iter()
+            .filter_map(|boot_node| match boot_node.state {
+                BootNodeState::Waiting(due) => Some(due),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Records that the boot node dialed on `address`, if any, was reached. The first boot node
+    /// reached starts a search for peers.
+    fn boot_node_reached(&mut self, peer_id: &PeerId, address: &Multiaddr) {
+        let boot_node = match self.boot_nodes.iter_mut().find(|boot_node| {
+            boot_node.address == *address && boot_node.state == BootNodeState::Dialing
+        }) {
+            Some(boot_node) => boot_node,
+            None => return,
+        };
+        boot_node.state = BootNodeState::Reached;
+        let addresses = self.boot_node_peers.entry(peer_id.clone()).or_default();
+        if !addresses.contains(address) {
+            addresses.push(address.clone());
+        }
+        info!(self.log, "Connected to boot node"; "address" => format!("{}", address));
+
+        if !self.bootstrapped {
+            self.bootstrapped = true;
+            info!(self.log, "Starting peer discovery from boot node");
+            self.discover_peers();
+        }
+    }
+
+    /// Records that the boot node dialed on `address`, if any, could not be reached, scheduling
+    /// it to be redialed after a backoff which doubles with each failure.
+    fn boot_node_unreachable(
+        &mut self,
+        address: &Multiaddr,
+        kind: DialErrorKind,
+        error: &dyn std::error::Error,
+    ) {
+        let boot_node = match self.boot_nodes.iter_mut().find(|boot_node| {
+            boot_node.address == *address && boot_node.state == BootNodeState::Dialing
+        }) {
+            Some(boot_node) => boot_node,
+            None => return,
+        };
+        // a node that does not speak our protocols will not learn to by being redialed soon
+        let backoff = match kind {
+            DialErrorKind::ProtocolMismatch => MAX_BOOT_NODE_BACKOFF,
+            _ => boot_node.backoff,
+        };
+        boot_node.state = BootNodeState::Waiting(Instant::now() + Duration::from_secs(backoff));
+        boot_node.backoff = (backoff * 2).min(MAX_BOOT_NODE_BACKOFF);
+        warn!(
+            self.log,
+            "Failed to reach boot node";
+            "address" => format!("{}", address),
+            "error" => format!("{}", error),
+            "kind" => format!("{:?}", kind),
+            "retry_in_secs" => backoff
+        );
+
+        let all_unreachable = self
+            .boot_nodes
+            .iter()
+            .all(|boot_node| match boot_node.state {
+                BootNodeState::Waiting(_) => boot_node.backoff > INITIAL_BOOT_NODE_BACKOFF,
+                _ => false,
+            });
+        if all_unreachable {
+            warn!(
+                self.log,
+                "Could not reach any boot node";
+                "boot_nodes" => self.boot_nodes.len()
+            );
+        }
+    }
+
+    /// Returns the address of the next trusted peer due to be dialed at `now`, marking it as being
+    /// dialed.
+    fn next_trusted_peer_dial(&mut self, now: Instant) -> Option<Multiaddr> {
+        let trusted = self
+            .trusted_peers
+            .iter_mut()
+            .find(|trusted| match trusted.state {
+                TrustedPeerState::Waiting(due) => due <= now,
+                _ => false,
+            })?;
+        trusted.state = TrustedPeerState::Dialing;
+        Some(trusted.address.clone())
+    }
+
+    /// Returns the time the next disconnected trusted peer is due to be redialed, if any.
+    fn next_trusted_peer_redial(&self) -> Option<Instant> {
+        self.trusted_peers
+            .iter()
+            .filter_map(|trusted| match trusted.state {
+                TrustedPeerState::Waiting(due) => Some(due),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Records `peer_id` as connected if it is a trusted peer, either dialed on the address of one
+    /// or already known by its id, returning `true` if it is.
+    fn trusted_peer_connected(&mut self, peer_id: &PeerId, endpoint: &ConnectedPoint) -> bool {
+        let trusted = match self.trusted_peers.iter_mut().find(|trusted| {
+            trusted.peer_id.as_ref() == Some(peer_id)
+                || match endpoint {
+                    ConnectedPoint::Dialer { address } => {
+                        trusted.address == *address && trusted.state == TrustedPeerState::Dialing
+                    }
+                    ConnectedPoint::Listener { .. } => false,
+                }
+        }) {
+            Some(trusted) => trusted,
+            None => return false,
+        };
+        trusted.peer_id = Some(peer_id.clone());
+        trusted.state = TrustedPeerState::Connected;
+        trusted.backoff = INITIAL_TRUSTED_PEER_BACKOFF;
+        true
+    }
+
+    /// Schedules the trusted peer matching `is_trusted`, if it is not `already_waiting`, to be
+    /// redialed after a backoff which doubles with each consecutive attempt.
+    fn redial_trusted_peer<F>(&mut self, is_trusted: F)
+    where
+        F: Fn(&TrustedPeer) -> bool,
+    {
+        let trusted = match self.trusted_peers.iter_mut().find(|trusted| {
+            is_trusted(trusted)
+                && match trusted.state {
+                    TrustedPeerState::Waiting(_) => false,
+                    _ => true,
+                }
+        }) {
+            Some(trusted) => trusted,
+            None => return,
+        };
+        let backoff = trusted.backoff;
+        trusted.state = TrustedPeerState::Waiting(Instant::now() + Duration::from_secs(backoff));
+        trusted.backoff = (backoff * 2).min(MAX_TRUSTED_PEER_BACKOFF);
+        info!(
+            self.log,
+            "Redialing trusted peer";
+            "address" => format!("{}", trusted.address),
+            "retry_in_secs" => backoff
+        );
+    }
+
+    /// Starts a discv5 search for the peers closest to `target`.
+    fn start_search(&mut self, target: NodeId) {
+        self.searches_in_progress += 1;
+        self.discovery.find_node(target);
+    }
+
+    /// Records that mDNS discovered `peer_id` on `address` on the local network.
+    pub fn add_mdns_address(&mut self, peer_id: PeerId, address: Multiaddr) {
+        self.mdns_addresses
+            .entry(peer_id)
+            .or_default()
+            .insert(address);
+    }
+
+    /// Forgets the `address` of `peer_id` mDNS no longer finds, and the peer once it has none.
+    pub fn remove_mdns_address(&mut self, peer_id: &PeerId, address: &Multiaddr) {
+        if let Some(addresses) = self.mdns_addresses.get_mut(peer_id) {
+            addresses.remove(address);
+            if addresses.is_empty() {
+                self.mdns_addresses.remove(peer_id);
+            }
+        }
+    }
+
+    /// Returns `true` if mDNS has discovered `peer_id` on the local network.
+    pub fn is_mdns_peer(&self, peer_id: &PeerId) -> bool {
+        self.mdns_addresses.contains_key(peer_id)
+    }
+
+    /// Returns the peers we know of, with their addresses and how we came to know of them. A
+    /// peer known from several sources is reported with the most deliberate: manual, then boot
+    /// node, mDNS, discv5 and identify. Boot nodes dialed by address are included once reached.
+    pub fn known_peers(&mut self) -> Vec<(PeerId, Vec<Multiaddr>, PeerSource)> {
+        let mut known: HashMap<PeerId, (Vec<Multiaddr>, PeerSource)> = HashMap::new();
+        let mut add = |peer_id: PeerId, addresses: Vec<Multiaddr>, source: PeerSource| {
+            let entry = known.entry(peer_id).or_insert_with(|| (Vec::new(), source));
+            for address in addresses {
+                if !entry.0.contains(&address) {
+                    entry.0.push(address);
+                }
+            }
+            // added from the least to the most deliberate source
+            entry.1 = source;
+        };
+
+        let now = Instant::now();
+        for peer_id in self.identified_addresses.keys() {
+            let addresses = self.identified_addresses_at(peer_id, now);
+            if !addresses.is_empty() {
+                add(peer_id.clone(), addresses, PeerSource::Identify);
+            }
+        }
+        for enr in self.discovery.enr_entries() {
+            add(
+                enr.peer_id(),
+                enr_tcp_address(enr).into_iter().collect(),
+                PeerSource::Discv5,
+            );
+        }
+        for (peer_id, addresses) in &self.mdns_addresses {
+            add(
+                peer_id.clone(),
+                addresses.iter().cloned().collect(),
+                PeerSource::Mdns,
+            );
+        }
+        for (peer_id, addresses) in &self.boot_node_peers {
+            add(peer_id.clone(), addresses.clone(), PeerSource::BootNode);
+        }
+        for trusted in &self.trusted_peers {
+            if let Some(peer_id) = &trusted.peer_id {
+                add(
+                    peer_id.clone(),
+                    vec![trusted.address.clone()],
+                    PeerSource::Manual,
+                );
+            }
+        }
+
+        let mut known: Vec<(PeerId, Vec<Multiaddr>, PeerSource)> = known
+            .into_iter()
+            .map(|(peer_id, (addresses, source))| (peer_id, addresses, source))
+            .collect();
+        known.sort_by_key(|(peer_id, _addresses, _source)| peer_id.to_base58());
+        known
+    }
+
+    /// Returns the number of peers in each non-empty bucket of the discv5 routing table, by the
+    /// log2 distance of the bucket from us, nearest first.
+    pub fn bucket_occupancy(&mut self) -> Vec<(u32, usize)> {
+        let local_node_id = self.discovery.local_enr().node_id();
+        let mut occupancy: HashMap<u32, usize> = HashMap::new();
+        for enr in self.discovery.enr_entries() {
+            if let Some(distance) = log2_distance(&local_node_id, &enr.node_id()) {
+                *occupancy.entry(distance).or_default() += 1;
+            }
+        }
+        let mut occupancy: Vec<(u32, usize)> = occupancy.into_iter().collect();
+        occupancy.sort();
+        occupancy
+    }
+
+    /// Returns the number of discv5 searches in progress.
+    pub fn pending_queries(&self) -> usize {
+        self.searches_in_progress
+    }
+
+    /// Returns the number of searches for peers on attestation subnets in progress.
+    pub fn pending_subnet_queries(&self) -> usize {
+        self.subnet_queries.len()
+    }
+
+    /// Search for new peers at `now` using the underlying discovery mechanism, scheduling the
+    /// next search.
+    fn find_peers(&mut self, now: Instant) {
+        // pick a random NodeId
+        let random_node = NodeId::random();
+        debug!(self.log, "Searching for peers...");
+        self.start_search(random_node);
+
+        self.search_interval = self.next_search_interval();
+        self.peer_discovery_delay.reset(now + self.search_interval);
+    }
+
+    /// Returns the interval to the next search: shorter while we are short of peers, longer while
+    /// we have all we want, and the configured interval otherwise.
+    fn next_search_interval(&self) -> Duration {
+        let peers = self.connected_peers.len();
+        if peers < self.min_peers {
+            (self.search_interval.min(self.discovery_interval) / 2).max(MIN_SEARCH_INTERVAL)
+        } else if peers >= self.target_peers {
+            (self.search_interval.max(self.discovery_interval) * 2)
+                .min(self.discovery_interval * MAX_SEARCH_INTERVAL_MULTIPLE)
+        } else {
+            self.discovery_interval
+        }
+    }
+}
+
+// Redirect all behaviour events to underlying discovery behaviour.
+impl<TSubstream> NetworkBehaviour for Discovery<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    type ProtocolsHandler = <Discv5<TSubstream> as NetworkBehaviour>::ProtocolsHandler;
+    type OutEvent = DiscoveryEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        NetworkBehaviour::new_handler(&mut self.discovery)
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        if self.is_banned(peer_id) {
+            return Vec::new();
+        }
+        // Let discovery track possible known peers.
+        let mut addresses = self.discovery.addresses_of_peer(peer_id);
+        for address in self.identified_addresses(peer_id) {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        let now = Instant::now();
+        addresses.retain(|address| !self.is_ip_banned(address, now));
+        rank_addresses(addresses, self.ip_preference)
+    }
+
+    fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+        self.dialing.remove(&peer_id);
+        // the peer is reachable after all
+        self.dial_backoffs.remove(&peer_id);
+        if let ConnectedPoint::Dialer { address } = &endpoint {
+            self.boot_node_reached(&peer_id, address);
+        }
+        let trusted = self.trusted_peer_connected(&peer_id, &endpoint);
+
+        if self.is_banned(&peer_id) {
+            debug!(
+                self.log,
+                "Refusing connection from banned peer";
+                "peer" => format!("{:?}", peer_id)
+            );
+            self.refused_peers.push(peer_id);
+            return;
+        }
+
+        let remote_address = match &endpoint {
+            ConnectedPoint::Dialer { address } => address,
+            ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+        };
+        if self.is_ip_banned(remote_address, Instant::now()) && !trusted {
+            debug!(
+                self.log,
+                "Refusing connection from banned address";
+                "peer" => format!("{:?}", peer_id),
+                "address" => format!("{}", remote_address)
+            );
+            self.refused_peers.push(peer_id);
+            return;
+        }
+        // a peer speaking our protocols was reached on the address
+        if let Some(ip) = ip_of(remote_address) {
+            self.ip_dial_failures.remove(&ip);
+        }
+
+        // the rate of inbound connections is limited by the transport, before they are negotiated
+        // trusted peers are kept whatever our limits
+        if let (ConnectedPoint::Listener { .. }, false) = (&endpoint, trusted) {
+            if self.connected_peers.len() >= self.max_peers {
+                debug!(
+                    self.log,
+                    "Refusing inbound connection beyond max peers";
+                    "peer" => format!("{:?}", peer_id),
+                    "connected_peers" => self.connected_peers.len()
+                );
+                self.excess_peers.push(peer_id);
+                return;
+            }
+        }
+
+        // limit the peers from a single subnet, so a single operator cannot eclipse us. Peers on
+        // this host or our local network, such as a testnet or our own nodes, are not limited
+        let limited_subnet = match AddressClass::of(remote_address) {
+            Some(AddressClass::Public) => IpSubnet::of(remote_address),
+            _ => None,
+        };
+        if let Some(subnet) = limited_subnet {
+            let subnet_peers = self.peer_subnets.values().filter(|s| **s == subnet).count();
+            if subnet_peers >= self.max_peers_per_subnet && !trusted {
+                debug!(
+                    self.log,
+                    "Refusing peer from a full subnet";
+                    "peer" => format!("{:?}", peer_id),
+                    "subnet" => format!("{:?}", subnet)
+                );
+                self.refused_peers.push(peer_id);
+                return;
+            }
+            self.peer_subnets.insert(peer_id.clone(), subnet);
+        }
+
+        self.connected_addresses
+            .insert(peer_id.clone(), remote_address.clone());
+        self.peer_cache.seen(peer_id.clone(), SystemTime::now());
+        // the address an inbound peer connected from is not one it listens on
+        if let ConnectedPoint::Dialer { address } = &endpoint {
+            self.peer_cache.add_addresses(&peer_id, &[address.clone()]);
+        }
+        self.connected_peers.insert(peer_id);
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId, _endpoint: ConnectedPoint) {
+        if self.connected_peers.remove(peer_id) {
+            self.peer_cache.seen(peer_id.clone(), SystemTime::now());
+        }
+        self.connected_addresses.remove(peer_id);
+        self.peer_subnets.remove(peer_id);
+        self.redial_trusted_peer(|trusted| {
+            trusted.peer_id.as_ref() == Some(peer_id)
+                && trusted.state == TrustedPeerState::Connected
+        });
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.dialing.remove(peer_id);
+        // counted once every address has failed, so each address of the dial backs off alike
+        if let Some(backoff) = self.dial_backoffs.get_mut(peer_id) {
+            backoff.failures += 1;
+        }
+        self.identified_addresses.remove(peer_id);
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn std::error::Error,
+    ) {
+        let kind = DialErrorKind::classify(error);
+        debug!(
+            self.log,
+            "Failed to dial address";
+            "address" => format!("{}", addr),
+            "peer" => format!("{:?}", peer_id),
+            "error" => format!("{}", error),
+            "kind" => format!("{:?}", kind)
+        );
+        let now = Instant::now();
+        if let Some(peer_id) = peer_id {
+            self.back_off_dial(peer_id.clone(), kind, now);
+        }
+        self.count_ip_failure(addr, kind, now);
+        self.boot_node_unreachable(addr, kind, error);
+        self.redial_trusted_peer(|trusted| {
+            trusted.address == *addr && trusted.state == TrustedPeerState::Dialing
+        });
+        self.events.push_back(DiscoveryEvent::DialFailed {
+            addr: addr.clone(),
+            kind,
+        });
+    }
+
+    fn inject_replaced(
+        &mut self,
+        _peer_id: PeerId,
+        _closed: ConnectedPoint,
+        _opened: ConnectedPoint,
+    ) {
+        // discv5 doesn't implement
+    }
+
+    fn inject_node_event(
+        &mut self,
+        _peer_id: PeerId,
+        _event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        // discv5 doesn't implement
+    }
+
+    fn poll(
+        &mut self,
+        params: &mut impl PollParameters,
+    ) -> Async<
+        NetworkBehaviourAction<
+            <Self::ProtocolsHandler as ProtocolsHandler>::InEvent,
+            Self::OutEvent,
+        >,
+    > {
+        // search for peers if it is time
+        loop {
+            match self.peer_discovery_delay.poll() {
+                // searching while full keeps the routing table fresh, should peers leave
+                Ok(Async::Ready(_)) => self.find_peers(Instant::now()),
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    warn!(self.log, "Discovery peer search failed: {:?}", e);
+                }
+            }
+        }
+
+        // persist the peer cache, and forget expired addresses and backoffs, if it is time
+        loop {
+            match self.peer_cache_save.poll() {
+                Ok(Async::Ready(_)) => {
+                    self.save_peer_cache();
+                    let now = Instant::now();
+                    self.prune_identified_addresses(now);
+                    self.prune_dial_backoffs(now);
+                    self.peer_cache_save
+                        .reset(Instant::now() + PEER_CACHE_SAVE_INTERVAL);
+                }
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    warn!(self.log, "Peer cache timer failed: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        // Poll discovery
+        loop {
+            match self.discovery.poll(params) {
+                Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+                    match event {
+                        Discv5Event::Discovered(enr) => {
+                            // peers are dialed from the result of an entire query, but the
+                            // subnets each record advertises are worth keeping
+                            self.enr_discovered(&enr);
+                        }
+                        Discv5Event::SocketUpdated(socket) => {
+                            info!(self.log, "Address updated"; "IP" => format!("{}",socket.ip()));
+                            // discv5 has updated our ENR to the address peers see us on
+                            if let Some(address) = self.socket_updated(socket.ip()) {
+                                return Async::Ready(NetworkBehaviourAction::ReportObservedAddr {
+                                    address,
+                                });
+                            }
+                        }
+                        Discv5Event::FindNodeResult { closer_peers, .. } => {
+                            self.searches_in_progress = self.searches_in_progress.saturating_sub(1);
+                            debug!(self.log, "Discv5 query found {} peers", closer_peers.len());
+                            if closer_peers.is_empty() {
+                                debug!(self.log, "Discv5 random query yielded empty results");
+                            } else {
+                                self.last_successful_search = Some(Instant::now());
+                            }
+                            self.peers_found(closer_peers);
+                            // keep searching while subnet queries lack peers
+                            if !self.subnet_queries.is_empty() {
+                                self.start_search(NodeId::random());
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                // discv5 does not output any other NetworkBehaviourAction
+                Async::Ready(_) => {}
+                Async::NotReady => break,
+            }
+        }
+
+        // give up on the subnet queries which are due, and wake when the next is
+        self.complete_subnet_queries(Instant::now());
+        if let Some(deadline) = self.subnet_queries.iter().map(|query| query.deadline).min() {
+            self.subnet_query_timeout.reset(deadline);
+            if let Err(e) = self.subnet_query_timeout.poll() {
+                warn!(self.log, "Subnet query timer failed: {:?}", e);
+            }
+        }
+
+        if let Some(event) = self.events.pop_front() {
+            return Async::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+
+        // dial the boot nodes which are due, and wake when the next is
+        if let Some(address) = self.next_boot_node_dial(Instant::now()) {
+            debug!(self.log, "Dialing boot node"; "address" => format!("{}", address));
+            return Async::Ready(NetworkBehaviourAction::DialAddress { address });
+        }
+        if let Some(due) = self.next_boot_node_redial() {
+            self.boot_node_redial.reset(due);
+            if let Err(e) = self.boot_node_redial.poll() {
+                warn!(self.log, "Boot node redial timer failed: {:?}", e);
+            }
+        }
+
+        // dial the trusted peers which are due, and wake when the next is
+        if let Some(address) = self.next_trusted_peer_dial(Instant::now()) {
+            debug!(self.log, "Dialing trusted peer"; "address" => format!("{}", address));
+            return Async::Ready(NetworkBehaviourAction::DialAddress { address });
+        }
+        if let Some(due) = self.next_trusted_peer_redial() {
+            self.trusted_peer_redial.reset(due);
+            if let Err(e) = self.trusted_peer_redial.poll() {
+                warn!(self.log, "Trusted peer redial timer failed: {:?}", e);
+            }
+        }
+
+        // if we need more peers, attempt a connection
+        if let Some(peer_id) = self.next_dial() {
+            debug!(self.log, "Discv5: Dialing discovered peer"; "Peer" => format!("{:?}", peer_id));
+            return Async::Ready(NetworkBehaviourAction::DialPeer { peer_id });
+        }
+        Async::NotReady
+    }
+}
+
+/// Parses an ENR from its base64 text form, with or without the `enr:` prefix other clients
+/// print it with.
+pub fn parse_enr(enr: &str) -> Result<Enr, String> {
+    let trimmed = enr.trim();
+    let base64 = if trimmed.starts_with(ENR_PREFIX) {
+        &trimmed[ENR_PREFIX.len()..]
+    } else {
+        trimmed
+    };
+    Enr::from_str(base64).map_err(|e| format!("Invalid ENR {}: {:?}", enr, e))
+}
+
+/// Loads an ENR from file if it exists and matches the current NodeId and sequence number. If none
+/// exists, generates a new one.
+///
+/// If an ENR exists, with the same NodeId and IP address, we use the disk-generated one as its
+/// ENR sequence will be equal or higher than a newly generated one.
+fn load_enr(
+    local_key: &Keypair,
+    config: &NetworkConfig,
+    log: &slog::Logger,
+) -> Result<Enr, String> {
+    // Build the local ENR.
+    // Note: Discovery should update the ENR record's IP to the external IP as seen by the
+    // majority of our peers.
+    let mut local_enr = EnrBuilder::new()
+        .ip(config.discovery_address.into())
+        .tcp(config.libp2p_port)
+        .udp(config.discovery_port)
+        .build(&local_key)
+        .map_err(|e| format!("Could not build Local ENR: {:?}", e))?;
+
+    let enr_f = config.network_dir.join(ENR_FILENAME);
+    if let Ok(mut enr_file) = File::open(enr_f.clone()) {
+        let mut enr_string = String::new();
+        match enr_file.read_to_string(&mut enr_string) {
+            Err(_) => debug!(log, "Could not read ENR from file"),
+            Ok(_) => {
+                match parse_enr(&enr_string) {
+                    Ok(enr) => {
+                        debug!(log, "ENR found in file: {:?}", enr_f);
+
+                        if enr.node_id() == local_enr.node_id() {
+                            if enr.ip() == config.discovery_address.into()
+                                && enr.tcp() == Some(config.libp2p_port)
+                                && enr.udp() == Some(config.discovery_port)
+                            {
+                                debug!(log, "ENR loaded from file");
+                                // the stored ENR has the same configuration, use it
+                                return Ok(enr);
+                            }
+
+                            // same node id, different configuration - update the sequence number
+                            let new_seq_no = enr.seq().checked_add(1).ok_or_else(|| "ENR sequence number on file is too large. Remove it to generate a new NodeId")?;
+                            local_enr.set_seq(new_seq_no, local_key).map_err(|e| {
+                                format!("Could not update ENR sequence number: {:?}", e)
+                            })?;
+                            debug!(log, "ENR sequence number increased to: {}", new_seq_no);
+                        }
+                    }
+                    Err(e) => {
+                        warn!(log, "ENR from file could not be decoded: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    save_enr(&local_enr, &config.network_dir, log);
+    Ok(local_enr)
+}
+
+/// Returns the attestation subnets advertised in `enr`, if it has a valid `attnets` field.
+pub(crate) fn enr_attnets(enr: &Enr) -> Option<Bitfield> {
+    enr.get(ATTNETS_ENR_KEY)
+        .and_then(|bytes| Bitfield::from_ssz_bytes(bytes).ok())
+}
+
+/// Writes `enr` to disk in `network_dir`, from where `load_enr` reads it.
+fn save_enr(enr: &Enr, network_dir: &Path, log: &slog::Logger) {
+    let enr_f = network_dir.join(ENR_FILENAME);
+    let _ = std::fs::create_dir_all(network_dir);
+    match File::create(enr_f.clone()).and_then(|mut f| f.write_all(&enr.to_base64().as_bytes())) {
+        Ok(_) => {
+            debug!(log, "ENR written to disk");
+        }
+        Err(e) => {
+            warn!(
+                log,
+                "Could not write ENR to file: {:?}. Error: {}", enr_f, e
+            );
+        }
+    }
+}
+
+/// Events emitted by the discovery behaviour.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiscoveryEvent {
+    /// A search found a peer, which is queued to be dialed.
+    PeerDiscovered(PeerId),
+    /// Dialing an address failed.
+    DialFailed {
+        addr: Multiaddr,
+        kind: DialErrorKind,
+    },
+    /// A subnet query found the given number of peers on the attestation subnet, enough.
+    SubnetPeersFound(u64, usize),
+    /// A subnet query gave up, having found the given number of peers on the attestation subnet,
+    /// too few.
+    SubnetQueryTimedOut(u64, usize),
+}
+
+/// A search for peers on an attestation subnet, made by `discover_subnet_peers`.
+struct SubnetQuery {
+    subnet_id: u64,
+    /// The number of peers on the subnet sought.
+    min_peers: usize,
+    /// The peers found on the subnet.
+    found: HashSet<PeerId>,
+    /// The time the query gives up at.
+    deadline: Instant,
+}
+
+/// Why dialing an address failed, classified from the error the transport gave.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DialErrorKind {
+    /// The address did not answer in time.
+    Timeout,
+    /// The host answered, but nothing listens on the address.
+    Refused,
+    /// There is no route to the address.
+    Unreachable,
+    /// A connection was made, but the node does not speak our protocols.
+    ProtocolMismatch,
+    /// Any other error.
+    Other,
+}
+
+impl DialErrorKind {
+    /// Classifies `error` by the first `io::Error` in its chain of sources, falling back on the
+    /// descriptions of the chain. Errors not exposing their cause can only be told apart by the
+    /// latter.
+    pub fn classify(error: &dyn std::error::Error) -> Self {
+        let mut descriptions = error.to_string().to_lowercase();
+        let mut source = error.source();
+        while let Some(cause) = source {
+            if let Some(kind) = cause
+                .downcast_ref::<io::Error>()
+                .and_then(|e| Self::of_io_error_kind(e.kind()))
+            {
+                return kind;
+            }
+            descriptions.push_str(&cause.to_string().to_lowercase());
+            source = cause.source();
+        }
+
+        if descriptions.contains("timed out") || descriptions.contains("timeout") {
+            DialErrorKind::Timeout
+        } else if descriptions.contains("refused") {
+            DialErrorKind::Refused
+        } else if descriptions.contains("unreachable") || descriptions.contains("no route") {
+            DialErrorKind::Unreachable
+        } else if descriptions.contains("negotiat") || descriptions.contains("handshake") {
+            DialErrorKind::ProtocolMismatch
+        } else {
+            DialErrorKind::Other
+        }
+    }
+
+    fn of_io_error_kind(kind: io::ErrorKind) -> Option<Self> {
+        match kind {
+            io::ErrorKind::TimedOut => Some(DialErrorKind::Timeout),
+            io::ErrorKind::ConnectionRefused => Some(DialErrorKind::Refused),
+            io::ErrorKind::AddrNotAvailable => Some(DialErrorKind::Unreachable),
+            io::ErrorKind::InvalidData => Some(DialErrorKind::ProtocolMismatch),
+            _ => None,
+        }
+    }
+
+    /// How long a peer is not redialed after a dial fails with this kind of error. A timeout may
+    /// be passing congestion, while a node that does not speak our protocols will not learn to.
+    pub fn redial_delay(self) -> Duration {
+        match self {
+            DialErrorKind::Timeout | DialErrorKind::Other => Duration::from_secs(30),
+            DialErrorKind::Refused => Duration::from_secs(60),
+            DialErrorKind::Unreachable => Duration::from_secs(300),
+            DialErrorKind::ProtocolMismatch => Duration::from_secs(3600),
+        }
+    }
+
+    /// How long a peer is not redialed after a dial fails with this kind of error, following
+    /// `failures` consecutive failed dials. The `redial_delay` doubles with each failure, up to
+    /// `MAX_DIAL_BACKOFF`.
+    pub fn redial_backoff(self, failures: u32) -> Duration {
+        // beyond 2^12 every delay has reached the cap
+        self.redial_delay()
+            .checked_mul(1 << failures.min(12))
+            .map_or(MAX_DIAL_BACKOFF, |delay| delay.min(MAX_DIAL_BACKOFF))
+    }
+}
+
+/// The failed dials of a peer since it last connected.
+struct DialBackoff {
+    /// The number of consecutive dials of the peer which have failed.
+    failures: u32,
+    /// The time before which the peer is not dialed again.
+    until: Instant,
+}
+
+/// An address a peer has told us it listens on.
+struct IdentifiedAddress {
+    address: Multiaddr,
+    /// The last time the peer told us of the address.
+    confirmed_at: Instant,
+}
+
+/// A boot node dialed by address.
+struct BootNode {
+    /// The address the node is dialed on, without any peer id.
+    address: Multiaddr,
+    /// The seconds to wait before redialing the node should the next dial fail.
+    backoff: u64,
+    state: BootNodeState,
+}
+
+impl BootNode {
+    /// A boot node on `address`, due to be dialed at `due`.
+    fn new(address: &Multiaddr, due: Instant) -> Self {
+        BootNode {
+            address: without_peer_id(address),
+            backoff: INITIAL_BOOT_NODE_BACKOFF,
+            state: BootNodeState::Waiting(due),
+        }
+    }
+}
+
+/// A peer kept connected by address.
+struct TrustedPeer {
+    /// The address the peer is dialed on, without any peer id.
+    address: Multiaddr,
+    /// The id of the peer, once known from the address or from connecting to it.
+    peer_id: Option<PeerId>,
+    /// The seconds to wait before redialing the peer should it disconnect or the next dial fail.
+    backoff: u64,
+    state: TrustedPeerState,
+}
+
+impl TrustedPeer {
+    /// A trusted peer on `address`, due to be dialed at `due`.
+    fn new(address: &Multiaddr, due: Instant) -> Self {
+        TrustedPeer {
+            address: without_peer_id(address),
+            peer_id: peer_id_of(address),
+            backoff: INITIAL_TRUSTED_PEER_BACKOFF,
+            state: TrustedPeerState::Waiting(due),
+        }
+    }
+}
+
+/// Our connection to a trusted peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TrustedPeerState {
+    /// Waiting to be dialed at the given time.
+    Waiting(Instant),
+    /// Dialed, without having connected or failed yet.
+    Dialing,
+    /// Connected, to be redialed once disconnected.
+    Connected,
+}
+
+/// Our progress in reaching a boot node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BootNodeState {
+    /// Waiting to be dialed at the given time.
+    Waiting(Instant),
+    /// Dialed, without having connected or failed yet.
+    Dialing,
+    /// Connected to once, after which it is not dialed again.
+    Reached,
+}
+
+/// Returns `address` without any peer id. The transports dial the address alone, the peer id is
+/// learnt on connecting.
+fn without_peer_id(address: &Multiaddr) -> Multiaddr {
+    address
+        .iter()
+        .filter(|protocol| match protocol {
+            Protocol::P2p(_) => false,
+            _ => true,
+        })
+        .collect()
+}
+
+/// Returns the peer id `address` ends in, if any.
+fn peer_id_of(address: &Multiaddr) -> Option<PeerId> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    })
+}
+
+/// Returns the TCP address libp2p dials the node of `enr` on, if the ENR advertises one.
+fn enr_tcp_address(enr: &Enr) -> Option<Multiaddr> {
+    let mut address = Multiaddr::from(enr.ip()?);
+    address.push(Protocol::Tcp(enr.tcp()?));
+    Some(address)
+}
+
+/// Returns the log2 distance between two node ids, by which discv5 places nodes in the buckets
+/// of its routing table, or `None` if they are the same.
+fn log2_distance(a: &NodeId, b: &NodeId) -> Option<u32> {
+    let (a, b) = (a.raw(), b.raw());
+    a.iter()
+        .zip(b.iter())
+        .enumerate()
+        .find(|(_i, (a, b))| a != b)
+        .map(|(i, (a, b))| 256 - (i as u32 * 8 + (a ^ b).leading_zeros()))
+}
+
+/// Returns `true` if a block ending at `until`, or never if `None`, is in place at `now`.
+fn is_blocked_at(until: Option<&Option<Instant>>, now: Instant) -> bool {
+    match until {
+        Some(Some(until)) => *until > now,
+        Some(None) => true,
+        None => false,
+    }
+}
+
+/// Orders `addresses` so those of the preferred IP version are dialed first. Addresses of the
+/// other version are kept as a fallback, and the order within each version is preserved.
+fn rank_addresses(mut addresses: Vec<Multiaddr>, preference: IpPreference) -> Vec<Multiaddr> {
+    let rank =
+        |address: &Multiaddr| match (address.iter().next(), preference) {
+            (Some(Protocol::Ip4(_)), IpPreference::V4)
+            | (Some(Protocol::Ip6(_)), IpPreference::V6) => 0,
+            (Some(Protocol::Ip4(_)), IpPreference::V6)
+            | (Some(Protocol::Ip6(_)), IpPreference::V4) => 1,
+            _ => 0,
+        };
+    // the sort is stable, so with no preference the discovered order is kept
+    addresses.sort_by_key(rank);
+    addresses
+}
+
+/// How discovery came to know of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    /// A configured boot node.
+    BootNode,
+    /// Found by a discv5 search, or otherwise held in the discv5 routing table.
+    Discv5,
+    /// Found on the local network by mDNS.
+    Mdns,
+    /// Told us its addresses itself, through the identify protocol.
+    Identify,
+    /// Added by hand, as a trusted peer.
+    Manual,
+}
+
+/// How widely an IP address can be reached, by which addresses only peers on the same host or
+/// network could dial are kept out of discovery and our ENR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressClass {
+    /// An address of this host, such as `127.0.0.1` or `::1`.
+    Loopback,
+    /// An address of a private network, such as `10.0.0.0/8` or an IPv6 unique local address.
+    Private,
+    /// An address valid only on the local link, such as `169.254.0.0/16` or `fe80::/10`.
+    LinkLocal,
+    /// Any other address, assumed reachable from anywhere.
+    Public,
+}
+
+impl AddressClass {
+    /// Classifies an IP address.
+    pub fn of_ip(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) if ip.is_loopback() => AddressClass::Loopback,
+            IpAddr::V4(ip) if ip.is_private() => AddressClass::Private,
+            IpAddr::V4(ip) if ip.is_link_local() => AddressClass::LinkLocal,
+            IpAddr::V6(ip) if ip.is_loopback() => AddressClass::Loopback,
+            // fc00::/7
+            IpAddr::V6(ip) if ip.segments()[0] & 0xfe00 == 0xfc00 => AddressClass::Private,
+            // fe80::/10
+            IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => AddressClass::LinkLocal,
+            _ => AddressClass::Public,
+        }
+    }
+
+    /// Classifies the IP address `address` starts with, or returns `None` if it has none.
+    pub fn of(address: &Multiaddr) -> Option<Self> {
+        match address.iter().next()? {
+            Protocol::Ip4(ip) => Some(AddressClass::of_ip(ip.into())),
+            Protocol::Ip6(ip) => Some(AddressClass::of_ip(ip.into())),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the IP address `address` starts with, if any.
+fn ip_of(address: &Multiaddr) -> Option<IpAddr> {
+    match address.iter().next()? {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+/// The subnet a peer connects from: the /24 of an IPv4 address or the /64 of an IPv6 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum IpSubnet {
+    V4([u8; 3]),
+    V6([u16; 4]),
+}
+
+impl IpSubnet {
+    /// Returns the subnet of the IP address `address` starts with, if any.
+    fn of(address: &Multiaddr) -> Option<Self> {
+        match address.iter().next()? {
+            Protocol::Ip4(ip) => {
+                let octets = ip.octets();
+                Some(IpSubnet::V4([octets[0], octets[1], octets[2]]))
+            }
+            Protocol::Ip6(ip) => {
+                let segments = ip.segments();
+                Some(IpSubnet::V6([
+                    segments[0],
+                    segments[1],
+                    segments[2],
+                    segments[3],
+                ]))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libp2p::core::{muxing::StreamMuxerBox, nodes::Substream};
+    use std::ops::{Deref, DerefMut};
+    use tempfile::{tempdir, TempDir};
+
+    /// A discovery behaviour built by a test, with the directory it stores its network files in,
+    /// which is removed as the behaviour is dropped.
+    struct TestDiscovery {
+        discovery: Discovery<Substream<StreamMuxerBox>>,
+        _network_dir: TempDir,
+    }
+
+    impl Deref for TestDiscovery {
+        type Target = Discovery<Substream<StreamMuxerBox>>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.discovery
+        }
+    }
+
+    impl DerefMut for TestDiscovery {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.discovery
+        }
+    }
+
+    /// Stores the network files of `config` in a new temporary directory, returned to be kept
+    /// for the lifetime of the test, and listens for discovery on an ephemeral port.
+    fn use_temp_network_dir(config: &mut NetworkConfig) -> TempDir {
+        let network_dir = tempdir().expect("should create network directory");
+        config.network_dir = network_dir.path().to_path_buf();
+        config.discovery_port = 0;
+        network_dir
+    }
+
+    fn build_discovery(max_concurrent_dials: usize) -> TestDiscovery {
+        let mut config = NetworkConfig::default();
+        config.max_concurrent_dials = max_concurrent_dials;
+        build_discovery_with_config(config)
+    }
+
+    fn build_discovery_with_config(mut config: NetworkConfig) -> TestDiscovery {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let local_key = Keypair::generate_secp256k1();
+        let network_dir = use_temp_network_dir(&mut config);
+        TestDiscovery {
+            discovery: Discovery::new(&local_key, &config, &log).expect("should build discovery"),
+            _network_dir: network_dir,
+        }
+    }
+
+    fn dialer() -> ConnectedPoint {
+        ConnectedPoint::Dialer {
+            address: Multiaddr::empty(),
+        }
+    }
+
+    fn listener(remote_address: &str) -> ConnectedPoint {
+        ConnectedPoint::Listener {
+            listen_addr: "/ip4/0.0.0.0/tcp/9000".parse().expect("valid address"),
+            send_back_addr: remote_address.parse().expect("valid address"),
+        }
+    }
+
+    /// Connects a new peer from `remote_address`, returning its id.
+    fn connect_from(
+        discovery: &mut Discovery<Substream<StreamMuxerBox>>,
+        remote_address: &str,
+    ) -> PeerId {
+        let peer_id = PeerId::random();
+        discovery.inject_connected(peer_id.clone(), listener(remote_address));
+        peer_id
+    }
+
+    #[test]
+    fn dials_beyond_cap_are_queued() {
+        let mut discovery = build_discovery(2);
+        let peers: Vec<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        for peer_id in &peers {
+            discovery.queue_dial(peer_id.clone());
+        }
+
+        // only the cap is dialed at once
+        assert_eq!(discovery.next_dial(), Some(peers[0].clone()));
+        assert_eq!(discovery.next_dial(), Some(peers[1].clone()));
+        assert_eq!(discovery.next_dial(), None);
+
+        // each resolved dial makes room for the next
+        discovery.inject_dial_failure(&peers[0]);
+        assert_eq!(discovery.next_dial(), Some(peers[2].clone()));
+        assert_eq!(discovery.next_dial(), None);
+
+        discovery.inject_connected(peers[1].clone(), dialer());
+        assert_eq!(discovery.next_dial(), Some(peers[3].clone()));
+        assert_eq!(discovery.next_dial(), None);
+    }
+
+    #[test]
+    fn unreachable_boot_nodes_redialed_with_backoff() {
+        let mut config = NetworkConfig::default();
+        let address: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        let mut with_peer_id = address.clone();
+        with_peer_id.push(Protocol::P2p(PeerId::random().into()));
+        config.libp2p_nodes = vec![with_peer_id];
+        let mut discovery = build_discovery_with_config(config);
+
+        // boot nodes are dialed on their address alone, straight away
+        let now = Instant::now();
+        assert_eq!(discovery.next_boot_node_dial(now), Some(address.clone()));
+        assert_eq!(discovery.next_boot_node_dial(now), None);
+
+        // each failure doubles the wait before the next dial
+        let error = std::io::Error::new(std::io::ErrorKind::Other, "unreachable");
+        for backoff in &[5, 10, 20] {
+            discovery.boot_node_unreachable(&address, DialErrorKind::Unreachable, &error);
+            let due = discovery
+                .next_boot_node_redial()
+                .expect("redial is scheduled");
+            assert!(due >= now + Duration::from_secs(*backoff));
+            assert_eq!(discovery.next_boot_node_dial(now), None);
+            assert_eq!(discovery.next_boot_node_dial(due), Some(address.clone()));
+        }
+
+        // the first boot node reached starts the search for peers, and is not dialed again
+        assert!(!discovery.bootstrapped);
+        discovery.inject_connected(
+            PeerId::random(),
+            ConnectedPoint::Dialer {
+                address: address.clone(),
+            },
+        );
+        assert!(discovery.bootstrapped);
+        assert_eq!(discovery.next_boot_node_redial(), None);
+        let later = now + Duration::from_secs(MAX_BOOT_NODE_BACKOFF * 2);
+        assert_eq!(discovery.next_boot_node_dial(later), None);
+    }
+
+    /// An error wrapping the error that caused it, as transport errors do.
+    #[derive(Debug)]
+    struct Wrapped(&'static str, io::Error);
+
+    impl std::fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl std::error::Error for Wrapped {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.1)
+        }
+    }
+
+    #[test]
+    fn dial_errors_classified() {
+        let io_error = |kind, description| io::Error::new(kind, description);
+        let classify = |error: &dyn std::error::Error| DialErrorKind::classify(error);
+
+        // the kind of a wrapped io error decides, whatever the descriptions say
+        let refused = Wrapped(
+            "dial failed",
+            io_error(io::ErrorKind::ConnectionRefused, "x"),
+        );
+        assert_eq!(classify(&refused), DialErrorKind::Refused);
+        let timeout = Wrapped("refused", io_error(io::ErrorKind::TimedOut, "x"));
+        assert_eq!(classify(&timeout), DialErrorKind::Timeout);
+        let garbled = Wrapped("dial failed", io_error(io::ErrorKind::InvalidData, "x"));
+        assert_eq!(classify(&garbled), DialErrorKind::ProtocolMismatch);
+
+        // otherwise the descriptions are searched
+        let unreachable = io_error(
+            io::ErrorKind::Other,
+            "Network is unreachable (os error 101)",
+        );
+        assert_eq!(classify(&unreachable), DialErrorKind::Unreachable);
+        let mismatch = Wrapped(
+            "upgrade failed",
+            io_error(io::ErrorKind::Other, "Protocol negotiation failed"),
+        );
+        assert_eq!(classify(&mismatch), DialErrorKind::ProtocolMismatch);
+        let other = io_error(io::ErrorKind::Other, "something else");
+        assert_eq!(classify(&other), DialErrorKind::Other);
+    }
+
+    #[test]
+    fn dial_failures_delay_redial_by_kind() {
+        let mut discovery = build_discovery(1);
+        let address: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        let (slow, foreign) = (PeerId::random(), PeerId::random());
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let mismatched = io::Error::new(io::ErrorKind::Other, "Protocol negotiation failed");
+
+        let now = Instant::now();
+        discovery.inject_addr_reach_failure(Some(&slow), &address, &timed_out);
+        discovery.inject_addr_reach_failure(Some(&foreign), &address, &mismatched);
+        assert_eq!(
+            discovery.events.drain(..).collect::<Vec<_>>(),
+            vec![
+                DiscoveryEvent::DialFailed {
+                    addr: address.clone(),
+                    kind: DialErrorKind::Timeout
+                },
+                DiscoveryEvent::DialFailed {
+                    addr: address.clone(),
+                    kind: DialErrorKind::ProtocolMismatch
+                },
+            ]
+        );
+
+        let blocked_until = |discovery: &Discovery<Substream<StreamMuxerBox>>, peer_id: &PeerId| {
+            discovery.dial_backoffs[peer_id].until
+        };
+        assert!(blocked_until(&discovery, &slow) < now + Duration::from_secs(60));
+        assert!(blocked_until(&discovery, &foreign) >= now + Duration::from_secs(3600));
+
+        // a later, shorter delay does not cut an earlier one short, nor lift a ban
+        discovery.inject_addr_reach_failure(Some(&foreign), &address, &timed_out);
+        assert!(blocked_until(&discovery, &foreign) >= now + Duration::from_secs(3600));
+        discovery.ban_peer(slow.clone(), None);
+        discovery.inject_addr_reach_failure(Some(&slow), &address, &timed_out);
+        assert_eq!(discovery.dial_blocked_until[&slow], None);
+    }
+
+    #[test]
+    fn protocol_mismatches_ban_address() {
+        let mut discovery = build_discovery(1);
+        let address: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        let local: Multiaddr = "/ip4/192.168.0.2/tcp/9000".parse().expect("valid address");
+        let peer_id = PeerId::random();
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let mismatched = io::Error::new(io::ErrorKind::Other, "Protocol negotiation failed");
+
+        // only protocol mismatches of public addresses count towards a ban
+        for _ in 0..IP_BAN_THRESHOLD {
+            discovery.inject_addr_reach_failure(Some(&peer_id), &address, &timed_out);
+            discovery.inject_addr_reach_failure(Some(&peer_id), &local, &mismatched);
+        }
+        let now = Instant::now();
+        assert!(!discovery.is_ip_banned(&address, now));
+        assert!(!discovery.is_ip_banned(&local, now));
+
+        for _ in 0..IP_BAN_THRESHOLD {
+            discovery.inject_addr_reach_failure(None, &address, &mismatched);
+        }
+        let now = Instant::now();
+        assert!(discovery.is_ip_banned(&address, now));
+        assert!(!discovery.is_ip_banned(&address, now + IP_BAN_DURATION));
+
+        // other peers on the address are refused
+        let other = connect_from(&mut discovery, "/ip4/1.2.3.4/tcp/40000");
+        assert_eq!(discovery.refused_peers, vec![other]);
+
+        // the ban ends
+        discovery.take_expired_bans(now + IP_BAN_DURATION);
+        assert!(discovery.banned_ips.is_empty());
+    }
+
+    #[test]
+    fn dial_backoff_doubles_until_connected() {
+        let mut discovery = build_discovery(1);
+        let address: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        let peer_id = PeerId::random();
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+
+        // each failed dial doubles the delay, whatever the number of addresses failing in it
+        for failures in 0..4 {
+            let before = Instant::now();
+            discovery.inject_addr_reach_failure(Some(&peer_id), &address, &timed_out);
+            discovery.inject_addr_reach_failure(Some(&peer_id), &address, &timed_out);
+            discovery.inject_dial_failure(&peer_id);
+            let delay = Duration::from_secs(30 << failures);
+            let until = discovery.dial_backoffs[&peer_id].until;
+            assert!(until >= before + delay);
+            assert!(until <= Instant::now() + delay);
+            assert_eq!(discovery.dial_failures(&peer_id), failures + 1);
+            assert!(discovery.is_dial_blocked(&peer_id));
+        }
+        assert_eq!(DialErrorKind::Timeout.redial_backoff(100), MAX_DIAL_BACKOFF);
+
+        // connecting clears the backoff
+        let endpoint = ConnectedPoint::Dialer { address };
+        discovery.inject_connected(peer_id.clone(), endpoint);
+        assert_eq!(discovery.dial_failures(&peer_id), 0);
+        assert_eq!(discovery.redial_at(&peer_id), None);
+        assert!(!discovery.is_dial_blocked(&peer_id));
+    }
+
+    #[test]
+    fn elapsed_dial_backoffs_forgotten() {
+        let mut discovery = build_discovery(1);
+        let address: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        let (failed, refused) = (PeerId::random(), PeerId::random());
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let refusal = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        discovery.inject_addr_reach_failure(Some(&failed), &address, &timed_out);
+        discovery.inject_addr_reach_failure(Some(&refused), &address, &refusal);
+        let failed_until = discovery.dial_backoffs[&failed].until;
+        let refused_until = discovery.dial_backoffs[&refused].until;
+        assert!(failed_until < refused_until);
+
+        // a backoff is kept a while after its delay, so a further failure doubles it
+        discovery.prune_dial_backoffs(failed_until);
+        assert_eq!(discovery.dial_backoffs.len(), 2);
+        discovery.prune_dial_backoffs(failed_until + MAX_DIAL_BACKOFF);
+        assert!(!discovery.dial_backoffs.contains_key(&failed));
+        assert!(discovery.dial_backoffs.contains_key(&refused));
+        discovery.prune_dial_backoffs(refused_until + MAX_DIAL_BACKOFF);
+        assert!(discovery.dial_backoffs.is_empty());
+    }
+
+    #[test]
+    fn goodbyes_and_ended_bans_cool_down_dials() {
+        let mut discovery = build_discovery(1);
+        let (departed, banned) = (PeerId::random(), PeerId::random());
+
+        let now = Instant::now();
+        discovery.cool_down(departed.clone());
+        assert!(discovery.is_dial_blocked(&departed));
+        assert!(discovery.redial_at(&departed).expect("cooling down") >= now + DIAL_COOLDOWN);
+
+        // the cooldown follows the end of the ban
+        discovery.ban_peer(banned.clone(), Some(now));
+        assert_eq!(discovery.take_expired_bans(now), vec![banned.clone()]);
+        assert!(!discovery.is_banned(&banned));
+        assert!(discovery.is_dial_blocked(&banned));
+        assert_eq!(discovery.redial_at(&banned), Some(now + DIAL_COOLDOWN));
+        discovery.queue_dial(banned.clone());
+        assert!(!discovery.is_dial_pending(&banned));
+    }
+
+    #[test]
+    fn attnets_advertised_in_enr() {
+        let mut discovery = build_discovery(1);
+        let attnets = |subnets: &[usize]| {
+            let mut attnets = Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT);
+            for subnet in subnets {
+                attnets.set(*subnet, true);
+            }
+            attnets
+        };
+
+        // our record starts out advertising no subnets
+        let seq = discovery.local_enr().seq();
+        assert_eq!(enr_attnets(discovery.local_enr()), Some(attnets(&[])));
+
+        // each change bumps the sequence number, re-applying the same subnets does not
+        discovery
+            .update_attnets(&attnets(&[3, 5]))
+            .expect("updates");
+        assert_eq!(discovery.local_enr().seq(), seq + 1);
+        assert_eq!(enr_attnets(discovery.local_enr()), Some(attnets(&[3, 5])));
+        discovery
+            .update_attnets(&attnets(&[3, 5]))
+            .expect("updates");
+        assert_eq!(discovery.local_enr().seq(), seq + 1);
+        discovery.update_attnets(&attnets(&[5])).expect("updates");
+        assert_eq!(discovery.local_enr().seq(), seq + 2);
+        assert_eq!(enr_attnets(discovery.local_enr()), Some(attnets(&[5])));
+
+        // the subnets of a remote record are parsed out of it
+        let remote_key = Keypair::generate_secp256k1();
+        let mut remote = EnrBuilder::new()
+            .ip("10.0.0.1".parse().expect("valid IP"))
+            .udp(9000)
+            .build(&remote_key)
+            .expect("builds");
+        discovery.enr_discovered(&remote);
+        assert!(discovery.peers_for_subnet(1).is_empty());
+
+        remote
+            .insert(
+                ATTNETS_ENR_KEY,
+                attnets(&[1, 2]).as_ssz_bytes(),
+                &remote_key,
+            )
+            .expect("inserts");
+        discovery.enr_discovered(&remote);
+        assert_eq!(discovery.peers_for_subnet(1), vec![remote.peer_id()]);
+        assert_eq!(discovery.peers_for_subnet(2), vec![remote.peer_id()]);
+        assert!(discovery.peers_for_subnet(5).is_empty());
+
+        // a newer record replaces the subnets of the older
+        remote
+            .insert(ATTNETS_ENR_KEY, attnets(&[2]).as_ssz_bytes(), &remote_key)
+            .expect("inserts");
+        discovery.enr_discovered(&remote);
+        assert!(discovery.peers_for_subnet(1).is_empty());
+        assert_eq!(discovery.peers_for_subnet(2), vec![remote.peer_id()]);
+    }
+
+    #[test]
+    fn cached_peers_dialed_before_boot_nodes() {
+        let dir = tempdir().expect("should create directory");
+        let dir = dir.path();
+        let log = slog::Logger::root(slog::Discard, o!());
+        let mut cache = PeerCache::new();
+        let now = SystemTime::now();
+        let (fresh, stale) = (PeerId::random(), PeerId::random());
+        let address: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        cache.seen(fresh.clone(), now);
+        cache.seen(stale.clone(), now - Duration::from_secs(2 * 60 * 60));
+        for peer_id in &[&fresh, &stale] {
+            cache.add_addresses(peer_id, &[address.clone()]);
+        }
+        cache.save(&dir.join("peers"), &log);
+
+        let mut config = NetworkConfig::default();
+        config.peer_cache_file = Some(dir.join("peers"));
+        config.peer_cache_max_age = Duration::from_secs(60 * 60);
+        let boot_node: Multiaddr = "/ip4/5.6.7.8/tcp/9000".parse().expect("valid address");
+        config.libp2p_nodes = vec![boot_node.clone()];
+        let mut discovery = build_discovery_with_config(config);
+
+        assert!(discovery.is_dial_pending(&fresh));
+        assert!(!discovery.is_dial_pending(&stale));
+        assert!(discovery.has_addresses(&fresh));
+
+        // the boot nodes wait while the cached peers are dialed
+        let started = Instant::now();
+        assert_eq!(discovery.next_boot_node_dial(started), None);
+        assert_eq!(
+            discovery.next_boot_node_dial(started + CACHED_PEER_GRACE),
+            Some(boot_node)
+        );
+    }
+
+    #[test]
+    fn stale_peers_pruned_on_save() {
+        let dir = tempdir().expect("should create directory");
+        let mut config = NetworkConfig::default();
+        config.peer_cache_file = Some(dir.path().join("peers"));
+        config.peer_cache_max_age = Duration::from_secs(60 * 60);
+        let mut discovery = build_discovery_with_config(config);
+        let now = SystemTime::now();
+        let (fresh, stale) = (PeerId::random(), PeerId::random());
+        let address: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        discovery.peer_cache.seen(fresh.clone(), now);
+        discovery
+            .peer_cache
+            .seen(stale.clone(), now - Duration::from_secs(2 * 60 * 60));
+        for peer_id in &[&fresh, &stale] {
+            discovery
+                .peer_cache
+                .add_addresses(peer_id, &[address.clone()]);
+        }
+
+        discovery.save_peer_cache();
+        assert_eq!(discovery.peer_cache.len(), 1);
+        assert_eq!(discovery.peer_cache.freshest(2)[0].0, fresh);
+    }
+
+    /// Returns an ENR of a fresh node advertising the attestation subnets `subnets`.
+    fn subnet_enr(subnets: &[usize]) -> Enr {
+        let key = Keypair::generate_secp256k1();
+        let mut attnets = Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT);
+        for subnet in subnets {
+            attnets.set(*subnet, true);
+        }
+        let mut enr = EnrBuilder::new()
+            .ip("10.0.0.1".parse().expect("valid IP"))
+            .udp(9000)
+            .build(&key)
+            .expect("builds");
+        enr.insert(ATTNETS_ENR_KEY, attnets.as_ssz_bytes(), &key)
+            .expect("inserts");
+        enr
+    }
+
+    #[test]
+    fn known_peers_reported_by_source() {
+        let tcp_enr = |ip: &str| {
+            EnrBuilder::new()
+                .ip(ip.parse().expect("valid IP"))
+                .tcp(9000)
+                .udp(9000)
+                .build(&Keypair::generate_secp256k1())
+                .expect("builds")
+        };
+        let with_peer_id = |address: &str, peer_id: &PeerId| {
+            let mut address: Multiaddr = address.parse().expect("valid address");
+            address.push(Protocol::P2p(peer_id.clone().into()));
+            address
+        };
+        let address = |address: &str| -> Multiaddr { address.parse().expect("valid address") };
+        let (boot_node, identified, local, manual) = (
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+        );
+        let boot_enr = tcp_enr("1.1.1.1");
+        let found_enr = tcp_enr("2.2.2.2");
+
+        let mut config = NetworkConfig::default();
+        config.boot_nodes = vec![boot_enr.clone()];
+        config.libp2p_nodes = vec![with_peer_id("/ip4/3.3.3.3/tcp/9000", &boot_node)];
+        let mut discovery = build_discovery_with_config(config);
+        discovery.add_enr(found_enr.clone());
+        discovery
+            .add_identified_addresses(identified.clone(), vec![address("/ip4/4.4.4.4/tcp/9000")]);
+        discovery.add_mdns_address(local.clone(), address("/ip4/192.168.0.2/tcp/9000"));
+        discovery.add_trusted_peer(with_peer_id("/ip4/5.5.5.5/tcp/9000", &manual));
+        // the more deliberate source is reported, with the addresses of both
+        discovery.add_identified_addresses(manual.clone(), vec![address("/ip4/6.6.6.6/tcp/9000")]);
+
+        let known: HashMap<PeerId, (Vec<Multiaddr>, PeerSource)> = discovery
+            .known_peers()
+            .into_iter()
+            .map(|(peer_id, addresses, source)| (peer_id, (addresses, source)))
+            .collect();
+        let expected: HashMap<PeerId, (Vec<Multiaddr>, PeerSource)> = vec![
+            (
+                boot_enr.peer_id(),
+                (vec![address("/ip4/1.1.1.1/tcp/9000")], PeerSource::BootNode),
+            ),
+            (
+                found_enr.peer_id(),
+                (vec![address("/ip4/2.2.2.2/tcp/9000")], PeerSource::Discv5),
+            ),
+            (
+                boot_node,
+                (vec![address("/ip4/3.3.3.3/tcp/9000")], PeerSource::BootNode),
+            ),
+            (
+                identified,
+                (vec![address("/ip4/4.4.4.4/tcp/9000")], PeerSource::Identify),
+            ),
+            (
+                local,
+                (vec![address("/ip4/192.168.0.2/tcp/9000")], PeerSource::Mdns),
+            ),
+            (
+                manual,
+                (
+                    vec![
+                        address("/ip4/6.6.6.6/tcp/9000"),
+                        address("/ip4/5.5.5.5/tcp/9000"),
+                    ],
+                    PeerSource::Manual,
+                ),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(known, expected);
+
+        // both ENRs are in the routing table
+        let occupancy = discovery.bucket_occupancy();
+        assert_eq!(
+            occupancy
+                .iter()
+                .map(|(_distance, peers)| peers)
+                .sum::<usize>(),
+            2
+        );
+        assert!(occupancy.iter().all(|(distance, _peers)| *distance <= 256));
+
+        assert_eq!(discovery.pending_queries(), 0);
+        discovery.discover_subnet_peers(3, 1);
+        assert_eq!(discovery.pending_queries(), 1);
+        assert_eq!(discovery.pending_subnet_queries(), 1);
+    }
+
+    #[test]
+    fn subnet_query_dials_matching_peers() {
+        let mut discovery = build_discovery(1);
+        let known = subnet_enr(&[17]);
+        discovery.enr_discovered(&known);
+
+        discovery.discover_subnet_peers(17, 3);
+        assert!(discovery.is_dial_pending(&known.peer_id()));
+
+        // peers off the subnet are neither counted nor dialed
+        let (elsewhere, unsubscribed) = (subnet_enr(&[3, 16, 18]), subnet_enr(&[]));
+        discovery.enr_discovered(&elsewhere);
+        discovery.enr_discovered(&unsubscribed);
+        assert!(!discovery.is_dial_pending(&elsewhere.peer_id()));
+        assert!(!discovery.is_dial_pending(&unsubscribed.peer_id()));
+
+        // rediscovering a peer does not count it twice
+        let matching = subnet_enr(&[2, 17]);
+        discovery.enr_discovered(&matching);
+        discovery.enr_discovered(&matching);
+        assert!(discovery.is_dial_pending(&matching.peer_id()));
+        assert!(discovery.events.is_empty());
+
+        discovery.enr_discovered(&subnet_enr(&[17]));
+        assert_eq!(
+            discovery.events.drain(..).collect::<Vec<_>>(),
+            vec![DiscoveryEvent::SubnetPeersFound(17, 3)]
+        );
+        assert!(discovery.subnet_queries.is_empty());
+
+        // a query already satisfied by the known peers completes at once
+        discovery.discover_subnet_peers(17, 2);
+        assert_eq!(
+            discovery.events.drain(..).collect::<Vec<_>>(),
+            vec![DiscoveryEvent::SubnetPeersFound(17, 3)]
+        );
+    }
+
+    #[test]
+    fn subnet_query_times_out() {
+        let mut discovery = build_discovery(1);
+        let now = Instant::now();
+        discovery.discover_subnet_peers_at(5, 2, now);
+        discovery.enr_discovered(&subnet_enr(&[5]));
+
+        discovery.complete_subnet_queries(now + SUBNET_QUERY_TIMEOUT - Duration::from_secs(1));
+        assert!(discovery.events.is_empty());
+        discovery.complete_subnet_queries(now + SUBNET_QUERY_TIMEOUT);
+        assert_eq!(
+            discovery.events.drain(..).collect::<Vec<_>>(),
+            vec![DiscoveryEvent::SubnetQueryTimedOut(5, 1)]
+        );
+        assert!(discovery.subnet_queries.is_empty());
+    }
+
+    #[test]
+    fn trusted_peers_redialed_after_disconnect() {
+        let address: Multiaddr = "/ip4/10.0.0.2/tcp/9000".parse().expect("valid address");
+        let mut config = NetworkConfig::default();
+        config.trusted_peers = vec![address.clone()];
+        let mut discovery = build_discovery_with_config(config);
+        let now = Instant::now();
+        assert_eq!(discovery.next_trusted_peer_dial(now), Some(address.clone()));
+        assert_eq!(discovery.next_trusted_peer_dial(now), None);
+
+        // the peer id is learnt on connecting
+        let peer_id = PeerId::random();
+        discovery.inject_connected(
+            peer_id.clone(),
+            ConnectedPoint::Dialer {
+                address: address.clone(),
+            },
+        );
+        assert!(discovery.is_trusted(&peer_id));
+        assert_eq!(discovery.next_trusted_peer_redial(), None);
+
+        // each disconnection or failed redial backs off further
+        discovery.inject_disconnected(&peer_id, dialer());
+        let first_due = discovery.next_trusted_peer_redial().expect("is redialed");
+        assert_eq!(
+            discovery.next_trusted_peer_dial(first_due),
+            Some(address.clone())
+        );
+        let error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        discovery.inject_addr_reach_failure(None, &address, &error);
+        let second_due = discovery.next_trusted_peer_redial().expect("is redialed");
+        assert!(second_due - first_due >= Duration::from_secs(INITIAL_TRUSTED_PEER_BACKOFF));
+
+        // reconnecting resets the backoff, and adding the address again changes nothing
+        assert_eq!(
+            discovery.next_trusted_peer_dial(second_due),
+            Some(address.clone())
+        );
+        discovery.inject_connected(peer_id.clone(), listener("/ip4/10.0.0.2/tcp/4000"));
+        assert_eq!(
+            discovery.trusted_peers[0].backoff,
+            INITIAL_TRUSTED_PEER_BACKOFF
+        );
+        discovery.add_trusted_peer(address);
+        assert_eq!(discovery.trusted_peers.len(), 1);
+    }
+
+    #[test]
+    fn trusted_peers_exempt_from_peer_limits() {
+        let mut config = NetworkConfig::default();
+        config.max_peers = 1;
+        config.max_peers_per_subnet = 1;
+        let mut discovery = build_discovery_with_config(config);
+        let trusted = PeerId::random();
+        let mut address: Multiaddr = "/ip4/10.0.0.2/tcp/9000".parse().expect("valid address");
+        address.push(Protocol::P2p(trusted.clone().into()));
+        discovery.add_trusted_peer(address);
+        assert!(discovery.is_trusted(&trusted));
+
+        let untrusted = connect_from(&mut discovery, "/ip4/10.0.0.3/tcp/4000");
+        discovery.inject_connected(trusted.clone(), listener("/ip4/10.0.0.4/tcp/4000"));
+        let excess = connect_from(&mut discovery, "/ip4/10.0.1.1/tcp/4000");
+        assert_eq!(discovery.take_excess_peers(), vec![excess]);
+        assert!(discovery.take_refused_peers().is_empty());
+        let mut connected = discovery.connected_peer_ids();
+        connected.sort_by_key(|peer_id| *peer_id != trusted);
+        assert_eq!(connected, vec![trusted, untrusted]);
+    }
+
+    #[test]
+    fn search_interval_adapts_to_peer_count() {
+        let interval = Duration::from_secs(60);
+        let mut config = NetworkConfig::default();
+        config.min_peers = 2;
+        config.target_peers = 4;
+        config.discovery_interval = interval;
+        let mut discovery = build_discovery_with_config(config);
+        let mut now = Instant::now();
+        let mut search = |discovery: &mut Discovery<_>, peers: usize| {
+            let peer_ids: Vec<PeerId> = (0..peers).map(|_| PeerId::random()).collect();
+            discovery.connected_peers = peer_ids.into_iter().collect();
+            discovery.find_peers(now);
+            let next = discovery.peer_discovery_delay.deadline();
+            let waited = next - now;
+            now = next;
+            waited
+        };
+
+        // short of peers, searches are as frequent as allowed
+        assert_eq!(search(&mut discovery, 0), MIN_SEARCH_INTERVAL);
+        assert_eq!(search(&mut discovery, 1), MIN_SEARCH_INTERVAL);
+
+        // with enough peers, the configured interval is kept
+        assert_eq!(search(&mut discovery, 2), interval);
+        assert_eq!(search(&mut discovery, 3), interval);
+
+        // once full, the interval grows up to its limit
+        assert_eq!(search(&mut discovery, 4), interval * 2);
+        assert_eq!(search(&mut discovery, 4), interval * 4);
+        assert_eq!(search(&mut discovery, 4), interval * 8);
+        assert_eq!(search(&mut discovery, 4), interval * 8);
+
+        // losing peers shrinks it again
+        assert_eq!(search(&mut discovery, 3), interval);
+        assert_eq!(search(&mut discovery, 1), interval / 2);
+        assert_eq!(search(&mut discovery, 1), interval / 4);
+        assert_eq!(search(&mut discovery, 1), interval / 8);
+        assert_eq!(search(&mut discovery, 1), MIN_SEARCH_INTERVAL);
+    }
+
+    #[test]
+    fn found_peers_dialed_up_to_target() {
+        let mut config = NetworkConfig::default();
+        config.target_peers = 3;
+        let mut discovery = build_discovery_with_config(config);
+        let connected = connect_from(&mut discovery, "/ip4/1.2.3.4/tcp/9000");
+        let banned = PeerId::random();
+        discovery.ban_peer(banned.clone(), None);
+        let discovered: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+
+        let mut found = vec![discovery.local_peer_id.clone(), connected, banned];
+        found.extend(discovered.iter().cloned());
+        discovery.peers_found(found.clone());
+
+        // ourselves, connected and banned peers are skipped
+        let events: Vec<DiscoveryEvent> = discovery.events.drain(..).collect();
+        let expected: Vec<DiscoveryEvent> = discovered
+            .iter()
+            .cloned()
+            .map(DiscoveryEvent::PeerDiscovered)
+            .collect();
+        assert_eq!(events, expected);
+
+        // only enough peers to reach the target are dialed
+        assert_eq!(discovery.next_dial(), Some(discovered[0].clone()));
+        assert_eq!(discovery.next_dial(), Some(discovered[1].clone()));
+        assert_eq!(discovery.next_dial(), None);
+
+        // peers found again are not reported again
+        discovery.peers_found(found);
+        assert!(discovery.events.is_empty());
+    }
+
+    #[test]
+    fn dialing_paused_at_target_peers() {
+        let mut config = NetworkConfig::default();
+        config.target_peers = 2;
+        let mut discovery = build_discovery_with_config(config);
+        let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        for peer_id in &peers {
+            discovery.queue_dial(peer_id.clone());
+        }
+
+        // pending dials count towards the target
+        assert_eq!(discovery.next_dial(), Some(peers[0].clone()));
+        assert_eq!(discovery.next_dial(), Some(peers[1].clone()));
+        assert_eq!(discovery.next_dial(), None);
+        assert_eq!(
+            (discovery.connected_peers(), discovery.pending_dials()),
+            (0, 2)
+        );
+
+        // as do established peers
+        discovery.inject_connected(peers[0].clone(), dialer());
+        discovery.inject_connected(peers[1].clone(), dialer());
+        assert_eq!(
+            (discovery.connected_peers(), discovery.pending_dials()),
+            (2, 0)
+        );
+        assert_eq!(discovery.next_dial(), None);
+
+        // dialing resumes below the target
+        discovery.inject_disconnected(&peers[0], dialer());
+        assert_eq!(discovery.next_dial(), Some(peers[2].clone()));
+    }
+
+    #[test]
+    fn inbound_peers_beyond_max_refused() {
+        let mut config = NetworkConfig::default();
+        config.target_peers = 2;
+        config.max_peers = 3;
+        let mut discovery = build_discovery_with_config(config);
+
+        // inbound peers are accepted beyond the target, up to the max
+        for subnet in 1..=3 {
+            connect_from(&mut discovery, &format!("/ip4/10.0.{}.1/tcp/9000", subnet));
+        }
+        assert_eq!(discovery.connected_peers(), 3);
+        assert!(discovery.take_excess_peers().is_empty());
+
+        let excess = connect_from(&mut discovery, "/ip4/10.0.4.1/tcp/9000");
+        assert_eq!(discovery.connected_peers(), 3);
+        assert_eq!(discovery.take_excess_peers(), vec![excess]);
+        assert!(discovery.take_refused_peers().is_empty());
+    }
+
+    #[test]
+    fn local_enr_sequence_survives_restart() {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let local_key = Keypair::generate_secp256k1();
+        let mut config = NetworkConfig::default();
+        let _network_dir = use_temp_network_dir(&mut config);
+        let restart = || -> Discovery<Substream<StreamMuxerBox>> {
+            Discovery::new(&local_key, &config, &log).expect("should build discovery")
+        };
+
+        // the record round-trips through its text form
+        let mut discovery = restart();
+        let enr = discovery.local_enr().clone();
+        let decoded = parse_enr(&enr.to_base64()).expect("valid ENR");
+        assert_eq!(decoded.to_base64(), enr.to_base64());
+        assert_eq!(decoded.tcp(), Some(config.libp2p_port));
+
+        // an unchanged record is reloaded as it was
+        drop(discovery);
+        discovery = restart();
+        assert_eq!(discovery.local_enr().seq(), enr.seq());
+
+        // changing the address bumps the sequence number
+        let ip = "1.2.3.4".parse().expect("valid IP");
+        discovery
+            .update_local_address(ip, 9001)
+            .expect("should update ENR");
+        let updated = discovery.local_enr().clone();
+        assert!(updated.seq() > enr.seq());
+        assert_eq!((updated.ip(), updated.tcp()), (Some(ip), Some(9001)));
+
+        // restarting with the configured address is a further change, never reusing a number
+        drop(discovery);
+        discovery = restart();
+        assert!(discovery.local_enr().seq() > updated.seq());
+    }
+
+    #[test]
+    fn enr_of_other_client_decoded() {
+        // the example record of EIP-778, as printed by go-ethereum
+        let record = "enr:-IS4QHCYrYZbAKWCBRlAy5zzaDZXJBGkcnh4MHcBFZntXNFrdvJjX04jRzjzCBOonrkTfj499SZuOh8R33Ls8RRcy5wBgmlkgnY0gmlwhH8AAAGJc2VjcDI1NmsxoQPKY0yuDUmstAHYpMa2_oxVtw0RW_QAdpzBQA8yWM0xOIN1ZHCCdl8";
+        for text in &[record, &record[ENR_PREFIX.len()..]] {
+            let enr = parse_enr(text).expect("valid ENR");
+            assert_eq!(enr.seq(), 1);
+            assert_eq!(enr.ip(), Some("127.0.0.1".parse().expect("valid IP")));
+            assert_eq!(enr.udp(), Some(30303));
+            assert_eq!(enr.tcp(), None);
+        }
+
+        assert!(parse_enr("enr:not-a-record").is_err());
+    }
+
+    #[test]
+    fn identified_addresses_bounded_and_current() {
+        let mut discovery = build_discovery(2);
+        discovery.identified_address_ttl = Duration::from_secs(60);
+        let peer_id = PeerId::random();
+        let address = |i: usize| -> Multiaddr {
+            format!("/ip4/1.0.{}.{}/tcp/9000", i / 256, i % 256)
+                .parse()
+                .expect("valid address")
+        };
+        let addresses = |range: std::ops::Range<usize>| range.map(address).collect::<Vec<_>>();
+
+        // overlapping lists are deduplicated and kept in the order first told
+        let start = Instant::now();
+        discovery.add_identified_addresses_at(peer_id.clone(), addresses(0..4), start);
+        discovery.add_identified_addresses_at(peer_id.clone(), addresses(2..6), start);
+        assert_eq!(
+            discovery.identified_addresses_at(&peer_id, start),
+            addresses(0..6)
+        );
+
+        // repeated identification never holds more than the bound, the stalest being evicted
+        for i in 0..20 {
+            let now = start + Duration::from_secs(i as u64);
+            let window = (i * 4)..(i * 4 + 12);
+            discovery.add_identified_addresses_at(peer_id.clone(), addresses(window.clone()), now);
+            let identified = discovery.identified_addresses_at(&peer_id, now);
+            assert!(identified.len() <= MAX_IDENTIFIED_ADDRESSES);
+            for address in window.map(address) {
+                assert!(identified.contains(&address));
+            }
+        }
+
+        // a list beyond the bound is cut down to it
+        let now = start + Duration::from_secs(20);
+        discovery.add_identified_addresses_at(peer_id.clone(), addresses(100..140), now);
+        assert_eq!(
+            discovery.identified_addresses_at(&peer_id, now),
+            addresses(100..100 + MAX_IDENTIFIED_ADDRESSES)
+        );
+
+        // addresses not confirmed again within the window expire
+        let later = now + Duration::from_secs(30);
+        discovery.add_identified_addresses_at(peer_id.clone(), addresses(100..102), later);
+        let expired = later + Duration::from_secs(45);
+        assert_eq!(
+            discovery.identified_addresses_at(&peer_id, expired),
+            addresses(100..102)
+        );
+        discovery.add_identified_addresses_at(peer_id.clone(), vec![], expired);
+        assert_eq!(discovery.identified_addresses[&peer_id].len(), 2);
+        let forgotten = expired + Duration::from_secs(60);
+        discovery.add_identified_addresses_at(peer_id.clone(), vec![], forgotten);
+        assert!(!discovery.identified_addresses.contains_key(&peer_id));
+
+        // the addresses of peers which never identify again are pruned all the same
+        let (departed, present) = (PeerId::random(), PeerId::random());
+        discovery.add_identified_addresses_at(departed.clone(), addresses(0..2), now);
+        discovery.add_identified_addresses_at(present.clone(), addresses(2..4), later);
+        discovery.prune_identified_addresses(expired);
+        assert!(!discovery.identified_addresses.contains_key(&departed));
+        assert_eq!(discovery.identified_addresses[&present].len(), 2);
+    }
+
+    #[test]
+    fn addresses_classified() {
+        let class = |ip: &str| AddressClass::of_ip(ip.parse().expect("valid IP"));
+        assert_eq!(class("127.0.0.1"), AddressClass::Loopback);
+        assert_eq!(class("::1"), AddressClass::Loopback);
+        for private in &["10.1.2.3", "172.16.0.1", "192.168.1.1", "fd00::1"] {
+            assert_eq!(class(private), AddressClass::Private);
+        }
+        assert_eq!(class("169.254.1.1"), AddressClass::LinkLocal);
+        assert_eq!(class("fe80::1"), AddressClass::LinkLocal);
+        for public in &["1.2.3.4", "172.32.0.1", "2001:4860::8888"] {
+            assert_eq!(class(public), AddressClass::Public);
+        }
+
+        let address: Multiaddr = "/ip4/192.168.1.1/tcp/9000".parse().expect("valid address");
+        assert_eq!(AddressClass::of(&address), Some(AddressClass::Private));
+        let address: Multiaddr = "/dns4/example.com/tcp/9000".parse().expect("valid address");
+        assert_eq!(AddressClass::of(&address), None);
+    }
+
+    #[test]
+    fn unroutable_addresses_neither_stored_nor_advertised() {
+        let mut discovery = build_discovery(2);
+        let peer_id = PeerId::random();
+        discovery.add_identified_addresses(
+            peer_id.clone(),
+            addresses(&[
+                "/ip4/127.0.0.1/tcp/9000",
+                "/ip4/192.168.1.1/tcp/9000",
+                "/ip6/fe80::1/tcp/9000",
+                "/ip4/1.2.3.4/tcp/9000",
+                "/memory/1234",
+            ]),
+        );
+        assert_eq!(
+            discovery.identified_addresses(&peer_id),
+            addresses(&["/ip4/1.2.3.4/tcp/9000", "/memory/1234"])
+        );
+
+        let seq = discovery.local_enr().seq();
+        assert!(discovery
+            .update_local_address("10.0.0.1".parse().expect("valid IP"), 9000)
+            .is_err());
+        assert_eq!(discovery.local_enr().seq(), seq);
+
+        // local testnets keep them all
+        let mut config = NetworkConfig::default();
+        config.allow_private_addresses = true;
+        let mut discovery = build_discovery_with_config(config);
+        let private = addresses(&["/ip4/127.0.0.1/tcp/9000", "/ip4/192.168.1.1/tcp/9000"]);
+        discovery.add_identified_addresses(peer_id.clone(), private.clone());
+        assert_eq!(discovery.identified_addresses(&peer_id), private);
+        let ip = "10.0.0.1".parse().expect("valid IP");
+        discovery
+            .update_local_address(ip, 9000)
+            .expect("should update ENR");
+        assert_eq!(discovery.local_enr().ip(), Some(ip));
+    }
+
+    #[test]
+    fn observed_unroutable_address_reverted() {
+        let mut discovery = build_discovery(2);
+        let public: IpAddr = "1.2.3.4".parse().expect("valid IP");
+        let observed: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        assert_eq!(discovery.socket_updated(public), Some(observed));
+        let enr_file = discovery.network_dir.join(ENR_FILENAME);
+        let persisted = || std::fs::read_to_string(&enr_file).expect("persisted");
+        assert_eq!(persisted(), discovery.local_enr().to_base64());
+
+        // discv5 writes the address peers see us on into our record before reporting it
+        let private: IpAddr = "192.168.0.1".parse().expect("valid IP");
+        let key = discovery.local_key.clone();
+        discovery
+            .discovery
+            .local_enr_mut()
+            .set_ip(private, &key)
+            .expect("sets IP");
+        assert_eq!(discovery.socket_updated(private), None);
+        assert_eq!(discovery.local_enr().ip(), Some(public));
+        assert_eq!(persisted(), discovery.local_enr().to_base64());
+    }
+
+    #[test]
+    fn banned_peers_hidden_and_refused() {
+        let mut discovery = build_discovery(2);
+        let peer_id = PeerId::random();
+        let address: Multiaddr = "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address");
+        discovery.add_identified_addresses(peer_id.clone(), vec![address.clone()]);
+
+        let until = Instant::now() + Duration::from_secs(60);
+        discovery.ban_peer(peer_id.clone(), Some(until));
+        assert!(discovery.addresses_of_peer(&peer_id).is_empty());
+        discovery.queue_dial(peer_id.clone());
+        assert_eq!(discovery.next_dial(), None);
+
+        // the banned peer dials us
+        discovery.inject_connected(peer_id.clone(), listener("/ip4/1.2.3.4/tcp/9000"));
+        assert_eq!(discovery.take_refused_peers(), vec![peer_id.clone()]);
+        assert_eq!(discovery.connected_peers(), 0);
+
+        // the ban runs out
+        assert!(discovery.take_expired_bans(Instant::now()).is_empty());
+        assert_eq!(discovery.take_expired_bans(until), vec![peer_id.clone()]);
+        assert_eq!(discovery.addresses_of_peer(&peer_id), vec![address]);
+        discovery.inject_connected(peer_id, listener("/ip4/1.2.3.4/tcp/9000"));
+        assert!(discovery.take_refused_peers().is_empty());
+        assert_eq!(discovery.connected_peers(), 1);
+    }
+
+    #[test]
+    fn connected_and_queued_peers_not_requeued() {
+        let mut discovery = build_discovery(2);
+        let connected = PeerId::random();
+        let discovered = PeerId::random();
+        discovery.inject_connected(connected.clone(), dialer());
+
+        discovery.queue_dial(connected);
+        discovery.queue_dial(discovered.clone());
+        discovery.queue_dial(discovered.clone());
+
+        assert_eq!(discovery.next_dial(), Some(discovered.clone()));
+        // an outstanding dial is not repeated
+        discovery.queue_dial(discovered);
+        assert_eq!(discovery.next_dial(), None);
+    }
+
+    #[test]
+    fn trusted_peers_admitted_to_full_dial_queue() {
+        let mut config = NetworkConfig::default();
+        config.target_peers = 2;
+        let mut discovery = build_discovery_with_config(config);
+        let queued: Vec<PeerId> = (0..2).map(|_| PeerId::random()).collect();
+        let trusted = PeerId::random();
+        for peer_id in &queued {
+            discovery.queue_dial(peer_id.clone());
+        }
+
+        // the queue is full to untrusted peers, the last of which makes room
+        discovery.queue_dial(PeerId::random());
+        discovery.queue_trusted_dial(trusted.clone());
+        assert_eq!(
+            discovery.dial_queue,
+            vec![trusted.clone(), queued[0].clone()]
+        );
+
+        // a queued peer found to be trusted is moved to the front
+        discovery.queue_trusted_dial(queued[0].clone());
+        assert_eq!(discovery.dial_queue, vec![queued[0].clone(), trusted]);
+    }
+
+    #[test]
+    fn peers_per_ipv4_subnet_limited() {
+        let mut discovery = build_discovery(2);
+        let max = discovery.max_peers_per_subnet;
+
+        let subnet_peers: Vec<PeerId> = (0..max)
+            .map(|i| connect_from(&mut discovery, &format!("/ip4/1.2.3.{}/tcp/9000", i)))
+            .collect();
+        let refused = connect_from(&mut discovery, "/ip4/1.2.3.200/tcp/9000");
+        assert_eq!(discovery.take_refused_peers(), vec![refused]);
+        assert_eq!(discovery.connected_peers(), max);
+
+        // other subnets are unaffected
+        connect_from(&mut discovery, "/ip4/1.2.4.1/tcp/9000");
+        assert!(discovery.take_refused_peers().is_empty());
+        assert_eq!(discovery.connected_peers(), max + 1);
+
+        // a disconnection makes room in the subnet
+        discovery.inject_disconnected(&subnet_peers[0], listener("/ip4/1.2.3.0/tcp/9000"));
+        connect_from(&mut discovery, "/ip4/1.2.3.201/tcp/9000");
+        assert!(discovery.take_refused_peers().is_empty());
+        assert_eq!(discovery.connected_peers(), max + 1);
+    }
+
+    #[test]
+    fn peers_per_ipv6_subnet_limited() {
+        let mut discovery = build_discovery(2);
+        discovery.max_peers_per_subnet = 1;
+
+        connect_from(&mut discovery, "/ip6/2001:db8:0:1::1/tcp/9000");
+        let refused = connect_from(&mut discovery, "/ip6/2001:db8:0:1:ffff::2/tcp/9000");
+        connect_from(&mut discovery, "/ip6/2001:db8:0:2::1/tcp/9000");
+        assert_eq!(discovery.take_refused_peers(), vec![refused]);
+        assert_eq!(discovery.connected_peers(), 2);
+
+        // addresses without an IP are not limited
+        discovery.inject_connected(PeerId::random(), dialer());
+        discovery.inject_connected(PeerId::random(), dialer());
+        assert!(discovery.take_refused_peers().is_empty());
+        assert_eq!(discovery.connected_peers(), 4);
+    }
+
+    #[test]
+    fn local_peers_per_subnet_not_limited() {
+        let mut discovery = build_discovery(2);
+        discovery.max_peers_per_subnet = 1;
+
+        for address in &[
+            "/ip4/127.0.0.1/tcp/9000",
+            "/ip4/127.0.0.1/tcp/9001",
+            "/ip4/192.168.0.1/tcp/9000",
+            "/ip4/192.168.0.2/tcp/9000",
+            "/ip6/fd00::1/tcp/9000",
+            "/ip6/fd00::2/tcp/9000",
+        ] {
+            connect_from(&mut discovery, address);
+        }
+        assert!(discovery.take_refused_peers().is_empty());
+        assert_eq!(discovery.connected_peers(), 6);
+    }
+
+    fn addresses(addresses: &[&str]) -> Vec<Multiaddr> {
+        addresses
+            .iter()
+            .map(|address| address.parse().expect("valid address"))
+            .collect()
+    }
+
+    #[test]
+    fn identified_addresses_ranked_by_ip_preference() {
+        let mut discovery = build_discovery(2);
+        let ipv6_only = PeerId::random();
+        let dual_stack = PeerId::random();
+        discovery.add_identified_addresses(
+            ipv6_only.clone(),
+            addresses(&["/ip6/::/tcp/9000", "/ip6/2001:db8::1/tcp/9000"]),
+        );
+        discovery.add_identified_addresses(
+            dual_stack.clone(),
+            addresses(&["/ip4/1.2.3.4/tcp/9000", "/ip6/2001:db8::2/tcp/9000"]),
+        );
+
+        // IPv6 addresses are never dropped, only the unspecified one which cannot be dialed
+        for preference in &[IpPreference::V4, IpPreference::V6, IpPreference::Dual] {
+            discovery.ip_preference = *preference;
+            assert_eq!(
+                discovery.addresses_of_peer(&ipv6_only),
+                addresses(&["/ip6/2001:db8::1/tcp/9000"])
+            );
+        }
+
+        discovery.ip_preference = IpPreference::V6;
+        assert_eq!(
+            discovery.addresses_of_peer(&dual_stack),
+            addresses(&["/ip6/2001:db8::2/tcp/9000", "/ip4/1.2.3.4/tcp/9000"])
+        );
+        discovery.ip_preference = IpPreference::V4;
+        assert_eq!(
+            discovery.addresses_of_peer(&dual_stack),
+            addresses(&["/ip4/1.2.3.4/tcp/9000", "/ip6/2001:db8::2/tcp/9000"])
+        );
+        discovery.ip_preference = IpPreference::Dual;
+        assert_eq!(
+            discovery.addresses_of_peer(&dual_stack),
+            addresses(&["/ip4/1.2.3.4/tcp/9000", "/ip6/2001:db8::2/tcp/9000"])
+        );
+
+        // a failed dial forgets the addresses the peer identified
+        discovery.inject_dial_failure(&dual_stack);
+        assert!(!discovery.has_addresses(&dual_stack));
     }
-    Ok(local_enr)
 }
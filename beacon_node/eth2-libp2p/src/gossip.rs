@@ -0,0 +1,492 @@
+//! Wraps the gossipsub behaviour, bounding the gossip we queue for each peer and reporting the
+//! changes to our mesh.
+//!
+//! Gossipsub hands every message it publishes or forwards to the connection of each recipient,
+//! where it waits to be written. A peer that reads slowly would have messages queued for it
+//! without bound, so the bytes handed to each peer are counted and, while they exceed
+//! `MAX_PEER_GOSSIP_BUFFER`, further messages to the peer are skipped. The peer stays in the mesh
+//! and still receives the IHAVE gossip of the messages it missed, which it may request with an
+//! IWANT once its queue has drained.
+//!
+//! Gossipsub does not report when a message has been written, so the queued bytes are drained by
+//! the pings of the connection: a pong received shows that everything handed to the connection
+//! before its ping was sent has been written.
+//!
+//! Gossipsub does not report its mesh either, so it is followed from the GRAFT and PRUNE control
+//! messages exchanged with each peer.
+use crate::Topic;
+use crate::TopicHash;
+use futures::prelude::*;
+use libp2p::core::swarm::{
+    ConnectedPoint, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+};
+use libp2p::core::{Multiaddr, PeerId, ProtocolsHandler};
+use libp2p::gossipsub::protocol::GossipsubControlAction;
+use libp2p::gossipsub::{Gossipsub, GossipsubConfig, GossipsubEvent, GossipsubRpc};
+use slog::{debug, o};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The most bytes of gossip messages queued for a peer before further messages are skipped.
+/// Queued bytes are only drained by pings, so this covers at least a ping interval of gossip.
+pub const MAX_PEER_GOSSIP_BUFFER: usize = 16 * 1024 * 1024;
+
+/// The events emitted by `BoundedGossipsub`.
+pub enum GossipEvent {
+    /// An event of the gossipsub behaviour.
+    Gossipsub(GossipsubEvent),
+    /// A peer has joined, or left, our mesh of a topic.
+    MeshChanged {
+        peer_id: PeerId,
+        topic: TopicHash,
+        grafted: bool,
+    },
+}
+
+/// The gossipsub behaviour, with the gossip queued for each peer bounded.
+pub struct BoundedGossipsub<TSubstream> {
+    gossipsub: Gossipsub<TSubstream>,
+    send_queues: GossipSendQueues,
+    /// The topics we are subscribed to, the only ones we keep a mesh of.
+    subscriptions: HashSet<TopicHash>,
+    /// The peers of our mesh of each topic.
+    mesh: HashMap<TopicHash, HashSet<PeerId>>,
+    /// The mesh changes waiting to be emitted.
+    events: VecDeque<GossipEvent>,
+    log: slog::Logger,
+}
+
+impl<TSubstream> BoundedGossipsub<TSubstream> {
+    pub fn new(local_peer_id: PeerId, config: GossipsubConfig, log: &slog::Logger) -> Self {
+        BoundedGossipsub {
+            gossipsub: Gossipsub::new(local_peer_id, config),
+            send_queues: GossipSendQueues::new(MAX_PEER_GOSSIP_BUFFER),
+            subscriptions: HashSet::new(),
+            mesh: HashMap::new(),
+            events: VecDeque::new(),
+            log: log.new(o!("service" => "gossip")),
+        }
+    }
+
+    /// Subscribes to a topic, returning `false` if we already were.
+    pub fn subscribe(&mut self, topic: Topic) -> bool {
+        self.subscriptions.insert(topic.hash().clone());
+        self.gossipsub.subscribe(topic)
+    }
+
+    /// Unsubscribes from a topic, returning `false` if we were not subscribed. The peers of our
+    /// mesh of the topic leave it.
+    pub fn unsubscribe(&mut self, topic: Topic) -> bool {
+        let topic_hash = topic.hash().clone();
+        self.subscriptions.remove(&topic_hash);
+        for peer_id in self.mesh.remove(&topic_hash).into_iter().flatten() {
+            self.events.push_back(GossipEvent::MeshChanged {
+                peer_id,
+                topic: topic_hash.clone(),
+                grafted: false,
+            });
+        }
+        self.gossipsub.unsubscribe(topic)
+    }
+
+    /// Publishes a message on a topic.
+    pub fn publish(&mut self, topic: Topic, data: Vec<u8>) {
+        self.gossipsub.publish(topic, data)
+    }
+
+    /// Drains the gossip queued for the peer, given a pong to a ping sent at `ping_sent`.
+    pub fn ping_returned(&mut self, peer_id: &PeerId, ping_sent: Instant) {
+        if self.send_queues.drain(peer_id, ping_sent) {
+            debug!(self.log, "Gossiping to peer again, its queue has drained";
+                "peer" => format!("{}", peer_id)
+            );
+        }
+    }
+
+    /// Returns the bytes of gossip messages queued for the peer.
+    pub fn queued_bytes(&self, peer_id: &PeerId) -> usize {
+        self.send_queues.queued_bytes(peer_id)
+    }
+
+    /// Returns `true` if gossip messages to the peer are being skipped, as its queue is full.
+    pub fn is_backed_up(&self, peer_id: &PeerId) -> bool {
+        self.send_queues.is_backed_up(peer_id)
+    }
+
+    /// Takes the mesh changes waiting to be emitted, as peer, topic and whether the peer was
+    /// grafted.
+    #[cfg(test)]
+    pub fn take_mesh_changes(&mut self) -> Vec<(PeerId, TopicHash, bool)> {
+        let mut changes = Vec::new();
+        self.events.retain(|event| match event {
+            GossipEvent::MeshChanged {
+                peer_id,
+                topic,
+                grafted,
+            } => {
+                changes.push((peer_id.clone(), topic.clone(), *grafted));
+                false
+            }
+            GossipEvent::Gossipsub(_) => true,
+        });
+        changes
+    }
+
+    /// Follows the mesh of each topic from the GRAFT and PRUNE messages of an RPC, `sent` to the
+    /// peer or received from it.
+    ///
+    /// Gossipsub adds the peers it sends a GRAFT to its mesh, and removes those it sends a PRUNE.
+    /// A GRAFT received is accepted if we are subscribed to the topic, and answered with a PRUNE
+    /// otherwise.
+    fn observe_control(&mut self, peer_id: &PeerId, rpc: &GossipsubRpc, sent: bool) {
+        for control in &rpc.control_msgs {
+            match control {
+                GossipsubControlAction::Graft { topic_hash } => {
+                    if sent || self.subscriptions.contains(topic_hash) {
+                        self.mesh_changed(peer_id, topic_hash, true);
+                    }
+                }
+                GossipsubControlAction::Prune { topic_hash } => {
+                    self.mesh_changed(peer_id, topic_hash, false)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds the peer to, or removes it from, our mesh of a topic, emitting a `MeshChanged` event
+    /// if it was not already, or was, a member.
+    fn mesh_changed(&mut self, peer_id: &PeerId, topic: &TopicHash, grafted: bool) {
+        let changed = if grafted {
+            self.mesh
+                .entry(topic.clone())
+                .or_insert_with(HashSet::new)
+                .insert(peer_id.clone())
+        } else {
+            let removed = self
+                .mesh
+                .get_mut(topic)
+                .map(|peers| peers.remove(peer_id))
+                .unwrap_or(false);
+            if self.mesh.get(topic).map_or(false, HashSet::is_empty) {
+                self.mesh.remove(topic);
+            }
+            removed
+        };
+        if changed {
+            self.events.push_back(GossipEvent::MeshChanged {
+                peer_id: peer_id.clone(),
+                topic: topic.clone(),
+                grafted,
+            });
+        }
+    }
+
+    /// Removes a disconnected peer from every mesh it was in.
+    fn leave_meshes(&mut self, peer_id: &PeerId) {
+        let topics: Vec<TopicHash> = self
+            .mesh
+            .iter()
+            .filter(|(_, peers)| peers.contains(peer_id))
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        for topic in topics {
+            self.mesh_changed(peer_id, &topic, false);
+        }
+    }
+}
+
+// Redirect all behaviour events to the underlying gossipsub behaviour, bounding its sends.
+impl<TSubstream> NetworkBehaviour for BoundedGossipsub<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    type ProtocolsHandler = <Gossipsub<TSubstream> as NetworkBehaviour>::ProtocolsHandler;
+    type OutEvent = GossipEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.gossipsub.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.gossipsub.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+        self.gossipsub.inject_connected(peer_id, endpoint)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId, endpoint: ConnectedPoint) {
+        self.send_queues.remove(peer_id);
+        self.leave_meshes(peer_id);
+        self.gossipsub.inject_disconnected(peer_id, endpoint)
+    }
+
+    fn inject_replaced(&mut self, peer_id: PeerId, closed: ConnectedPoint, opened: ConnectedPoint) {
+        // the messages queued on the closed connection went with it, and gossipsub forgets the
+        // peer as it would on a disconnection
+        self.send_queues.remove(&peer_id);
+        self.leave_meshes(&peer_id);
+        self.gossipsub.inject_replaced(peer_id, closed, opened)
+    }
+
+    fn inject_node_event(
+        &mut self,
+        peer_id: PeerId,
+        event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.observe_control(&peer_id, &event, false);
+        self.gossipsub.inject_node_event(peer_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        params: &mut impl PollParameters,
+    ) -> Async<
+        NetworkBehaviourAction<
+            <Self::ProtocolsHandler as ProtocolsHandler>::InEvent,
+            Self::OutEvent,
+        >,
+    > {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Async::Ready(NetworkBehaviourAction::GenerateEvent(event));
+            }
+
+            match self.gossipsub.poll(params) {
+                Async::Ready(NetworkBehaviourAction::SendEvent { peer_id, event }) => {
+                    self.observe_control(&peer_id, &event, true);
+                    let was_backed_up = self.send_queues.is_backed_up(&peer_id);
+                    let event = self.send_queues.admit(&peer_id, event, Instant::now());
+                    if !was_backed_up && self.send_queues.is_backed_up(&peer_id) {
+                        debug!(self.log, "Skipping gossip to peer, its queue is full";
+                            "peer" => format!("{}", peer_id),
+                            "queued_bytes" => self.send_queues.queued_bytes(&peer_id)
+                        );
+                    }
+                    if let Some(event) = event {
+                        return Async::Ready(NetworkBehaviourAction::SendEvent { peer_id, event });
+                    }
+                }
+                Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+                    if let GossipsubEvent::Unsubscribed { peer_id, topic } = &event {
+                        // a peer leaving a topic is no longer in its mesh
+                        self.mesh_changed(peer_id, topic, false);
+                    }
+                    self.events.push_back(GossipEvent::Gossipsub(event));
+                }
+                Async::Ready(other) => return Async::Ready(other.map_out(GossipEvent::Gossipsub)),
+                Async::NotReady => return Async::NotReady,
+            }
+        }
+    }
+}
+
+/// The bytes of gossip messages handed to the connection of each peer which may not yet have
+/// been written.
+struct GossipSendQueues {
+    max_bytes: usize,
+    queues: HashMap<PeerId, SendQueue>,
+}
+
+/// The gossip queued for a peer, as the time and size of each handover, oldest first.
+#[derive(Default)]
+struct SendQueue {
+    sends: VecDeque<(Instant, usize)>,
+    bytes: usize,
+    /// Set once the queue is full, until it has drained to half of the bound.
+    backed_up: bool,
+}
+
+impl GossipSendQueues {
+    fn new(max_bytes: usize) -> Self {
+        GossipSendQueues {
+            max_bytes,
+            queues: HashMap::new(),
+        }
+    }
+
+    /// Queues the messages of `rpc` for the peer, returning the RPC to send. The messages are
+    /// removed from the RPC while the peer is backed up, and the RPC is dropped if nothing
+    /// else remains in it.
+    fn admit(
+        &mut self,
+        peer_id: &PeerId,
+        mut rpc: GossipsubRpc,
+        now: Instant,
+    ) -> Option<GossipsubRpc> {
+        let size: usize = rpc.messages.iter().map(|message| message.data.len()).sum();
+        if size == 0 {
+            return Some(rpc);
+        }
+
+        let queue = self.queues.entry(peer_id.clone()).or_default();
+        if queue.bytes + size > self.max_bytes {
+            queue.backed_up = true;
+        }
+        if queue.backed_up {
+            rpc.messages.clear();
+            if rpc.subscriptions.is_empty() && rpc.control_msgs.is_empty() {
+                return None;
+            }
+            return Some(rpc);
+        }
+
+        queue.bytes += size;
+        queue.sends.push_back((now, size));
+        Some(rpc)
+    }
+
+    /// Forgets the gossip handed to the peer's connection before `written_before`, as it has
+    /// been written. Returns `true` if the peer was backed up and is no longer.
+    fn drain(&mut self, peer_id: &PeerId, written_before: Instant) -> bool {
+        let queue = match self.queues.get_mut(peer_id) {
+            Some(queue) => queue,
+            None => return false,
+        };
+        while let Some((sent, size)) = queue.sends.front().cloned() {
+            if sent > written_before {
+                break;
+            }
+            queue.sends.pop_front();
+            queue.bytes -= size;
+        }
+
+        let readmitted = queue.backed_up && queue.bytes <= self.max_bytes / 2;
+        if readmitted {
+            queue.backed_up = false;
+        }
+        if queue.sends.is_empty() && !queue.backed_up {
+            self.queues.remove(peer_id);
+        }
+        readmitted
+    }
+
+    fn queued_bytes(&self, peer_id: &PeerId) -> usize {
+        self.queues
+            .get(peer_id)
+            .map(|queue| queue.bytes)
+            .unwrap_or(0)
+    }
+
+    fn is_backed_up(&self, peer_id: &PeerId) -> bool {
+        self.queues
+            .get(peer_id)
+            .map(|queue| queue.backed_up)
+            .unwrap_or(false)
+    }
+
+    /// Stops tracking a peer.
+    fn remove(&mut self, peer_id: &PeerId) {
+        self.queues.remove(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::TopicBuilder;
+    use libp2p::core::{muxing::StreamMuxerBox, nodes::Substream};
+    use libp2p::gossipsub::GossipsubMessage;
+    use std::time::Duration;
+
+    type TestGossipsub = BoundedGossipsub<Substream<StreamMuxerBox>>;
+
+    fn control_rpc(control: GossipsubControlAction) -> GossipsubRpc {
+        GossipsubRpc {
+            messages: vec![],
+            subscriptions: vec![],
+            control_msgs: vec![control],
+        }
+    }
+
+    #[test]
+    fn slow_peer_gossip_queue_bounded() {
+        let max_bytes = 10_000;
+        let mut queues = GossipSendQueues::new(max_bytes);
+        let slow_peer = PeerId::random();
+        let fast_peer = PeerId::random();
+        let start = Instant::now();
+
+        let mut sent_to_slow_peer = 0;
+        for i in 0..100 {
+            let now = start + Duration::from_millis(i * 10);
+            if queues.admit(&slow_peer, rpc_of(&[1_000]), now).is_some() {
+                sent_to_slow_peer += 1;
+            }
+            assert!(queues.queued_bytes(&slow_peer) <= max_bytes);
+
+            // the fast peer's pings return as soon as they are sent
+            assert!(queues.admit(&fast_peer, rpc_of(&[1_000]), now).is_some());
+            queues.drain(&fast_peer, now);
+        }
+        assert_eq!(sent_to_slow_peer, 10);
+        assert!(queues.is_backed_up(&slow_peer));
+        assert!(!queues.is_backed_up(&fast_peer));
+        assert_eq!(queues.queued_bytes(&fast_peer), 0);
+
+        // a pong drains the messages queued before its ping was sent, though too few of them to
+        // readmit the peer
+        assert!(!queues.drain(&slow_peer, start + Duration::from_millis(30)));
+        assert_eq!(queues.queued_bytes(&slow_peer), 6_000);
+        assert!(queues.admit(&slow_peer, rpc_of(&[1]), start).is_none());
+
+        assert!(queues.drain(&slow_peer, start + Duration::from_millis(50)));
+        assert_eq!(queues.queued_bytes(&slow_peer), 4_000);
+        assert!(queues.admit(&slow_peer, rpc_of(&[1_000]), start).is_some());
+
+        queues.remove(&slow_peer);
+        assert_eq!(queues.queued_bytes(&slow_peer), 0);
+    }
+
+    #[test]
+    fn graft_then_prune_reported() {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let mut gossipsub = TestGossipsub::new(PeerId::random(), GossipsubConfig::default(), &log);
+        let topic = TopicBuilder::new("test").build();
+        let hash = topic.hash().clone();
+        let (peer_id, other_peer_id) = (PeerId::random(), PeerId::random());
+        let graft = || {
+            control_rpc(GossipsubControlAction::Graft {
+                topic_hash: hash.clone(),
+            })
+        };
+        let prune = || {
+            control_rpc(GossipsubControlAction::Prune {
+                topic_hash: hash.clone(),
+            })
+        };
+
+        // a graft is refused while we are not subscribed to the topic
+        gossipsub.inject_node_event(peer_id.clone(), graft());
+        assert!(gossipsub.take_mesh_changes().is_empty());
+
+        gossipsub.subscribe(topic.clone());
+        gossipsub.inject_node_event(peer_id.clone(), graft());
+        gossipsub.inject_node_event(peer_id.clone(), graft());
+        assert_eq!(
+            gossipsub.take_mesh_changes(),
+            vec![(peer_id.clone(), hash.clone(), true)]
+        );
+
+        gossipsub.inject_node_event(peer_id.clone(), prune());
+        gossipsub.inject_node_event(peer_id.clone(), prune());
+        assert_eq!(
+            gossipsub.take_mesh_changes(),
+            vec![(peer_id.clone(), hash.clone(), false)]
+        );
+
+        // the peers we graft join the mesh, and leave it as we unsubscribe
+        gossipsub.observe_control(&other_peer_id, &graft(), true);
+        assert_eq!(
+            gossipsub.take_mesh_changes(),
+            vec![(other_peer_id.clone(), hash.clone(), true)]
+        );
+        gossipsub.unsubscribe(topic);
+        assert_eq!(
+            gossipsub.take_mesh_changes(),
+            vec![(other_peer_id, hash, false)]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Limits the rate at which inbound connections are accepted, so a flood of connection attempts
+//! cannot exhaust our resources.
+//!
+//! The limit is applied by the transport, as each inbound connection is accepted and before it is
+//! upgraded: a connection beyond the rate is dropped without negotiating its encryption or
+//! multiplexing. The remote peer is not yet known at that point, so no peer, not even a trusted
+//! one, is exempt. Our own dials do not pass through the listener and are never limited.
+
+use crate::config::InboundRateLimit;
+use futures::prelude::*;
+use futures::try_ready;
+use libp2p::core::transport::{ListenerEvent, TransportError};
+use libp2p::{Multiaddr, Transport};
+use slog::debug;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A token bucket of inbound connections.
+#[derive(Debug)]
+pub struct InboundAllowance {
+    limit: InboundRateLimit,
+    /// The time at which the allowance is full again.
+    full_at: Instant,
+}
+
+impl InboundAllowance {
+    pub fn new(limit: InboundRateLimit, now: Instant) -> Self {
+        InboundAllowance {
+            limit,
+            full_at: now,
+        }
+    }
+
+    /// Takes an inbound connection accepted at `now` from the allowance, returning `false` if
+    /// the allowance is exhausted.
+    pub fn take(&mut self, now: Instant) -> bool {
+        let limit = self.limit;
+        if limit.max_connections == 0 {
+            return false;
+        }
+        let full_at = self.full_at.max(now) + limit.period / limit.max_connections;
+        if full_at > now + limit.period {
+            return false;
+        }
+        self.full_at = full_at;
+        true
+    }
+}
+
+/// A transport whose listeners drop inbound connections beyond `InboundRateLimit` before they
+/// are upgraded.
+#[derive(Clone)]
+pub struct InboundRateLimited<T> {
+    inner: T,
+    allowance: Arc<Mutex<InboundAllowance>>,
+    log: slog::Logger,
+}
+
+impl<T> InboundRateLimited<T> {
+    pub fn new(inner: T, limit: InboundRateLimit, log: slog::Logger) -> Self {
+        InboundRateLimited {
+            inner,
+            allowance: Arc::new(Mutex::new(InboundAllowance::new(limit, Instant::now()))),
+            log,
+        }
+    }
+}
+
+impl<T: Transport> Transport for InboundRateLimited<T> {
+    type Output = T::Output;
+    type Error = T::Error;
+    type Listener = RateLimitedListener<T::Listener>;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        Ok(RateLimitedListener {
+            inner: self.inner.listen_on(addr)?,
+            allowance: self.allowance,
+            log: self.log,
+        })
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial(addr)
+    }
+}
+
+/// The listener of an `InboundRateLimited` transport.
+pub struct RateLimitedListener<L> {
+    inner: L,
+    allowance: Arc<Mutex<InboundAllowance>>,
+    log: slog::Logger,
+}
+
+impl<L, U> Stream for RateLimitedListener<L>
+where
+    L: Stream<Item = ListenerEvent<U>>,
+{
+    type Item = ListenerEvent<U>;
+    type Error = L::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let event = try_ready!(self.inner.poll());
+            if let Some(ListenerEvent::Upgrade { remote_addr, .. }) = &event {
+                let allowed = self
+                    .allowance
+                    .lock()
+                    .expect("allowance lock poisoned")
+                    .take(Instant::now());
+                if !allowed {
+                    // dropping the upgrade closes the connection before any negotiation
+                    debug!(
+                        self.log,
+                        "Refusing inbound connection beyond rate limit";
+                        "address" => format!("{}", remote_addr)
+                    );
+                    continue;
+                }
+            }
+            return Ok(Async::Ready(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{future, stream};
+    use slog::o;
+    use std::time::Duration;
+
+    #[test]
+    fn allowance_replenished_over_period() {
+        let period = Duration::from_secs(10);
+        let limit = InboundRateLimit {
+            max_connections: 2,
+            period,
+        };
+        let now = Instant::now();
+        let mut allowance = InboundAllowance::new(limit, now);
+        assert!(allowance.take(now));
+        assert!(allowance.take(now));
+        assert!(!allowance.take(now));
+
+        let later = now + period / 2;
+        assert!(allowance.take(later));
+        assert!(!allowance.take(later));
+
+        let mut closed = InboundAllowance::new(
+            InboundRateLimit {
+                max_connections: 0,
+                period,
+            },
+            now,
+        );
+        assert!(!closed.take(now));
+    }
+
+    #[test]
+    fn connections_beyond_rate_dropped_before_upgrade() {
+        let limit = InboundRateLimit {
+            max_connections: 2,
+            period: Duration::from_secs(60),
+        };
+        let address = |port: u16| -> Multiaddr {
+            format!("/ip4/1.2.3.4/tcp/{}", port)
+                .parse()
+                .expect("valid address")
+        };
+        let listen_address = address(9000);
+        let mut events = vec![ListenerEvent::NewAddress(listen_address.clone())];
+        for port in 1..=4 {
+            events.push(ListenerEvent::Upgrade {
+                upgrade: future::ok::<u16, ()>(port),
+                local_addr: listen_address.clone(),
+                remote_addr: address(port),
+            });
+        }
+        let listener = RateLimitedListener {
+            inner: stream::iter_ok::<_, ()>(events),
+            allowance: Arc::new(Mutex::new(InboundAllowance::new(limit, Instant::now()))),
+            log: slog::Logger::root(slog::Discard, o!()),
+        };
+
+        let accepted: Vec<u16> = listener
+            .filter_map(|event| match event {
+                ListenerEvent::Upgrade { upgrade, .. } => upgrade.wait().ok(),
+                _ => None,
+            })
+            .collect()
+            .wait()
+            .expect("listener should not fail");
+        assert_eq!(accepted, vec![1, 2]);
+    }
+}
@@ -6,13 +6,27 @@ pub mod behaviour;
 mod config;
 mod discovery;
 pub mod error;
+mod gossip;
+mod inbound_limit;
+pub mod metrics;
+mod peer_cache;
 pub mod rpc;
 mod service;
 
-pub use behaviour::PubsubMessage;
+pub use behaviour::{
+    BehaviourError, ClientInfo, DiscoveryInfo, GossipBlockInfo, GossipObserver, NetworkCommand,
+    NetworkSender, PeerChainInfo, PeerInfo, PeerSummary, PubsubDecodeError, PubsubMessage,
+    RPCResponseQuality,
+};
 pub use config::{
-    Config as NetworkConfig, BEACON_ATTESTATION_TOPIC, BEACON_PUBSUB_TOPIC, SHARD_TOPIC_PREFIX,
+    attestation_subnet_topic, eth2_message_id, Config as NetworkConfig, InboundRateLimit,
+    IpPreference, MessageAuthenticity, MessageId, MessageIdFn, NodeRole, SubnetId,
+    ATTESTATION_SUBNET_TOPIC_POSTFIX, ATTESTATION_SUBNET_TOPIC_PREFIX, ATTESTER_SLASHING_TOPIC,
+    BEACON_ATTESTATION_TOPIC, BEACON_PUBSUB_TOPIC, LIGHT_CLIENT_FINALITY_UPDATE_TOPIC,
+    LIGHT_CLIENT_OPTIMISTIC_UPDATE_TOPIC, PROPOSER_SLASHING_TOPIC, SHARD_TOPIC_PREFIX,
+    VOLUNTARY_EXIT_TOPIC,
 };
+pub use discovery::{AddressClass, DialErrorKind, PeerSource};
 pub use libp2p::floodsub::{Topic, TopicBuilder, TopicHash};
 pub use libp2p::multiaddr;
 pub use libp2p::Multiaddr;
@@ -23,3 +37,4 @@ pub use libp2p::{
 pub use rpc::RPCEvent;
 pub use service::Libp2pEvent;
 pub use service::Service;
+pub use service::{build_memory_transport, build_transport, Libp2pTransport};
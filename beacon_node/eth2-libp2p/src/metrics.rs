@@ -0,0 +1,104 @@
+pub use prometheus::Error;
+use prometheus::{
+    exponential_buckets, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry,
+};
+
+/// Metrics for the libp2p network behaviour.
+#[derive(Clone)]
+pub struct Metrics {
+    pub gossip_messages_received: IntCounter,
+    pub gossip_duplicates_received: IntCounter,
+    pub gossip_messages_published: IntCounter,
+    // only recorded and registered with the `metrics` feature
+    pub gossip_topic_publishes: IntCounterVec,
+    pub gossip_published_bytes: HistogramVec,
+    pub gossip_validation_seconds: HistogramVec,
+    pub rpc_outbound_queue_depth: IntGauge,
+    pub rpc_bytes_sent: IntCounterVec,
+    pub rpc_inbound_failures: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self {
+            gossip_messages_received: {
+                let opts = Opts::new("gossip_messages_received", "total_gossip_messages_received");
+                IntCounter::with_opts(opts)?
+            },
+            gossip_duplicates_received: {
+                let opts = Opts::new(
+                    "gossip_duplicates_received",
+                    "total_duplicate_gossip_messages_received",
+                );
+                IntCounter::with_opts(opts)?
+            },
+            gossip_messages_published: {
+                let opts = Opts::new(
+                    "gossip_messages_published",
+                    "total_gossip_messages_published",
+                );
+                IntCounter::with_opts(opts)?
+            },
+            gossip_topic_publishes: {
+                let opts = Opts::new(
+                    "gossip_topic_publishes",
+                    "total_gossip_messages_published_on_each_topic",
+                );
+                IntCounterVec::new(opts, &["topic"])?
+            },
+            gossip_published_bytes: {
+                // 64 bytes up to the 4MB maximum gossip size
+                let opts = HistogramOpts::new(
+                    "gossip_published_bytes",
+                    "size_of_gossip_messages_published_on_each_topic",
+                )
+                .buckets(exponential_buckets(64.0, 4.0, 9)?);
+                HistogramVec::new(opts, &["topic"])?
+            },
+            gossip_validation_seconds: {
+                // 1ms up to ~16s, beyond which a block has missed its slot
+                let opts = HistogramOpts::new(
+                    "gossip_validation_seconds",
+                    "time_from_receiving_a_gossip_message_to_its_validation_result_per_kind",
+                )
+                .buckets(exponential_buckets(0.001, 2.0, 15)?);
+                HistogramVec::new(opts, &["kind"])?
+            },
+            rpc_outbound_queue_depth: {
+                let opts = Opts::new(
+                    "rpc_outbound_queue_depth",
+                    "rpc_events_awaiting_send_to_all_peers",
+                );
+                IntGauge::with_opts(opts)?
+            },
+            rpc_bytes_sent: {
+                let opts = Opts::new("rpc_bytes_sent", "total_rpc_bytes_sent_per_peer");
+                IntCounterVec::new(opts, &["peer"])?
+            },
+            rpc_inbound_failures: {
+                let opts = Opts::new(
+                    "rpc_inbound_failures",
+                    "total_inbound_rpcs_failed_to_read_or_decode",
+                );
+                IntCounter::with_opts(opts)?
+            },
+        })
+    }
+
+    pub fn register(&self, registry: &Registry) -> Result<(), Error> {
+        registry.register(Box::new(self.gossip_messages_received.clone()))?;
+        registry.register(Box::new(self.gossip_duplicates_received.clone()))?;
+        registry.register(Box::new(self.gossip_messages_published.clone()))?;
+        if cfg!(feature = "metrics") {
+            registry.register(Box::new(self.gossip_topic_publishes.clone()))?;
+            registry.register(Box::new(self.gossip_published_bytes.clone()))?;
+        }
+        registry.register(Box::new(self.gossip_validation_seconds.clone()))?;
+        registry.register(Box::new(self.rpc_outbound_queue_depth.clone()))?;
+        registry.register(Box::new(self.rpc_bytes_sent.clone()))?;
+        registry.register(Box::new(self.rpc_inbound_failures.clone()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+//! A store of the peers we have been connected to, written to disk so that after a restart we
+//! can dial them again rather than relying on the boot nodes alone.
+//!
+//! The store is a text file, one peer per line after a version header:
+//!
+//! `<peer id> <last seen, in seconds since the unix epoch> <score> <ENR or -> [<address> ...]`
+
+use crate::discovery::parse_enr;
+use libp2p::enr::Enr;
+use libp2p::{Multiaddr, PeerId};
+use slog::{debug, warn};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The first line of a peer cache, changed should the format of the lines after it change.
+const PEER_CACHE_HEADER: &str = "lighthouse-peer-cache-v1";
+
+/// What we remember of a peer across restarts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedPeer {
+    /// The addresses the peer can be dialed on.
+    pub addresses: Vec<Multiaddr>,
+    /// The latest ENR of the peer, if it has been discovered.
+    pub enr: Option<Enr>,
+    /// The last time the peer was connected.
+    pub last_seen: SystemTime,
+    /// The RPC score of the peer.
+    pub score: i32,
+}
+
+impl CachedPeer {
+    /// Returns `true` if we know how to dial the peer.
+    fn is_dialable(&self) -> bool {
+        !self.addresses.is_empty() || self.enr.is_some()
+    }
+}
+
+/// The peers we have been connected to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerCache {
+    peers: HashMap<PeerId, CachedPeer>,
+}
+
+impl PeerCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the cache from `path`, discarding peers not seen within `max_age` of `now`.
+    ///
+    /// A missing file is an empty cache. A corrupted file is ignored with a warning, as the
+    /// cache only saves us from waiting on the boot nodes.
+    pub fn load(path: &Path, max_age: Duration, now: SystemTime, log: &slog::Logger) -> Self {
+        let mut contents = String::new();
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            debug!(
+                log,
+                "No peer cache read";
+                "path" => format!("{:?}", path),
+                "error" => format!("{}", e)
+            );
+            return Self::new();
+        }
+        match Self::decode(&contents) {
+            Ok(mut cache) => {
+                cache.prune(max_age, now);
+                debug!(log, "Peer cache loaded"; "peers" => cache.len());
+                cache
+            }
+            Err(e) => {
+                warn!(
+                    log,
+                    "Ignoring corrupted peer cache";
+                    "path" => format!("{:?}", path),
+                    "error" => e
+                );
+                Self::new()
+            }
+        }
+    }
+
+    /// Writes the cache to `path`, from where `load` reads it.
+    pub fn save(&self, path: &Path, log: &slog::Logger) {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        match File::create(path).and_then(|mut f| f.write_all(self.encode().as_bytes())) {
+            Ok(_) => debug!(log, "Peer cache written to disk"; "peers" => self.len()),
+            Err(e) => warn!(
+                log,
+                "Could not write peer cache to disk";
+                "path" => format!("{:?}", path),
+                "error" => format!("{}", e)
+            ),
+        }
+    }
+
+    /// Returns the number of peers in the cache.
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Records that `peer_id` is connected at `now`, adding it to the cache if it is new.
+    pub fn seen(&mut self, peer_id: PeerId, now: SystemTime) {
+        self.peers
+            .entry(peer_id)
+            .or_insert_with(|| CachedPeer {
+                addresses: vec![],
+                enr: None,
+                last_seen: now,
+                score: 0,
+            })
+            .last_seen = now;
+    }
+
+    /// Adds the addresses `peer_id` can be dialed on, if it is cached.
+    pub fn add_addresses(&mut self, peer_id: &PeerId, addresses: &[Multiaddr]) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            for address in addresses {
+                if !peer.addresses.contains(address) {
+                    peer.addresses.push(address.clone());
+                }
+            }
+        }
+    }
+
+    /// Replaces the ENR of `peer_id`, if it is cached and the record is newer.
+    pub fn set_enr(&mut self, peer_id: &PeerId, enr: &Enr) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            if peer
+                .enr
+                .as_ref()
+                .map_or(true, |cached| cached.seq() < enr.seq())
+            {
+                peer.enr = Some(enr.clone());
+            }
+        }
+    }
+
+    /// Sets the RPC score of `peer_id`, if it is cached.
+    pub fn set_score(&mut self, peer_id: &PeerId, score: i32) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.score = score;
+        }
+    }
+
+    /// Returns the non-zero RPC scores of the cached peers.
+    pub fn scores(&self) -> Vec<(PeerId, i32)> {
+        self.peers
+            .iter()
+            .filter(|(_peer_id, peer)| peer.score != 0)
+            .map(|(peer_id, peer)| (peer_id.clone(), peer.score))
+            .collect()
+    }
+
+    /// Removes the peers not seen within `max_age` of `now`.
+    pub fn prune(&mut self, max_age: Duration, now: SystemTime) {
+        self.peers
+            .retain(|_peer_id, peer| match now.duration_since(peer.last_seen) {
+                Ok(age) => age <= max_age,
+                // seen after `now`, the clock has gone backwards
+                Err(_) => true,
+            });
+    }
+
+    /// Removes all but the `count` most recently seen peers.
+    pub fn truncate(&mut self, count: usize) {
+        if self.peers.len() <= count {
+            return;
+        }
+        let mut peers: Vec<(PeerId, CachedPeer)> = self.peers.drain().collect();
+        peers.sort_by(|(_, a), (_, b)| b.last_seen.cmp(&a.last_seen));
+        self.peers = peers.into_iter().take(count).collect();
+    }
+
+    /// Returns up to `count` peers we know how to dial, the most recently seen first.
+    pub fn freshest(&self, count: usize) -> Vec<(PeerId, CachedPeer)> {
+        let mut peers: Vec<(&PeerId, &CachedPeer)> = self
+            .peers
+            .iter()
+            .filter(|(_peer_id, peer)| peer.is_dialable())
+            .collect();
+        peers.sort_by(|(_, a), (_, b)| b.last_seen.cmp(&a.last_seen));
+        peers
+            .into_iter()
+            .take(count)
+            .map(|(peer_id, peer)| (peer_id.clone(), peer.clone()))
+            .collect()
+    }
+
+    fn encode(&self) -> String {
+        let mut encoded = format!("{}\n", PEER_CACHE_HEADER);
+        for (peer_id, peer) in &self.peers {
+            let last_seen = peer
+                .last_seen
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or(0);
+            let enr = peer
+                .enr
+                .as_ref()
+                .map_or_else(|| "-".to_string(), Enr::to_base64);
+            encoded.push_str(&format!(
+                "{} {} {} {}",
+                peer_id.to_base58(),
+                last_seen,
+                peer.score,
+                enr
+            ));
+            for address in &peer.addresses {
+                encoded.push_str(&format!(" {}", address));
+            }
+            encoded.push('\n');
+        }
+        encoded
+    }
+
+    fn decode(encoded: &str) -> Result<Self, String> {
+        let mut lines = encoded.lines();
+        if lines.next() != Some(PEER_CACHE_HEADER) {
+            return Err("Unknown peer cache header".into());
+        }
+
+        let mut peers = HashMap::new();
+        for (number, line) in lines.enumerate().filter(|(_, line)| !line.is_empty()) {
+            let invalid = |field: &str| format!("Invalid {} on line {}", field, number + 2);
+            let mut fields = line.split_whitespace();
+            let peer_id = fields
+                .next()
+                .and_then(|field| field.parse::<PeerId>().ok())
+                .ok_or_else(|| invalid("peer id"))?;
+            let last_seen = fields
+                .next()
+                .and_then(|field| field.parse::<u64>().ok())
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+                .ok_or_else(|| invalid("last seen time"))?;
+            let score = fields
+                .next()
+                .and_then(|field| field.parse::<i32>().ok())
+                .ok_or_else(|| invalid("score"))?;
+            let enr = match fields.next() {
+                Some("-") => None,
+                Some(field) => Some(parse_enr(field).map_err(|_| invalid("ENR"))?),
+                None => return Err(invalid("ENR")),
+            };
+            let addresses = fields
+                .map(|field| field.parse::<Multiaddr>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid("address"))?;
+            peers.insert(
+                peer_id,
+                CachedPeer {
+                    addresses,
+                    enr,
+                    last_seen,
+                    score,
+                },
+            );
+        }
+        Ok(PeerCache { peers })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libp2p::core::identity::Keypair;
+    use libp2p::enr::EnrBuilder;
+    use slog::o;
+    use tempfile::tempdir;
+
+    fn cached_peer(last_seen: SystemTime) -> CachedPeer {
+        CachedPeer {
+            addresses: vec!["/ip4/1.2.3.4/tcp/9000".parse().expect("valid address")],
+            enr: None,
+            last_seen,
+            score: 0,
+        }
+    }
+
+    #[test]
+    fn store_round_trips() {
+        let key = Keypair::generate_secp256k1();
+        let enr = EnrBuilder::new()
+            .ip("1.2.3.4".parse().expect("valid IP"))
+            .udp(9000)
+            .build(&key)
+            .expect("builds");
+
+        let mut cache = PeerCache::new();
+        let now = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let (with_enr, without_enr) = (key.public().into_peer_id(), PeerId::random());
+        cache.seen(with_enr.clone(), now);
+        cache.set_enr(&with_enr, &enr);
+        cache.set_score(&with_enr, -3);
+        cache.seen(without_enr.clone(), now - Duration::from_secs(60));
+        cache.add_addresses(
+            &without_enr,
+            &[
+                "/ip4/1.2.3.4/tcp/9000".parse().expect("valid address"),
+                "/ip6/2001:db8::1/tcp/9000".parse().expect("valid address"),
+            ],
+        );
+
+        let decoded = PeerCache::decode(&cache.encode()).expect("decodes");
+        assert_eq!(decoded, cache);
+        assert_eq!(decoded.scores(), vec![(with_enr, -3)]);
+    }
+
+    #[test]
+    fn stale_peers_pruned() {
+        let now = SystemTime::now();
+        let max_age = Duration::from_secs(3600);
+        let mut cache = PeerCache::new();
+        let (fresh, recent, stale) = (PeerId::random(), PeerId::random(), PeerId::random());
+        cache.peers.insert(fresh.clone(), cached_peer(now));
+        let seen_recently = now - Duration::from_secs(60);
+        cache
+            .peers
+            .insert(recent.clone(), cached_peer(seen_recently));
+        let seen_long_ago = now - max_age - Duration::from_secs(1);
+        cache.peers.insert(stale, cached_peer(seen_long_ago));
+        // peers we cannot dial are kept, but never handed out
+        cache.seen(PeerId::random(), now);
+
+        let dir = tempdir().expect("should create directory");
+        let path = dir.path().join("peers");
+        let log = slog::Logger::root(slog::Discard, o!());
+        cache.save(&path, &log);
+        let loaded = PeerCache::load(&path, max_age, now, &log);
+
+        assert_eq!(loaded.len(), 3);
+        let freshest: Vec<PeerId> = loaded
+            .freshest(5)
+            .into_iter()
+            .map(|(peer_id, _peer)| peer_id)
+            .collect();
+        assert_eq!(freshest, vec![fresh.clone(), recent]);
+        assert_eq!(loaded.freshest(1)[0].0, fresh);
+    }
+
+    #[test]
+    fn least_recently_seen_truncated() {
+        let now = SystemTime::now();
+        let mut cache = PeerCache::new();
+        let peers: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        for (age, peer_id) in peers.iter().enumerate() {
+            cache.seen(peer_id.clone(), now - Duration::from_secs(age as u64));
+        }
+
+        cache.truncate(5);
+        assert_eq!(cache.len(), 4);
+        cache.truncate(2);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.peers.contains_key(&peers[0]));
+        assert!(cache.peers.contains_key(&peers[1]));
+    }
+
+    #[test]
+    fn corrupted_store_ignored() {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let dir = tempdir().expect("should create directory");
+        let dir = dir.path();
+        let max_age = Duration::from_secs(3600);
+
+        let corrupted = [
+            "not a peer cache".to_string(),
+            format!("{}\nnot-a-peer-id 0 0 -", PEER_CACHE_HEADER),
+            format!(
+                "{}\n{} 0 0 - /not/an/address",
+                PEER_CACHE_HEADER,
+                PeerId::random().to_base58()
+            ),
+        ];
+        for (i, contents) in corrupted.iter().enumerate() {
+            let path = dir.join(i.to_string());
+            std::fs::write(&path, contents).expect("writes");
+            assert!(PeerCache::decode(contents).is_err());
+            assert_eq!(
+                PeerCache::load(&path, max_age, SystemTime::now(), &log).len(),
+                0
+            );
+        }
+
+        // a missing store is an empty one
+        let missing = PeerCache::load(&dir.join("missing"), max_age, SystemTime::now(), &log);
+        assert_eq!(missing.len(), 0);
+    }
+}
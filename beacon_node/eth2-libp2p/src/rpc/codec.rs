@@ -0,0 +1,263 @@
+//! Drives the RPC wire codec over in-memory bytes rather than a live substream.
+//!
+//! Every frame we receive is attacker controlled. These functions expose exactly the path an
+//! inbound substream takes, so it can be fuzzed and tested against arbitrary input.
+//!
+//! The module is only built for tests, and for the fuzz targets and benchmarks through the
+//! `fuzzing` feature.
+
+use super::methods::*;
+use super::protocol::{
+    encode_frame, length_prefixed, ProtocolId, RPCEncoding, RPCEvent, RPCProtocol, RPCSizeLimits,
+    ReadFrame, RequestId,
+};
+pub use super::protocol::{DecodeError, InboundFrame};
+use futures::Future;
+use libp2p::core::UpgradeInfo;
+use std::io;
+use types::{BeaconBlock, ChainSpec, Epoch, Hash256, Slot};
+
+/// The ways frames are written to substreams: `ssz` and compressed `ssz_snappy` frames, and
+/// `ssz_snappy` frames stored uncompressed, as encoding and compression threshold.
+pub const FRAME_FORMATS: [(RPCEncoding, usize); 3] = [
+    (RPCEncoding::SSZ, 0),
+    (RPCEncoding::SSZSnappy, 0),
+    (RPCEncoding::SSZSnappy, std::usize::MAX),
+];
+
+/// Returns the protocol of every method, version and encoding we accept RPCs on.
+pub fn inbound_protocols() -> Vec<ProtocolId> {
+    RPCProtocol::default().protocol_info()
+}
+
+/// Encodes `event` as the length-prefixed chunk written to a substream of the given encoding.
+/// Frames smaller than `compression_threshold` are stored rather than compressed by `ssz_snappy`.
+pub fn encode_chunk(
+    event: &RPCEvent,
+    encoding: RPCEncoding,
+    compression_threshold: usize,
+) -> io::Result<Vec<u8>> {
+    encode_frame(event, encoding, compression_threshold).map(|frame| length_prefixed(&frame))
+}
+
+/// Reads and decodes `bytes` as if they were received on an inbound substream negotiated on
+/// `protocol`. Bytes following the first chunk are ignored, as they are on a substream.
+pub fn decode_chunk(
+    bytes: &[u8],
+    protocol: ProtocolId,
+    size_limits: &RPCSizeLimits,
+) -> InboundFrame {
+    // reads from memory never block, and failures resolve to `InboundFrame::Failed`
+    ReadFrame::new(io::Cursor::new(bytes), protocol, size_limits)
+        .wait()
+        .map(|(frame, _protocol)| frame)
+        .unwrap_or_else(InboundFrame::Failed)
+}
+
+/// Returns a valid event of every request and response type, to seed fuzzing corpora.
+pub fn example_events() -> Vec<RPCEvent> {
+    let spec = ChainSpec::mainnet();
+    let block = BeaconBlock::empty(&spec);
+    let hello = HelloMessage {
+        network_id: 1,
+        genesis_root: Hash256::zero(),
+        latest_finalized_root: Hash256::from_low_u64_be(1),
+        latest_finalized_epoch: Epoch::new(1),
+        best_root: Hash256::from_low_u64_be(2),
+        best_slot: Slot::new(64),
+    };
+    let roots = vec![Hash256::from_low_u64_be(3), Hash256::from_low_u64_be(4)];
+
+    let requests = vec![
+        RPCRequest::Hello(hello.clone()),
+        RPCRequest::Goodbye(GoodbyeReason::ClientShutdown),
+        RPCRequest::BeaconBlockRoots(BeaconBlockRootsRequest {
+            start_slot: Slot::new(1),
+            count: 10,
+        }),
+        RPCRequest::BeaconBlockHeaders(BeaconBlockHeadersRequest {
+            start_root: roots[0],
+            start_slot: Slot::new(1),
+            max_headers: 10,
+            skip_slots: 0,
+        }),
+        RPCRequest::BeaconBlockBodies(BeaconBlockBodiesRequest {
+            block_roots: roots.clone(),
+        }),
+        RPCRequest::BeaconChainState(BeaconChainStateRequest {
+            hashes: roots.clone(),
+        }),
+        RPCRequest::BeaconBlocksByRange(BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(1),
+            count: 10,
+            step: 1,
+        }),
+        RPCRequest::BeaconBlocksByRoot(BeaconBlocksByRootRequest {
+            block_roots: roots.clone(),
+        }),
+        RPCRequest::BeaconBlockHeadersByRange(BeaconBlockHeadersByRangeRequest {
+            start_slot: Slot::new(1),
+            count: 10,
+        }),
+        RPCRequest::BeaconBlockBodiesByRoot(BeaconBlockBodiesByRootRequest {
+            body_roots: roots.clone(),
+        }),
+        RPCRequest::MetaData,
+        RPCRequest::Ping(PingMessage { data: 1 }),
+    ];
+    let responses = vec![
+        RPCResponse::Hello(hello),
+        RPCResponse::BeaconBlockRoots(BeaconBlockRootsResponse {
+            roots: vec![BlockRootSlot {
+                block_root: roots[0],
+                slot: Slot::new(1),
+            }],
+        }),
+        RPCResponse::BeaconBlockHeaders(BeaconBlockHeadersResponse {
+            headers: vec![block.block_header()],
+        }),
+        RPCResponse::BeaconBlockBodies(BeaconBlockBodiesResponse {
+            block_bodies: vec![block.body.clone()],
+        }),
+        RPCResponse::BeaconChainState(BeaconChainStateResponse { values: true }),
+        RPCResponse::BeaconBlocksByRange(Box::new(block.clone())),
+        RPCResponse::BeaconBlocksByRoot(Box::new(block.clone())),
+        RPCResponse::BeaconBlockHeadersByRange(block.block_header()),
+        RPCResponse::BeaconBlockBodiesByRoot(Box::new(BeaconBlockBodyByRoot {
+            index: 0,
+            body_root: roots[1],
+            body: block.body,
+        })),
+        RPCResponse::MetaData(MetaData::default()),
+        RPCResponse::Ping(PingMessage { data: 1 }),
+    ];
+
+    let id = RequestId::from(1);
+    let mut events: Vec<RPCEvent> = requests
+        .into_iter()
+        .map(|body| RPCEvent::Request {
+            id,
+            method_id: body.method_id(),
+            body,
+        })
+        .collect();
+    events.extend(responses.into_iter().map(|result| RPCEvent::Response {
+        id,
+        method_id: result.method_id(),
+        result,
+    }));
+    events.push(RPCEvent::StreamTermination {
+        id,
+        method_id: RPCMethod::BeaconBlocksByRange.into(),
+    });
+    events.push(RPCEvent::Error {
+        id,
+        method_id: RPCMethod::BeaconBlocksByRange.into(),
+        error: RPCError::new(RPCErrorCode::ResourceUnavailable, "No blocks".to_string()),
+    });
+    events
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ssz::Encode;
+
+    /// Decodes `bytes` on every protocol, checking each outcome is well formed.
+    fn decode_everywhere(bytes: &[u8]) {
+        let size_limits = RPCSizeLimits::default();
+        for protocol in inbound_protocols() {
+            let method_id = protocol.method_id;
+            let limit = size_limits.limit(method_id);
+            match decode_chunk(bytes, protocol, &size_limits) {
+                InboundFrame::Event(event) => assert_eq!(event.method_id(), method_id),
+                InboundFrame::OversizedRequest {
+                    method_id: id,
+                    size,
+                    ..
+                } => {
+                    assert_eq!(id, method_id);
+                    assert!(size > limit.max_request);
+                }
+                InboundFrame::Failed(error) => {
+                    let error = RPCError::from(error);
+                    let invalid_request: u8 = RPCErrorCode::InvalidRequest.into();
+                    assert_eq!(error.code(), invalid_request);
+                    assert!(!error.message().is_empty());
+                }
+            }
+        }
+    }
+
+    /// Returns the protocols of the method of `event`.
+    fn protocols_of(event: &RPCEvent) -> Vec<ProtocolId> {
+        inbound_protocols()
+            .into_iter()
+            .filter(|protocol| protocol.method_id == event.method_id())
+            .collect()
+    }
+
+    #[test]
+    fn example_events_round_trip() {
+        let size_limits = RPCSizeLimits::default();
+        for event in example_events() {
+            for protocol in protocols_of(&event) {
+                for (encoding, threshold) in &FRAME_FORMATS {
+                    if *encoding != protocol.encoding {
+                        continue;
+                    }
+                    let chunk =
+                        encode_chunk(&event, *encoding, *threshold).expect("should encode event");
+                    match decode_chunk(&chunk, protocol.clone(), &size_limits) {
+                        InboundFrame::Event(decoded) => {
+                            assert_eq!(decoded.as_ssz_bytes(), event.as_ssz_bytes())
+                        }
+                        frame => panic!("expected {:?} to decode, got {:?}", event, frame),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutated_examples_fail_cleanly() {
+        let mut inputs = vec![vec![], vec![0], vec![0xff; 16]];
+        for event in example_events() {
+            for (encoding, threshold) in &FRAME_FORMATS {
+                let chunk =
+                    encode_chunk(&event, *encoding, *threshold).expect("should encode event");
+
+                // the peer stops at every point of the chunk
+                for cut in (0..chunk.len()).step_by(7) {
+                    inputs.push(chunk[..cut].to_vec());
+                }
+                // a byte of the chunk is corrupted
+                for index in (0..chunk.len()).step_by(11) {
+                    let mut corrupted = chunk.clone();
+                    corrupted[index] ^= 0xff;
+                    inputs.push(corrupted);
+                }
+            }
+        }
+        for input in inputs {
+            decode_everywhere(&input);
+        }
+    }
+
+    #[test]
+    fn hostile_length_fields_fail_cleanly() {
+        // a length prefix claiming far more than the limit allows, and one which never ends
+        decode_everywhere(&[0xff, 0xff, 0xff, 0xff, 0x0f]);
+        decode_everywhere(&[0x80; 16]);
+
+        // an SSZ container whose `bytes` offset points far beyond the frame
+        let mut frame = vec![1];
+        frame.extend_from_slice(&1u64.to_le_bytes());
+        frame.extend_from_slice(&0u16.to_le_bytes());
+        frame.extend_from_slice(&u32::max_value().to_le_bytes());
+        decode_everywhere(&length_prefixed(&frame));
+
+        // a snappy frame claiming to decompress to an enormous size
+        decode_everywhere(&length_prefixed(&[0xff, 0xff, 0xff, 0xff, 0x0f, 0x00]));
+    }
+}
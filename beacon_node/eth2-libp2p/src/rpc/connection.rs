@@ -0,0 +1,249 @@
+//! The connection handler of the RPC behaviour.
+//!
+//! Each RPC is sent on its own substream by a `OneShotHandler`, which closes the whole connection
+//! on the first substream that fails. The handler here wraps it, reporting failed outbound
+//! substreams to the behaviour as `OutboundOutcome::Failed` so only the RPC they carried fails.
+
+use super::methods::RPCError;
+use super::protocol::{OutboundOutcome, OutboundRPC, RPCEvent, RPCProtocol, RequestId};
+use super::OneShotEvent;
+use futures::prelude::*;
+use futures::try_ready;
+use libp2p::core::protocols_handler::{
+    KeepAlive, OneShotHandler, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+    SubstreamProtocol,
+};
+use libp2p::core::upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeError};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The RPC carried by an outbound substream, to report should the substream fail.
+#[derive(Debug, Clone, Copy)]
+pub struct OutboundInfo {
+    id: RequestId,
+    method_id: u16,
+    /// Whether the RPC is a request of ours, rather than part of a response.
+    request: bool,
+}
+
+impl From<&RPCEvent> for OutboundInfo {
+    fn from(event: &RPCEvent) -> Self {
+        OutboundInfo {
+            id: event.id(),
+            method_id: event.method_id(),
+            request: match event {
+                RPCEvent::Request { .. } => true,
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Sends and receives RPCs on a connection, one substream each.
+pub struct RPCHandler<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    inner: OneShotHandler<TSubstream, RPCProtocol, OutboundRPC, OneShotEvent>,
+}
+
+impl<TSubstream> RPCHandler<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    pub fn new(listen_protocol: SubstreamProtocol<RPCProtocol>) -> Self {
+        RPCHandler {
+            inner: OneShotHandler::new(listen_protocol),
+        }
+    }
+}
+
+/// Classifies the failure of an outbound substream.
+fn outbound_error(error: ProtocolsHandlerUpgrErr<io::Error>) -> RPCError {
+    match error {
+        ProtocolsHandlerUpgrErr::Timeout => RPCError::Timeout,
+        ProtocolsHandlerUpgrErr::Timer => RPCError::IoError("Substream timer failed".into()),
+        ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Select(_)) => RPCError::NegotiationFailed,
+        ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(e)) => match e.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => RPCError::StreamReset,
+            _ => RPCError::IoError(e.to_string()),
+        },
+    }
+}
+
+impl<TSubstream> ProtocolsHandler for RPCHandler<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    type InEvent = OutboundRPC;
+    type OutEvent = OneShotEvent;
+    type Error = ProtocolsHandlerUpgrErr<io::Error>;
+    type Substream = TSubstream;
+    type InboundProtocol = RPCProtocol;
+    type OutboundProtocol = OutboundRPC;
+    type OutboundOpenInfo = OutboundInfo;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        self.inner.listen_protocol()
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        output: <Self::InboundProtocol as InboundUpgrade<Self::Substream>>::Output,
+    ) {
+        self.inner.inject_fully_negotiated_inbound(output)
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        output: <Self::OutboundProtocol as OutboundUpgrade<Self::Substream>>::Output,
+        _info: Self::OutboundOpenInfo,
+    ) {
+        self.inner.inject_fully_negotiated_outbound(output, ())
+    }
+
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr<io::Error>,
+    ) {
+        // completing the substream with the failure keeps the connection open and frees the slot
+        // the substream held in the inner handler
+        let outcome = OutboundOutcome::Failed {
+            id: info.id,
+            method_id: info.method_id,
+            request: info.request,
+            error: outbound_error(error),
+        };
+        self.inner.inject_fully_negotiated_outbound(outcome, ())
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+    ) -> Poll<
+        ProtocolsHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::OutEvent>,
+        Self::Error,
+    > {
+        let event = match try_ready!(self.inner.poll()) {
+            ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol, .. } => {
+                let info = OutboundInfo::from(&protocol.upgrade().event);
+                ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol, info }
+            }
+            ProtocolsHandlerEvent::Custom(event) => ProtocolsHandlerEvent::Custom(event),
+        };
+        Ok(Async::Ready(event))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::methods::RPCRequest;
+    use crate::rpc::{RPCEncoding, RPCSizeLimits};
+    use ssz::Encode;
+
+    type TestHandler = RPCHandler<io::Cursor<Vec<u8>>>;
+
+    fn build_handler() -> TestHandler {
+        RPCHandler::new(SubstreamProtocol::new(RPCProtocol {
+            size_limits: RPCSizeLimits::default(),
+        }))
+    }
+
+    /// Hands `event` to the handler, returning the info of the substream opened for it.
+    fn open_substream(handler: &mut TestHandler, event: RPCEvent) -> OutboundInfo {
+        handler.inject_event(OutboundRPC {
+            ssz: event.as_ssz_bytes(),
+            event,
+            encodings: vec![RPCEncoding::SSZSnappy],
+            compression_threshold: 0,
+        });
+        match handler.poll() {
+            Ok(Async::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest { info, .. })) => info,
+            _ => panic!("expected a substream to be opened"),
+        }
+    }
+
+    /// Returns the failure reported by the handler.
+    fn failure(handler: &mut TestHandler) -> (RequestId, bool, RPCError) {
+        match handler.poll() {
+            Ok(Async::Ready(ProtocolsHandlerEvent::Custom(OneShotEvent::SendFailed {
+                id,
+                request,
+                error,
+                ..
+            }))) => (id, request, error),
+            _ => panic!("expected a failure to be reported"),
+        }
+    }
+
+    #[test]
+    fn failed_substreams_reported() {
+        let mut handler = build_handler();
+        let request = RPCEvent::Request {
+            id: RequestId::from(7),
+            method_id: RPCRequest::MetaData.method_id(),
+            body: RPCRequest::MetaData,
+        };
+
+        // the peer resets the substream part way through the request
+        let info = open_substream(&mut handler, request.clone());
+        handler.inject_dial_upgrade_error(
+            info,
+            ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "stream reset",
+            ))),
+        );
+        assert_eq!(
+            failure(&mut handler),
+            (RequestId::from(7), true, RPCError::StreamReset)
+        );
+
+        // the connection stays usable
+        let info = open_substream(&mut handler, request.clone());
+        handler.inject_dial_upgrade_error(
+            info,
+            ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(io::Error::new(
+                io::ErrorKind::Other,
+                "write failed",
+            ))),
+        );
+        assert_eq!(
+            failure(&mut handler),
+            (
+                RequestId::from(7),
+                true,
+                RPCError::IoError("write failed".into())
+            )
+        );
+
+        let info = open_substream(&mut handler, request);
+        handler.inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::Timeout);
+        assert_eq!(
+            failure(&mut handler),
+            (RequestId::from(7), true, RPCError::Timeout)
+        );
+
+        // responses are marked as such, their ids being chosen by the peer
+        let info = open_substream(
+            &mut handler,
+            RPCEvent::StreamTermination {
+                id: RequestId::from(7),
+                method_id: RPCRequest::MetaData.method_id(),
+            },
+        );
+        assert!(!info.request);
+    }
+}
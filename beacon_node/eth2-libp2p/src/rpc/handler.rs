@@ -0,0 +1,111 @@
+//! Answering of inbound RPC requests by a handler registered by the application, off the swarm
+//! thread.
+
+use super::methods::{RPCError, RPCErrorCode, RPCMethod, RPCRequest, RPCResponse};
+use super::protocol::RequestId;
+use futures::prelude::*;
+use futures::sync::mpsc;
+use libp2p::PeerId;
+use std::collections::HashSet;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::thread;
+
+/// Answers inbound RPC requests on behalf of the application.
+///
+/// Requests of the handler's methods are answered without being passed to the application, all
+/// other requests are passed on as events.
+pub trait RequestHandler: Send + Sync {
+    /// The methods whose requests the handler answers.
+    fn methods(&self) -> Vec<RPCMethod>;
+
+    /// Answers `request`. Requests of streamed methods are answered with every item of the stream
+    /// in order, all other requests with a single response.
+    fn handle(&self, request: RPCRequest) -> Result<Vec<RPCResponse>, RPCError>;
+}
+
+/// A request for the handler to answer.
+struct Job {
+    peer_id: PeerId,
+    id: RequestId,
+    request: RPCRequest,
+}
+
+/// The handler's answer to the request `id` from `peer_id`.
+pub struct HandledRequest {
+    pub peer_id: PeerId,
+    pub id: RequestId,
+    pub result: Result<Vec<RPCResponse>, RPCError>,
+}
+
+/// Runs a `RequestHandler` on a dedicated thread, which exits once the worker is dropped.
+pub struct HandlerWorker {
+    /// The ids of the methods the handler answers.
+    method_ids: HashSet<u16>,
+    /// The requests for the handler thread.
+    jobs: std_mpsc::Sender<Job>,
+    /// The answers of the handler thread, waking the swarm task as they arrive.
+    results: mpsc::UnboundedReceiver<HandledRequest>,
+}
+
+impl HandlerWorker {
+    pub fn spawn(handler: Arc<dyn RequestHandler>) -> io::Result<Self> {
+        let method_ids: HashSet<u16> = handler.methods().into_iter().map(Into::into).collect();
+        let (jobs, job_recv) = std_mpsc::channel::<Job>();
+        let (result_send, results) = mpsc::unbounded();
+
+        thread::Builder::new()
+            .name("rpc-request-handler".into())
+            .spawn(move || {
+                for job in job_recv {
+                    // a panicking handler must not take the worker down with it
+                    let result =
+                        panic::catch_unwind(AssertUnwindSafe(|| handler.handle(job.request)))
+                            .unwrap_or_else(|_| {
+                                Err(RPCError::new(
+                                    RPCErrorCode::ServerError,
+                                    "Request handler failed".to_string(),
+                                ))
+                            });
+                    let handled = HandledRequest {
+                        peer_id: job.peer_id,
+                        id: job.id,
+                        result,
+                    };
+                    if result_send.unbounded_send(handled).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(HandlerWorker {
+            method_ids,
+            jobs,
+            results,
+        })
+    }
+
+    /// Returns `true` if the handler answers requests of the method `method_id`.
+    pub fn handles(&self, method_id: u16) -> bool {
+        self.method_ids.contains(&method_id)
+    }
+
+    /// Hands the request `id` from `peer_id` to the handler thread.
+    pub fn submit(&self, peer_id: PeerId, id: RequestId, request: RPCRequest) {
+        // the thread only exits once the worker is dropped
+        let _ = self.jobs.send(Job {
+            peer_id,
+            id,
+            request,
+        });
+    }
+
+    /// Returns the next answer of the handler, if one is ready.
+    pub fn poll_result(&mut self) -> Option<HandledRequest> {
+        match self.results.poll() {
+            Ok(Async::Ready(Some(handled))) => Some(handled),
+            Ok(Async::Ready(None)) | Ok(Async::NotReady) | Err(_) => None,
+        }
+    }
+}
@@ -1,10 +1,26 @@
 //!Available RPC methods types and ids.
 
+use super::protocol::RPCVersion;
 use ssz::{impl_decode_via_from, impl_encode_via_from};
 use ssz_derive::{Decode, Encode};
-use types::{BeaconBlockBody, BeaconBlockHeader, Epoch, Hash256, Slot};
+use types::{BeaconBlock, BeaconBlockBody, BeaconBlockHeader, Bitfield, Epoch, Hash256, Slot};
 
-#[derive(Debug)]
+/// The maximum number of blocks that can be requested in a single `BeaconBlocksByRange`,
+/// `BeaconBlocksByRoot`, `BeaconBlockHeadersByRange` or `BeaconBlockBodiesByRoot` request.
+pub const MAX_REQUEST_BLOCKS: u64 = 1024;
+
+/// The maximum length in bytes of the message carried by an `RPCError`. Longer messages are
+/// truncated.
+pub const MAX_ERROR_MESSAGE_LENGTH: usize = 256;
+
+/// The maximum size in bytes of a single item of a streamed response, such as one block of a
+/// `BeaconBlocksByRange` response.
+pub const MAX_CHUNK_SIZE: usize = 1_048_576; // 1M
+
+/// The number of attestation subnets a node may subscribe to, one bit each in `MetaData::attnets`.
+pub const ATTESTATION_SUBNET_COUNT: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 /// Available Serenity Libp2p RPC methods
 pub enum RPCMethod {
     /// Initialise handshake between connecting peers.
@@ -19,10 +35,74 @@ pub enum RPCMethod {
     BeaconBlockBodies,
     /// Requests values for a merkle proof for the current blocks state root.
     BeaconChainState, // Note: experimental, not complete.
+    /// Requests a range of beacon blocks, streamed back one block per response.
+    BeaconBlocksByRange,
+    /// Requests beacon blocks by block root, streamed back one block per response.
+    BeaconBlocksByRoot,
+    /// Requests a range of beacon block headers, streamed back one header per response.
+    BeaconBlockHeadersByRange,
+    /// Requests beacon block bodies by body root, streamed back one body per response.
+    BeaconBlockBodiesByRoot,
+    /// Requests the peer's metadata.
+    MetaData,
+    /// Exchanges metadata sequence numbers to check the peer is responsive and detect changes to
+    /// its metadata.
+    Ping,
     /// Unknown method received.
     Unknown,
 }
 
+impl RPCMethod {
+    /// All known methods.
+    pub fn known() -> Vec<RPCMethod> {
+        vec![
+            RPCMethod::Hello,
+            RPCMethod::Goodbye,
+            RPCMethod::BeaconBlockRoots,
+            RPCMethod::BeaconBlockHeaders,
+            RPCMethod::BeaconBlockBodies,
+            RPCMethod::BeaconChainState,
+            RPCMethod::BeaconBlocksByRange,
+            RPCMethod::BeaconBlocksByRoot,
+            RPCMethod::BeaconBlockHeadersByRange,
+            RPCMethod::BeaconBlockBodiesByRoot,
+            RPCMethod::MetaData,
+            RPCMethod::Ping,
+        ]
+    }
+
+    /// The name of the method in its protocol ids.
+    pub fn name(self) -> &'static str {
+        match self {
+            RPCMethod::Hello => "hello",
+            RPCMethod::Goodbye => "goodbye",
+            RPCMethod::BeaconBlockRoots => "beacon_block_roots",
+            RPCMethod::BeaconBlockHeaders => "beacon_block_headers",
+            RPCMethod::BeaconBlockBodies => "beacon_block_bodies",
+            RPCMethod::BeaconChainState => "beacon_chain_state",
+            RPCMethod::BeaconBlocksByRange => "beacon_blocks_by_range",
+            RPCMethod::BeaconBlocksByRoot => "beacon_blocks_by_root",
+            RPCMethod::BeaconBlockHeadersByRange => "beacon_block_headers_by_range",
+            RPCMethod::BeaconBlockBodiesByRoot => "beacon_block_bodies_by_root",
+            RPCMethod::MetaData => "metadata",
+            RPCMethod::Ping => "ping",
+            RPCMethod::Unknown => "unknown",
+        }
+    }
+
+    /// Returns `true` if responses to the method are streamed, one chunk per item, and terminated
+    /// by an `RPCEvent::StreamTermination`.
+    pub fn is_streamed(self) -> bool {
+        match self {
+            RPCMethod::BeaconBlocksByRange
+            | RPCMethod::BeaconBlocksByRoot
+            | RPCMethod::BeaconBlockHeadersByRange
+            | RPCMethod::BeaconBlockBodiesByRoot => true,
+            _ => false,
+        }
+    }
+}
+
 impl From<u16> for RPCMethod {
     fn from(method_id: u16) -> Self {
         match method_id {
@@ -32,6 +112,12 @@ impl From<u16> for RPCMethod {
             11 => RPCMethod::BeaconBlockHeaders,
             12 => RPCMethod::BeaconBlockBodies,
             13 => RPCMethod::BeaconChainState,
+            14 => RPCMethod::BeaconBlocksByRange,
+            15 => RPCMethod::BeaconBlocksByRoot,
+            16 => RPCMethod::BeaconBlockHeadersByRange,
+            17 => RPCMethod::BeaconBlockBodiesByRoot,
+            18 => RPCMethod::MetaData,
+            19 => RPCMethod::Ping,
 
             _ => RPCMethod::Unknown,
         }
@@ -47,6 +133,12 @@ impl Into<u16> for RPCMethod {
             RPCMethod::BeaconBlockHeaders => 11,
             RPCMethod::BeaconBlockBodies => 12,
             RPCMethod::BeaconChainState => 13,
+            RPCMethod::BeaconBlocksByRange => 14,
+            RPCMethod::BeaconBlocksByRoot => 15,
+            RPCMethod::BeaconBlockHeadersByRange => 16,
+            RPCMethod::BeaconBlockBodiesByRoot => 17,
+            RPCMethod::MetaData => 18,
+            RPCMethod::Ping => 19,
             _ => 0,
         }
     }
@@ -60,6 +152,14 @@ pub enum RPCRequest {
     BeaconBlockHeaders(BeaconBlockHeadersRequest),
     BeaconBlockBodies(BeaconBlockBodiesRequest),
     BeaconChainState(BeaconChainStateRequest),
+    BeaconBlocksByRange(BeaconBlocksByRangeRequest),
+    BeaconBlocksByRoot(BeaconBlocksByRootRequest),
+    BeaconBlockHeadersByRange(BeaconBlockHeadersByRangeRequest),
+    BeaconBlockBodiesByRoot(BeaconBlockBodiesByRootRequest),
+    /// Requests the peer's metadata. The request has no body.
+    MetaData,
+    /// Carries our metadata sequence number.
+    Ping(PingMessage),
 }
 
 impl RPCRequest {
@@ -71,9 +171,113 @@ impl RPCRequest {
             RPCRequest::BeaconBlockHeaders(_) => RPCMethod::BeaconBlockHeaders,
             RPCRequest::BeaconBlockBodies(_) => RPCMethod::BeaconBlockBodies,
             RPCRequest::BeaconChainState(_) => RPCMethod::BeaconChainState,
+            RPCRequest::BeaconBlocksByRange(_) => RPCMethod::BeaconBlocksByRange,
+            RPCRequest::BeaconBlocksByRoot(_) => RPCMethod::BeaconBlocksByRoot,
+            RPCRequest::BeaconBlockHeadersByRange(_) => RPCMethod::BeaconBlockHeadersByRange,
+            RPCRequest::BeaconBlockBodiesByRoot(_) => RPCMethod::BeaconBlockBodiesByRoot,
+            RPCRequest::MetaData => RPCMethod::MetaData,
+            RPCRequest::Ping(_) => RPCMethod::Ping,
         };
         method.into()
     }
+
+    /// The earliest version of its method that supports this request.
+    pub fn min_version(&self) -> RPCVersion {
+        match self {
+            RPCRequest::BeaconBlocksByRange(_)
+            | RPCRequest::BeaconBlocksByRoot(_)
+            | RPCRequest::BeaconBlockHeadersByRange(_)
+            | RPCRequest::BeaconBlockBodiesByRoot(_) => RPCVersion::V2,
+            RPCRequest::Hello(_) => RPCVersion::V3,
+            _ => RPCVersion::V1,
+        }
+    }
+
+    /// Returns `true` if the remote is expected to respond to this request.
+    pub fn expects_response(&self) -> bool {
+        match self {
+            RPCRequest::Goodbye(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Returns `true` if the requester must have completed the `Hello` handshake before this
+    /// request is served.
+    pub fn requires_handshake(&self) -> bool {
+        match self {
+            RPCRequest::Hello(_)
+            | RPCRequest::Goodbye(_)
+            | RPCRequest::MetaData
+            | RPCRequest::Ping(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Checks the request against the protocol limits.
+    ///
+    /// Requests which fail this check are answered with an `InvalidRequest` error and are not
+    /// passed to the application.
+    pub fn validate(&self) -> Result<(), RPCError> {
+        match self {
+            RPCRequest::BeaconBlocksByRange(req) => {
+                if req.count > MAX_REQUEST_BLOCKS {
+                    return Err(RPCError::new(
+                        RPCErrorCode::InvalidRequest,
+                        format!(
+                            "Requested {} blocks, maximum is {}",
+                            req.count, MAX_REQUEST_BLOCKS
+                        ),
+                    ));
+                }
+                if req.step == 0 {
+                    return Err(RPCError::new(
+                        RPCErrorCode::InvalidRequest,
+                        "Step must be greater than zero".to_string(),
+                    ));
+                }
+                Ok(())
+            }
+            RPCRequest::BeaconBlocksByRoot(req) => {
+                if req.block_roots.len() as u64 > MAX_REQUEST_BLOCKS {
+                    return Err(RPCError::new(
+                        RPCErrorCode::InvalidRequest,
+                        format!(
+                            "Requested {} roots, maximum is {}",
+                            req.block_roots.len(),
+                            MAX_REQUEST_BLOCKS
+                        ),
+                    ));
+                }
+                Ok(())
+            }
+            RPCRequest::BeaconBlockHeadersByRange(req) => {
+                if req.count > MAX_REQUEST_BLOCKS {
+                    return Err(RPCError::new(
+                        RPCErrorCode::InvalidRequest,
+                        format!(
+                            "Requested {} headers, maximum is {}",
+                            req.count, MAX_REQUEST_BLOCKS
+                        ),
+                    ));
+                }
+                Ok(())
+            }
+            RPCRequest::BeaconBlockBodiesByRoot(req) => {
+                if req.body_roots.len() as u64 > MAX_REQUEST_BLOCKS {
+                    return Err(RPCError::new(
+                        RPCErrorCode::InvalidRequest,
+                        format!(
+                            "Requested {} bodies, maximum is {}",
+                            req.body_roots.len(),
+                            MAX_REQUEST_BLOCKS
+                        ),
+                    ));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -83,6 +287,17 @@ pub enum RPCResponse {
     BeaconBlockHeaders(BeaconBlockHeadersResponse),
     BeaconBlockBodies(BeaconBlockBodiesResponse),
     BeaconChainState(BeaconChainStateResponse),
+    /// A single block of a `BeaconBlocksByRange` response stream.
+    BeaconBlocksByRange(Box<BeaconBlock>),
+    /// A single block of a `BeaconBlocksByRoot` response stream.
+    BeaconBlocksByRoot(Box<BeaconBlock>),
+    /// A single header of a `BeaconBlockHeadersByRange` response stream.
+    BeaconBlockHeadersByRange(BeaconBlockHeader),
+    /// A single body of a `BeaconBlockBodiesByRoot` response stream.
+    BeaconBlockBodiesByRoot(Box<BeaconBlockBodyByRoot>),
+    MetaData(MetaData),
+    /// Carries the responder's metadata sequence number.
+    Ping(PingMessage),
 }
 
 impl RPCResponse {
@@ -93,9 +308,176 @@ impl RPCResponse {
             RPCResponse::BeaconBlockHeaders(_) => RPCMethod::BeaconBlockHeaders,
             RPCResponse::BeaconBlockBodies(_) => RPCMethod::BeaconBlockBodies,
             RPCResponse::BeaconChainState(_) => RPCMethod::BeaconChainState,
+            RPCResponse::BeaconBlocksByRange(_) => RPCMethod::BeaconBlocksByRange,
+            RPCResponse::BeaconBlocksByRoot(_) => RPCMethod::BeaconBlocksByRoot,
+            RPCResponse::BeaconBlockHeadersByRange(_) => RPCMethod::BeaconBlockHeadersByRange,
+            RPCResponse::BeaconBlockBodiesByRoot(_) => RPCMethod::BeaconBlockBodiesByRoot,
+            RPCResponse::MetaData(_) => RPCMethod::MetaData,
+            RPCResponse::Ping(_) => RPCMethod::Ping,
         };
         method.into()
     }
+
+    /// Returns `true` if this response is one item of a stream, terminated by an
+    /// `RPCEvent::StreamTermination`.
+    pub fn is_streamed(&self) -> bool {
+        RPCMethod::from(self.method_id()).is_streamed()
+    }
+}
+
+/// The reason a responder gives for failing a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RPCErrorCode {
+    /// The request was malformed or exceeded a protocol limit.
+    InvalidRequest,
+    /// The responder failed to process a valid request.
+    ServerError,
+    /// The responder does not have the requested data.
+    ResourceUnavailable,
+    /// The requester has sent too many requests.
+    RateLimited,
+}
+
+impl RPCErrorCode {
+    /// Returns the code matching the wire value `code`, if any.
+    pub fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(RPCErrorCode::InvalidRequest),
+            2 => Some(RPCErrorCode::ServerError),
+            3 => Some(RPCErrorCode::ResourceUnavailable),
+            4 => Some(RPCErrorCode::RateLimited),
+            _ => None,
+        }
+    }
+}
+
+impl Into<u8> for RPCErrorCode {
+    fn into(self) -> u8 {
+        match self {
+            RPCErrorCode::InvalidRequest => 1,
+            RPCErrorCode::ServerError => 2,
+            RPCErrorCode::ResourceUnavailable => 3,
+            RPCErrorCode::RateLimited => 4,
+        }
+    }
+}
+
+/// An error which terminated an RPC request.
+///
+/// Errors are either sent by the responder in place of a response, or generated locally.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RPCError {
+    /// The responder failed the request, optionally explaining why.
+    Response {
+        code: RPCErrorCode,
+        message: Option<String>,
+    },
+    /// The peer did not respond in time. This error is generated locally and is never sent.
+    Timeout,
+    /// A response, or streamed response chunk, of the given size exceeded the size limit of its
+    /// method. This error is generated locally and is never sent.
+    ResponseTooLarge(usize),
+    /// The peer disconnected before completing its response. This error is generated locally and
+    /// is never sent.
+    Disconnected,
+    /// The peer disconnected, closed the stream or stopped responding after sending the given
+    /// number of chunks of a streamed response, without terminating the stream. This error is
+    /// generated locally and is never sent.
+    Incomplete(usize),
+    /// The request could not be sent as the peer could not be dialed. This error is generated
+    /// locally and is never sent.
+    DialFailed,
+    /// The request was not sent as too many RPCs are already waiting to be sent to the peer. This
+    /// error is generated locally and is never sent.
+    Backpressure,
+    /// The request was not sent as too many requests to the peer are already in flight or waiting
+    /// to be sent. This error is generated locally and is never sent.
+    Busy,
+    /// The request was dropped by the behaviour before being sent, for instance while shutting
+    /// down. This error is generated locally and is never sent.
+    NotSent,
+    /// The peer supports none of the protocols proposed for the request. This error is generated
+    /// locally and is never sent.
+    NegotiationFailed,
+    /// The request was not sent as the protocol version negotiated with the peer does not support
+    /// it. This error is generated locally and is never sent.
+    UnsupportedProtocol,
+    /// The peer reset the substream before the request was sent. This error is generated locally
+    /// and is never sent.
+    StreamReset,
+    /// Writing the request to the substream failed with the given error. This error is generated
+    /// locally and is never sent.
+    IoError(String),
+}
+
+impl RPCError {
+    /// Builds an error to send in place of a response. Messages longer than
+    /// `MAX_ERROR_MESSAGE_LENGTH` bytes are truncated.
+    pub fn new(code: RPCErrorCode, message: impl Into<Option<String>>) -> Self {
+        RPCError::Response {
+            code,
+            message: message.into().map(truncate_error_message),
+        }
+    }
+
+    /// The error code used to identify the error on the wire. Locally generated errors use `0`,
+    /// which is never accepted from a peer.
+    pub fn code(&self) -> u8 {
+        match self {
+            RPCError::Timeout
+            | RPCError::ResponseTooLarge(_)
+            | RPCError::Disconnected
+            | RPCError::Incomplete(_)
+            | RPCError::DialFailed
+            | RPCError::Backpressure
+            | RPCError::Busy
+            | RPCError::NotSent
+            | RPCError::NegotiationFailed
+            | RPCError::UnsupportedProtocol
+            | RPCError::StreamReset
+            | RPCError::IoError(_) => 0,
+            RPCError::Response { code, .. } => (*code).into(),
+        }
+    }
+
+    /// The human readable error message.
+    pub fn message(&self) -> &str {
+        match self {
+            RPCError::Response {
+                message: Some(msg), ..
+            } => msg,
+            RPCError::Response { code, .. } => match code {
+                RPCErrorCode::InvalidRequest => "Invalid request",
+                RPCErrorCode::ServerError => "Server error",
+                RPCErrorCode::ResourceUnavailable => "Resource unavailable",
+                RPCErrorCode::RateLimited => "Rate limited",
+            },
+            RPCError::Timeout => "Request timed out",
+            RPCError::ResponseTooLarge(_) => "Response too large",
+            RPCError::Disconnected => "Peer disconnected",
+            RPCError::Incomplete(_) => "Response stream incomplete",
+            RPCError::DialFailed => "Dial failed",
+            RPCError::Backpressure => "Outbound queue full",
+            RPCError::Busy => "Too many requests in flight",
+            RPCError::NotSent => "Request not sent",
+            RPCError::NegotiationFailed => "Protocol negotiation failed",
+            RPCError::UnsupportedProtocol => "Request unsupported by peer",
+            RPCError::StreamReset => "Stream reset",
+            RPCError::IoError(msg) => msg,
+        }
+    }
+}
+
+/// Truncates `message` to at most `MAX_ERROR_MESSAGE_LENGTH` bytes, on a character boundary.
+fn truncate_error_message(mut message: String) -> String {
+    if message.len() > MAX_ERROR_MESSAGE_LENGTH {
+        let mut end = MAX_ERROR_MESSAGE_LENGTH;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    message
 }
 
 /* Request/Response data structures for RPC methods */
@@ -113,6 +495,9 @@ pub struct HelloMessage {
     pub best_root: Hash256,
     /// The peers last slot.
     pub best_slot: Slot,
+    /// The root of the peer's genesis block. The field was appended in `RPCVersion::V3` of the
+    /// method, so hellos are only exchanged on that version and later.
+    pub genesis_root: Hash256,
 }
 
 /// The reason given for a `Goodbye` message.
@@ -125,6 +510,10 @@ pub enum GoodbyeReason {
     ClientShutdown,
     IrreleventNetwork,
     Fault,
+    /// We are connected to as many peers as we accept.
+    TooManyPeers,
+    /// The connection carried no traffic beyond keep-alives for the idle timeout.
+    Idle,
     Unknown,
 }
 
@@ -134,6 +523,8 @@ impl From<u64> for GoodbyeReason {
             1 => GoodbyeReason::ClientShutdown,
             2 => GoodbyeReason::IrreleventNetwork,
             3 => GoodbyeReason::Fault,
+            129 => GoodbyeReason::TooManyPeers,
+            130 => GoodbyeReason::Idle,
             _ => GoodbyeReason::Unknown,
         }
     }
@@ -146,6 +537,8 @@ impl Into<u64> for GoodbyeReason {
             GoodbyeReason::ClientShutdown => 1,
             GoodbyeReason::IrreleventNetwork => 2,
             GoodbyeReason::Fault => 3,
+            GoodbyeReason::TooManyPeers => 129,
+            GoodbyeReason::Idle => 130,
         }
     }
 }
@@ -239,3 +632,141 @@ pub struct BeaconChainStateResponse {
     /// The values corresponding the to the requested tree hashes.
     pub values: bool, //TBD - stubbed with encodeable bool
 }
+
+/// Request a range of beacon blocks from a peer.
+///
+/// The responder returns each block as a separate `RPCResponse::BeaconBlocksByRange`, followed by
+/// an `RPCEvent::StreamTermination`. Slots without a block are skipped, so fewer than `count`
+/// blocks may be returned.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct BeaconBlocksByRangeRequest {
+    /// The starting slot of the requested blocks.
+    pub start_slot: Slot,
+    /// The number of slots from the start slot. Must not exceed `MAX_REQUEST_BLOCKS`.
+    pub count: u64,
+    /// The slot interval between requested blocks.
+    pub step: u64,
+}
+
+/// Request a number of beacon blocks by their block roots from a peer.
+///
+/// The responder returns each known block as a separate `RPCResponse::BeaconBlocksByRoot`,
+/// followed by an `RPCEvent::StreamTermination`. Unknown roots are skipped.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct BeaconBlocksByRootRequest {
+    /// The roots of the requested blocks. Must not exceed `MAX_REQUEST_BLOCKS`.
+    pub block_roots: Vec<Hash256>,
+}
+
+impl BeaconBlocksByRootRequest {
+    /// Returns the requested roots which are not in `received_roots`, in request order.
+    pub fn missing_roots(&self, received_roots: &[Hash256]) -> Vec<Hash256> {
+        self.block_roots
+            .iter()
+            .filter(|root| !received_roots.contains(root))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Request a range of beacon block headers from a peer.
+///
+/// The responder returns each header as a separate `RPCResponse::BeaconBlockHeadersByRange`, in
+/// ascending slot order, followed by an `RPCEvent::StreamTermination`. Slots without a block are
+/// skipped.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct BeaconBlockHeadersByRangeRequest {
+    /// The starting slot of the requested headers.
+    pub start_slot: Slot,
+    /// The number of slots from the start slot. Must not exceed `MAX_REQUEST_BLOCKS`.
+    pub count: u64,
+}
+
+impl BeaconBlockHeadersByRangeRequest {
+    /// Pairs each slot in the requested range with the received header for that slot, if any.
+    ///
+    /// A `None` indicates a skipped slot (or a header the responder withheld). Headers outside the
+    /// requested range are ignored.
+    pub fn pair_with_slots(
+        &self,
+        headers: Vec<BeaconBlockHeader>,
+    ) -> Vec<(Slot, Option<BeaconBlockHeader>)> {
+        let mut paired: Vec<(Slot, Option<BeaconBlockHeader>)> = (0..self.count)
+            .map(|i| (self.start_slot + i, None))
+            .collect();
+
+        for header in headers {
+            if header.slot >= self.start_slot {
+                let index = (header.slot - self.start_slot).as_usize();
+                if let Some((_slot, entry)) = paired.get_mut(index) {
+                    *entry = Some(header);
+                }
+            }
+        }
+
+        paired
+    }
+}
+
+/// Request a number of beacon block bodies by their body roots from a peer.
+///
+/// The responder returns each known body as a separate `RPCResponse::BeaconBlockBodiesByRoot`,
+/// followed by an `RPCEvent::StreamTermination`. Unknown roots are skipped.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct BeaconBlockBodiesByRootRequest {
+    /// The body roots of the requested bodies. Must not exceed `MAX_REQUEST_BLOCKS`.
+    pub body_roots: Vec<Hash256>,
+}
+
+impl BeaconBlockBodiesByRootRequest {
+    /// Returns the requested roots which were not answered by any of `responses`, in request
+    /// order.
+    pub fn missing_roots(&self, responses: &[BeaconBlockBodyByRoot]) -> Vec<Hash256> {
+        self.body_roots
+            .iter()
+            .enumerate()
+            .filter(|(index, root)| {
+                !responses
+                    .iter()
+                    .any(|response| response.index == *index as u64 && response.body_root == **root)
+            })
+            .map(|(_index, root)| *root)
+            .collect()
+    }
+}
+
+/// A single body returned in response to a `BeaconBlockBodiesByRootRequest`.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct BeaconBlockBodyByRoot {
+    /// The position of `body_root` in the request.
+    pub index: u64,
+    /// The requested body root.
+    pub body_root: Hash256,
+    /// The block body.
+    pub body: BeaconBlockBody,
+}
+
+/// The metadata a node advertises to its peers.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct MetaData {
+    /// Incremented whenever the rest of the metadata changes.
+    pub seq_number: u64,
+    /// The attestation subnets the node is subscribed to, one bit per subnet.
+    pub attnets: Bitfield,
+}
+
+impl Default for MetaData {
+    fn default() -> Self {
+        MetaData {
+            seq_number: 0,
+            attnets: Bitfield::with_capacity(ATTESTATION_SUBNET_COUNT),
+        }
+    }
+}
+
+/// The body of a `Ping` request or response.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct PingMessage {
+    /// The metadata sequence number of the sender.
+    pub data: u64,
+}
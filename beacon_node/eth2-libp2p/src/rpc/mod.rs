@@ -1,21 +1,80 @@
 /// RPC Protocol over libp2p.
 ///
-/// This is purpose built for Ethereum 2.0 serenity and the protocol listens on
-/// `/eth/serenity/rpc/1.0.0`
+/// This is purpose built for Ethereum 2.0 serenity. Each method is versioned independently and
+/// listens on `/eth2/beacon_chain/req/<method>/<version>/<encoding>`, with plain `ssz` and snappy
+/// compressed `ssz_snappy` encodings.
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod codec;
+mod connection;
+mod handler;
 pub mod methods;
 mod protocol;
+mod rate_limiter;
 
+use connection::RPCHandler;
 use futures::prelude::*;
-use libp2p::core::protocols_handler::{OneShotHandler, ProtocolsHandler};
+use futures::task;
+pub use handler::RequestHandler;
+use handler::{HandledRequest, HandlerWorker};
+use libp2p::core::protocols_handler::{ProtocolsHandler, SubstreamProtocol};
 use libp2p::core::swarm::{
     ConnectedPoint, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
 };
 use libp2p::{Multiaddr, PeerId};
-pub use methods::{HelloMessage, RPCMethod, RPCRequest, RPCResponse};
-pub use protocol::{RPCEvent, RPCProtocol, RequestId};
-use slog::o;
+pub use methods::{
+    HelloMessage, MetaData, RPCError, RPCErrorCode, RPCMethod, RPCRequest, RPCResponse,
+    MAX_CHUNK_SIZE,
+};
+use prometheus::{IntCounter, IntCounterVec, IntGauge};
+use protocol::{DecodeError, InboundFrame, OutboundOutcome};
+pub use protocol::{
+    OutboundRPC, ProtocolId, RPCEncoding, RPCEvent, RPCProtocol, RPCSizeLimits, RPCVersion,
+    RequestId, SizeLimit,
+};
+pub use rate_limiter::{Quota, RPCQuotas};
+use rate_limiter::{RateLimitResult, RateLimiter};
+use serde_derive::{Deserialize, Serialize};
+use slog::{debug, info, o, warn};
+use ssz::Encode;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_timer::Interval;
+
+/// The interval at which pending requests are checked for timeouts.
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The shortest timeout a request may be given. Timeouts are only checked every
+/// `TIMEOUT_CHECK_INTERVAL`, so shorter timeouts cannot be honoured.
+const MIN_REQUEST_TIMEOUT: Duration = TIMEOUT_CHECK_INTERVAL;
+/// The longest timeout a request may be given.
+const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+/// The number of requests a peer may make beyond its concurrent request limit before it should be
+/// banned.
+const MAX_CONCURRENCY_OFFENCES: u32 = 10;
+/// The maximum number of requests queued for a single peer while it is being dialed.
+const MAX_QUEUED_REQUESTS: usize = 16;
+/// The time allowed for a peer to be dialed before the requests queued for it fail.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(15);
+/// The number of RPCs handed to the swarm before it is given time to send them.
+const MAX_SENDS_PER_POLL: usize = 64;
+/// The time allowed for a peer to send a complete chunk on a substream it opened. Substreams
+/// stalling part way through a chunk are dropped, and a response they carried is left to time out.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Logs a step in the lifecycle of an RPC, at the level set by `rpc_trace`.
+macro_rules! lifecycle {
+    ($rpc:expr, $($args:tt)+) => {
+        if $rpc.rpc_trace {
+            info!($rpc.log, $($args)+)
+        } else {
+            debug!($rpc.log, $($args)+)
+        }
+    };
+}
 
 /// The network behaviour handles RPC requests/responses as specified in the Eth 2.0 phase 0
 /// specification.
@@ -23,28 +82,1515 @@ use tokio::io::{AsyncRead, AsyncWrite};
 pub struct Rpc<TSubstream> {
     /// Queue of events to processed.
     events: Vec<NetworkBehaviourAction<RPCEvent, RPCMessage>>,
+    /// The outbound requests awaiting a response, keyed by peer and request id. Each request is
+    /// sent on its own substream, so many requests may be in flight to a single peer at once.
+    pending_requests: HashMap<PeerId, HashMap<RequestId, PendingRequest>>,
+    /// The inbound requests we have yet to finish responding to, keyed by peer and the request id
+    /// chosen by that peer.
+    inbound_requests: HashMap<PeerId, HashMap<RequestId, InboundRequest>>,
+    /// The maximum number of inbound requests of a single peer we respond to at once.
+    max_concurrent_requests: usize,
+    /// The number of requests each peer has made beyond `max_concurrent_requests`.
+    concurrency_offences: HashMap<PeerId, u32>,
+    /// The id assigned to the next outbound request.
+    next_request_id: RequestId,
+    /// The peers we are connected to.
+    connected_peers: HashSet<PeerId>,
+    /// The connected peers which have sent us their `Hello`, either as a request or as the response
+    /// to ours. Other peers may only make handshake and liveness requests.
+    handshaken_peers: HashSet<PeerId>,
+    /// The requests to peers we are dialing, sent once the peer connects.
+    dial_queue: HashMap<PeerId, QueuedRequests>,
+    /// The timeout applied to requests sent without an explicit timeout.
+    default_timeout: Duration,
+    /// The time an inbound request may go without a response before it is closed.
+    idle_timeout: Duration,
+    /// Periodically triggers a check for timed out requests.
+    timeout_check: Interval,
+    /// The version of each method negotiated with each peer, keyed by method id.
+    peer_capabilities: HashMap<PeerId, HashMap<u16, RPCVersion>>,
+    /// Limits the rate at which each peer may make requests.
+    rate_limiter: RateLimiter,
+    /// The peers whose requests are not rate limited.
+    rate_limit_exempt: HashSet<PeerId>,
+    /// The size limits of the requests and responses we receive.
+    size_limits: RPCSizeLimits,
+    /// The encodings proposed to peers for outbound RPCs, in order of preference.
+    encodings: Vec<RPCEncoding>,
+    /// The size below which outbound frames are stored rather than compressed.
+    compression_threshold: usize,
+    /// The RPCs handed to the swarm for each peer which have yet to be sent.
+    outbound_queues: HashMap<PeerId, OutboundQueue>,
+    /// The SSZ encoding of each send to a peer waiting in `events`, oldest first. RPCs are encoded
+    /// once, when queued, and the same bytes are framed by the connection handler.
+    encoded_sends: HashMap<PeerId, VecDeque<Vec<u8>>>,
+    /// The limits on each peer's outbound queue.
+    outbound_limits: OutboundQueueLimits,
+    /// The number of RPCs in the outbound queues of all peers. Peers are not labelled, as they come
+    /// and go without bound.
+    outbound_queue_depth: IntGauge,
+    /// The bytes of RPCs sent to each connected peer, labelled by peer id.
+    bytes_sent: IntCounterVec,
+    /// The number of RPCs received which could not be read or decoded.
+    inbound_failures: IntCounter,
+    /// The requests to each peer waiting for requests in flight to complete, oldest first.
+    waiting_requests: HashMap<PeerId, VecDeque<RPCEvent>>,
+    /// The number of requests in flight allowed to peers overriding `max_requests_in_flight`.
+    request_caps: HashMap<PeerId, usize>,
+    /// The peers with RPCs waiting to be handed to the swarm, in the order they are served.
+    send_rotation: VecDeque<PeerId>,
+    /// The number of RPCs handed to the swarm since it was last given time to send them.
+    sent_since_yield: usize,
+    /// Answers the inbound requests of the methods it handles, if registered.
+    request_handler: Option<HandlerWorker>,
     /// Pins the generic substream.
     marker: PhantomData<TSubstream>,
+    /// Whether the steps in the lifecycle of each RPC are raised from debug to info, to trace RPCs
+    /// without trace logging everything else.
+    rpc_trace: bool,
     /// Slog logger for RPC behaviour.
-    _log: slog::Logger,
+    log: slog::Logger,
 }
 
 impl<TSubstream> Rpc<TSubstream> {
-    pub fn new(log: &slog::Logger) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        default_timeout: Duration,
+        idle_timeout: Duration,
+        quotas: RPCQuotas,
+        size_limits: RPCSizeLimits,
+        max_concurrent_requests: usize,
+        encodings: &[RPCEncoding],
+        compression_threshold: usize,
+        outbound_limits: OutboundQueueLimits,
+        outbound_queue_depth: IntGauge,
+        bytes_sent: IntCounterVec,
+        inbound_failures: IntCounter,
+        trace: bool,
+        log: &slog::Logger,
+    ) -> Self {
         let log = log.new(o!("Service" => "Libp2p-RPC"));
         Rpc {
             events: Vec::new(),
+            pending_requests: HashMap::new(),
+            inbound_requests: HashMap::new(),
+            max_concurrent_requests,
+            concurrency_offences: HashMap::new(),
+            next_request_id: RequestId::from(1),
+            connected_peers: HashSet::new(),
+            handshaken_peers: HashSet::new(),
+            dial_queue: HashMap::new(),
+            default_timeout,
+            idle_timeout,
+            timeout_check: Interval::new_interval(TIMEOUT_CHECK_INTERVAL),
+            peer_capabilities: HashMap::new(),
+            rate_limiter: RateLimiter::new(quotas),
+            rate_limit_exempt: HashSet::new(),
+            size_limits,
+            encodings: encodings.to_vec(),
+            compression_threshold,
+            outbound_queues: HashMap::new(),
+            encoded_sends: HashMap::new(),
+            outbound_limits,
+            outbound_queue_depth,
+            bytes_sent,
+            inbound_failures,
+            waiting_requests: HashMap::new(),
+            request_caps: HashMap::new(),
+            send_rotation: VecDeque::new(),
+            sent_since_yield: 0,
+            request_handler: None,
             marker: PhantomData,
-            _log: log,
+            rpc_trace: trace,
+            log,
         }
     }
 
     /// Submits and RPC request.
-    pub fn send_rpc(&mut self, peer_id: PeerId, rpc_event: RPCEvent) {
-        self.events.push(NetworkBehaviourAction::SendEvent {
-            peer_id,
-            event: rpc_event,
+    ///
+    /// Returns the id of the request the event belongs to, or `None` if the event was dropped.
+    /// Requests are assigned a new id by the behaviour, replacing any id set by the caller.
+    /// Requests which are not supported by the protocol version negotiated with the peer are
+    /// failed with `RPCError::UnsupportedProtocol` without being sent. Responses to requests we
+    /// have not received and streamed responses larger than `MAX_CHUNK_SIZE` are dropped.
+    ///
+    /// Requests to a peer we are not connected to are queued and the peer is dialed. The requests
+    /// are sent once the peer connects, or failed with `RPCError::DialFailed` if the dial fails or
+    /// does not complete within `DIAL_TIMEOUT`. Requests beyond `MAX_QUEUED_REQUESTS` for a peer
+    /// being dialed are failed with `RPCError::Busy`, and requests which expect no response are
+    /// dropped.
+    ///
+    /// Requests to a peer whose outbound queue is full are failed with `RPCError::Backpressure`
+    /// without being sent, responses are dropped.
+    ///
+    /// At most `max_requests_in_flight` requests awaiting a response are sent to a peer at once,
+    /// unless overridden for the peer with `set_request_cap`. Further requests wait, in the order
+    /// they were submitted, for earlier requests to complete. Requests beyond
+    /// `max_waiting_requests` are failed with `RPCError::Busy`.
+    ///
+    /// Requests whose substream cannot be negotiated, or is reset or fails while the request is
+    /// written, are failed with `RPCError::NegotiationFailed`, `StreamReset` or `IoError`
+    /// respectively, leaving the connection open.
+    pub fn send_rpc(&mut self, peer_id: PeerId, rpc_event: RPCEvent) -> Option<RequestId> {
+        let timeout = self.default_timeout;
+        self.send_rpc_with_timeout(peer_id, rpc_event, timeout)
+    }
+
+    /// Submits an RPC, failing the request with an `RPCError::Timeout` if the peer does not
+    /// respond within `timeout`. For streamed responses the timeout restarts on each response.
+    ///
+    /// The timeout is clamped to between `MIN_REQUEST_TIMEOUT` and `MAX_REQUEST_TIMEOUT`.
+    pub fn send_rpc_with_timeout(
+        &mut self,
+        peer_id: PeerId,
+        mut rpc_event: RPCEvent,
+        timeout: Duration,
+    ) -> Option<RequestId> {
+        let timeout = timeout.max(MIN_REQUEST_TIMEOUT).min(MAX_REQUEST_TIMEOUT);
+        let ssz = rpc_event.as_ssz_bytes();
+        let size = ssz.len() as u64;
+        // responses count towards the peer's response budget
+        let response_bytes = match &rpc_event {
+            RPCEvent::Response { .. } => size,
+            _ => 0,
+        };
+        let connected = self.connected_peers.contains(&peer_id);
+
+        if connected && self.is_backpressured(&peer_id, size) {
+            debug!(
+                self.log,
+                "Not sending RPC to peer with a full outbound queue";
+                "peer" => format!("{:?}", peer_id),
+                "request_id" => format!("{:?}", rpc_event.id()),
+                "method_id" => rpc_event.method_id(),
+                "queued" => self.outbound_queue_len(&peer_id)
+            );
+            return match rpc_event {
+                RPCEvent::Request { .. } => {
+                    self.reject_request(peer_id, rpc_event, RPCError::Backpressure)
+                }
+                _ => None,
+            };
+        }
+
+        let expects_response = match &rpc_event {
+            RPCEvent::Request { body, .. } => body.expects_response(),
+            _ => false,
+        };
+        if connected && expects_response && self.is_busy(&peer_id) {
+            debug!(
+                self.log,
+                "Not sending RPC request to peer with too many requests in flight";
+                "peer" => format!("{:?}", peer_id),
+                "method_id" => rpc_event.method_id(),
+                "in_flight" => self.requests_in_flight(&peer_id),
+                "waiting" => self.waiting_requests(&peer_id)
+            );
+            return self.reject_request(peer_id, rpc_event, RPCError::Busy);
+        }
+
+        let id = match &mut rpc_event {
+            RPCEvent::Request {
+                id,
+                method_id,
+                body,
+            } => {
+                if !connected {
+                    let queued = self
+                        .dial_queue
+                        .get(&peer_id)
+                        .map(|queued| queued.events.len())
+                        .unwrap_or(0);
+                    if !body.expects_response() {
+                        debug!(
+                            self.log,
+                            "Not queueing RPC request to disconnected peer";
+                            "peer" => format!("{:?}", peer_id),
+                            "method_id" => *method_id
+                        );
+                        return None;
+                    }
+                    if queued >= MAX_QUEUED_REQUESTS {
+                        debug!(
+                            self.log,
+                            "Not queueing RPC request to disconnected peer with a full dial queue";
+                            "peer" => format!("{:?}", peer_id),
+                            "method_id" => *method_id,
+                            "queued" => queued
+                        );
+                        return self.reject_request(peer_id, rpc_event, RPCError::Busy);
+                    }
+                }
+
+                // a peer we are dialing for the request is offered every version of it, as is the
+                // hello from which the versions of a connected peer are learned
+                let handshake = match body {
+                    RPCRequest::Hello(_) => true,
+                    _ => false,
+                };
+                if connected && !handshake {
+                    let version = self.assumed_version(&peer_id, *method_id);
+                    if version < body.min_version() {
+                        debug!(
+                            self.log,
+                            "Not sending RPC request unsupported by peer";
+                            "peer" => format!("{:?}", peer_id),
+                            "method_id" => *method_id,
+                            "peer_version" => format!("{:?}", version),
+                            "version_known" => self.peer_capabilities.contains_key(&peer_id)
+                        );
+                        return self.reject_request(
+                            peer_id,
+                            rpc_event,
+                            RPCError::UnsupportedProtocol,
+                        );
+                    }
+                }
+
+                *id = self.next_request_id;
+                self.next_request_id.increment();
+                lifecycle!(
+                    self,
+                    "RPC request submitted";
+                    "peer" => format!("{:?}", peer_id),
+                    "request_id" => format!("{:?}", id),
+                    "method_id" => *method_id,
+                    "timeout" => format!("{:?}", timeout)
+                );
+
+                if body.expects_response() {
+                    self.pending_requests
+                        .entry(peer_id.clone())
+                        .or_insert_with(HashMap::new)
+                        .insert(
+                            *id,
+                            PendingRequest {
+                                method_id: *method_id,
+                                timeout,
+                                deadline: Instant::now() + timeout,
+                                chunks: 0,
+                                sent: false,
+                            },
+                        );
+                }
+                *id
+            }
+            RPCEvent::Response {
+                id,
+                method_id,
+                result,
+            } => {
+                if result.is_streamed() && response_bytes > MAX_CHUNK_SIZE as u64 {
+                    warn!(
+                        self.log,
+                        "Not sending oversized RPC response chunk";
+                        "peer" => format!("{:?}", peer_id),
+                        "request_id" => format!("{:?}", id),
+                        "method_id" => *method_id,
+                        "size" => response_bytes
+                    );
+                    return None;
+                }
+
+                // a streamed response is followed by a stream termination
+                let remove = !result.is_streamed();
+                if !self.respond_to_request(&peer_id, *id, *method_id, remove) {
+                    return None;
+                }
+                self.rate_limiter.record_response(
+                    &peer_id,
+                    *method_id,
+                    response_bytes,
+                    Instant::now(),
+                );
+                *id
+            }
+            RPCEvent::StreamTermination { id, method_id }
+            | RPCEvent::Error { id, method_id, .. } => {
+                if !self.respond_to_request(&peer_id, *id, *method_id, true) {
+                    return None;
+                }
+                *id
+            }
+        };
+
+        if let RPCEvent::Request { .. } = rpc_event {
+            if !connected {
+                self.queue_request(peer_id, rpc_event);
+                return Some(id);
+            }
+        } else {
+            lifecycle!(
+                self,
+                "Sending RPC response";
+                "peer" => format!("{:?}", peer_id),
+                "request_id" => format!("{:?}", id),
+                "method_id" => rpc_event.method_id(),
+                "response" => event_kind(&rpc_event)
+            );
+        }
+
+        match rpc_event {
+            _ if expects_response => self.dispatch_request(peer_id, rpc_event),
+            // the request was given its id after it was encoded
+            RPCEvent::Request { .. } => self.push_send(peer_id, rpc_event),
+            _ => self.push_encoded_send(peer_id, rpc_event, ssz),
+        }
+        Some(id)
+    }
+
+    /// Fails the request `rpc_event` with `RPCError::DialFailed` without sending it, for peers
+    /// which are known to be undialable.
+    ///
+    /// Returns the id assigned to the request, or `None` if the event is not a request.
+    pub fn fail_request(&mut self, peer_id: PeerId, rpc_event: RPCEvent) -> Option<RequestId> {
+        self.reject_request(peer_id, rpc_event, RPCError::DialFailed)
+    }
+
+    /// Fails the request `rpc_event` with `error` without sending it.
+    ///
+    /// Returns the id assigned to the request, or `None` if the event is not a request.
+    fn reject_request(
+        &mut self,
+        peer_id: PeerId,
+        rpc_event: RPCEvent,
+        error: RPCError,
+    ) -> Option<RequestId> {
+        let method_id = match rpc_event {
+            RPCEvent::Request { method_id, .. } => method_id,
+            _ => return None,
+        };
+        let id = self.next_request_id;
+        self.next_request_id.increment();
+        lifecycle!(
+            self,
+            "RPC request failed before it was sent";
+            "peer" => format!("{:?}", peer_id),
+            "request_id" => format!("{:?}", id),
+            "method_id" => method_id,
+            "error" => format!("{:?}", error)
+        );
+
+        self.events
+            .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                peer_id,
+                RPCEvent::Error {
+                    id,
+                    method_id,
+                    error,
+                },
+            )));
+        Some(id)
+    }
+
+    /// Returns the number of RPCs handed to the swarm for `peer_id` which have yet to be sent.
+    pub fn outbound_queue_len(&self, peer_id: &PeerId) -> usize {
+        self.outbound_queues
+            .get(peer_id)
+            .map(|queue| queue.sizes.len())
+            .unwrap_or(0)
+    }
+
+    /// Returns `true` if an RPC of `size` bytes would exceed the limits of the peer's outbound
+    /// queue. An empty queue accepts an RPC of any size.
+    fn is_backpressured(&self, peer_id: &PeerId, size: u64) -> bool {
+        match self.outbound_queues.get(peer_id) {
+            Some(queue) if !queue.sizes.is_empty() => {
+                queue.sizes.len() >= self.outbound_limits.max_events
+                    || queue.bytes + size > self.outbound_limits.max_bytes
+            }
+            _ => false,
+        }
+    }
+
+    /// Hands `event` to the swarm to be sent to `peer_id`, in order with the RPCs already queued
+    /// for the peer. The event is dropped if the peer's outbound queue is full.
+    fn push_send(&mut self, peer_id: PeerId, event: RPCEvent) {
+        let ssz = event.as_ssz_bytes();
+        self.push_encoded_send(peer_id, event, ssz)
+    }
+
+    /// As `push_send`, for an event already encoded as `ssz`.
+    fn push_encoded_send(&mut self, peer_id: PeerId, event: RPCEvent, ssz: Vec<u8>) {
+        let size = ssz.len() as u64;
+        if self.is_backpressured(&peer_id, size) {
+            debug!(
+                self.log,
+                "Dropping RPC to peer with a full outbound queue";
+                "peer" => format!("{:?}", peer_id),
+                "request_id" => format!("{:?}", event.id()),
+                "method_id" => event.method_id()
+            );
+            return;
+        }
+
+        let queue = self
+            .outbound_queues
+            .entry(peer_id.clone())
+            .or_insert_with(OutboundQueue::default);
+        queue.sizes.push_back(size);
+        queue.bytes += size;
+        self.outbound_queue_depth.inc();
+
+        if !self.send_rotation.contains(&peer_id) {
+            self.send_rotation.push_back(peer_id.clone());
+        }
+        self.encoded_sends
+            .entry(peer_id.clone())
+            .or_insert_with(VecDeque::new)
+            .push_back(ssz);
+        self.events
+            .push(NetworkBehaviourAction::SendEvent { peer_id, event });
+    }
+
+    /// Wraps the next send to `peer_id` taken from `events` for the connection handler, along
+    /// with the encoding it was measured by.
+    fn outbound_rpc(&mut self, peer_id: &PeerId, event: RPCEvent) -> OutboundRPC {
+        let ssz = match self.encoded_sends.get_mut(peer_id) {
+            Some(encoded) => {
+                let ssz = encoded.pop_front();
+                if encoded.is_empty() {
+                    self.encoded_sends.remove(peer_id);
+                }
+                ssz
+            }
+            None => None,
+        };
+        OutboundRPC {
+            ssz: ssz.unwrap_or_else(|| event.as_ssz_bytes()),
+            event,
+            encodings: self.encodings.clone(),
+            compression_threshold: self.compression_threshold,
+        }
+    }
+
+    /// Takes the next action to hand to the swarm, if any. Actions other than sends are taken
+    /// first, in order. Sends are taken oldest first from each peer in turn, so a peer with many
+    /// queued RPCs cannot hold up the others, and only if `sends_allowed`.
+    fn next_event(
+        &mut self,
+        sends_allowed: bool,
+    ) -> Option<NetworkBehaviourAction<RPCEvent, RPCMessage>> {
+        let is_send_to =
+            |event: &NetworkBehaviourAction<RPCEvent, RPCMessage>, peer: &PeerId| match event {
+                NetworkBehaviourAction::SendEvent { peer_id, .. } => peer_id == peer,
+                _ => false,
+            };
+        let other = self.events.iter().position(|event| match event {
+            NetworkBehaviourAction::SendEvent { .. } => false,
+            _ => true,
         });
+        if let Some(index) = other {
+            return Some(self.events.remove(index));
+        }
+        if !sends_allowed {
+            return None;
+        }
+
+        while let Some(peer_id) = self.send_rotation.pop_front() {
+            let index = match self
+                .events
+                .iter()
+                .position(|event| is_send_to(event, &peer_id))
+            {
+                Some(index) => index,
+                // the peer's sends were dropped
+                None => continue,
+            };
+            let event = self.events.remove(index);
+            if self.events.iter().any(|event| is_send_to(event, &peer_id)) {
+                self.send_rotation.push_back(peer_id);
+            }
+            self.sent_since_yield += 1;
+            return Some(event);
+        }
+        None
+    }
+
+    /// Removes the oldest RPC from the outbound queue of `peer_id` once it has been sent.
+    fn on_sent(&mut self, peer_id: &PeerId) {
+        if let Some(size) = self.pop_outbound(peer_id) {
+            self.bytes_sent
+                .with_label_values(&[&peer_id.to_base58()])
+                .inc_by(size as i64);
+        }
+    }
+
+    /// Removes the oldest RPC from the outbound queue of `peer_id`, returning its size.
+    fn pop_outbound(&mut self, peer_id: &PeerId) -> Option<u64> {
+        let (size, depth) = match self.outbound_queues.get_mut(peer_id) {
+            Some(queue) => {
+                let size = queue.sizes.pop_front();
+                if let Some(size) = size {
+                    queue.bytes -= size;
+                    self.outbound_queue_depth.dec();
+                }
+                (size, queue.sizes.len())
+            }
+            None => return None,
+        };
+        if depth == 0 {
+            self.remove_outbound_queue(peer_id);
+        }
+        size
+    }
+
+    /// Drops the outbound queue of `peer_id` along with its metric.
+    fn remove_outbound_queue(&mut self, peer_id: &PeerId) {
+        if let Some(queue) = self.outbound_queues.remove(peer_id) {
+            self.outbound_queue_depth.sub(queue.sizes.len() as i64);
+        }
+    }
+
+    /// Returns `true` if we are connected to `peer_id`.
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.connected_peers.contains(peer_id)
+    }
+
+    /// Queues a request to a peer we are not connected to, asking for the peer to be dialed if
+    /// it is not already being dialed. Dials are left to discovery, which paces them with its
+    /// other dials and respects bans and dial backoffs.
+    ///
+    /// A request whose peer is never dialed fails once `DIAL_TIMEOUT` elapses.
+    fn queue_request(&mut self, peer_id: PeerId, rpc_event: RPCEvent) {
+        lifecycle!(
+            self,
+            "RPC request waiting for peer to be dialed";
+            "peer" => format!("{:?}", peer_id),
+            "request_id" => format!("{:?}", rpc_event.id()),
+            "method_id" => rpc_event.method_id()
+        );
+        match self.dial_queue.entry(peer_id.clone()) {
+            Entry::Occupied(entry) => entry.into_mut().events.push(rpc_event),
+            Entry::Vacant(entry) => {
+                entry.insert(QueuedRequests {
+                    events: vec![rpc_event],
+                    deadline: Instant::now() + DIAL_TIMEOUT,
+                });
+                self.events
+                    .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::DialPeer(
+                        peer_id,
+                    )));
+            }
+        }
+    }
+
+    /// Sends the requests queued for a newly connected peer.
+    fn on_connected(&mut self, peer_id: PeerId) {
+        self.connected_peers.insert(peer_id.clone());
+        // a reconnecting peer must repeat the handshake
+        self.handshaken_peers.remove(&peer_id);
+
+        let queued = match self.dial_queue.remove(&peer_id) {
+            Some(queued) => queued,
+            None => return,
+        };
+        for event in queued.events {
+            self.dispatch_request(peer_id.clone(), event);
+        }
+    }
+
+    /// Returns the number of requests to `peer_id` sent and awaiting a response.
+    pub fn requests_in_flight(&self, peer_id: &PeerId) -> usize {
+        self.pending_requests
+            .get(peer_id)
+            .map(|requests| requests.values().filter(|request| request.sent).count())
+            .unwrap_or(0)
+    }
+
+    /// Returns the number of requests to `peer_id` waiting for requests in flight to complete.
+    pub fn waiting_requests(&self, peer_id: &PeerId) -> usize {
+        self.waiting_requests
+            .get(peer_id)
+            .map(VecDeque::len)
+            .unwrap_or(0)
+    }
+
+    /// Overrides the number of requests which may be in flight to `peer_id` at once, or restores
+    /// `max_requests_in_flight` if `cap` is `None`. The override is kept across reconnections.
+    /// Waiting requests allowed by a raised cap are sent.
+    pub fn set_request_cap(&mut self, peer_id: PeerId, cap: Option<usize>) {
+        match cap {
+            Some(cap) => self.request_caps.insert(peer_id.clone(), cap),
+            None => self.request_caps.remove(&peer_id),
+        };
+        self.dispatch_waiting(&peer_id);
+    }
+
+    /// Exempts the requests of `peer_id` from rate limiting, or subjects them to it again. The
+    /// exemption is kept across reconnections.
+    pub fn set_rate_limit_exempt(&mut self, peer_id: PeerId, exempt: bool) {
+        if exempt {
+            self.rate_limit_exempt.insert(peer_id);
+        } else {
+            self.rate_limit_exempt.remove(&peer_id);
+        }
+    }
+
+    /// Returns the number of requests which may be in flight to `peer_id` at once.
+    fn request_cap(&self, peer_id: &PeerId) -> usize {
+        self.request_caps
+            .get(peer_id)
+            .cloned()
+            .unwrap_or(self.outbound_limits.max_requests_in_flight)
+    }
+
+    /// Returns `true` if a further request to `peer_id` could neither be sent nor wait.
+    fn is_busy(&self, peer_id: &PeerId) -> bool {
+        self.requests_in_flight(peer_id) >= self.request_cap(peer_id)
+            && self.waiting_requests(peer_id) >= self.outbound_limits.max_waiting_requests
+    }
+
+    /// Sends a pending request to a connected peer, or leaves it waiting if the peer's cap of
+    /// requests in flight is reached.
+    fn dispatch_request(&mut self, peer_id: PeerId, rpc_event: RPCEvent) {
+        if self.requests_in_flight(&peer_id) >= self.request_cap(&peer_id) {
+            lifecycle!(
+                self,
+                "RPC request waiting for requests in flight";
+                "peer" => format!("{:?}", peer_id),
+                "request_id" => format!("{:?}", rpc_event.id()),
+                "method_id" => rpc_event.method_id(),
+                "in_flight" => self.requests_in_flight(&peer_id)
+            );
+            self.waiting_requests
+                .entry(peer_id)
+                .or_insert_with(VecDeque::new)
+                .push_back(rpc_event);
+            return;
+        }
+
+        // the time spent dialing or waiting does not count towards the request timeout
+        if let Some(request) = self
+            .pending_requests
+            .get_mut(&peer_id)
+            .and_then(|requests| requests.get_mut(&rpc_event.id()))
+        {
+            request.sent = true;
+            request.deadline = Instant::now() + request.timeout;
+        }
+        lifecycle!(
+            self,
+            "Sending RPC request";
+            "peer" => format!("{:?}", peer_id),
+            "request_id" => format!("{:?}", rpc_event.id()),
+            "method_id" => rpc_event.method_id()
+        );
+        self.push_send(peer_id, rpc_event);
+    }
+
+    /// Sends the requests waiting for `peer_id`, oldest first, until its cap of requests in
+    /// flight is reached.
+    fn dispatch_waiting(&mut self, peer_id: &PeerId) {
+        while self.requests_in_flight(peer_id) < self.request_cap(peer_id) {
+            let event = match self
+                .waiting_requests
+                .get_mut(peer_id)
+                .and_then(VecDeque::pop_front)
+            {
+                Some(event) => event,
+                None => break,
+            };
+            self.dispatch_request(peer_id.clone(), event);
+        }
+        if self.waiting_requests(peer_id) == 0 {
+            self.waiting_requests.remove(peer_id);
+        }
+    }
+
+    /// Fails the requests queued for a peer which could not be dialed with
+    /// `RPCError::DialFailed`.
+    fn on_dial_failure(&mut self, peer_id: &PeerId) {
+        let queued = match self.dial_queue.remove(peer_id) {
+            Some(queued) => queued,
+            None => return,
+        };
+        debug!(
+            self.log,
+            "Failed to dial peer for queued RPC requests";
+            "peer" => format!("{:?}", peer_id),
+            "requests" => queued.events.len()
+        );
+        for event in queued.events {
+            let id = event.id();
+            let method_id = match &event {
+                RPCEvent::Request { method_id, .. } => *method_id,
+                _ => continue,
+            };
+            lifecycle!(
+                self,
+                "RPC request failed to dial peer";
+                "peer" => format!("{:?}", peer_id),
+                "request_id" => format!("{:?}", id),
+                "method_id" => method_id
+            );
+            if let Some(requests) = self.pending_requests.get_mut(peer_id) {
+                requests.remove(&id);
+                if requests.is_empty() {
+                    self.pending_requests.remove(peer_id);
+                }
+            }
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    peer_id.clone(),
+                    RPCEvent::Error {
+                        id,
+                        method_id,
+                        error: RPCError::DialFailed,
+                    },
+                )));
+        }
+    }
+
+    /// Fails the inbound request `id` from `peer_id` with an error in place of a response. An error
+    /// sent after chunks of a streamed response terminates the stream in place of
+    /// `terminate_stream`.
+    ///
+    /// Returns `None` if the peer is not awaiting a response to the request.
+    pub fn send_error(
+        &mut self,
+        peer_id: PeerId,
+        id: RequestId,
+        code: RPCErrorCode,
+        message: Option<String>,
+    ) -> Option<RequestId> {
+        let method_id = self.inbound_requests.get(&peer_id)?.get(&id)?.method_id;
+        self.send_rpc(
+            peer_id,
+            RPCEvent::Error {
+                id,
+                method_id,
+                error: RPCError::new(code, message),
+            },
+        )
+    }
+
+    /// Sends `response` as the next chunk of the streamed response to the inbound request `id`
+    /// from `peer_id`, leaving the stream open for further chunks.
+    ///
+    /// Returns `None` if the peer is not awaiting a response to the request or the chunk was
+    /// dropped.
+    pub fn send_stream_chunk(
+        &mut self,
+        peer_id: PeerId,
+        id: RequestId,
+        response: RPCResponse,
+    ) -> Option<RequestId> {
+        self.send_rpc(
+            peer_id,
+            RPCEvent::Response {
+                id,
+                method_id: response.method_id(),
+                result: response,
+            },
+        )
+    }
+
+    /// Terminates the streamed response to the inbound request `id` from `peer_id`.
+    ///
+    /// Returns `None` if the peer is not awaiting a response to the request.
+    pub fn terminate_stream(&mut self, peer_id: PeerId, id: RequestId) -> Option<RequestId> {
+        let method_id = self.inbound_requests.get(&peer_id)?.get(&id)?.method_id;
+        self.send_rpc(peer_id, RPCEvent::StreamTermination { id, method_id })
+    }
+
+    /// Returns `true` if the peer is awaiting a response to the inbound request `id`, removing it
+    /// if `remove` is set and otherwise recording the activity on the request.
+    fn respond_to_request(
+        &mut self,
+        peer_id: &PeerId,
+        id: RequestId,
+        method_id: u16,
+        remove: bool,
+    ) -> bool {
+        let request = self
+            .inbound_requests
+            .get_mut(peer_id)
+            .and_then(|requests| requests.get_mut(&id))
+            .filter(|request| request.method_id == method_id);
+        match request {
+            Some(request) => request.last_activity = Instant::now(),
+            None => {
+                debug!(
+                    self.log,
+                    "Not sending RPC response to unknown request";
+                    "peer" => format!("{:?}", peer_id),
+                    "request_id" => format!("{:?}", id),
+                    "method_id" => method_id
+                );
+                return false;
+            }
+        }
+
+        if remove {
+            if let Some(requests) = self.inbound_requests.get_mut(peer_id) {
+                requests.remove(&id);
+                if requests.is_empty() {
+                    // the peer has caught up, forgive past offences
+                    self.inbound_requests.remove(peer_id);
+                    self.concurrency_offences.remove(peer_id);
+                }
+            }
+        }
+        true
+    }
+
+    /// Registers `handler` to answer inbound requests of its methods on a dedicated thread,
+    /// replacing any handler registered before.
+    ///
+    /// Requests the handler answers are not passed to the user. A handler which panics fails the
+    /// request with a `ServerError`.
+    pub fn set_request_handler(&mut self, handler: Arc<dyn RequestHandler>) -> io::Result<()> {
+        self.request_handler = Some(HandlerWorker::spawn(handler)?);
+        Ok(())
+    }
+
+    /// Sends the answers the request handler has produced, returning `true` if there were any.
+    fn poll_request_handler(&mut self) -> bool {
+        let mut handled_any = false;
+        while let Some(handled) = self
+            .request_handler
+            .as_mut()
+            .and_then(HandlerWorker::poll_result)
+        {
+            handled_any = true;
+            self.send_handled(handled);
+        }
+        handled_any
+    }
+
+    /// Sends the request handler's answer to an inbound request.
+    fn send_handled(&mut self, handled: HandledRequest) {
+        let HandledRequest {
+            peer_id,
+            id,
+            result,
+        } = handled;
+        let method_id = match self
+            .inbound_requests
+            .get(&peer_id)
+            .and_then(|requests| requests.get(&id))
+        {
+            Some(request) => request.method_id,
+            // the peer disconnected while the request was being handled
+            None => return,
+        };
+
+        let responses = match result {
+            Ok(responses) => responses,
+            Err(error) => {
+                self.send_rpc(
+                    peer_id,
+                    RPCEvent::Error {
+                        id,
+                        method_id,
+                        error,
+                    },
+                );
+                return;
+            }
+        };
+
+        if RPCMethod::from(method_id).is_streamed() {
+            for response in responses {
+                // a dropped chunk aborts the stream
+                if self
+                    .send_stream_chunk(peer_id.clone(), id, response)
+                    .is_none()
+                {
+                    return;
+                }
+            }
+            self.terminate_stream(peer_id, id);
+        } else {
+            match responses.into_iter().next() {
+                Some(response) => {
+                    self.send_rpc(
+                        peer_id,
+                        RPCEvent::Response {
+                            id,
+                            method_id: response.method_id(),
+                            result: response,
+                        },
+                    );
+                }
+                None => {
+                    self.send_error(
+                        peer_id,
+                        id,
+                        RPCErrorCode::ServerError,
+                        Some("No response".to_string()),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the version of the method `method_id` negotiated with the peer, if known.
+    ///
+    /// The version is learned from the latest RPC of the method sent to or received from the peer.
+    pub fn peer_version(&self, peer_id: &PeerId, method_id: u16) -> Option<RPCVersion> {
+        self.peer_capabilities
+            .get(peer_id)
+            .and_then(|versions| versions.get(&method_id))
+            .cloned()
+    }
+
+    /// Returns the version of the method `method_id` a request to the peer can rely on.
+    ///
+    /// Each version supports all methods of the previous versions, so without a version negotiated
+    /// for the method, the latest version negotiated for any method is assumed. A peer no version
+    /// has been negotiated with is assumed to only support `RPCVersion::V1`, until an RPC with it
+    /// tells us otherwise.
+    fn assumed_version(&self, peer_id: &PeerId, method_id: u16) -> RPCVersion {
+        let versions = match self.peer_capabilities.get(peer_id) {
+            Some(versions) => versions,
+            None => return RPCVersion::V1,
+        };
+        versions
+            .get(&method_id)
+            .or_else(|| versions.values().max())
+            .cloned()
+            .unwrap_or(RPCVersion::V1)
+    }
+
+    /// Returns the version of each method negotiated with each peer, keyed by method id.
+    pub fn peer_capabilities(&self) -> &HashMap<PeerId, HashMap<u16, RPCVersion>> {
+        &self.peer_capabilities
+    }
+
+    /// Records the protocol negotiated with a peer for an RPC.
+    fn record_protocol(&mut self, peer_id: &PeerId, protocol: &ProtocolId) {
+        self.peer_capabilities
+            .entry(peer_id.clone())
+            .or_insert_with(HashMap::new)
+            .insert(protocol.method_id, protocol.version);
+    }
+
+    /// Rejects a request from `source` whose payload exceeded the size limit of its method, and
+    /// was therefore not decoded.
+    fn on_oversized_request(&mut self, source: PeerId, id: RequestId, method_id: u16, size: usize) {
+        debug!(
+            self.log,
+            "Rejecting oversized RPC request";
+            "peer" => format!("{:?}", source),
+            "request_id" => format!("{:?}", id),
+            "method_id" => method_id,
+            "size" => size
+        );
+        self.push_send(
+            source,
+            RPCEvent::Error {
+                id,
+                method_id,
+                error: RPCError::new(
+                    RPCErrorCode::InvalidRequest,
+                    "Request too large".to_string(),
+                ),
+            },
+        );
+    }
+
+    /// Logs and counts an RPC from `source` over `protocol` which could not be read or decoded.
+    /// The peer is left to time out any request it was making.
+    fn on_inbound_failure(&mut self, source: &PeerId, error: DecodeError, protocol: &ProtocolId) {
+        debug!(
+            self.log,
+            "Failed to receive RPC";
+            "peer" => format!("{:?}", source),
+            "method_id" => protocol.method_id,
+            "error" => format!("{:?}", error)
+        );
+        self.inbound_failures.inc();
+    }
+
+    /// Fails the RPC `id` to `peer_id` whose substream failed with `error` before it was sent.
+    ///
+    /// Our requests are failed with `error`, so they can be retried elsewhere. Responses are
+    /// dropped, leaving the peer to time out its request.
+    fn on_send_failed(
+        &mut self,
+        peer_id: &PeerId,
+        id: RequestId,
+        method_id: u16,
+        request: bool,
+        error: RPCError,
+    ) {
+        self.pop_outbound(peer_id);
+        lifecycle!(
+            self,
+            "Failed to send RPC";
+            "peer" => format!("{:?}", peer_id),
+            "request_id" => format!("{:?}", id),
+            "method_id" => method_id,
+            "error" => format!("{:?}", error)
+        );
+        let chunks = self
+            .pending_requests
+            .get(peer_id)
+            .and_then(|requests| requests.get(&id))
+            .filter(|pending| request && pending.method_id == method_id)
+            .map(|pending| pending.chunks);
+        if let Some(chunks) = chunks {
+            self.complete_request(peer_id, id, method_id, true);
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    peer_id.clone(),
+                    RPCEvent::Error {
+                        id,
+                        method_id,
+                        error: request_failure(chunks, error),
+                    },
+                )));
+        }
+    }
+
+    /// Processes an RPC received from `source` over `protocol`.
+    ///
+    /// Requests on a version of their method which does not support them are failed with a
+    /// `ServerError`.
+    fn on_inbound(&mut self, source: PeerId, event: RPCEvent, protocol: ProtocolId) {
+        self.record_protocol(&source, &protocol);
+
+        if let RPCEvent::Request {
+            id,
+            method_id,
+            body,
+        } = &event
+        {
+            if body.min_version() > protocol.version {
+                debug!(
+                    self.log,
+                    "Rejecting RPC request unsupported by its protocol version";
+                    "peer" => format!("{:?}", source),
+                    "request_id" => format!("{:?}", id),
+                    "method_id" => *method_id,
+                    "version" => format!("{:?}", protocol.version)
+                );
+                self.push_send(
+                    source,
+                    RPCEvent::Error {
+                        id: *id,
+                        method_id: *method_id,
+                        error: RPCError::new(
+                            RPCErrorCode::ServerError,
+                            format!("Method not supported in version {:?}", protocol.version),
+                        ),
+                    },
+                );
+                return;
+            }
+        }
+
+        self.on_rpc_event(source, event);
+    }
+
+    /// Returns the number of requests sent to `peer_id` that are awaiting a response.
+    pub fn pending_requests(&self, peer_id: &PeerId) -> usize {
+        self.pending_requests
+            .get(peer_id)
+            .map(HashMap::len)
+            .unwrap_or(0)
+    }
+
+    /// Returns the number of requests to `peer_id` waiting for the peer to be dialed.
+    pub fn queued_requests(&self, peer_id: &PeerId) -> usize {
+        self.dial_queue
+            .get(peer_id)
+            .map(|queued| queued.events.len())
+            .unwrap_or(0)
+    }
+
+    /// Processes an RPC received from `source`.
+    ///
+    /// Responses are matched against the pending request with the same id. Responses that do not
+    /// correspond to a pending request of the same method are dropped.
+    fn on_rpc_event(&mut self, source: PeerId, event: RPCEvent) {
+        match &event {
+            // reject requests that exceed the protocol limits before they reach the user
+            RPCEvent::Request {
+                id,
+                method_id,
+                body,
+            } => {
+                if let Err(error) = body.validate() {
+                    debug!(
+                        self.log,
+                        "Rejecting invalid RPC request";
+                        "peer" => format!("{:?}", source),
+                        "request_id" => format!("{:?}", id),
+                        "method_id" => *method_id,
+                        "error" => error.message()
+                    );
+                    self.push_send(
+                        source,
+                        RPCEvent::Error {
+                            id: *id,
+                            method_id: *method_id,
+                            error,
+                        },
+                    );
+                    return;
+                }
+
+                if body.requires_handshake() && !self.handshaken_peers.contains(&source) {
+                    debug!(
+                        self.log,
+                        "Rejecting RPC request from peer before status handshake";
+                        "peer" => format!("{:?}", source),
+                        "request_id" => format!("{:?}", id),
+                        "method_id" => *method_id
+                    );
+                    self.push_send(
+                        source,
+                        RPCEvent::Error {
+                            id: *id,
+                            method_id: *method_id,
+                            error: RPCError::new(
+                                RPCErrorCode::InvalidRequest,
+                                "Status handshake not complete".to_string(),
+                            ),
+                        },
+                    );
+                    return;
+                }
+
+                let in_flight = self
+                    .inbound_requests
+                    .get(&source)
+                    .map(HashMap::len)
+                    .unwrap_or(0);
+                if body.expects_response() && in_flight >= self.max_concurrent_requests {
+                    let offences = self.concurrency_offences.entry(source.clone()).or_insert(0);
+                    *offences = offences.saturating_add(1);
+                    let should_ban = *offences >= MAX_CONCURRENCY_OFFENCES;
+                    debug!(
+                        self.log,
+                        "Refusing RPC request beyond concurrent request limit";
+                        "peer" => format!("{:?}", source),
+                        "request_id" => format!("{:?}", id),
+                        "method_id" => *method_id,
+                        "in_flight" => in_flight,
+                        "offences" => *offences
+                    );
+                    self.push_send(
+                        source.clone(),
+                        RPCEvent::Error {
+                            id: *id,
+                            method_id: *method_id,
+                            error: RPCError::new(
+                                RPCErrorCode::RateLimited,
+                                "Too many concurrent requests".to_string(),
+                            ),
+                        },
+                    );
+                    if should_ban {
+                        self.events.push(NetworkBehaviourAction::GenerateEvent(
+                            RPCMessage::PeerShouldBan(source),
+                        ));
+                    }
+                    return;
+                }
+
+                let limited = if self.rate_limit_exempt.contains(&source) {
+                    RateLimitResult::Allowed
+                } else {
+                    self.rate_limiter
+                        .allows(&source, *method_id, Instant::now())
+                };
+                match limited {
+                    RateLimitResult::Allowed => {}
+                    result => {
+                        debug!(
+                            self.log,
+                            "Rate limiting RPC request";
+                            "peer" => format!("{:?}", source),
+                            "request_id" => format!("{:?}", id),
+                            "method_id" => *method_id
+                        );
+                        self.push_send(
+                            source.clone(),
+                            RPCEvent::Error {
+                                id: *id,
+                                method_id: *method_id,
+                                error: RPCError::new(RPCErrorCode::RateLimited, None),
+                            },
+                        );
+                        if result == RateLimitResult::Ban {
+                            self.events.push(NetworkBehaviourAction::GenerateEvent(
+                                RPCMessage::PeerShouldBan(source),
+                            ));
+                        }
+                        return;
+                    }
+                }
+
+                lifecycle!(
+                    self,
+                    "RPC request received";
+                    "peer" => format!("{:?}", source),
+                    "request_id" => format!("{:?}", id),
+                    "method_id" => *method_id
+                );
+                if let RPCRequest::Hello(_) = body {
+                    self.handshaken_peers.insert(source.clone());
+                }
+
+                if body.expects_response() {
+                    self.inbound_requests
+                        .entry(source.clone())
+                        .or_insert_with(HashMap::new)
+                        .insert(
+                            *id,
+                            InboundRequest {
+                                method_id: *method_id,
+                                last_activity: Instant::now(),
+                            },
+                        );
+                }
+            }
+            RPCEvent::Response {
+                id,
+                method_id,
+                result,
+            } => {
+                // streamed responses remain pending until the stream is terminated
+                if !self.complete_request(&source, *id, *method_id, !result.is_streamed()) {
+                    return;
+                }
+                lifecycle!(
+                    self,
+                    "RPC response received";
+                    "peer" => format!("{:?}", source),
+                    "request_id" => format!("{:?}", id),
+                    "method_id" => *method_id,
+                    "response" => event_kind(&event)
+                );
+                if let RPCResponse::Hello(_) = result {
+                    self.handshaken_peers.insert(source.clone());
+                }
+            }
+            RPCEvent::StreamTermination { id, method_id }
+            | RPCEvent::Error { id, method_id, .. } => {
+                if !self.complete_request(&source, *id, *method_id, true) {
+                    return;
+                }
+                lifecycle!(
+                    self,
+                    "RPC request completed";
+                    "peer" => format!("{:?}", source),
+                    "request_id" => format!("{:?}", id),
+                    "method_id" => *method_id,
+                    "response" => event_kind(&event)
+                );
+            }
+        }
+
+        // requests of the methods the request handler answers are not passed to the user
+        if let (
+            RPCEvent::Request {
+                id,
+                method_id,
+                body,
+            },
+            Some(worker),
+        ) = (&event, &self.request_handler)
+        {
+            if worker.handles(*method_id) {
+                worker.submit(source, *id, body.clone());
+                return;
+            }
+        }
+
+        // send the event to the user
+        self.events
+            .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                source, event,
+            )));
+    }
+
+    /// Returns `true` if a request with `id` and `method_id` is pending for `peer_id`, removing it
+    /// if `remove` is set.
+    fn complete_request(
+        &mut self,
+        peer_id: &PeerId,
+        id: RequestId,
+        method_id: u16,
+        remove: bool,
+    ) -> bool {
+        let requests = match self.pending_requests.get_mut(peer_id) {
+            Some(requests) => requests,
+            None => return false,
+        };
+
+        match requests.get_mut(&id) {
+            Some(request) if request.method_id == method_id => {
+                // the peer is making progress, restart the timeout
+                request.deadline = Instant::now() + request.timeout;
+                if !remove {
+                    request.chunks += 1;
+                }
+            }
+            _ => {
+                debug!(
+                    self.log,
+                    "Dropping unsolicited RPC response";
+                    "peer" => format!("{:?}", peer_id),
+                    "request_id" => format!("{:?}", id),
+                    "method_id" => method_id
+                );
+                return false;
+            }
+        }
+
+        if remove {
+            requests.remove(&id);
+            if requests.is_empty() {
+                self.pending_requests.remove(peer_id);
+            }
+            self.dispatch_waiting(peer_id);
+        }
+        true
+    }
+
+    /// Drops all state held for a disconnected peer.
+    ///
+    /// Requests still awaiting a response from the peer are failed with `RPCError::Disconnected`,
+    /// or `RPCError::Incomplete` if part of a streamed response was received.
+    fn on_disconnected(&mut self, peer_id: &PeerId) {
+        self.connected_peers.remove(peer_id);
+        self.handshaken_peers.remove(peer_id);
+        self.remove_outbound_queue(peer_id);
+        // waiting requests are pending, and failed with them
+        self.waiting_requests.remove(peer_id);
+
+        // responses can no longer arrive for requests to this peer, fail any that are pending so
+        // partially received streams are not mistaken for complete ones
+        if let Some(requests) = self.pending_requests.remove(peer_id) {
+            for (id, request) in requests {
+                let error = request_failure(request.chunks, RPCError::Disconnected);
+                lifecycle!(
+                    self,
+                    "RPC request failed on disconnection";
+                    "peer" => format!("{:?}", peer_id),
+                    "request_id" => format!("{:?}", id),
+                    "method_id" => request.method_id,
+                    "error" => format!("{:?}", error)
+                );
+                self.events
+                    .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                        peer_id.clone(),
+                        RPCEvent::Error {
+                            id,
+                            method_id: request.method_id,
+                            error,
+                        },
+                    )));
+            }
+        }
+        self.inbound_requests.remove(peer_id);
+        self.peer_capabilities.remove(peer_id);
+        self.rate_limiter.remove_peer(peer_id);
+        // the counter may never have been set for the peer
+        let _ = self.bytes_sent.remove_label_values(&[&peer_id.to_base58()]);
+        self.concurrency_offences.remove(peer_id);
+
+        self.events.push(NetworkBehaviourAction::GenerateEvent(
+            RPCMessage::PeerDisconnected(peer_id.clone()),
+        ));
+    }
+
+    /// Fails all pending requests whose deadline has passed at `now`, the requests queued for
+    /// peers that have not connected within `DIAL_TIMEOUT` and the inbound requests which have
+    /// gone without a response for `idle_timeout`.
+    fn check_timeouts(&mut self, now: Instant) {
+        self.close_idle_requests(now);
+
+        let failed_dials: Vec<PeerId> = self
+            .dial_queue
+            .iter()
+            .filter(|(_peer_id, queued)| queued.deadline <= now)
+            .map(|(peer_id, _queued)| peer_id.clone())
+            .collect();
+        for peer_id in failed_dials {
+            self.on_dial_failure(&peer_id);
+        }
+
+        let mut timed_out = vec![];
+        for (peer_id, requests) in self.pending_requests.iter_mut() {
+            // requests awaiting a dial or earlier requests have yet to be sent
+            let expired: Vec<RequestId> = requests
+                .iter()
+                .filter(|(_id, request)| request.sent && request.deadline <= now)
+                .map(|(id, _request)| *id)
+                .collect();
+            for id in expired {
+                if let Some(request) = requests.remove(&id) {
+                    timed_out.push((peer_id.clone(), id, request));
+                }
+            }
+        }
+        self.pending_requests
+            .retain(|_peer_id, requests| !requests.is_empty());
+
+        for (peer_id, id, request) in timed_out {
+            self.dispatch_waiting(&peer_id);
+            lifecycle!(
+                self,
+                "RPC request timed out";
+                "peer" => format!("{:?}", peer_id),
+                "request_id" => format!("{:?}", id),
+                "method_id" => request.method_id,
+                "timeout" => format!("{:?}", request.timeout)
+            );
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    peer_id,
+                    RPCEvent::Error {
+                        id,
+                        method_id: request.method_id,
+                        error: request_failure(request.chunks, RPCError::Timeout),
+                    },
+                )));
+        }
+    }
+
+    /// Closes the inbound requests which have gone without a response for `idle_timeout` at
+    /// `now`, failing them with a `ServerError` so the peer stops waiting. The user is told by an
+    /// `RPCMessage::InboundRequestIdle`, and later responses to the requests are dropped.
+    ///
+    /// Our own requests are bounded by their deadlines instead, which restart on each response.
+    fn close_idle_requests(&mut self, now: Instant) {
+        let idle_timeout = self.idle_timeout;
+        let mut idle = vec![];
+        for (peer_id, requests) in self.inbound_requests.iter() {
+            for (id, request) in requests {
+                if request.last_activity + idle_timeout <= now {
+                    idle.push((peer_id.clone(), *id, request.method_id));
+                }
+            }
+        }
+
+        for (peer_id, id, method_id) in idle {
+            lifecycle!(
+                self,
+                "Closing idle RPC request";
+                "peer" => format!("{:?}", peer_id),
+                "request_id" => format!("{:?}", id),
+                "method_id" => method_id
+            );
+            let error = RPCEvent::Error {
+                id,
+                method_id,
+                error: RPCError::new(RPCErrorCode::ServerError, "Request idle".to_string()),
+            };
+            if self.respond_to_request(&peer_id, id, method_id, true) {
+                self.push_send(peer_id.clone(), error);
+            }
+            self.events.push(NetworkBehaviourAction::GenerateEvent(
+                RPCMessage::InboundRequestIdle(peer_id, id),
+            ));
+        }
     }
 }
 
@@ -52,11 +1598,16 @@ impl<TSubstream> NetworkBehaviour for Rpc<TSubstream>
 where
     TSubstream: AsyncRead + AsyncWrite,
 {
-    type ProtocolsHandler = OneShotHandler<TSubstream, RPCProtocol, RPCEvent, OneShotEvent>;
+    type ProtocolsHandler = RPCHandler<TSubstream>;
     type OutEvent = RPCMessage;
 
     fn new_handler(&mut self) -> Self::ProtocolsHandler {
-        Default::default()
+        RPCHandler::new(
+            SubstreamProtocol::new(RPCProtocol {
+                size_limits: self.size_limits,
+            })
+            .with_timeout(CHUNK_TIMEOUT),
+        )
     }
 
     fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
@@ -64,6 +1615,8 @@ where
     }
 
     fn inject_connected(&mut self, peer_id: PeerId, connected_point: ConnectedPoint) {
+        self.on_connected(peer_id.clone());
+
         // if initialised the connection, report this upwards to send the HELLO request
         if let ConnectedPoint::Dialer { .. } = connected_point {
             self.events.push(NetworkBehaviourAction::GenerateEvent(
@@ -72,24 +1625,40 @@ where
         }
     }
 
-    fn inject_disconnected(&mut self, _: &PeerId, _: ConnectedPoint) {}
+    fn inject_disconnected(&mut self, peer_id: &PeerId, _: ConnectedPoint) {
+        self.on_disconnected(peer_id);
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.on_dial_failure(peer_id);
+    }
 
     fn inject_node_event(
         &mut self,
         source: PeerId,
         event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
     ) {
-        // ignore successful send events
-        let event = match event {
-            OneShotEvent::Rx(event) => event,
-            OneShotEvent::Sent => return,
-        };
-
-        // send the event to the user
-        self.events
-            .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
-                source, event,
-            )));
+        match event {
+            OneShotEvent::Rx(event, protocol) => self.on_inbound(source, event, protocol),
+            OneShotEvent::OversizedRequest {
+                id,
+                method_id,
+                size,
+            } => self.on_oversized_request(source, id, method_id, size),
+            OneShotEvent::InboundFailed(error, protocol) => {
+                self.on_inbound_failure(&source, error, &protocol)
+            }
+            OneShotEvent::Sent(protocol) => {
+                self.record_protocol(&source, &protocol);
+                self.on_sent(&source);
+            }
+            OneShotEvent::SendFailed {
+                id,
+                method_id,
+                request,
+                error,
+            } => self.on_send_failed(&source, id, method_id, request, error),
+        }
     }
 
     fn poll(
@@ -101,38 +1670,1961 @@ where
             Self::OutEvent,
         >,
     > {
-        if !self.events.is_empty() {
-            return Async::Ready(self.events.remove(0));
+        loop {
+            match self.timeout_check.poll() {
+                Ok(Async::Ready(_)) => {
+                    let now = Instant::now();
+                    self.check_timeouts(now);
+                    self.rate_limiter.prune(now);
+                }
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    warn!(self.log, "RPC timeout check failed: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        self.poll_request_handler();
+
+        let sends_allowed = self.sent_since_yield < MAX_SENDS_PER_POLL;
+        if let Some(event) = self.next_event(sends_allowed) {
+            return Async::Ready(match event {
+                NetworkBehaviourAction::SendEvent { peer_id, event } => {
+                    let event = self.outbound_rpc(&peer_id, event);
+                    NetworkBehaviourAction::SendEvent { peer_id, event }
+                }
+                other => {
+                    other.map_in(|_| unreachable!("only sends carry an event for the handler"))
+                }
+            });
+        }
+        self.sent_since_yield = 0;
+        if !sends_allowed && !self.send_rotation.is_empty() {
+            // hand over the remaining sends once the swarm has written those it has
+            task::current().notify();
         }
         Async::NotReady
     }
 }
 
+/// Names the kind of `event`, for logging.
+fn event_kind(event: &RPCEvent) -> &'static str {
+    match event {
+        RPCEvent::Request { .. } => "request",
+        RPCEvent::Response { result, .. } if result.is_streamed() => "chunk",
+        RPCEvent::Response { .. } => "response",
+        RPCEvent::StreamTermination { .. } => "stream termination",
+        RPCEvent::Error { .. } => "error",
+    }
+}
+
+/// An outbound request awaiting a response.
+struct PendingRequest {
+    /// The method id of the request, to verify the response against.
+    method_id: u16,
+    /// The time allowed between responses.
+    timeout: Duration,
+    /// The time after which the request fails.
+    deadline: Instant,
+    /// The number of streamed response chunks received.
+    chunks: usize,
+    /// Whether the request has been handed to the swarm, rather than waiting for the peer to
+    /// connect or for earlier requests to complete.
+    sent: bool,
+}
+
+/// The limits on the RPCs waiting to be sent to a single peer, and on the requests in flight to
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundQueueLimits {
+    /// The maximum number of RPCs waiting to be sent.
+    pub max_events: usize,
+    /// The maximum total SSZ encoded size of the RPCs waiting to be sent.
+    pub max_bytes: u64,
+    /// The maximum number of requests sent and awaiting a response at once.
+    pub max_requests_in_flight: usize,
+    /// The maximum number of requests waiting for requests in flight to complete. With none,
+    /// requests beyond `max_requests_in_flight` are refused rather than queued.
+    pub max_waiting_requests: usize,
+}
+
+impl Default for OutboundQueueLimits {
+    fn default() -> Self {
+        OutboundQueueLimits {
+            max_events: 128,
+            max_bytes: 32 * MAX_CHUNK_SIZE as u64,
+            max_requests_in_flight: 8,
+            max_waiting_requests: 64,
+        }
+    }
+}
+
+/// The RPCs handed to the swarm for a peer which have yet to be sent, oldest first.
+#[derive(Default)]
+struct OutboundQueue {
+    /// The SSZ encoded size of each RPC.
+    sizes: VecDeque<u64>,
+    /// The total size of the RPCs.
+    bytes: u64,
+}
+
+/// An inbound request we have yet to finish responding to.
+struct InboundRequest {
+    /// The method id of the request, to verify responses against.
+    method_id: u16,
+    /// The time the request was received or last responded to.
+    last_activity: Instant,
+}
+
+/// Outbound requests waiting for a peer to be dialed.
+struct QueuedRequests {
+    /// The requests to send once the peer connects, in the order they were made.
+    events: Vec<RPCEvent>,
+    /// The time after which the dial is considered failed.
+    deadline: Instant,
+}
+
 /// Messages sent to the user from the RPC protocol.
 pub enum RPCMessage {
     RPC(PeerId, RPCEvent),
     PeerDialed(PeerId),
+    PeerDisconnected(PeerId),
+    /// Requests are waiting for the peer to be dialed.
+    DialPeer(PeerId),
+    /// The peer has repeatedly exceeded its RPC quotas.
+    PeerShouldBan(PeerId),
+    /// The inbound request was closed after going without a response for the idle timeout.
+    /// Responses to it are dropped.
+    InboundRequestIdle(PeerId, RequestId),
 }
 
-/// Transmission between the `OneShotHandler` and the `RPCEvent`.
+/// Transmission between the `RPCHandler` and the `RPCEvent`.
 #[derive(Debug)]
 pub enum OneShotEvent {
-    /// We received an RPC from a remote.
-    Rx(RPCEvent),
-    /// We successfully sent an RPC request.
-    Sent,
+    /// We received an RPC from a remote, using the given protocol.
+    Rx(RPCEvent, ProtocolId),
+    /// We received a request exceeding the size limit of its method from a remote.
+    OversizedRequest {
+        id: RequestId,
+        method_id: u16,
+        size: usize,
+    },
+    /// We received an RPC from a remote which could not be read or decoded.
+    InboundFailed(DecodeError, ProtocolId),
+    /// We successfully sent an RPC, using the given protocol.
+    Sent(ProtocolId),
+    /// We failed to send an RPC, as its substream could not be negotiated or was closed.
+    SendFailed {
+        id: RequestId,
+        method_id: u16,
+        request: bool,
+        error: RPCError,
+    },
 }
 
-impl From<RPCEvent> for OneShotEvent {
+impl From<(InboundFrame, ProtocolId)> for OneShotEvent {
     #[inline]
-    fn from(rpc: RPCEvent) -> OneShotEvent {
-        OneShotEvent::Rx(rpc)
+    fn from((frame, protocol): (InboundFrame, ProtocolId)) -> OneShotEvent {
+        match frame {
+            InboundFrame::Event(rpc) => OneShotEvent::Rx(rpc, protocol),
+            InboundFrame::OversizedRequest {
+                id,
+                method_id,
+                size,
+            } => OneShotEvent::OversizedRequest {
+                id,
+                method_id,
+                size,
+            },
+            InboundFrame::Failed(error) => OneShotEvent::InboundFailed(error, protocol),
+        }
     }
 }
 
-impl From<()> for OneShotEvent {
+impl From<OutboundOutcome> for OneShotEvent {
     #[inline]
-    fn from(_: ()) -> OneShotEvent {
-        OneShotEvent::Sent
+    fn from(outcome: OutboundOutcome) -> OneShotEvent {
+        match outcome {
+            OutboundOutcome::Sent(protocol) => OneShotEvent::Sent(protocol),
+            OutboundOutcome::Failed {
+                id,
+                method_id,
+                request,
+                error,
+            } => OneShotEvent::SendFailed {
+                id,
+                method_id,
+                request,
+                error,
+            },
+        }
+    }
+}
+
+/// The error failing a pending request for `error`, or `RPCError::Incomplete` once `chunks` of a
+/// streamed response have been received, so a cut off stream is not mistaken for a request which
+/// returned nothing.
+fn request_failure(chunks: usize, error: RPCError) -> RPCError {
+    if chunks > 0 {
+        RPCError::Incomplete(chunks)
+    } else {
+        error
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::methods::{
+        BeaconBlockRootsRequest, BeaconBlockRootsResponse, BeaconBlocksByRangeRequest,
+    };
+    use futures::future;
+    use prometheus::Opts;
+    use types::{BeaconBlock, Epoch, EthSpec, Hash256, MainnetEthSpec, Slot};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+    const MAX_CONCURRENT_REQUESTS: usize = 16;
+    const ENCODINGS: &[RPCEncoding] = &[RPCEncoding::SSZSnappy, RPCEncoding::SSZ];
+    const COMPRESSION_THRESHOLD: usize = 128;
+
+    fn new_rpc(quotas: RPCQuotas, max_concurrent_requests: usize) -> Rpc<()> {
+        new_rpc_with_outbound_limits(
+            quotas,
+            max_concurrent_requests,
+            OutboundQueueLimits::default(),
+        )
+    }
+
+    fn new_rpc_with_outbound_limits(
+        quotas: RPCQuotas,
+        max_concurrent_requests: usize,
+        outbound_limits: OutboundQueueLimits,
+    ) -> Rpc<()> {
+        let log = slog::Logger::root(slog::Discard, o!());
+        new_rpc_with_log(
+            quotas,
+            max_concurrent_requests,
+            outbound_limits,
+            false,
+            &log,
+        )
+    }
+
+    fn new_rpc_with_log(
+        quotas: RPCQuotas,
+        max_concurrent_requests: usize,
+        outbound_limits: OutboundQueueLimits,
+        trace: bool,
+        log: &slog::Logger,
+    ) -> Rpc<()> {
+        let queue_depth = IntGauge::new("queue_depth", "queue_depth").expect("valid gauge");
+        let bytes_sent = IntCounterVec::new(Opts::new("bytes_sent", "bytes_sent"), &["peer"])
+            .expect("valid counter");
+        let inbound_failures =
+            IntCounter::new("inbound_failures", "inbound_failures").expect("valid counter");
+        Rpc::new(
+            TIMEOUT,
+            IDLE_TIMEOUT,
+            quotas,
+            RPCSizeLimits::default(),
+            max_concurrent_requests,
+            ENCODINGS,
+            COMPRESSION_THRESHOLD,
+            outbound_limits,
+            queue_depth,
+            bytes_sent,
+            inbound_failures,
+            trace,
+            log,
+        )
+    }
+
+    fn request(id: u64, body: RPCRequest) -> RPCEvent {
+        RPCEvent::Request {
+            id: RequestId::from(id),
+            method_id: body.method_id(),
+            body,
+        }
+    }
+
+    fn response(id: u64, result: RPCResponse) -> RPCEvent {
+        RPCEvent::Response {
+            id: RequestId::from(id),
+            method_id: result.method_id(),
+            result,
+        }
+    }
+
+    fn roots_request() -> RPCRequest {
+        RPCRequest::BeaconBlockRoots(BeaconBlockRootsRequest {
+            start_slot: Slot::new(0),
+            count: 10,
+        })
+    }
+
+    fn roots_response() -> RPCResponse {
+        RPCResponse::BeaconBlockRoots(BeaconBlockRootsResponse { roots: vec![] })
+    }
+
+    /// Marks `peer_id` as having sent us its `Hello`.
+    fn complete_handshake(rpc: &mut Rpc<()>, peer_id: &PeerId) {
+        rpc.handshaken_peers.insert(peer_id.clone());
+    }
+
+    /// Records a `RPCVersion::V2` hello with the peer, so that requests of any method are sent.
+    fn negotiate_v2(rpc: &mut Rpc<()>, peer_id: &PeerId) {
+        rpc.record_protocol(
+            peer_id,
+            &ProtocolId::new(RPCMethod::Hello, RPCVersion::V2, RPCEncoding::SSZSnappy),
+        );
+    }
+
+    fn hello() -> HelloMessage {
+        HelloMessage {
+            network_id: 0,
+            genesis_root: Hash256::zero(),
+            latest_finalized_root: Hash256::zero(),
+            latest_finalized_epoch: Epoch::new(0),
+            best_root: Hash256::zero(),
+            best_slot: Slot::new(0),
+        }
+    }
+
+    /// Returns the ids of the RPC responses the behaviour has passed to the user.
+    fn routed_response_ids(rpc: &Rpc<()>, peer_id: &PeerId) -> Vec<RequestId> {
+        rpc.events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(source, event))
+                    if source == peer_id =>
+                {
+                    Some(event.id())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_requests_to_one_peer() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        let first = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be sent");
+        let second = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be sent");
+        assert_ne!(first, second);
+        assert_eq!(rpc.pending_requests(&peer_id), 2);
+
+        // responses arrive out of order
+        rpc.on_rpc_event(peer_id.clone(), response(second.into(), roots_response()));
+        rpc.on_rpc_event(peer_id.clone(), response(first.into(), roots_response()));
+
+        assert_eq!(routed_response_ids(&rpc, &peer_id), vec![second, first]);
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+    }
+
+    #[test]
+    fn interleaved_streams_from_one_peer() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+        let range_request = || {
+            RPCRequest::BeaconBlocksByRange(BeaconBlocksByRangeRequest {
+                start_slot: Slot::new(0),
+                count: 10,
+                step: 1,
+            })
+        };
+        let block = || {
+            RPCResponse::BeaconBlocksByRange(Box::new(BeaconBlock::empty(
+                &MainnetEthSpec::default_spec(),
+            )))
+        };
+        let termination = |id: RequestId| RPCEvent::StreamTermination {
+            id,
+            method_id: RPCMethod::BeaconBlocksByRange.into(),
+        };
+
+        let first = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .expect("request should be sent");
+        let second = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .expect("request should be sent");
+
+        rpc.on_rpc_event(peer_id.clone(), response(second.into(), block()));
+        rpc.on_rpc_event(peer_id.clone(), response(first.into(), block()));
+        rpc.on_rpc_event(peer_id.clone(), response(second.into(), block()));
+        rpc.on_rpc_event(peer_id.clone(), termination(first));
+        assert_eq!(rpc.pending_requests(&peer_id), 1);
+        rpc.on_rpc_event(peer_id.clone(), termination(second));
+
+        assert_eq!(
+            routed_response_ids(&rpc, &peer_id),
+            vec![second, first, second, first, second]
+        );
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+    }
+
+    fn range_request() -> RPCRequest {
+        RPCRequest::BeaconBlocksByRange(BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(0),
+            count: 10,
+            step: 1,
+        })
+    }
+
+    fn range_block() -> RPCResponse {
+        RPCResponse::BeaconBlocksByRange(Box::new(BeaconBlock::empty(
+            &MainnetEthSpec::default_spec(),
+        )))
+    }
+
+    /// Returns the errors the behaviour has passed to the user, by request id.
+    fn routed_errors(rpc: &Rpc<()>) -> Vec<(RequestId, RPCError)> {
+        rpc.events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    _,
+                    RPCEvent::Error { id, error, .. },
+                )) => Some((*id, error.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blocks_streamed_in_chunks() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        complete_handshake(&mut rpc, &peer_id);
+        let id = RequestId::from(4);
+
+        rpc.on_rpc_event(peer_id.clone(), request(4, range_request()));
+        rpc.events.clear();
+
+        // blocks are sent one at a time as they are read
+        for _ in 0..3 {
+            assert_eq!(
+                rpc.send_stream_chunk(peer_id.clone(), id, range_block()),
+                Some(id)
+            );
+        }
+        assert_eq!(rpc.terminate_stream(peer_id.clone(), id), Some(id));
+        assert!(rpc
+            .send_stream_chunk(peer_id.clone(), id, range_block())
+            .is_none());
+
+        let sent: Vec<bool> = rpc
+            .events
+            .iter()
+            .map(|event| match event {
+                NetworkBehaviourAction::SendEvent {
+                    event: RPCEvent::Response { .. },
+                    ..
+                } => false,
+                NetworkBehaviourAction::SendEvent {
+                    event: RPCEvent::StreamTermination { .. },
+                    ..
+                } => true,
+                _ => panic!("expected only chunks and a termination to be sent"),
+            })
+            .collect();
+        assert_eq!(sent, vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn oversized_chunk_aborts_stream() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .expect("request should be sent");
+        rpc.events.clear();
+
+        rpc.on_rpc_event(peer_id.clone(), response(id.into(), range_block()));
+        // the protocol replaces an oversized chunk with an error
+        rpc.on_rpc_event(
+            peer_id.clone(),
+            RPCEvent::Error {
+                id,
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+                error: RPCError::ResponseTooLarge(MAX_CHUNK_SIZE + 1),
+            },
+        );
+        // later chunks of the aborted stream are dropped
+        rpc.on_rpc_event(peer_id.clone(), response(id.into(), range_block()));
+
+        assert_eq!(routed_response_ids(&rpc, &peer_id), vec![id, id]);
+        assert_eq!(
+            routed_errors(&rpc),
+            vec![(id, RPCError::ResponseTooLarge(MAX_CHUNK_SIZE + 1))]
+        );
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+    }
+
+    #[test]
+    fn stream_cut_off_before_termination() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .expect("request should be sent");
+        rpc.events.clear();
+
+        rpc.on_rpc_event(peer_id.clone(), response(id.into(), range_block()));
+        rpc.on_disconnected(&peer_id);
+
+        // the received chunk is followed by an error rather than a termination
+        assert_eq!(routed_response_ids(&rpc, &peer_id), vec![id, id]);
+        assert_eq!(routed_errors(&rpc), vec![(id, RPCError::Incomplete(1))]);
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+    }
+
+    #[test]
+    fn stalled_or_reset_stream_incomplete() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+        let (stalled, reset) = (
+            rpc.send_rpc(peer_id.clone(), request(0, range_request()))
+                .expect("request should be sent"),
+            rpc.send_rpc(peer_id.clone(), request(0, range_request()))
+                .expect("request should be sent"),
+        );
+        rpc.events.clear();
+        for id in &[stalled, reset] {
+            rpc.on_rpc_event(peer_id.clone(), response((*id).into(), range_block()));
+        }
+
+        // the peer closes one stream early and stops sending on the other
+        rpc.on_send_failed(
+            &peer_id,
+            reset,
+            RPCMethod::BeaconBlocksByRange.into(),
+            true,
+            RPCError::StreamReset,
+        );
+        rpc.check_timeouts(Instant::now() + TIMEOUT);
+
+        assert_eq!(
+            routed_errors(&rpc),
+            vec![
+                (reset, RPCError::Incomplete(1)),
+                (stalled, RPCError::Incomplete(1))
+            ]
+        );
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+    }
+
+    #[test]
+    fn stream_endings_distinguished() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+        let send = |rpc: &mut Rpc<()>| {
+            rpc.send_rpc(peer_id.clone(), request(0, range_request()))
+                .expect("request should be sent")
+        };
+        let (complete, failed, cut_off, unanswered) = (
+            send(&mut rpc),
+            send(&mut rpc),
+            send(&mut rpc),
+            send(&mut rpc),
+        );
+        rpc.events.clear();
+        let method_id = RPCMethod::BeaconBlocksByRange.into();
+
+        for id in &[complete, failed, cut_off] {
+            rpc.on_rpc_event(peer_id.clone(), response((*id).into(), range_block()));
+        }
+        rpc.on_rpc_event(
+            peer_id.clone(),
+            RPCEvent::StreamTermination {
+                id: complete,
+                method_id,
+            },
+        );
+        let error = RPCError::new(
+            RPCErrorCode::ResourceUnavailable,
+            "Blocks pruned".to_string(),
+        );
+        rpc.on_rpc_event(
+            peer_id.clone(),
+            RPCEvent::Error {
+                id: failed,
+                method_id,
+                error: error.clone(),
+            },
+        );
+        assert_eq!(rpc.pending_requests(&peer_id), 2);
+        rpc.on_disconnected(&peer_id);
+
+        let terminated: Vec<RequestId> = rpc
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    _,
+                    RPCEvent::StreamTermination { id, .. },
+                )) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(terminated, vec![complete]);
+        let mut errors = routed_errors(&rpc);
+        errors.sort_by_key(|(id, _error)| Into::<u64>::into(*id));
+        assert_eq!(
+            errors,
+            vec![
+                (failed, error),
+                (cut_off, RPCError::Incomplete(1)),
+                (unanswered, RPCError::Disconnected)
+            ]
+        );
+    }
+
+    #[test]
+    fn responses_only_sent_to_received_requests() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        complete_handshake(&mut rpc, &peer_id);
+
+        // the peer chooses the id of its own request
+        rpc.on_rpc_event(peer_id.clone(), request(7, roots_request()));
+        rpc.events.clear();
+
+        assert!(rpc
+            .send_rpc(peer_id.clone(), response(8, roots_response()))
+            .is_none());
+        assert_eq!(
+            rpc.send_rpc(peer_id.clone(), response(7, roots_response())),
+            Some(RequestId::from(7))
+        );
+        // the request has been answered
+        assert!(rpc
+            .send_rpc(peer_id.clone(), response(7, roots_response()))
+            .is_none());
+        assert_eq!(rpc.events.len(), 1);
+    }
+
+    #[test]
+    fn unsolicited_response_is_dropped() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be sent");
+
+        // unknown id and a duplicate response
+        rpc.on_rpc_event(peer_id.clone(), response(5, roots_response()));
+        rpc.on_rpc_event(peer_id.clone(), response(id.into(), roots_response()));
+        rpc.on_rpc_event(peer_id.clone(), response(id.into(), roots_response()));
+
+        assert_eq!(routed_response_ids(&rpc, &peer_id), vec![id]);
+    }
+
+    #[test]
+    fn errors_only_sent_to_received_requests() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        complete_handshake(&mut rpc, &peer_id);
+
+        rpc.on_rpc_event(peer_id.clone(), request(7, roots_request()));
+        rpc.events.clear();
+
+        let id = RequestId::from(7);
+        assert!(rpc
+            .send_error(
+                peer_id.clone(),
+                RequestId::from(8),
+                RPCErrorCode::ServerError,
+                None
+            )
+            .is_none());
+        assert_eq!(
+            rpc.send_error(peer_id.clone(), id, RPCErrorCode::ResourceUnavailable, None),
+            Some(id)
+        );
+        // the error completes the request
+        assert!(rpc
+            .send_rpc(peer_id.clone(), response(7, roots_response()))
+            .is_none());
+
+        match rpc.events.as_slice() {
+            [NetworkBehaviourAction::SendEvent {
+                event: RPCEvent::Error { error, .. },
+                ..
+            }] => assert_eq!(error.code(), RPCErrorCode::ResourceUnavailable.into()),
+            _ => panic!("expected a single error to be sent"),
+        }
+    }
+
+    #[test]
+    fn requests_over_quota_are_refused() {
+        let quotas = RPCQuotas {
+            other: Quota {
+                max_requests: 2,
+                max_response_bytes: 1_000_000,
+                period: Duration::from_secs(10),
+            },
+            ..RPCQuotas::default()
+        };
+        let mut rpc = new_rpc(quotas, MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+        complete_handshake(&mut rpc, &peer_id);
+        complete_handshake(&mut rpc, &other_peer_id);
+
+        for id in 0..3 {
+            rpc.on_rpc_event(peer_id.clone(), request(id, roots_request()));
+        }
+        rpc.on_rpc_event(other_peer_id.clone(), request(0, roots_request()));
+
+        // only requests within the quota reach the user
+        let received = |peer: &PeerId| {
+            rpc.events
+                .iter()
+                .filter(|event| match event {
+                    NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(source, _)) => {
+                        source == peer
+                    }
+                    _ => false,
+                })
+                .count()
+        };
+        assert_eq!(received(&peer_id), 2);
+        assert_eq!(received(&other_peer_id), 1);
+
+        match rpc.events.as_slice() {
+            [_, _, NetworkBehaviourAction::SendEvent {
+                event: RPCEvent::Error { id, error, .. },
+                ..
+            }, _] => {
+                assert_eq!(*id, RequestId::from(2));
+                assert_eq!(error.code(), RPCErrorCode::RateLimited.into());
+            }
+            _ => panic!("expected the third request to be rate limited"),
+        }
+    }
+
+    #[test]
+    fn concurrent_requests_are_capped() {
+        let mut rpc = new_rpc(RPCQuotas::default(), 2);
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+        complete_handshake(&mut rpc, &peer_id);
+        complete_handshake(&mut rpc, &other_peer_id);
+
+        for id in 0..3 {
+            rpc.on_rpc_event(peer_id.clone(), request(id, roots_request()));
+        }
+        rpc.on_rpc_event(other_peer_id.clone(), request(0, roots_request()));
+        assert_eq!(rpc.concurrency_offences.get(&peer_id), Some(&1));
+        assert!(rpc.concurrency_offences.get(&other_peer_id).is_none());
+
+        // the excess request is refused, the other peer is unaffected
+        let refused: Vec<(PeerId, RequestId)> = rpc
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::SendEvent {
+                    peer_id,
+                    event: RPCEvent::Error { id, .. },
+                } => Some((peer_id.clone(), *id)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(refused, vec![(peer_id.clone(), RequestId::from(2))]);
+        assert_eq!(
+            routed_response_ids(&rpc, &other_peer_id),
+            vec![RequestId::from(0)]
+        );
+
+        // answering a request makes room for another
+        rpc.send_rpc(peer_id.clone(), response(0, roots_response()));
+        rpc.events.clear();
+        rpc.on_rpc_event(peer_id.clone(), request(3, roots_request()));
+        assert_eq!(
+            routed_response_ids(&rpc, &peer_id),
+            vec![RequestId::from(3)]
+        );
+    }
+
+    #[test]
+    fn repeatedly_exceeding_concurrency_cap_bans_peer() {
+        let mut rpc = new_rpc(RPCQuotas::default(), 1);
+        let peer_id = PeerId::random();
+        complete_handshake(&mut rpc, &peer_id);
+
+        for id in 0..=u64::from(MAX_CONCURRENCY_OFFENCES) {
+            rpc.on_rpc_event(peer_id.clone(), request(id, roots_request()));
+        }
+
+        let bans = rpc
+            .events
+            .iter()
+            .filter(|event| match event {
+                NetworkBehaviourAction::GenerateEvent(RPCMessage::PeerShouldBan(banned)) => {
+                    *banned == peer_id
+                }
+                _ => false,
+            })
+            .count();
+        assert_eq!(bans, 1);
+    }
+
+    #[test]
+    fn newer_requests_not_sent_to_older_peers() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        rpc.record_protocol(
+            &peer_id,
+            &ProtocolId::new(
+                RPCMethod::BeaconBlocksByRange,
+                RPCVersion::V1,
+                RPCEncoding::SSZ,
+            ),
+        );
+
+        let range_request = RPCRequest::BeaconBlocksByRange(BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(0),
+            count: 10,
+            step: 1,
+        });
+        assert_eq!(range_request.min_version(), RPCVersion::V2);
+
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request))
+            .expect("request should be assigned an id");
+        assert_eq!(
+            routed_errors(&rpc),
+            vec![(id, RPCError::UnsupportedProtocol)]
+        );
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+        rpc.events.clear();
+
+        // requests supported by the peer are still sent
+        assert!(rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .is_some());
+        assert_eq!(rpc.events.len(), 1);
+        assert_eq!(rpc.pending_requests(&peer_id), 1);
+    }
+
+    #[test]
+    fn unknown_versions_fall_back_to_v1() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        // nothing has been negotiated with the peer yet
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .expect("request should be assigned an id");
+        assert_eq!(
+            routed_errors(&rpc),
+            vec![(id, RPCError::UnsupportedProtocol)]
+        );
+        rpc.events.clear();
+        assert!(rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .is_some());
+        // the hello, from which the peer's versions are learned, is offered on every version
+        assert!(rpc
+            .send_rpc(peer_id.clone(), request(0, RPCRequest::Hello(hello())))
+            .is_some());
+
+        // an RPC on the first version tells us the peer does not support the second
+        rpc.record_protocol(
+            &peer_id,
+            &ProtocolId::new(
+                RPCMethod::BeaconBlockRoots,
+                RPCVersion::V1,
+                RPCEncoding::SSZ,
+            ),
+        );
+        rpc.events.clear();
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .expect("request should be assigned an id");
+        assert_eq!(
+            routed_errors(&rpc),
+            vec![(id, RPCError::UnsupportedProtocol)]
+        );
+
+        negotiate_v2(&mut rpc, &peer_id);
+        assert!(rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .is_some());
+    }
+
+    #[test]
+    fn requests_on_unsupported_version_fail() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        complete_handshake(&mut rpc, &peer_id);
+        let range_request = RPCRequest::BeaconBlocksByRange(BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(0),
+            count: 10,
+            step: 1,
+        });
+        let protocol = |version| {
+            ProtocolId::new(
+                RPCMethod::BeaconBlocksByRange,
+                version,
+                RPCEncoding::SSZSnappy,
+            )
+        };
+
+        rpc.on_inbound(
+            peer_id.clone(),
+            request(1, range_request.clone()),
+            protocol(RPCVersion::V1),
+        );
+        match rpc.events.as_slice() {
+            [NetworkBehaviourAction::SendEvent {
+                event: RPCEvent::Error { id, error, .. },
+                ..
+            }] => {
+                assert_eq!(*id, RequestId::from(1));
+                assert_eq!(error.code(), RPCErrorCode::ServerError.into());
+            }
+            _ => panic!("expected the request to be failed"),
+        }
+        rpc.events.clear();
+
+        // the same request on a supporting version reaches the user
+        rpc.on_inbound(
+            peer_id.clone(),
+            request(2, range_request),
+            protocol(RPCVersion::V2),
+        );
+        assert_eq!(
+            routed_response_ids(&rpc, &peer_id),
+            vec![RequestId::from(2)]
+        );
+        assert_eq!(
+            rpc.peer_version(&peer_id, RPCMethod::BeaconBlocksByRange.into()),
+            Some(RPCVersion::V2)
+        );
+    }
+
+    #[test]
+    fn capabilities_recorded_per_method() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let v1_peer = PeerId::random();
+        let v2_peer = PeerId::random();
+        let roots_protocol =
+            |version| ProtocolId::new(RPCMethod::BeaconBlockRoots, version, RPCEncoding::SSZ);
+
+        // the versions our requests were sent with
+        rpc.record_protocol(&v1_peer, &roots_protocol(RPCVersion::V1));
+        rpc.record_protocol(&v2_peer, &roots_protocol(RPCVersion::V2));
+
+        let roots_method: u16 = RPCMethod::BeaconBlockRoots.into();
+        let v2_capable: Vec<&PeerId> = rpc
+            .peer_capabilities()
+            .iter()
+            .filter(|(_peer_id, versions)| versions.get(&roots_method) == Some(&RPCVersion::V2))
+            .map(|(peer_id, _versions)| peer_id)
+            .collect();
+        assert_eq!(v2_capable, vec![&v2_peer]);
+        assert_eq!(rpc.peer_version(&v1_peer, RPCMethod::Hello.into()), None);
+    }
+
+    /// Returns the ids of the timeouts the behaviour has passed to the user.
+    fn timed_out_ids(rpc: &Rpc<()>) -> Vec<RequestId> {
+        rpc.events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    _,
+                    RPCEvent::Error {
+                        id,
+                        error: RPCError::Timeout,
+                        ..
+                    },
+                )) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be sent");
+        rpc.send_rpc_with_timeout(
+            peer_id.clone(),
+            request(0, roots_request()),
+            Duration::from_secs(60),
+        );
+
+        rpc.check_timeouts(Instant::now());
+        assert!(timed_out_ids(&rpc).is_empty());
+
+        rpc.check_timeouts(Instant::now() + TIMEOUT);
+        assert_eq!(timed_out_ids(&rpc), vec![id]);
+        assert_eq!(rpc.pending_requests(&peer_id), 1);
+
+        // a late response is dropped, only the timeout reaches the user
+        rpc.on_rpc_event(peer_id.clone(), response(id.into(), roots_response()));
+        assert_eq!(routed_response_ids(&rpc, &peer_id), vec![id]);
+    }
+
+    #[test]
+    fn requests_time_out_at_their_own_deadlines() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+
+        let impatient = rpc
+            .send_rpc_with_timeout(
+                peer_id.clone(),
+                request(0, roots_request()),
+                Duration::from_secs(2),
+            )
+            .expect("request should be sent");
+        let patient = rpc
+            .send_rpc_with_timeout(
+                peer_id.clone(),
+                request(0, range_request()),
+                Duration::from_secs(60),
+            )
+            .expect("request should be sent");
+
+        rpc.check_timeouts(Instant::now() + Duration::from_secs(2));
+        assert_eq!(timed_out_ids(&rpc), vec![impatient]);
+        assert_eq!(rpc.pending_requests(&peer_id), 1);
+
+        rpc.events.clear();
+        rpc.check_timeouts(Instant::now() + Duration::from_secs(60));
+        assert_eq!(timed_out_ids(&rpc), vec![patient]);
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+    }
+
+    #[test]
+    fn request_timeouts_are_clamped() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        let instant = rpc
+            .send_rpc_with_timeout(
+                peer_id.clone(),
+                request(0, roots_request()),
+                Duration::from_secs(0),
+            )
+            .expect("request should be sent");
+        let endless = rpc
+            .send_rpc_with_timeout(
+                peer_id.clone(),
+                request(0, roots_request()),
+                Duration::from_secs(u64::max_value()),
+            )
+            .expect("request should be sent");
+
+        // a zero timeout is given the minimum instead of failing immediately
+        rpc.check_timeouts(Instant::now());
+        assert!(timed_out_ids(&rpc).is_empty());
+        rpc.check_timeouts(Instant::now() + MIN_REQUEST_TIMEOUT);
+        assert_eq!(timed_out_ids(&rpc), vec![instant]);
+
+        rpc.events.clear();
+        rpc.check_timeouts(Instant::now() + MAX_REQUEST_TIMEOUT);
+        assert_eq!(timed_out_ids(&rpc), vec![endless]);
+    }
+
+    #[test]
+    fn requests_sent_once_peer_dialed() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+
+        let first = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be queued");
+        let second = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be queued");
+        assert_eq!(rpc.queued_requests(&peer_id), 2);
+        assert_eq!(rpc.pending_requests(&peer_id), 2);
+
+        // the peer is dialed once
+        match rpc.events.as_slice() {
+            [NetworkBehaviourAction::GenerateEvent(RPCMessage::DialPeer(dialed))] => {
+                assert_eq!(*dialed, peer_id)
+            }
+            _ => panic!("expected the peer to be dialed"),
+        }
+        rpc.events.clear();
+
+        // dialing does not count towards the request timeout
+        rpc.check_timeouts(Instant::now() + TIMEOUT);
+        assert!(timed_out_ids(&rpc).is_empty());
+
+        rpc.on_connected(peer_id.clone());
+        let sent: Vec<RequestId> = rpc
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::SendEvent {
+                    event: RPCEvent::Request { id, .. },
+                    ..
+                } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, vec![first, second]);
+        assert_eq!(rpc.queued_requests(&peer_id), 0);
+
+        rpc.on_rpc_event(peer_id.clone(), response(first.into(), roots_response()));
+        assert_eq!(routed_response_ids(&rpc, &peer_id), vec![first]);
+    }
+
+    #[test]
+    fn failed_dial_fails_queued_requests() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be queued");
+        let other_id = rpc
+            .send_rpc(other_peer_id.clone(), request(0, roots_request()))
+            .expect("request should be queued");
+        rpc.events.clear();
+
+        rpc.on_dial_failure(&peer_id);
+        assert_eq!(routed_errors(&rpc), vec![(id, RPCError::DialFailed)]);
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+        rpc.events.clear();
+
+        // the other dial times out
+        rpc.check_timeouts(Instant::now() + DIAL_TIMEOUT);
+        assert_eq!(routed_errors(&rpc), vec![(other_id, RPCError::DialFailed)]);
+        assert_eq!(rpc.queued_requests(&other_peer_id), 0);
+        assert_eq!(rpc.pending_requests(&other_peer_id), 0);
+    }
+
+    #[test]
+    fn queued_requests_are_bounded() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+
+        for _ in 0..MAX_QUEUED_REQUESTS {
+            assert!(rpc
+                .send_rpc(peer_id.clone(), request(0, roots_request()))
+                .is_some());
+        }
+        rpc.events.clear();
+
+        // the request beyond the queue fails, without affecting those queued
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be assigned an id");
+        assert_eq!(routed_errors(&rpc), vec![(id, RPCError::Busy)]);
+        assert_eq!(rpc.queued_requests(&peer_id), MAX_QUEUED_REQUESTS);
+        assert_eq!(rpc.pending_requests(&peer_id), MAX_QUEUED_REQUESTS);
+    }
+
+    #[test]
+    fn undialable_request_fails() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+
+        let id = rpc
+            .fail_request(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be assigned an id");
+        assert_eq!(routed_errors(&rpc), vec![(id, RPCError::DialFailed)]);
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+        assert_eq!(rpc.queued_requests(&peer_id), 0);
+    }
+
+    /// Returns the ids of the RPCs handed to the swarm for `peer_id`, in order.
+    fn sent_ids(rpc: &Rpc<()>, peer_id: &PeerId) -> Vec<RequestId> {
+        rpc.events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::SendEvent {
+                    peer_id: target,
+                    event,
+                } if target == peer_id => Some(event.id()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_outbound_queue_refuses_rpcs() {
+        let limits = OutboundQueueLimits {
+            max_events: 4,
+            max_bytes: u64::max_value(),
+            ..OutboundQueueLimits::default()
+        };
+        let mut rpc = new_rpc_with_outbound_limits(RPCQuotas::default(), 16, limits);
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        rpc.on_connected(other_peer_id.clone());
+        complete_handshake(&mut rpc, &peer_id);
+        let depth = |rpc: &Rpc<()>| rpc.outbound_queue_depth.get();
+
+        // the peer never reads, so nothing is acknowledged as sent
+        let queued: Vec<RequestId> = (0..4)
+            .map(|_| {
+                rpc.send_rpc(peer_id.clone(), request(0, roots_request()))
+                    .expect("request should be sent")
+            })
+            .collect();
+        assert_eq!(rpc.outbound_queue_len(&peer_id), 4);
+        assert_eq!(depth(&rpc), 4);
+
+        let refused = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be assigned an id");
+        assert_eq!(routed_errors(&rpc), vec![(refused, RPCError::Backpressure)]);
+        assert_eq!(rpc.pending_requests(&peer_id), 4);
+
+        // responses are dropped
+        rpc.on_rpc_event(peer_id.clone(), request(9, roots_request()));
+        assert!(rpc
+            .send_rpc(peer_id.clone(), response(9, roots_response()))
+            .is_none());
+
+        // other peers are unaffected
+        assert!(rpc
+            .send_rpc(other_peer_id.clone(), request(0, roots_request()))
+            .is_some());
+        assert_eq!(sent_ids(&rpc, &other_peer_id).len(), 1);
+        assert_eq!(depth(&rpc), 5);
+
+        // the queue drains in order
+        assert_eq!(sent_ids(&rpc, &peer_id), queued);
+        rpc.on_sent(&peer_id);
+        assert_eq!(depth(&rpc), 4);
+        let next = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be sent");
+        assert_eq!(sent_ids(&rpc, &peer_id).last(), Some(&next));
+
+        rpc.on_disconnected(&peer_id);
+        assert_eq!(rpc.outbound_queue_len(&peer_id), 0);
+        assert_eq!(depth(&rpc), 1);
+    }
+
+    #[test]
+    fn outbound_queue_bounded_by_size() {
+        let size = request(0, roots_request()).as_ssz_bytes().len() as u64;
+        let limits = OutboundQueueLimits {
+            max_events: 16,
+            max_bytes: 2 * size,
+            ..OutboundQueueLimits::default()
+        };
+        let mut rpc = new_rpc_with_outbound_limits(RPCQuotas::default(), 16, limits);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        for _ in 0..2 {
+            rpc.send_rpc(peer_id.clone(), request(0, roots_request()));
+        }
+        let refused = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be assigned an id");
+        assert_eq!(routed_errors(&rpc), vec![(refused, RPCError::Backpressure)]);
+        assert_eq!(rpc.outbound_queue_len(&peer_id), 2);
+    }
+
+    #[test]
+    fn progressing_stream_does_not_time_out() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+
+        let range_request = RPCRequest::BeaconBlocksByRange(BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(0),
+            count: 10,
+            step: 1,
+        });
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request))
+            .expect("request should be sent");
+
+        let block = BeaconBlock::empty(&MainnetEthSpec::default_spec());
+        for _ in 0..3 {
+            // the timeout is about to expire when the next block arrives
+            rpc.pending_requests
+                .get_mut(&peer_id)
+                .and_then(|requests| requests.get_mut(&id))
+                .expect("request should be pending")
+                .deadline = Instant::now();
+
+            rpc.on_rpc_event(
+                peer_id.clone(),
+                response(
+                    id.into(),
+                    RPCResponse::BeaconBlocksByRange(Box::new(block.clone())),
+                ),
+            );
+            rpc.check_timeouts(Instant::now() + TIMEOUT / 2);
+        }
+
+        assert!(timed_out_ids(&rpc).is_empty());
+        assert_eq!(rpc.pending_requests(&peer_id), 1);
+    }
+
+    /// Returns the ids and error codes of the errors sent to `peer_id`.
+    fn sent_error_codes(rpc: &Rpc<()>, peer_id: &PeerId) -> Vec<(RequestId, u8)> {
+        rpc.events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::SendEvent {
+                    peer_id: target,
+                    event: RPCEvent::Error { id, error, .. },
+                } if target == peer_id => Some((*id, error.code())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn requests_refused_before_status_handshake() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        // block requests are refused, liveness checks are still answered
+        rpc.on_rpc_event(peer_id.clone(), request(1, range_request()));
+        rpc.on_rpc_event(peer_id.clone(), request(2, RPCRequest::MetaData));
+        assert_eq!(
+            sent_error_codes(&rpc, &peer_id),
+            vec![(RequestId::from(1), RPCErrorCode::InvalidRequest.into())]
+        );
+        assert_eq!(
+            routed_response_ids(&rpc, &peer_id),
+            vec![RequestId::from(2)]
+        );
+        rpc.events.clear();
+
+        // the same request is served once the peer has sent its status
+        rpc.on_rpc_event(peer_id.clone(), request(3, RPCRequest::Hello(hello())));
+        rpc.on_rpc_event(peer_id.clone(), request(1, range_request()));
+        assert!(sent_error_codes(&rpc, &peer_id).is_empty());
+        assert_eq!(
+            routed_response_ids(&rpc, &peer_id),
+            vec![RequestId::from(3), RequestId::from(1)]
+        );
+    }
+
+    #[test]
+    fn hello_response_completes_handshake() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, RPCRequest::Hello(hello())))
+            .expect("request should be sent");
+        rpc.on_rpc_event(
+            peer_id.clone(),
+            response(id.into(), RPCResponse::Hello(hello())),
+        );
+        rpc.events.clear();
+
+        rpc.on_rpc_event(peer_id.clone(), request(1, range_request()));
+        assert!(sent_error_codes(&rpc, &peer_id).is_empty());
+        assert_eq!(
+            routed_response_ids(&rpc, &peer_id),
+            vec![RequestId::from(1)]
+        );
+    }
+
+    #[test]
+    fn handshake_repeated_after_reconnect() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        rpc.on_rpc_event(peer_id.clone(), request(0, RPCRequest::Hello(hello())));
+
+        rpc.on_disconnected(&peer_id);
+        rpc.on_connected(peer_id.clone());
+        rpc.events.clear();
+
+        rpc.on_rpc_event(peer_id.clone(), request(1, range_request()));
+        assert_eq!(
+            sent_error_codes(&rpc, &peer_id),
+            vec![(RequestId::from(1), RPCErrorCode::InvalidRequest.into())]
+        );
+    }
+
+    /// Answers range requests with two blocks, and panics on roots requests.
+    struct MockHandler;
+
+    impl RequestHandler for MockHandler {
+        fn methods(&self) -> Vec<RPCMethod> {
+            vec![RPCMethod::BeaconBlocksByRange, RPCMethod::BeaconBlockRoots]
+        }
+
+        fn handle(&self, request: RPCRequest) -> Result<Vec<RPCResponse>, RPCError> {
+            match request {
+                RPCRequest::BeaconBlocksByRange(_) => Ok(vec![range_block(), range_block()]),
+                _ => panic!("mock handler failure"),
+            }
+        }
+    }
+
+    /// Waits for the request handler to answer and sends its answers.
+    fn wait_for_handler(rpc: &mut Rpc<()>) {
+        future::poll_fn(|| {
+            if rpc.poll_request_handler() {
+                Ok::<_, ()>(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        })
+        .wait()
+        .expect("handler should answer");
+    }
+
+    #[test]
+    fn request_handler_answers_requests() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        complete_handshake(&mut rpc, &peer_id);
+        rpc.set_request_handler(Arc::new(MockHandler))
+            .expect("should spawn handler");
+
+        rpc.on_rpc_event(peer_id.clone(), request(1, range_request()));
+        assert!(routed_response_ids(&rpc, &peer_id).is_empty());
+        wait_for_handler(&mut rpc);
+
+        let sent: Vec<RPCEvent> = rpc
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::SendEvent { event, .. } => Some(event.clone()),
+                _ => None,
+            })
+            .collect();
+        match sent.as_slice() {
+            [RPCEvent::Response { .. }, RPCEvent::Response { .. }, RPCEvent::StreamTermination { id, .. }] =>
+            {
+                assert_eq!(*id, RequestId::from(1))
+            }
+            _ => panic!("expected a terminated stream of two blocks"),
+        }
+        // the request is complete and never reached the user
+        assert!(rpc.inbound_requests.get(&peer_id).is_none());
+        assert!(routed_response_ids(&rpc, &peer_id).is_empty());
+    }
+
+    #[test]
+    fn request_handler_panic_fails_request() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        complete_handshake(&mut rpc, &peer_id);
+        rpc.set_request_handler(Arc::new(MockHandler))
+            .expect("should spawn handler");
+
+        rpc.on_rpc_event(peer_id.clone(), request(2, roots_request()));
+        wait_for_handler(&mut rpc);
+        assert_eq!(
+            sent_error_codes(&rpc, &peer_id),
+            vec![(RequestId::from(2), RPCErrorCode::ServerError.into())]
+        );
+
+        // methods the handler does not answer still reach the user
+        rpc.events.clear();
+        rpc.on_rpc_event(peer_id.clone(), request(3, RPCRequest::MetaData));
+        assert_eq!(
+            routed_response_ids(&rpc, &peer_id),
+            vec![RequestId::from(3)]
+        );
+    }
+
+    #[test]
+    fn oversized_frames_fail_their_request() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+        let method_id = RPCMethod::BeaconBlocksByRange.into();
+
+        // an oversized request is refused without reaching the user
+        rpc.on_oversized_request(peer_id.clone(), RequestId::from(1), method_id, 1_000);
+        assert_eq!(
+            sent_error_codes(&rpc, &peer_id),
+            vec![(RequestId::from(1), RPCErrorCode::InvalidRequest.into())]
+        );
+        assert!(routed_response_ids(&rpc, &peer_id).is_empty());
+        rpc.events.clear();
+
+        // an oversized response fails our request
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .expect("request should be sent");
+        rpc.events.clear();
+        rpc.on_rpc_event(
+            peer_id.clone(),
+            RPCEvent::Error {
+                id,
+                method_id,
+                error: RPCError::ResponseTooLarge(1_000_000),
+            },
+        );
+        assert_eq!(
+            routed_errors(&rpc),
+            vec![(id, RPCError::ResponseTooLarge(1_000_000))]
+        );
+        assert_eq!(rpc.pending_requests(&peer_id), 0);
+    }
+
+    #[test]
+    fn idle_inbound_requests_closed() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+        complete_handshake(&mut rpc, &peer_id);
+        let outbound = rpc
+            .send_rpc_with_timeout(
+                peer_id.clone(),
+                request(0, range_request()),
+                MAX_REQUEST_TIMEOUT,
+            )
+            .expect("request should be sent");
+        rpc.on_rpc_event(peer_id.clone(), request(1, range_request()));
+        rpc.on_rpc_event(peer_id.clone(), request(2, range_request()));
+        let received = Instant::now();
+        rpc.send_stream_chunk(peer_id.clone(), RequestId::from(2), range_block())
+            .expect("chunk should be sent");
+        rpc.events.clear();
+
+        rpc.check_timeouts(received + IDLE_TIMEOUT / 2);
+        assert!(rpc.events.is_empty());
+
+        // only the request without a response since it was received is closed
+        rpc.check_timeouts(received + IDLE_TIMEOUT);
+        assert_eq!(
+            sent_error_codes(&rpc, &peer_id),
+            vec![(RequestId::from(1), RPCErrorCode::ServerError.into())]
+        );
+        let closed: Vec<RequestId> = rpc
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::GenerateEvent(RPCMessage::InboundRequestIdle(
+                    source,
+                    id,
+                )) if *source == peer_id => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(closed, vec![RequestId::from(1)]);
+        assert!(rpc
+            .send_error(
+                peer_id.clone(),
+                RequestId::from(1),
+                RPCErrorCode::ServerError,
+                None
+            )
+            .is_none());
+
+        // the streamed response and our request, still within its deadline, are left open
+        assert!(rpc
+            .terminate_stream(peer_id.clone(), RequestId::from(2))
+            .is_some());
+        assert_eq!(rpc.pending_requests(&peer_id), 1);
+        assert!(routed_errors(&rpc)
+            .iter()
+            .all(|(id, _error)| *id != outbound));
+    }
+
+    #[test]
+    fn sends_shared_fairly_between_peers() {
+        let mut rpc = new_rpc(RPCQuotas::default(), MAX_CONCURRENT_REQUESTS);
+        let saturated = PeerId::random();
+        let trickle = PeerId::random();
+        rpc.on_connected(saturated.clone());
+        rpc.on_connected(trickle.clone());
+        for _ in 0..8 {
+            rpc.send_rpc(saturated.clone(), request(0, roots_request()))
+                .expect("request should be sent");
+        }
+        for _ in 0..2 {
+            rpc.send_rpc(trickle.clone(), request(0, roots_request()))
+                .expect("request should be sent");
+        }
+        let bytes_sent = |rpc: &Rpc<()>, peer_id: &PeerId| {
+            rpc.bytes_sent
+                .with_label_values(&[&peer_id.to_base58()])
+                .get()
+        };
+
+        // the trickle peer's sends are not held up behind the saturated peer's
+        let mut served = vec![];
+        while let Some(event) = rpc.next_event(true) {
+            match event {
+                NetworkBehaviourAction::SendEvent { peer_id, .. } => {
+                    rpc.on_sent(&peer_id);
+                    served.push(peer_id);
+                }
+                _ => panic!("expected only sends"),
+            }
+            if served.len() == 4 {
+                assert!(bytes_sent(&rpc, &saturated) > 0);
+                assert_eq!(bytes_sent(&rpc, &trickle), bytes_sent(&rpc, &saturated));
+            }
+        }
+        assert_eq!(
+            &served[..4],
+            &[
+                saturated.clone(),
+                trickle.clone(),
+                saturated.clone(),
+                trickle.clone()
+            ]
+        );
+        assert_eq!(served.len(), 10);
+        assert_eq!(rpc.sent_since_yield, 10);
+
+        // beyond the budget only events for the user are taken
+        rpc.send_rpc(trickle.clone(), request(0, roots_request()))
+            .expect("request should be sent");
+        rpc.on_disconnected(&saturated);
+        let mut disconnected = false;
+        while let Some(event) = rpc.next_event(false) {
+            match event {
+                NetworkBehaviourAction::GenerateEvent(RPCMessage::PeerDisconnected(peer_id)) => {
+                    assert_eq!(peer_id, saturated);
+                    disconnected = true;
+                }
+                NetworkBehaviourAction::SendEvent { .. } => panic!("expected no sends"),
+                _ => {}
+            }
+        }
+        assert!(disconnected);
+        match rpc.next_event(true) {
+            Some(NetworkBehaviourAction::SendEvent { peer_id, .. }) => assert_eq!(peer_id, trickle),
+            _ => panic!("expected the trickle peer's send"),
+        }
+    }
+
+    #[test]
+    fn requests_beyond_cap_wait_in_order() {
+        let limits = OutboundQueueLimits {
+            max_requests_in_flight: 2,
+            max_waiting_requests: 2,
+            ..OutboundQueueLimits::default()
+        };
+        let mut rpc = new_rpc_with_outbound_limits(RPCQuotas::default(), 16, limits);
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        rpc.on_connected(other_peer_id.clone());
+
+        let ids: Vec<RequestId> = (0..4)
+            .map(|_| {
+                rpc.send_rpc(peer_id.clone(), request(0, roots_request()))
+                    .expect("request should be accepted")
+            })
+            .collect();
+        assert_eq!(sent_ids(&rpc, &peer_id), &ids[..2]);
+        assert_eq!(rpc.requests_in_flight(&peer_id), 2);
+        assert_eq!(rpc.waiting_requests(&peer_id), 2);
+        assert_eq!(rpc.pending_requests(&peer_id), 4);
+
+        // waiting requests do not time out
+        rpc.check_timeouts(Instant::now() + TIMEOUT + Duration::from_secs(1));
+        let timed_out: Vec<RequestId> = routed_errors(&rpc)
+            .into_iter()
+            .map(|(id, error)| {
+                assert_eq!(error, RPCError::Timeout);
+                id
+            })
+            .collect();
+        assert_eq!(timed_out.len(), 2);
+        assert!(timed_out.iter().all(|id| ids[..2].contains(id)));
+
+        // the waiting requests are sent in the order they were made as the others time out
+        assert_eq!(sent_ids(&rpc, &peer_id), ids);
+        assert_eq!(rpc.requests_in_flight(&peer_id), 2);
+        assert_eq!(rpc.waiting_requests(&peer_id), 0);
+        rpc.events.clear();
+
+        // completing a request sends the next waiting one
+        let later: Vec<RequestId> = (0..2)
+            .map(|_| {
+                rpc.send_rpc(peer_id.clone(), request(0, roots_request()))
+                    .expect("request should be accepted")
+            })
+            .collect();
+        assert!(sent_ids(&rpc, &peer_id).is_empty());
+        rpc.on_rpc_event(peer_id.clone(), response(ids[2].into(), roots_response()));
+        assert_eq!(sent_ids(&rpc, &peer_id), &later[..1]);
+
+        // other peers have their own cap
+        assert!(rpc
+            .send_rpc(other_peer_id.clone(), request(0, roots_request()))
+            .is_some());
+        assert_eq!(sent_ids(&rpc, &other_peer_id).len(), 1);
+    }
+
+    #[test]
+    fn full_waiting_queue_refuses_requests() {
+        let limits = OutboundQueueLimits {
+            max_requests_in_flight: 1,
+            max_waiting_requests: 1,
+            ..OutboundQueueLimits::default()
+        };
+        let mut rpc = new_rpc_with_outbound_limits(RPCQuotas::default(), 16, limits);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        for _ in 0..2 {
+            rpc.send_rpc(peer_id.clone(), request(0, roots_request()))
+                .expect("request should be accepted");
+        }
+        let refused = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be assigned an id");
+        assert_eq!(routed_errors(&rpc), vec![(refused, RPCError::Busy)]);
+        assert_eq!(rpc.pending_requests(&peer_id), 2);
+
+        // raising the peer's cap sends the waiting request and makes room for more
+        rpc.set_request_cap(peer_id.clone(), Some(3));
+        assert_eq!(rpc.requests_in_flight(&peer_id), 2);
+        assert_eq!(rpc.waiting_requests(&peer_id), 0);
+        assert!(rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .is_some());
+        assert_eq!(rpc.requests_in_flight(&peer_id), 3);
+
+        // the waiting requests of a disconnected peer fail with the others
+        rpc.set_request_cap(peer_id.clone(), None);
+        rpc.send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be accepted");
+        assert_eq!(rpc.waiting_requests(&peer_id), 1);
+        rpc.events.clear();
+        rpc.on_disconnected(&peer_id);
+        assert_eq!(rpc.waiting_requests(&peer_id), 0);
+        assert_eq!(routed_errors(&rpc).len(), 4);
+    }
+
+    #[test]
+    fn requests_beyond_cap_refused_without_waiting_queue() {
+        let limits = OutboundQueueLimits {
+            max_requests_in_flight: 2,
+            max_waiting_requests: 0,
+            ..OutboundQueueLimits::default()
+        };
+        let mut rpc = new_rpc_with_outbound_limits(RPCQuotas::default(), 16, limits);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+
+        let ids: Vec<RequestId> = (0..4)
+            .map(|_| {
+                rpc.send_rpc(peer_id.clone(), request(0, roots_request()))
+                    .expect("request should be assigned an id")
+            })
+            .collect();
+        assert_eq!(sent_ids(&rpc, &peer_id), &ids[..2]);
+        assert_eq!(
+            routed_errors(&rpc),
+            vec![(ids[2], RPCError::Busy), (ids[3], RPCError::Busy)]
+        );
+        assert_eq!(rpc.waiting_requests(&peer_id), 0);
+    }
+
+    #[test]
+    fn failed_sends_fail_their_request() {
+        let limits = OutboundQueueLimits {
+            max_requests_in_flight: 1,
+            ..OutboundQueueLimits::default()
+        };
+        let mut rpc = new_rpc_with_outbound_limits(RPCQuotas::default(), 16, limits);
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        let method_id = RPCMethod::BeaconBlockRoots.into();
+
+        let reset = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be sent");
+        let waiting = rpc
+            .send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should wait");
+        rpc.events.clear();
+
+        // the peer resets the substream of the first request, the waiting one is sent in its place
+        rpc.on_send_failed(&peer_id, reset, method_id, true, RPCError::StreamReset);
+        assert_eq!(routed_errors(&rpc), vec![(reset, RPCError::StreamReset)]);
+        assert_eq!(sent_ids(&rpc, &peer_id), vec![waiting]);
+        assert_eq!(rpc.pending_requests(&peer_id), 1);
+        assert_eq!(rpc.outbound_queue_len(&peer_id), 1);
+        rpc.events.clear();
+
+        // a failed response, whose id may match one of our requests, fails nothing
+        rpc.on_send_failed(
+            &peer_id,
+            waiting,
+            method_id,
+            false,
+            RPCError::NegotiationFailed,
+        );
+        assert!(routed_errors(&rpc).is_empty());
+        assert_eq!(rpc.pending_requests(&peer_id), 1);
+
+        // undecodable inbound RPCs are only counted
+        let protocol = ProtocolId::new(
+            RPCMethod::BeaconBlockRoots,
+            RPCVersion::V1,
+            RPCEncoding::SSZ,
+        );
+        rpc.on_inbound_failure(&peer_id, DecodeError::TruncatedChunk(10), &protocol);
+        assert_eq!(rpc.inbound_failures.get(), 1);
+        assert!(rpc.events.is_empty());
+    }
+
+    /// Collects each record logged as a line of its level, message and key-value pairs.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl CapturedLogs {
+        fn lines(&self) -> Vec<String> {
+            self.0.lock().expect("lock should not be poisoned").clone()
+        }
+    }
+
+    impl slog::Drain for CapturedLogs {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            let mut line = format!("{} {}", record.level().as_short_str(), record.msg());
+            slog::KV::serialize(&record.kv(), record, &mut LineSerializer(&mut line))
+                .expect("should serialize key-values");
+            self.0
+                .lock()
+                .expect("lock should not be poisoned")
+                .push(line);
+            Ok(())
+        }
+    }
+
+    /// Appends key-value pairs to a log line as ` key=value`.
+    struct LineSerializer<'a>(&'a mut String);
+
+    impl<'a> slog::Serializer for LineSerializer<'a> {
+        fn emit_arguments(&mut self, key: slog::Key, value: &std::fmt::Arguments) -> slog::Result {
+            self.0.push_str(&format!(" {}={}", key, value));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn traced_request_lifecycle_logged() {
+        let logs = CapturedLogs::default();
+        let log = slog::Logger::root(logs.clone(), o!());
+        let mut rpc = new_rpc_with_log(
+            RPCQuotas::default(),
+            MAX_CONCURRENT_REQUESTS,
+            OutboundQueueLimits::default(),
+            true,
+            &log,
+        );
+        let peer_id = PeerId::random();
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+
+        // an unrelated request, whose lines must not be mistaken for those of the traced one
+        rpc.send_rpc(peer_id.clone(), request(0, roots_request()))
+            .expect("request should be sent");
+        let id = rpc
+            .send_rpc(peer_id.clone(), request(0, range_request()))
+            .expect("request should be sent");
+        let method_id: u16 = RPCMethod::BeaconBlocksByRange.into();
+        // as must an unsolicited response
+        rpc.on_rpc_event(peer_id.clone(), response(0, range_block()));
+        rpc.on_rpc_event(
+            peer_id.clone(),
+            RPCEvent::Response {
+                id,
+                method_id,
+                result: range_block(),
+            },
+        );
+        rpc.on_rpc_event(
+            peer_id.clone(),
+            RPCEvent::StreamTermination { id, method_id },
+        );
+
+        let request_id = format!(" request_id={:?}", id);
+        let traced: Vec<String> = logs
+            .lines()
+            .into_iter()
+            .filter(|line| line.contains(&request_id))
+            .collect();
+        let expected = [
+            "INFO RPC request submitted",
+            "INFO Sending RPC request",
+            "INFO RPC response received",
+            "INFO RPC request completed",
+        ];
+        assert_eq!(traced.len(), expected.len(), "{:#?}", traced);
+        for (line, step) in traced.iter().zip(expected.iter()) {
+            assert!(line.starts_with(step), "expected {}, got {}", step, line);
+            assert!(line.contains(&format!(" peer={:?}", peer_id)));
+            assert!(line.contains(&format!(" method_id={}", method_id)));
+        }
+        assert!(traced[2].contains(" response=chunk"));
+        assert!(traced[3].contains(" response=stream termination"));
+
+        // without tracing the same steps are logged at debug
+        let logs = CapturedLogs::default();
+        let log = slog::Logger::root(logs.clone(), o!());
+        let mut rpc = new_rpc_with_log(
+            RPCQuotas::default(),
+            MAX_CONCURRENT_REQUESTS,
+            OutboundQueueLimits::default(),
+            false,
+            &log,
+        );
+        rpc.on_connected(peer_id.clone());
+        negotiate_v2(&mut rpc, &peer_id);
+        rpc.send_rpc(peer_id, request(0, range_request()));
+        let lines = logs.lines();
+        assert!(!lines.is_empty());
+        assert!(lines.iter().all(|line| line.starts_with("DEBG")));
     }
 }
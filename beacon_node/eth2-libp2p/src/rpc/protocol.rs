@@ -1,32 +1,275 @@
 use super::methods::*;
+use futures::prelude::*;
+use futures::{future, try_ready};
 use libp2p::core::{upgrade, InboundUpgrade, OutboundUpgrade, UpgradeInfo};
-use ssz::{impl_decode_via_from, impl_encode_via_from, ssz_encode, Decode, Encode};
+use serde_derive::{Deserialize, Serialize};
+use ssz::{impl_decode_via_from, impl_encode_via_from, Decode, Encode};
 use ssz_derive::{Decode, Encode};
 use std::hash::{Hash, Hasher};
 use std::io;
-use std::iter;
 use tokio::io::{AsyncRead, AsyncWrite};
+use types::{BeaconBlock, BeaconBlockHeader};
 
-/// The maximum bytes that can be sent across the RPC.
+/// The default size limit of the requests and responses of methods without a tighter limit.
 const MAX_READ_SIZE: usize = 4_194_304; // 4M
+/// The size of an `SszContainer` without its `bytes`.
+const CONTAINER_OVERHEAD: usize = 15;
+/// The payload size allowed for an error frame, whatever the method's limits. Error messages
+/// longer than `MAX_ERROR_MESSAGE_LENGTH` are truncated on receipt rather than rejected.
+const MAX_ERROR_PAYLOAD: usize = 1024;
+
+/* Values of `SszContainer::message_type` */
+/// The message is a response to a request.
+const RESPONSE_MESSAGE: u8 = 0;
+/// The message is a request.
+const REQUEST_MESSAGE: u8 = 1;
+/// The message terminates a stream of responses.
+const STREAM_TERMINATION_MESSAGE: u8 = 2;
+/// The message is an error sent in place of a response.
+const ERROR_MESSAGE: u8 = 3;
+
+/// The prefix of the protocol ids of all RPC methods.
+const PROTOCOL_PREFIX: &str = "/eth2/beacon_chain/req";
+
+/// The versions of an RPC method. Streamed responses are only supported from `V2`, and the
+/// `Hello` handshake carries the genesis root from `V3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RPCVersion {
+    V1,
+    V2,
+    V3,
+}
+
+impl RPCVersion {
+    /// The version as it appears in protocol ids.
+    fn as_str(self) -> &'static str {
+        match self {
+            RPCVersion::V1 => "1",
+            RPCVersion::V2 => "2",
+            RPCVersion::V3 => "3",
+        }
+    }
+}
+
+/// The encodings of RPC messages on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RPCEncoding {
+    /// Plain SSZ.
+    SSZ,
+    /// SSZ compressed with the snappy block format.
+    SSZSnappy,
+}
+
+impl RPCEncoding {
+    /// The encoding as it appears in protocol ids.
+    fn as_str(self) -> &'static str {
+        match self {
+            RPCEncoding::SSZ => "ssz",
+            RPCEncoding::SSZSnappy => "ssz_snappy",
+        }
+    }
+}
+
+/// The protocol id of a version of an RPC method and the encoding of its messages, of the form
+/// `/eth2/beacon_chain/req/<method>/<version>/<encoding>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolId {
+    /// The method id of the messages sent using this protocol.
+    pub method_id: u16,
+    /// The version of the method.
+    pub version: RPCVersion,
+    /// The encoding of the messages sent using this protocol.
+    pub encoding: RPCEncoding,
+    /// The protocol id advertised during upgrade negotiation.
+    protocol_id: String,
+}
+
+impl ProtocolId {
+    pub fn new(method: RPCMethod, version: RPCVersion, encoding: RPCEncoding) -> Self {
+        let protocol_id = format!(
+            "{}/{}/{}/{}",
+            PROTOCOL_PREFIX,
+            method.name(),
+            version.as_str(),
+            encoding.as_str()
+        );
+        ProtocolId {
+            method_id: method.into(),
+            version,
+            encoding,
+            protocol_id,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ProtocolId {
+    fn as_ref(&self) -> &[u8] {
+        self.protocol_id.as_bytes()
+    }
+}
+
+/// The protocol ids we support for `methods` from `min_version`, in order of preference.
+///
+/// Newer versions are always preferred. Within a version, `encodings` are preferred in the given
+/// order, followed by any encodings missing from it, so that every encoding remains supported.
+fn supported_protocols(
+    methods: &[RPCMethod],
+    min_version: RPCVersion,
+    encodings: &[RPCEncoding],
+) -> Vec<ProtocolId> {
+    let mut encodings = encodings.to_vec();
+    for encoding in &[RPCEncoding::SSZSnappy, RPCEncoding::SSZ] {
+        if !encodings.contains(encoding) {
+            encodings.push(*encoding);
+        }
+    }
+
+    let mut protocols = vec![];
+    for method in methods {
+        for version in &[RPCVersion::V3, RPCVersion::V2, RPCVersion::V1] {
+            if *version < min_version {
+                continue;
+            }
+            for encoding in &encodings {
+                protocols.push(ProtocolId::new(*method, *version, *encoding));
+            }
+        }
+    }
+    protocols
+}
+
+/// The maximum sizes of the SSZ encoded requests and responses of an RPC method, excluding the
+/// framing. Streamed responses are limited per chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SizeLimit {
+    pub max_request: usize,
+    pub max_response: usize,
+}
+
+impl SizeLimit {
+    /// The size of the largest frame of the method, before compression.
+    fn max_frame(self) -> usize {
+        CONTAINER_OVERHEAD
+            + self
+                .max_request
+                .max(self.max_response)
+                .max(MAX_ERROR_PAYLOAD)
+    }
+
+    /// The number of bytes read from a substream of the method in `encoding`.
+    fn max_read(self, encoding: RPCEncoding) -> usize {
+        match encoding {
+            RPCEncoding::SSZ => self.max_frame(),
+            RPCEncoding::SSZSnappy => snap::max_compress_len(self.max_frame()),
+        }
+    }
+}
+
+/// The size limits of each RPC method, enforced on received frames before their contents are
+/// decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RPCSizeLimits {
+    pub hello: SizeLimit,
+    pub goodbye: SizeLimit,
+    pub blocks_by_range: SizeLimit,
+    pub blocks_by_root: SizeLimit,
+    pub block_headers_by_range: SizeLimit,
+    pub block_bodies_by_root: SizeLimit,
+    pub metadata: SizeLimit,
+    pub ping: SizeLimit,
+    /// The limits of all other methods.
+    pub other: SizeLimit,
+}
+
+impl RPCSizeLimits {
+    /// Returns the size limit of the method `method_id`.
+    pub fn limit(&self, method_id: u16) -> SizeLimit {
+        match RPCMethod::from(method_id) {
+            RPCMethod::Hello => self.hello,
+            RPCMethod::Goodbye => self.goodbye,
+            RPCMethod::BeaconBlocksByRange => self.blocks_by_range,
+            RPCMethod::BeaconBlocksByRoot => self.blocks_by_root,
+            RPCMethod::BeaconBlockHeadersByRange => self.block_headers_by_range,
+            RPCMethod::BeaconBlockBodiesByRoot => self.block_bodies_by_root,
+            RPCMethod::MetaData => self.metadata,
+            RPCMethod::Ping => self.ping,
+            _ => self.other,
+        }
+    }
+}
+
+impl Default for RPCSizeLimits {
+    fn default() -> Self {
+        // a list of up to `MAX_REQUEST_BLOCKS` roots, behind its offset
+        let roots_request = 4 + 32 * MAX_REQUEST_BLOCKS as usize;
+        RPCSizeLimits {
+            hello: SizeLimit {
+                max_request: 128,
+                max_response: 128,
+            },
+            goodbye: SizeLimit {
+                max_request: 8,
+                max_response: 0,
+            },
+            blocks_by_range: SizeLimit {
+                max_request: 64,
+                max_response: MAX_CHUNK_SIZE,
+            },
+            blocks_by_root: SizeLimit {
+                max_request: roots_request,
+                max_response: MAX_CHUNK_SIZE,
+            },
+            block_headers_by_range: SizeLimit {
+                max_request: 64,
+                max_response: 1024,
+            },
+            block_bodies_by_root: SizeLimit {
+                max_request: roots_request,
+                max_response: MAX_CHUNK_SIZE,
+            },
+            metadata: SizeLimit {
+                max_request: 0,
+                max_response: 256,
+            },
+            ping: SizeLimit {
+                max_request: 8,
+                max_response: 8,
+            },
+            other: SizeLimit {
+                max_request: MAX_READ_SIZE,
+                max_response: MAX_READ_SIZE,
+            },
+        }
+    }
+}
 
 /// Implementation of the `ConnectionUpgrade` for the rpc protocol.
+///
+/// All versions of all methods are accepted from peers in every encoding, the dialer chooses
+/// amongst them. Requests on a version of a method which does not support them are failed by the
+/// RPC behaviour. Frames larger than the method's `SizeLimit` are rejected before being decoded.
 #[derive(Debug, Clone)]
-pub struct RPCProtocol;
+pub struct RPCProtocol {
+    pub size_limits: RPCSizeLimits,
+}
 
 impl UpgradeInfo for RPCProtocol {
-    type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+    type Info = ProtocolId;
+    type InfoIter = Vec<Self::Info>;
 
     #[inline]
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(b"/eth/serenity/rpc/1.0.0")
+        supported_protocols(&RPCMethod::known(), RPCVersion::V1, &[])
     }
 }
 
 impl Default for RPCProtocol {
     fn default() -> Self {
-        RPCProtocol
+        RPCProtocol {
+            size_limits: RPCSizeLimits::default(),
+        }
     }
 }
 
@@ -88,51 +331,394 @@ pub enum RPCEvent {
         method_id: u16, //TODO: Remove and process decoding upstream
         result: RPCResponse,
     },
+    /// Signals that no further responses will be sent for the streamed request `id`.
+    StreamTermination { id: RequestId, method_id: u16 },
+    /// The request `id` failed, either rejected by the responder or timed out.
+    Error {
+        id: RequestId,
+        method_id: u16,
+        error: RPCError,
+    },
 }
 
-impl UpgradeInfo for RPCEvent {
-    type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+impl RPCEvent {
+    /// Returns the `RequestId` associated with this event.
+    pub fn id(&self) -> RequestId {
+        match self {
+            RPCEvent::Request { id, .. } => *id,
+            RPCEvent::Response { id, .. } => *id,
+            RPCEvent::StreamTermination { id, .. } => *id,
+            RPCEvent::Error { id, .. } => *id,
+        }
+    }
+
+    /// Returns the id of the method this event belongs to.
+    pub fn method_id(&self) -> u16 {
+        match self {
+            RPCEvent::Request { method_id, .. } => *method_id,
+            RPCEvent::Response { method_id, .. } => *method_id,
+            RPCEvent::StreamTermination { method_id, .. } => *method_id,
+            RPCEvent::Error { method_id, .. } => *method_id,
+        }
+    }
+
+    /// The earliest version of its method which can carry this event.
+    pub fn min_version(&self) -> RPCVersion {
+        match self {
+            RPCEvent::Request { body, .. } => body.min_version(),
+            _ => RPCVersion::V1,
+        }
+    }
+}
+
+/// An RPC sent to a peer, along with the encodings to propose for it in order of preference.
+#[derive(Debug, Clone)]
+pub struct OutboundRPC {
+    pub event: RPCEvent,
+    /// The SSZ encoding of `event`, measured before it was queued and framed as is.
+    pub ssz: Vec<u8>,
+    pub encodings: Vec<RPCEncoding>,
+    /// The size below which frames are stored rather than compressed by `ssz_snappy`.
+    pub compression_threshold: usize,
+}
+
+impl UpgradeInfo for OutboundRPC {
+    type Info = ProtocolId;
+    type InfoIter = Vec<Self::Info>;
 
     #[inline]
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(b"/eth/serenity/rpc/1.0.0")
+        supported_protocols(
+            &[RPCMethod::from(self.event.method_id())],
+            self.event.min_version(),
+            &self.encodings,
+        )
     }
 }
 
-type FnDecodeRPCEvent = fn(Vec<u8>, ()) -> Result<RPCEvent, DecodeError>;
+/// A frame received from a remote.
+#[derive(Debug)]
+pub enum InboundFrame {
+    /// A decoded event.
+    Event(RPCEvent),
+    /// A request whose payload of the given size exceeds the limit of its method, left undecoded.
+    OversizedRequest {
+        id: RequestId,
+        method_id: u16,
+        size: usize,
+    },
+    /// A frame which could not be read or decoded.
+    Failed(DecodeError),
+}
+
+impl InboundFrame {
+    /// Returns the id of the method the frame belongs to, if it was decoded.
+    fn method_id(&self) -> Option<u16> {
+        match self {
+            InboundFrame::Event(event) => Some(event.method_id()),
+            InboundFrame::OversizedRequest { method_id, .. } => Some(*method_id),
+            InboundFrame::Failed(_) => None,
+        }
+    }
+}
 
 impl<TSocket> InboundUpgrade<TSocket> for RPCProtocol
 where
     TSocket: AsyncRead + AsyncWrite,
 {
-    type Output = RPCEvent;
+    /// The received frame, along with the protocol negotiated with the remote.
+    type Output = (InboundFrame, ProtocolId);
+    type Error = DecodeError;
+    type Future = ReadFrame<upgrade::Negotiated<TSocket>>;
+
+    fn upgrade_inbound(
+        self,
+        socket: upgrade::Negotiated<TSocket>,
+        protocol: Self::Info,
+    ) -> Self::Future {
+        ReadFrame::new(socket, protocol, &self.size_limits)
+    }
+}
+
+/// Reads a single length-prefixed chunk from a socket, resolving to the socket and the chunk.
+///
+/// Each RPC frame is sent as one chunk, prefixed by its length as an unsigned varint. Chunks
+/// longer than `max_size` are rejected without being read, and a socket closing part way through
+/// a chunk fails the read rather than leaving it waiting. Sockets which stall are dropped by the
+/// substream timeout of the handler.
+pub struct ReadChunk<TSocket> {
+    socket: Option<TSocket>,
+    max_size: usize,
+    /// The length of the chunk, once its prefix has been read.
+    length: Option<usize>,
+    /// The bytes of the length prefix read so far.
+    prefix: Vec<u8>,
+    /// The bytes of the chunk, of which the first `filled` have been read.
+    chunk: Vec<u8>,
+    filled: usize,
+}
+
+impl<TSocket> ReadChunk<TSocket> {
+    pub fn new(socket: TSocket, max_size: usize) -> Self {
+        ReadChunk {
+            socket: Some(socket),
+            max_size,
+            length: None,
+            prefix: Vec::new(),
+            chunk: Vec::new(),
+            filled: 0,
+        }
+    }
+
+    /// The number of bytes of the chunk and its prefix read so far.
+    fn received(&self) -> usize {
+        self.prefix.len() + self.filled
+    }
+}
+
+impl<TSocket> Future for ReadChunk<TSocket>
+where
+    TSocket: AsyncRead,
+{
+    type Item = (TSocket, Vec<u8>);
+    type Error = DecodeError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let socket = self
+                .socket
+                .as_mut()
+                .expect("ReadChunk polled after completion");
+            match self.length {
+                None => {
+                    let mut byte = [0; 1];
+                    if try_ready!(socket.poll_read(&mut byte)) == 0 {
+                        return Err(DecodeError::TruncatedChunk(self.received()));
+                    }
+                    self.prefix.push(byte[0]);
+                    if byte[0] & 0x80 != 0 {
+                        // a usize takes at most 10 bytes as a varint
+                        if self.prefix.len() >= 10 {
+                            return Err(DecodeError::InvalidLengthPrefix);
+                        }
+                        continue;
+                    }
+                    let length = self
+                        .prefix
+                        .iter()
+                        .enumerate()
+                        .fold(0u64, |length, (i, byte)| {
+                            length | (u64::from(byte & 0x7f) << (7 * i))
+                        }) as usize;
+                    if length > self.max_size {
+                        return Err(DecodeError::ChunkTooLarge(length));
+                    }
+                    self.length = Some(length);
+                    self.chunk = vec![0; length];
+                }
+                Some(length) if self.filled < length => {
+                    let read = try_ready!(socket.poll_read(&mut self.chunk[self.filled..]));
+                    if read == 0 {
+                        return Err(DecodeError::TruncatedChunk(self.received()));
+                    }
+                    self.filled += read;
+                }
+                Some(_) => {
+                    let socket = self
+                        .socket
+                        .take()
+                        .expect("socket is present until completion");
+                    return Ok(Async::Ready((
+                        socket,
+                        std::mem::replace(&mut self.chunk, Vec::new()),
+                    )));
+                }
+            }
+        }
+    }
+}
+
+/// Reads and decodes a frame from the remote, resolving to the frame and the protocol it was
+/// received with.
+///
+/// Frames which cannot be read or decoded resolve to `InboundFrame::Failed`, as the swarm drops
+/// inbound upgrade errors without reporting them to the behaviour.
+pub struct ReadFrame<TSocket> {
+    read: ReadChunk<TSocket>,
+    protocol: Option<(ProtocolId, SizeLimit)>,
+}
+
+impl<TSocket> ReadFrame<TSocket> {
+    pub fn new(socket: TSocket, protocol: ProtocolId, size_limits: &RPCSizeLimits) -> Self {
+        let limit = size_limits.limit(protocol.method_id);
+        ReadFrame {
+            read: ReadChunk::new(socket, limit.max_read(protocol.encoding)),
+            protocol: Some((protocol, limit)),
+        }
+    }
+}
+
+impl<TSocket> Future for ReadFrame<TSocket>
+where
+    TSocket: AsyncRead,
+{
+    type Item = (InboundFrame, ProtocolId);
     type Error = DecodeError;
-    type Future = upgrade::ReadOneThen<upgrade::Negotiated<TSocket>, (), FnDecodeRPCEvent>;
 
-    fn upgrade_inbound(self, socket: upgrade::Negotiated<TSocket>, _: Self::Info) -> Self::Future {
-        upgrade::read_one_then(socket, MAX_READ_SIZE, (), |packet, ()| Ok(decode(packet)?))
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let read = match self.read.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready((_socket, packet))) => Ok(packet),
+            Err(e) => Err(e),
+        };
+        let (protocol, limit) = self
+            .protocol
+            .take()
+            .expect("ReadFrame polled after completion");
+        let frame = read
+            .and_then(|packet| decode_frame(packet, protocol.encoding, limit))
+            .and_then(|frame| {
+                if frame.method_id() != Some(protocol.method_id) {
+                    return Err(DecodeError::ProtocolMismatch);
+                }
+                Ok(frame)
+            })
+            .unwrap_or_else(InboundFrame::Failed);
+        Ok(Async::Ready((frame, protocol)))
+    }
+}
+
+/// Appends `value` to `buf` as an unsigned varint.
+fn push_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Prefixes `frame` with its length as an unsigned varint, as it is written to a substream.
+#[cfg(any(test, feature = "fuzzing"))]
+pub(super) fn length_prefixed(frame: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(frame.len() + 5);
+    push_varint(&mut chunk, frame.len());
+    chunk.extend_from_slice(frame);
+    chunk
+}
+
+/// Encodes an event into a frame of the given encoding.
+///
+/// `ssz_snappy` frames smaller than `compression_threshold` are stored rather than compressed,
+/// as compressing them costs more than it saves. Stored frames are valid snappy, so they are
+/// decoded like any other by every peer.
+#[cfg(any(test, feature = "fuzzing"))]
+pub(super) fn encode_frame(
+    event: &RPCEvent,
+    encoding: RPCEncoding,
+    compression_threshold: usize,
+) -> io::Result<Vec<u8>> {
+    frame_ssz(event.as_ssz_bytes(), encoding, compression_threshold)
+}
+
+/// Frames the SSZ encoding of an event in the given encoding.
+fn frame_ssz(
+    bytes: Vec<u8>,
+    encoding: RPCEncoding,
+    compression_threshold: usize,
+) -> io::Result<Vec<u8>> {
+    match encoding {
+        RPCEncoding::SSZ => Ok(bytes),
+        RPCEncoding::SSZSnappy if bytes.len() < compression_threshold => Ok(stored_snappy(&bytes)),
+        RPCEncoding::SSZSnappy => snap::Encoder::new()
+            .compress_vec(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Encodes `bytes` as a snappy block holding them as a single literal, uncompressed.
+fn stored_snappy(bytes: &[u8]) -> Vec<u8> {
+    let mut block = Vec::with_capacity(bytes.len() + 10);
+    push_varint(&mut block, bytes.len());
+    if bytes.is_empty() {
+        return block;
+    }
+    // the literal tag holds lengths up to 60, longer ones follow it in up to four bytes
+    let length = bytes.len() - 1;
+    if length < 60 {
+        block.push((length as u8) << 2);
+    } else {
+        let length_bytes = (length as u32).to_le_bytes();
+        let count = 4 - length_bytes
+            .iter()
+            .rev()
+            .take_while(|byte| **byte == 0)
+            .count();
+        block.push(((59 + count) as u8) << 2);
+        block.extend_from_slice(&length_bytes[..count]);
+    }
+    block.extend_from_slice(bytes);
+    block
+}
+
+/// Decodes a frame of the given encoding, within the size limit of its method.
+///
+/// Compressed frames which claim to decompress to more than the method's largest frame are
+/// rejected before being decompressed.
+fn decode_frame(
+    packet: Vec<u8>,
+    encoding: RPCEncoding,
+    limit: SizeLimit,
+) -> Result<InboundFrame, DecodeError> {
+    match encoding {
+        RPCEncoding::SSZ => decode(packet, limit),
+        RPCEncoding::SSZSnappy => {
+            let decompressed_size = snap::decompress_len(&packet)?;
+            if decompressed_size > limit.max_frame() {
+                return Err(DecodeError::DecompressedSizeTooLarge(decompressed_size));
+            }
+            decode(snap::Decoder::new().decompress_vec(&packet)?, limit)
+        }
     }
 }
 
 /// A helper structed used to obtain SSZ serialization for RPC messages.
 #[derive(Encode, Decode, Default)]
 struct SszContainer {
-    /// Note: the `is_request` field is not included in the spec.
+    /// Note: the `message_type` field is not included in the spec.
     ///
     /// We are unable to determine a request from a response unless we add some flag to the
-    /// packet. Here we have added a bool (encoded as 1 byte) which is set to `1` if the
-    /// message is a request.
-    is_request: bool,
+    /// packet. Here we have added a byte which is set to `1` if the message is a request, `0` if
+    /// it is a response, `2` if it terminates a response stream and `3` if it is an error.
+    message_type: u8,
     id: u64,
     other: u16,
     bytes: Vec<u8>,
 }
 
-fn decode(packet: Vec<u8>) -> Result<RPCEvent, DecodeError> {
+/// Decodes an SSZ container. Payloads exceeding `limit` are not decoded: oversized requests are
+/// returned as such, oversized responses as an `RPCError::ResponseTooLarge`.
+fn decode(packet: Vec<u8>, limit: SizeLimit) -> Result<InboundFrame, DecodeError> {
     let msg = SszContainer::from_ssz_bytes(&packet)?;
 
-    if msg.is_request {
+    if msg.message_type == REQUEST_MESSAGE && msg.bytes.len() > limit.max_request {
+        return Ok(InboundFrame::OversizedRequest {
+            id: RequestId::from(msg.id),
+            method_id: msg.other,
+            size: msg.bytes.len(),
+        });
+    }
+    if msg.message_type == RESPONSE_MESSAGE && msg.bytes.len() > limit.max_response {
+        return Ok(InboundFrame::Event(RPCEvent::Error {
+            id: RequestId::from(msg.id),
+            method_id: msg.other,
+            error: RPCError::ResponseTooLarge(msg.bytes.len()),
+        }));
+    }
+    decode_container(msg).map(InboundFrame::Event)
+}
+
+fn decode_container(msg: SszContainer) -> Result<RPCEvent, DecodeError> {
+    if msg.message_type == REQUEST_MESSAGE {
         let body = match RPCMethod::from(msg.other) {
             RPCMethod::Hello => RPCRequest::Hello(HelloMessage::from_ssz_bytes(&msg.bytes)?),
             RPCMethod::Goodbye => RPCRequest::Goodbye(GoodbyeReason::from_ssz_bytes(&msg.bytes)?),
@@ -148,6 +734,30 @@ fn decode(packet: Vec<u8>) -> Result<RPCEvent, DecodeError> {
             RPCMethod::BeaconChainState => {
                 RPCRequest::BeaconChainState(BeaconChainStateRequest::from_ssz_bytes(&msg.bytes)?)
             }
+            RPCMethod::BeaconBlocksByRange => RPCRequest::BeaconBlocksByRange(
+                BeaconBlocksByRangeRequest::from_ssz_bytes(&msg.bytes)?,
+            ),
+            RPCMethod::BeaconBlocksByRoot => RPCRequest::BeaconBlocksByRoot(
+                BeaconBlocksByRootRequest::from_ssz_bytes(&msg.bytes)?,
+            ),
+            RPCMethod::BeaconBlockHeadersByRange => RPCRequest::BeaconBlockHeadersByRange(
+                BeaconBlockHeadersByRangeRequest::from_ssz_bytes(&msg.bytes)?,
+            ),
+            RPCMethod::BeaconBlockBodiesByRoot => RPCRequest::BeaconBlockBodiesByRoot(
+                BeaconBlockBodiesByRootRequest::from_ssz_bytes(&msg.bytes)?,
+            ),
+            RPCMethod::MetaData => {
+                if !msg.bytes.is_empty() {
+                    return Err(DecodeError::SSZDecodeError(
+                        ssz::DecodeError::InvalidByteLength {
+                            len: msg.bytes.len(),
+                            expected: 0,
+                        },
+                    ));
+                }
+                RPCRequest::MetaData
+            }
+            RPCMethod::Ping => RPCRequest::Ping(PingMessage::from_ssz_bytes(&msg.bytes)?),
             RPCMethod::Unknown => return Err(DecodeError::UnknownRPCMethod),
         };
 
@@ -157,8 +767,32 @@ fn decode(packet: Vec<u8>) -> Result<RPCEvent, DecodeError> {
             body,
         })
     }
+    // we have received the end of a response stream
+    else if msg.message_type == STREAM_TERMINATION_MESSAGE {
+        Ok(RPCEvent::StreamTermination {
+            id: RequestId::from(msg.id),
+            method_id: msg.other,
+        })
+    }
+    // we have received an error in place of a response
+    else if msg.message_type == ERROR_MESSAGE {
+        let error = SszErrorResponse::from_ssz_bytes(&msg.bytes)?;
+        let code = RPCErrorCode::from_u8(error.code).ok_or(DecodeError::UnknownErrorCode)?;
+        let message = if error.message.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&error.message).into_owned())
+        };
+        let error = RPCError::new(code, message);
+
+        Ok(RPCEvent::Error {
+            id: RequestId::from(msg.id),
+            method_id: msg.other,
+            error,
+        })
+    }
     // we have received a response
-    else {
+    else if msg.message_type == RESPONSE_MESSAGE {
         let result = match RPCMethod::from(msg.other) {
             RPCMethod::Hello => RPCResponse::Hello(HelloMessage::from_ssz_bytes(&msg.bytes)?),
             RPCMethod::BeaconBlockRoots => {
@@ -173,6 +807,20 @@ fn decode(packet: Vec<u8>) -> Result<RPCEvent, DecodeError> {
             RPCMethod::BeaconChainState => {
                 RPCResponse::BeaconChainState(BeaconChainStateResponse::from_ssz_bytes(&msg.bytes)?)
             }
+            RPCMethod::BeaconBlocksByRange => {
+                RPCResponse::BeaconBlocksByRange(Box::new(BeaconBlock::from_ssz_bytes(&msg.bytes)?))
+            }
+            RPCMethod::BeaconBlocksByRoot => {
+                RPCResponse::BeaconBlocksByRoot(Box::new(BeaconBlock::from_ssz_bytes(&msg.bytes)?))
+            }
+            RPCMethod::BeaconBlockHeadersByRange => RPCResponse::BeaconBlockHeadersByRange(
+                BeaconBlockHeader::from_ssz_bytes(&msg.bytes)?,
+            ),
+            RPCMethod::BeaconBlockBodiesByRoot => RPCResponse::BeaconBlockBodiesByRoot(Box::new(
+                BeaconBlockBodyByRoot::from_ssz_bytes(&msg.bytes)?,
+            )),
+            RPCMethod::MetaData => RPCResponse::MetaData(MetaData::from_ssz_bytes(&msg.bytes)?),
+            RPCMethod::Ping => RPCResponse::Ping(PingMessage::from_ssz_bytes(&msg.bytes)?),
             // We should never receive a goodbye response; it is invalid.
             RPCMethod::Goodbye => return Err(DecodeError::UnknownRPCMethod),
             RPCMethod::Unknown => return Err(DecodeError::UnknownRPCMethod),
@@ -183,21 +831,79 @@ fn decode(packet: Vec<u8>) -> Result<RPCEvent, DecodeError> {
             method_id: msg.other,
             result,
         })
+    } else {
+        Err(DecodeError::UnknownMessageType)
     }
 }
 
-impl<TSocket> OutboundUpgrade<TSocket> for RPCEvent
+/// A helper struct used to obtain SSZ serialization for `RPCError`s.
+#[derive(Encode, Decode)]
+struct SszErrorResponse {
+    code: u8,
+    message: Vec<u8>,
+}
+
+/// The outcome of sending an RPC on an outbound substream.
+#[derive(Debug)]
+pub enum OutboundOutcome {
+    /// The RPC was sent using the given protocol.
+    Sent(ProtocolId),
+    /// The RPC `id` of the method `method_id` could not be sent. `request` is set if the RPC was a
+    /// request of ours, rather than part of a response to a request of the peer.
+    Failed {
+        id: RequestId,
+        method_id: u16,
+        request: bool,
+        error: RPCError,
+    },
+}
+
+impl<TSocket> OutboundUpgrade<TSocket> for OutboundRPC
 where
     TSocket: AsyncWrite,
 {
-    type Output = ();
+    /// The protocol negotiated with the remote. Failures are reported by the connection handler.
+    type Output = OutboundOutcome;
     type Error = io::Error;
-    type Future = upgrade::WriteOne<upgrade::Negotiated<TSocket>>;
+    type Future =
+        future::Either<WriteFrame<TSocket>, future::FutureResult<OutboundOutcome, io::Error>>;
 
     #[inline]
-    fn upgrade_outbound(self, socket: upgrade::Negotiated<TSocket>, _: Self::Info) -> Self::Future {
-        let bytes = ssz_encode(&self);
-        upgrade::write_one(socket, bytes)
+    fn upgrade_outbound(
+        self,
+        socket: upgrade::Negotiated<TSocket>,
+        protocol: Self::Info,
+    ) -> Self::Future {
+        match frame_ssz(self.ssz, protocol.encoding, self.compression_threshold) {
+            Ok(bytes) => future::Either::A(WriteFrame {
+                write: upgrade::write_one(socket, bytes),
+                protocol: Some(protocol),
+            }),
+            Err(e) => future::Either::B(future::err(e)),
+        }
+    }
+}
+
+/// Writes a frame to the remote, resolving to the protocol it was sent with once it is sent.
+pub struct WriteFrame<TSocket> {
+    write: upgrade::WriteOne<upgrade::Negotiated<TSocket>>,
+    protocol: Option<ProtocolId>,
+}
+
+impl<TSocket> Future for WriteFrame<TSocket>
+where
+    TSocket: AsyncWrite,
+{
+    type Item = OutboundOutcome;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        try_ready!(self.write.poll());
+        Ok(Async::Ready(OutboundOutcome::Sent(
+            self.protocol
+                .take()
+                .expect("WriteFrame polled after completion"),
+        )))
     }
 }
 
@@ -213,7 +919,7 @@ impl Encode for RPCEvent {
                 method_id,
                 body,
             } => SszContainer {
-                is_request: true,
+                message_type: REQUEST_MESSAGE,
                 id: (*id).into(),
                 other: *method_id,
                 bytes: match body {
@@ -223,6 +929,12 @@ impl Encode for RPCEvent {
                     RPCRequest::BeaconBlockHeaders(body) => body.as_ssz_bytes(),
                     RPCRequest::BeaconBlockBodies(body) => body.as_ssz_bytes(),
                     RPCRequest::BeaconChainState(body) => body.as_ssz_bytes(),
+                    RPCRequest::BeaconBlocksByRange(body) => body.as_ssz_bytes(),
+                    RPCRequest::BeaconBlocksByRoot(body) => body.as_ssz_bytes(),
+                    RPCRequest::BeaconBlockHeadersByRange(body) => body.as_ssz_bytes(),
+                    RPCRequest::BeaconBlockBodiesByRoot(body) => body.as_ssz_bytes(),
+                    RPCRequest::MetaData => vec![],
+                    RPCRequest::Ping(body) => body.as_ssz_bytes(),
                 },
             },
             RPCEvent::Response {
@@ -230,7 +942,7 @@ impl Encode for RPCEvent {
                 method_id,
                 result,
             } => SszContainer {
-                is_request: false,
+                message_type: RESPONSE_MESSAGE,
                 id: (*id).into(),
                 other: *method_id,
                 bytes: match result {
@@ -239,8 +951,39 @@ impl Encode for RPCEvent {
                     RPCResponse::BeaconBlockHeaders(response) => response.as_ssz_bytes(),
                     RPCResponse::BeaconBlockBodies(response) => response.as_ssz_bytes(),
                     RPCResponse::BeaconChainState(response) => response.as_ssz_bytes(),
+                    RPCResponse::BeaconBlocksByRange(response) => response.as_ssz_bytes(),
+                    RPCResponse::BeaconBlocksByRoot(response) => response.as_ssz_bytes(),
+                    RPCResponse::BeaconBlockHeadersByRange(response) => response.as_ssz_bytes(),
+                    RPCResponse::BeaconBlockBodiesByRoot(response) => response.as_ssz_bytes(),
+                    RPCResponse::MetaData(response) => response.as_ssz_bytes(),
+                    RPCResponse::Ping(response) => response.as_ssz_bytes(),
                 },
             },
+            RPCEvent::StreamTermination { id, method_id } => SszContainer {
+                message_type: STREAM_TERMINATION_MESSAGE,
+                id: (*id).into(),
+                other: *method_id,
+                bytes: vec![],
+            },
+            RPCEvent::Error {
+                id,
+                method_id,
+                error,
+            } => SszContainer {
+                message_type: ERROR_MESSAGE,
+                id: (*id).into(),
+                other: *method_id,
+                bytes: SszErrorResponse {
+                    code: error.code(),
+                    message: match error {
+                        RPCError::Response {
+                            message: Some(msg), ..
+                        } => msg.as_bytes().to_vec(),
+                        _ => vec![],
+                    },
+                }
+                .as_ssz_bytes(),
+            },
         };
 
         container.ssz_append(buf)
@@ -249,21 +992,1067 @@ impl Encode for RPCEvent {
 
 #[derive(Debug)]
 pub enum DecodeError {
-    ReadError(upgrade::ReadOneError),
+    ReadError(io::Error),
+    /// The length prefix of a chunk is not a valid unsigned varint.
+    InvalidLengthPrefix,
+    /// The length prefix of a chunk exceeds the maximum read size of its method.
+    ChunkTooLarge(usize),
+    /// The stream ended after the given number of bytes of a chunk, including its prefix.
+    TruncatedChunk(usize),
     SSZDecodeError(ssz::DecodeError),
     UnknownRPCMethod,
+    UnknownMessageType,
+    UnknownErrorCode,
+    SnappyError(snap::Error),
+    /// A compressed frame would decompress to more than the maximum size.
+    DecompressedSizeTooLarge(usize),
+    /// The message does not belong to the method of the negotiated protocol.
+    ProtocolMismatch,
 }
 
-impl From<upgrade::ReadOneError> for DecodeError {
+impl From<io::Error> for DecodeError {
     #[inline]
-    fn from(err: upgrade::ReadOneError) -> Self {
+    fn from(err: io::Error) -> Self {
         DecodeError::ReadError(err)
     }
 }
 
+impl From<snap::Error> for DecodeError {
+    #[inline]
+    fn from(err: snap::Error) -> Self {
+        DecodeError::SnappyError(err)
+    }
+}
+
 impl From<ssz::DecodeError> for DecodeError {
     #[inline]
     fn from(err: ssz::DecodeError) -> Self {
         DecodeError::SSZDecodeError(err)
     }
 }
+
+/// The error a frame which failed to decode is answered with, were it a request.
+impl From<DecodeError> for RPCError {
+    fn from(err: DecodeError) -> Self {
+        let message = match err {
+            DecodeError::ReadError(_) => "Read failed",
+            DecodeError::InvalidLengthPrefix => "Invalid length prefix",
+            DecodeError::ChunkTooLarge(_) => "Chunk too large",
+            DecodeError::TruncatedChunk(_) => "Chunk truncated",
+            DecodeError::SSZDecodeError(_) => "Invalid SSZ",
+            DecodeError::UnknownRPCMethod => "Unknown method",
+            DecodeError::UnknownMessageType => "Unknown message type",
+            DecodeError::UnknownErrorCode => "Unknown error code",
+            DecodeError::SnappyError(_) => "Invalid snappy compression",
+            DecodeError::DecompressedSizeTooLarge(_) => "Decompressed frame too large",
+            DecodeError::ProtocolMismatch => "Message does not match protocol",
+        };
+        RPCError::new(RPCErrorCode::InvalidRequest, message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ssz::ssz_encode;
+    use types::{Epoch, EthSpec, Hash256, MainnetEthSpec, Slot};
+
+    /// The compression threshold of a peer which compresses every `ssz_snappy` frame.
+    const ALWAYS_COMPRESS: usize = 0;
+
+    /// Returns the default size limit of the method `method_id`.
+    fn limit_of(method_id: u16) -> SizeLimit {
+        RPCSizeLimits::default().limit(method_id)
+    }
+
+    /// Returns the event of a decoded frame, which must not be an oversized request.
+    fn into_event(frame: Result<InboundFrame, DecodeError>) -> Result<RPCEvent, DecodeError> {
+        frame.map(|frame| match frame {
+            InboundFrame::Event(event) => event,
+            frame => panic!("expected an event, got {:?}", frame),
+        })
+    }
+
+    fn round_trip(event: RPCEvent) -> RPCEvent {
+        into_event(decode(ssz_encode(&event), limit_of(event.method_id())))
+            .expect("should decode encoded event")
+    }
+
+    /// Builds the events a responder sends for a range request of which `returned` blocks are
+    /// known.
+    fn blocks_by_range_response(id: RequestId, returned: usize) -> Vec<RPCEvent> {
+        let block = BeaconBlock::empty(&MainnetEthSpec::default_spec());
+        let mut events: Vec<RPCEvent> = (0..returned)
+            .map(|_| RPCEvent::Response {
+                id,
+                method_id: RPCMethod::BeaconBlocksByRange.into(),
+                result: RPCResponse::BeaconBlocksByRange(Box::new(block.clone())),
+            })
+            .collect();
+        events.push(RPCEvent::StreamTermination {
+            id,
+            method_id: RPCMethod::BeaconBlocksByRange.into(),
+        });
+        events
+    }
+
+    /// Returns the number of blocks in `events`, checking they all belong to `id` and end with a
+    /// stream termination.
+    fn count_streamed_blocks(id: RequestId, events: Vec<RPCEvent>) -> usize {
+        let mut blocks = 0;
+        let mut terminated = false;
+        for event in events.into_iter().map(round_trip) {
+            assert!(
+                !terminated,
+                "no events should follow the stream termination"
+            );
+            assert_eq!(event.id(), id);
+            match event {
+                RPCEvent::Response {
+                    result: RPCResponse::BeaconBlocksByRange(_),
+                    ..
+                } => blocks += 1,
+                RPCEvent::StreamTermination { .. } => terminated = true,
+                _ => panic!("unexpected event"),
+            }
+        }
+        assert!(terminated);
+        blocks
+    }
+
+    /// Sends `event` over a substream negotiated with `encoding`, returning the frame and the
+    /// decoded event.
+    fn negotiated_round_trip(event: &RPCEvent, encoding: RPCEncoding) -> (Vec<u8>, RPCEvent) {
+        let frame = encode_frame(event, encoding, ALWAYS_COMPRESS).expect("should encode event");
+        let decoded = into_event(decode_frame(
+            frame.clone(),
+            encoding,
+            limit_of(event.method_id()),
+        ))
+        .expect("should decode frame");
+        (frame, decoded)
+    }
+
+    #[test]
+    fn snappy_and_plain_ssz_negotiation() {
+        let request = BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(10),
+            count: 64,
+            step: 1,
+        };
+        let event = RPCEvent::Request {
+            id: RequestId::from(1),
+            method_id: RPCMethod::BeaconBlocksByRange.into(),
+            body: RPCRequest::BeaconBlocksByRange(request.clone()),
+        };
+
+        let (plain_frame, plain) = negotiated_round_trip(&event, RPCEncoding::SSZ);
+        let (snappy_frame, snappy) = negotiated_round_trip(&event, RPCEncoding::SSZSnappy);
+        assert_eq!(plain_frame, ssz_encode(&event));
+        assert_ne!(snappy_frame, plain_frame);
+        for decoded in vec![plain, snappy] {
+            match decoded {
+                RPCEvent::Request {
+                    body: RPCRequest::BeaconBlocksByRange(decoded),
+                    ..
+                } => assert_eq!(decoded, request),
+                _ => panic!("decoded incorrect event"),
+            }
+        }
+
+        // frames are only readable with the negotiated encoding
+        let limit = limit_of(RPCMethod::BeaconBlocksByRange.into());
+        assert!(decode_frame(snappy_frame, RPCEncoding::SSZ, limit).is_err());
+    }
+
+    #[test]
+    fn snappy_compresses_block_responses() {
+        let event = RPCEvent::Response {
+            id: RequestId::from(2),
+            method_id: RPCMethod::BeaconBlocksByRange.into(),
+            result: RPCResponse::BeaconBlocksByRange(Box::new(BeaconBlock::empty(
+                &MainnetEthSpec::default_spec(),
+            ))),
+        };
+
+        let (plain_frame, _) = negotiated_round_trip(&event, RPCEncoding::SSZ);
+        let (snappy_frame, decoded) = negotiated_round_trip(&event, RPCEncoding::SSZSnappy);
+        assert!(snappy_frame.len() < plain_frame.len());
+        assert_eq!(decoded.id(), event.id());
+    }
+
+    #[test]
+    fn small_frames_stored() {
+        let threshold = 128;
+        let metadata = RPCEvent::Request {
+            id: RequestId::from(3),
+            method_id: RPCMethod::MetaData.into(),
+            body: RPCRequest::MetaData,
+        };
+        let block = RPCEvent::Response {
+            id: RequestId::from(3),
+            method_id: RPCMethod::BeaconBlocksByRange.into(),
+            result: RPCResponse::BeaconBlocksByRange(Box::new(BeaconBlock::empty(
+                &MainnetEthSpec::default_spec(),
+            ))),
+        };
+        assert!(ssz_encode(&metadata).len() < threshold);
+        assert!(ssz_encode(&block).len() >= threshold);
+
+        let stored = encode_frame(&metadata, RPCEncoding::SSZSnappy, threshold)
+            .expect("should encode event");
+        assert_eq!(stored, stored_snappy(&ssz_encode(&metadata)));
+        let compressed =
+            encode_frame(&block, RPCEncoding::SSZSnappy, threshold).expect("should encode event");
+        assert_eq!(
+            compressed,
+            encode_frame(&block, RPCEncoding::SSZSnappy, ALWAYS_COMPRESS)
+                .expect("should encode event")
+        );
+
+        // both forms are read alike
+        for (frame, event) in vec![(stored, metadata), (compressed, block)] {
+            let limit = limit_of(event.method_id());
+            let decoded = into_event(decode_frame(frame, RPCEncoding::SSZSnappy, limit))
+                .expect("should decode frame");
+            assert_eq!(ssz_encode(&decoded), ssz_encode(&event));
+        }
+    }
+
+    #[test]
+    fn stored_frames_read_by_always_compressing_peer() {
+        // lengths either side of each size of literal tag
+        for length in &[
+            0,
+            1,
+            60,
+            61,
+            256,
+            257,
+            65_536,
+            65_537,
+            1 << 24,
+            (1 << 24) + 1,
+        ] {
+            let bytes: Vec<u8> = (0..*length).map(|i| i as u8).collect();
+            let stored = stored_snappy(&bytes);
+            assert_eq!(
+                snap::decompress_len(&stored).expect("valid snappy"),
+                bytes.len()
+            );
+            let decompressed = snap::Decoder::new()
+                .decompress_vec(&stored)
+                .expect("valid snappy");
+            assert_eq!(decompressed, bytes);
+            assert!(stored.len() <= snap::max_compress_len(bytes.len()));
+        }
+
+        // the peer reads our stored frames, and we read its compressed ones
+        let hello = RPCEvent::Request {
+            id: RequestId::from(4),
+            method_id: RPCMethod::Hello.into(),
+            body: RPCRequest::Hello(HelloMessage {
+                network_id: 1,
+                genesis_root: Hash256::zero(),
+                latest_finalized_root: Hash256::zero(),
+                latest_finalized_epoch: Epoch::new(0),
+                best_root: Hash256::zero(),
+                best_slot: Slot::new(0),
+            }),
+        };
+        let limit = limit_of(hello.method_id());
+        let ours = encode_frame(&hello, RPCEncoding::SSZSnappy, usize::max_value())
+            .expect("should encode event");
+        let theirs = encode_frame(&hello, RPCEncoding::SSZSnappy, ALWAYS_COMPRESS)
+            .expect("should encode event");
+        assert_ne!(ours, theirs);
+        for frame in vec![ours, theirs] {
+            let decoded = into_event(decode_frame(frame, RPCEncoding::SSZSnappy, limit))
+                .expect("should decode frame");
+            assert_eq!(ssz_encode(&decoded), ssz_encode(&hello));
+        }
+    }
+
+    #[test]
+    fn oversized_decompression_rejected() {
+        for method in RPCMethod::known() {
+            let limit = limit_of(method.into());
+            let frame = snap::Encoder::new()
+                .compress_vec(&vec![0; limit.max_frame() + 1])
+                .expect("should compress");
+            assert!(frame.len() <= limit.max_read(RPCEncoding::SSZSnappy));
+
+            match decode_frame(frame, RPCEncoding::SSZSnappy, limit) {
+                Err(DecodeError::DecompressedSizeTooLarge(size)) => {
+                    assert_eq!(size, limit.max_frame() + 1)
+                }
+                other => panic!("expected the frame to be rejected, got {:?}", other),
+            }
+        }
+    }
+
+    /// Builds a container of `message_type` for `method` carrying `size` bytes.
+    fn container(message_type: u8, method: RPCMethod, size: usize) -> Vec<u8> {
+        SszContainer {
+            message_type,
+            id: 5,
+            other: method.into(),
+            bytes: vec![0; size],
+        }
+        .as_ssz_bytes()
+    }
+
+    #[test]
+    fn oversized_requests_left_undecoded() {
+        for method in RPCMethod::known() {
+            let limit = limit_of(method.into());
+            let oversized = container(REQUEST_MESSAGE, method, limit.max_request + 1);
+            match decode(oversized, limit) {
+                Ok(InboundFrame::OversizedRequest {
+                    id,
+                    method_id,
+                    size,
+                }) => {
+                    assert_eq!(id, RequestId::from(5));
+                    let expected: u16 = method.into();
+                    assert_eq!(method_id, expected);
+                    assert_eq!(size, limit.max_request + 1);
+                }
+                other => panic!(
+                    "expected an oversized {:?} request, got {:?}",
+                    method, other
+                ),
+            }
+
+            // requests within the limit are decoded as usual
+            let within = container(REQUEST_MESSAGE, method, limit.max_request);
+            if let Ok(InboundFrame::OversizedRequest { .. }) = decode(within, limit) {
+                panic!("expected the {:?} request to be decoded", method);
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_responses_rejected() {
+        for method in RPCMethod::known() {
+            let limit = limit_of(method.into());
+            let oversized = container(RESPONSE_MESSAGE, method, limit.max_response + 1);
+            match into_event(decode(oversized, limit)) {
+                Ok(RPCEvent::Error { id, error, .. }) => {
+                    assert_eq!(id, RequestId::from(5));
+                    assert_eq!(error, RPCError::ResponseTooLarge(limit.max_response + 1));
+                }
+                other => panic!(
+                    "expected an oversized {:?} response, got {:?}",
+                    method, other
+                ),
+            }
+
+            let within = container(RESPONSE_MESSAGE, method, limit.max_response);
+            if let Ok(RPCEvent::Error {
+                error: RPCError::ResponseTooLarge(_),
+                ..
+            }) = into_event(decode(within, limit))
+            {
+                panic!("expected the {:?} response to be decoded", method);
+            }
+        }
+    }
+
+    #[test]
+    fn largest_frames_fit_read_limit() {
+        for method in RPCMethod::known() {
+            let limit = limit_of(method.into());
+            let event = RPCEvent::Error {
+                id: RequestId::from(1),
+                method_id: method.into(),
+                error: RPCError::new(RPCErrorCode::ServerError, "a".repeat(MAX_ERROR_PAYLOAD)),
+            };
+            for encoding in &[RPCEncoding::SSZ, RPCEncoding::SSZSnappy] {
+                let frame =
+                    encode_frame(&event, *encoding, ALWAYS_COMPRESS).expect("should encode event");
+                assert!(frame.len() <= limit.max_read(*encoding));
+            }
+            // incompressible frames of the largest size are still read in full
+            assert!(
+                snap::max_compress_len(limit.max_frame()) <= limit.max_read(RPCEncoding::SSZSnappy)
+            );
+        }
+    }
+
+    /// Encodes `events` as a stream of length-prefixed chunks.
+    fn chunk_stream(events: &[RPCEvent], encoding: RPCEncoding) -> Vec<u8> {
+        events
+            .iter()
+            .flat_map(|event| {
+                length_prefixed(
+                    &encode_frame(event, encoding, ALWAYS_COMPRESS).expect("should encode event"),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multi_chunk_stream_decoded() {
+        let id = RequestId::from(1);
+        let method_id = RPCMethod::BeaconBlocksByRange.into();
+        let limit = limit_of(method_id);
+        let encoding = RPCEncoding::SSZSnappy;
+        let stream = chunk_stream(&blocks_by_range_response(id, 3), encoding);
+
+        let mut socket = io::Cursor::new(stream);
+        let mut events = vec![];
+        loop {
+            let (rest, chunk) = ReadChunk::new(socket, limit.max_read(encoding))
+                .wait()
+                .expect("should read chunk");
+            socket = rest;
+            let event = into_event(decode_frame(chunk, encoding, limit)).expect("should decode");
+            let terminated = match event {
+                RPCEvent::StreamTermination { .. } => true,
+                _ => false,
+            };
+            events.push(event);
+            if terminated {
+                break;
+            }
+        }
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|event| event.id() == id));
+
+        // nothing follows the termination
+        match ReadChunk::new(socket, limit.max_read(encoding)).wait() {
+            Err(DecodeError::TruncatedChunk(0)) => {}
+            _ => panic!("expected the stream to have ended"),
+        }
+    }
+
+    #[test]
+    fn truncated_stream_rejected() {
+        let id = RequestId::from(1);
+        let method_id = RPCMethod::BeaconBlocksByRange.into();
+        let max_read = limit_of(method_id).max_read(RPCEncoding::SSZ);
+        let events = blocks_by_range_response(id, 2);
+        let first = chunk_stream(&events[..1], RPCEncoding::SSZ);
+        let stream = chunk_stream(&events, RPCEncoding::SSZ);
+
+        // the peer stops part way through the second chunk
+        let cut = first.len() + 10;
+        let socket = io::Cursor::new(stream[..cut].to_vec());
+        let (socket, chunk) = ReadChunk::new(socket, max_read)
+            .wait()
+            .expect("the first chunk is complete");
+        assert_eq!(
+            chunk,
+            encode_frame(&events[0], RPCEncoding::SSZ, ALWAYS_COMPRESS)
+                .expect("should encode event")
+        );
+        match ReadChunk::new(socket, max_read).wait() {
+            Err(DecodeError::TruncatedChunk(received)) => assert_eq!(received, 10),
+            _ => panic!("expected the truncated chunk to fail"),
+        }
+
+        // a stream ending within the length prefix also fails
+        match ReadChunk::new(io::Cursor::new(vec![0x80]), max_read).wait() {
+            Err(DecodeError::TruncatedChunk(1)) => {}
+            _ => panic!("expected the truncated prefix to fail"),
+        }
+
+        // chunks longer than the method allows are not read
+        let oversized = vec![0; max_read + 1];
+        let socket = io::Cursor::new(length_prefixed(&oversized));
+        match ReadChunk::new(socket, max_read).wait() {
+            Err(DecodeError::ChunkTooLarge(size)) => assert_eq!(size, max_read + 1),
+            _ => panic!("expected the oversized chunk to be rejected"),
+        }
+    }
+
+    /// Returns the protocol ids of `protocols`.
+    fn protocol_ids(protocols: Vec<ProtocolId>) -> Vec<String> {
+        protocols
+            .into_iter()
+            .map(|protocol| protocol.protocol_id)
+            .collect()
+    }
+
+    #[test]
+    fn encoding_preference_order() {
+        let method = [RPCMethod::Hello];
+        assert_eq!(
+            protocol_ids(supported_protocols(
+                &method,
+                RPCVersion::V1,
+                &[RPCEncoding::SSZSnappy, RPCEncoding::SSZ]
+            )),
+            vec![
+                "/eth2/beacon_chain/req/hello/3/ssz_snappy",
+                "/eth2/beacon_chain/req/hello/3/ssz",
+                "/eth2/beacon_chain/req/hello/2/ssz_snappy",
+                "/eth2/beacon_chain/req/hello/2/ssz",
+                "/eth2/beacon_chain/req/hello/1/ssz_snappy",
+                "/eth2/beacon_chain/req/hello/1/ssz",
+            ]
+        );
+        // unlisted encodings remain supported at the lowest preference
+        assert_eq!(
+            protocol_ids(supported_protocols(
+                &method,
+                RPCVersion::V1,
+                &[RPCEncoding::SSZ]
+            )),
+            vec![
+                "/eth2/beacon_chain/req/hello/3/ssz",
+                "/eth2/beacon_chain/req/hello/3/ssz_snappy",
+                "/eth2/beacon_chain/req/hello/2/ssz",
+                "/eth2/beacon_chain/req/hello/2/ssz_snappy",
+                "/eth2/beacon_chain/req/hello/1/ssz",
+                "/eth2/beacon_chain/req/hello/1/ssz_snappy",
+            ]
+        );
+    }
+
+    /// Selects the first protocol proposed by the dialer that the listener supports, as
+    /// multistream-select does.
+    fn negotiate(dialer: Vec<ProtocolId>, listener: &[ProtocolId]) -> Option<ProtocolId> {
+        dialer
+            .into_iter()
+            .find(|protocol| listener.contains(protocol))
+    }
+
+    /// The protocols a peer supporting each method up to `version` listens on.
+    fn listener_up_to(version: RPCVersion) -> Vec<ProtocolId> {
+        RPCProtocol::default()
+            .protocol_info()
+            .into_iter()
+            .filter(|protocol| protocol.version <= version)
+            .collect()
+    }
+
+    #[test]
+    fn v2_peer_falls_back_to_v1_peer() {
+        let outbound = |body: RPCRequest| {
+            let event = RPCEvent::Request {
+                id: RequestId::from(1),
+                method_id: body.method_id(),
+                body,
+            };
+            OutboundRPC {
+                ssz: event.as_ssz_bytes(),
+                event,
+                encodings: vec![RPCEncoding::SSZSnappy, RPCEncoding::SSZ],
+                compression_threshold: ALWAYS_COMPRESS,
+            }
+        };
+        let roots_request = || {
+            RPCRequest::BeaconBlockRoots(BeaconBlockRootsRequest {
+                start_slot: Slot::new(0),
+                count: 10,
+            })
+        };
+
+        // a peer supporting every version uses the newest
+        let negotiated = negotiate(
+            outbound(roots_request()).protocol_info(),
+            &RPCProtocol::default().protocol_info(),
+        )
+        .expect("should negotiate");
+        assert_eq!(negotiated.version, RPCVersion::V3);
+        assert_eq!(negotiated.encoding, RPCEncoding::SSZSnappy);
+        assert_eq!(
+            negotiated.protocol_id,
+            "/eth2/beacon_chain/req/beacon_block_roots/3/ssz_snappy"
+        );
+
+        // a peer only supporting the first version is sent requests with it
+        let negotiated = negotiate(
+            outbound(roots_request()).protocol_info(),
+            &listener_up_to(RPCVersion::V1),
+        )
+        .expect("should negotiate");
+        let method_id: u16 = RPCMethod::BeaconBlockRoots.into();
+        assert_eq!(negotiated.method_id, method_id);
+        assert_eq!(negotiated.version, RPCVersion::V1);
+
+        // requests requiring the second version are not proposed to it
+        let range_request = RPCRequest::BeaconBlocksByRange(BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(0),
+            count: 10,
+            step: 1,
+        });
+        assert!(negotiate(
+            outbound(range_request).protocol_info(),
+            &listener_up_to(RPCVersion::V1)
+        )
+        .is_none());
+
+        // the hello, whose layout gained the genesis root, is only proposed on the third version
+        let hello = RPCRequest::Hello(HelloMessage {
+            network_id: 1,
+            latest_finalized_root: Hash256::zero(),
+            latest_finalized_epoch: Epoch::new(0),
+            best_root: Hash256::zero(),
+            best_slot: Slot::new(0),
+            genesis_root: Hash256::zero(),
+        });
+        assert!(negotiate(
+            outbound(hello.clone()).protocol_info(),
+            &listener_up_to(RPCVersion::V2)
+        )
+        .is_none());
+        let negotiated = negotiate(
+            outbound(hello).protocol_info(),
+            &listener_up_to(RPCVersion::V3),
+        )
+        .expect("should negotiate");
+        assert_eq!(negotiated.version, RPCVersion::V3);
+    }
+
+    #[test]
+    fn blocks_by_range_request_encoding() {
+        let request = BeaconBlocksByRangeRequest {
+            start_slot: Slot::new(10),
+            count: 20,
+            step: 1,
+        };
+        let event = round_trip(RPCEvent::Request {
+            id: RequestId::from(3),
+            method_id: RPCMethod::BeaconBlocksByRange.into(),
+            body: RPCRequest::BeaconBlocksByRange(request.clone()),
+        });
+
+        match event {
+            RPCEvent::Request {
+                id,
+                body: RPCRequest::BeaconBlocksByRange(decoded),
+                ..
+            } => {
+                assert_eq!(id, RequestId::from(3));
+                assert_eq!(decoded, request);
+            }
+            _ => panic!("decoded incorrect event"),
+        }
+    }
+
+    #[test]
+    fn metadata_encoding() {
+        let method_id: u16 = RPCMethod::MetaData.into();
+        let request = round_trip(RPCEvent::Request {
+            id: RequestId::from(1),
+            method_id,
+            body: RPCRequest::MetaData,
+        });
+        match request {
+            RPCEvent::Request {
+                body: RPCRequest::MetaData,
+                ..
+            } => {}
+            _ => panic!("decoded incorrect event"),
+        }
+
+        let mut metadata = MetaData::default();
+        metadata.seq_number = 2;
+        metadata.attnets.set(ATTESTATION_SUBNET_COUNT - 1, true);
+        let response = round_trip(RPCEvent::Response {
+            id: RequestId::from(1),
+            method_id,
+            result: RPCResponse::MetaData(metadata.clone()),
+        });
+        match response {
+            RPCEvent::Response {
+                result: RPCResponse::MetaData(decoded),
+                ..
+            } => assert_eq!(decoded, metadata),
+            _ => panic!("decoded incorrect event"),
+        }
+    }
+
+    #[test]
+    fn ping_encoding() {
+        let method_id: u16 = RPCMethod::Ping.into();
+        let ping = PingMessage { data: 42 };
+        let request = round_trip(RPCEvent::Request {
+            id: RequestId::from(1),
+            method_id,
+            body: RPCRequest::Ping(ping.clone()),
+        });
+        match request {
+            RPCEvent::Request {
+                body: RPCRequest::Ping(decoded),
+                ..
+            } => assert_eq!(decoded, ping),
+            _ => panic!("decoded incorrect event"),
+        }
+
+        let response = round_trip(RPCEvent::Response {
+            id: RequestId::from(1),
+            method_id,
+            result: RPCResponse::Ping(ping.clone()),
+        });
+        match response {
+            RPCEvent::Response {
+                result: RPCResponse::Ping(decoded),
+                ..
+            } => assert_eq!(decoded, ping),
+            _ => panic!("decoded incorrect event"),
+        }
+    }
+
+    #[test]
+    fn blocks_by_range_full_response() {
+        let id = RequestId::from(7);
+        assert_eq!(
+            count_streamed_blocks(id, blocks_by_range_response(id, 8)),
+            8
+        );
+    }
+
+    #[test]
+    fn blocks_by_range_partial_response() {
+        let id = RequestId::from(8);
+        assert_eq!(
+            count_streamed_blocks(id, blocks_by_range_response(id, 3)),
+            3
+        );
+    }
+
+    #[test]
+    fn blocks_by_range_chunked_frames() {
+        let id = RequestId::from(9);
+        let events = blocks_by_range_response(id, 4);
+
+        // each block is framed on its own, within the chunk limit
+        let mut decoded = vec![];
+        for event in &events {
+            let frame = encode_frame(event, RPCEncoding::SSZSnappy, ALWAYS_COMPRESS)
+                .expect("should encode event");
+            assert!(frame.len() <= MAX_CHUNK_SIZE);
+            let limit = limit_of(event.method_id());
+            decoded.push(
+                into_event(decode_frame(frame, RPCEncoding::SSZSnappy, limit))
+                    .expect("should decode frame"),
+            );
+        }
+        assert_eq!(count_streamed_blocks(id, decoded), 4);
+    }
+
+    #[test]
+    fn oversized_chunk_rejected() {
+        let chunk = |size| SszContainer {
+            message_type: RESPONSE_MESSAGE,
+            id: 3,
+            other: RPCMethod::BeaconBlocksByRange.into(),
+            bytes: vec![0; size],
+        };
+
+        let limit = limit_of(RPCMethod::BeaconBlocksByRange.into());
+        match into_event(decode(chunk(MAX_CHUNK_SIZE + 1).as_ssz_bytes(), limit)) {
+            Ok(RPCEvent::Error { id, error, .. }) => {
+                assert_eq!(id, RequestId::from(3));
+                assert_eq!(error, RPCError::ResponseTooLarge(MAX_CHUNK_SIZE + 1));
+            }
+            _ => panic!("expected the chunk to be rejected"),
+        }
+        // chunks within the limit are decoded as usual
+        match into_event(decode(chunk(MAX_CHUNK_SIZE).as_ssz_bytes(), limit)) {
+            Err(DecodeError::SSZDecodeError(_)) => {}
+            _ => panic!("expected the chunk to be decoded"),
+        }
+    }
+
+    #[test]
+    fn blocks_by_range_count_cap() {
+        let request = |count| {
+            RPCRequest::BeaconBlocksByRange(BeaconBlocksByRangeRequest {
+                start_slot: Slot::new(0),
+                count,
+                step: 1,
+            })
+        };
+        assert!(request(MAX_REQUEST_BLOCKS).validate().is_ok());
+
+        let error = request(MAX_REQUEST_BLOCKS + 1)
+            .validate()
+            .expect_err("should exceed the count cap");
+        let event = round_trip(RPCEvent::Error {
+            id: RequestId::from(9),
+            method_id: RPCMethod::BeaconBlocksByRange.into(),
+            error: error.clone(),
+        });
+
+        match event {
+            RPCEvent::Error { error: decoded, .. } => assert_eq!(decoded, error),
+            _ => panic!("decoded incorrect event"),
+        }
+    }
+
+    #[test]
+    fn error_codes_round_trip() {
+        let codes = [
+            RPCErrorCode::InvalidRequest,
+            RPCErrorCode::ServerError,
+            RPCErrorCode::ResourceUnavailable,
+            RPCErrorCode::RateLimited,
+        ];
+        for code in codes.iter() {
+            for message in vec![None, Some("reason".to_string())] {
+                let error = RPCError::new(*code, message);
+                let event = round_trip(RPCEvent::Error {
+                    id: RequestId::from(3),
+                    method_id: RPCMethod::BeaconBlocksByRoot.into(),
+                    error: error.clone(),
+                });
+
+                match event {
+                    RPCEvent::Error { error: decoded, .. } => assert_eq!(decoded, error),
+                    _ => panic!("decoded incorrect event"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_error_message_truncated() {
+        let error = RPCError::new(
+            RPCErrorCode::ServerError,
+            "é".repeat(MAX_ERROR_MESSAGE_LENGTH),
+        );
+        assert!(error.message().len() <= MAX_ERROR_MESSAGE_LENGTH);
+        assert_eq!(error.message(), "é".repeat(MAX_ERROR_MESSAGE_LENGTH / 2));
+
+        // a peer sending an oversized message is truncated on receipt
+        let container = SszContainer {
+            message_type: ERROR_MESSAGE,
+            id: 1,
+            other: RPCMethod::BeaconBlocksByRoot.into(),
+            bytes: SszErrorResponse {
+                code: RPCErrorCode::RateLimited.into(),
+                message: vec![b'a'; MAX_ERROR_MESSAGE_LENGTH * 2],
+            }
+            .as_ssz_bytes(),
+        };
+        let limit = limit_of(RPCMethod::BeaconBlocksByRoot.into());
+        match into_event(decode(container.as_ssz_bytes(), limit)) {
+            Ok(RPCEvent::Error { error, .. }) => {
+                assert_eq!(error.code(), RPCErrorCode::RateLimited.into());
+                assert_eq!(error.message().len(), MAX_ERROR_MESSAGE_LENGTH);
+            }
+            _ => panic!("decoded incorrect event"),
+        }
+    }
+
+    #[test]
+    fn blocks_by_root_known_and_unknown_roots() {
+        let spec = MainnetEthSpec::default_spec();
+        let known_blocks: Vec<BeaconBlock> = (0..3)
+            .map(|slot| {
+                let mut block = BeaconBlock::empty(&spec);
+                block.slot = Slot::new(slot);
+                block
+            })
+            .collect();
+        let known_roots: Vec<Hash256> = known_blocks
+            .iter()
+            .map(BeaconBlock::canonical_root)
+            .collect();
+        let unknown_roots = vec![Hash256::from_low_u64_be(1), Hash256::from_low_u64_be(2)];
+
+        let request = BeaconBlocksByRootRequest {
+            block_roots: vec![
+                known_roots[0],
+                unknown_roots[0],
+                known_roots[1],
+                known_roots[2],
+                unknown_roots[1],
+            ],
+        };
+        let id = RequestId::from(11);
+        let decoded_request = round_trip(RPCEvent::Request {
+            id,
+            method_id: RPCMethod::BeaconBlocksByRoot.into(),
+            body: RPCRequest::BeaconBlocksByRoot(request.clone()),
+        });
+        match decoded_request {
+            RPCEvent::Request {
+                body: RPCRequest::BeaconBlocksByRoot(decoded),
+                ..
+            } => assert_eq!(decoded, request),
+            _ => panic!("decoded incorrect event"),
+        }
+
+        // the responder skips the roots it does not know
+        let mut received_roots = vec![];
+        for block in known_blocks {
+            let event = round_trip(RPCEvent::Response {
+                id,
+                method_id: RPCMethod::BeaconBlocksByRoot.into(),
+                result: RPCResponse::BeaconBlocksByRoot(Box::new(block)),
+            });
+            match event {
+                RPCEvent::Response {
+                    result: RPCResponse::BeaconBlocksByRoot(block),
+                    ..
+                } => received_roots.push(block.canonical_root()),
+                _ => panic!("decoded incorrect event"),
+            }
+        }
+
+        assert_eq!(request.missing_roots(&received_roots), unknown_roots);
+    }
+
+    #[test]
+    fn blocks_by_root_cap() {
+        let request = |count| {
+            RPCRequest::BeaconBlocksByRoot(BeaconBlocksByRootRequest {
+                block_roots: vec![Hash256::zero(); count],
+            })
+        };
+        assert!(request(MAX_REQUEST_BLOCKS as usize).validate().is_ok());
+        assert!(request(MAX_REQUEST_BLOCKS as usize + 1).validate().is_err());
+    }
+
+    /// Encodes and decodes a stream of header responses, returning the received headers.
+    fn stream_headers(id: RequestId, headers: Vec<BeaconBlockHeader>) -> Vec<BeaconBlockHeader> {
+        let method_id: u16 = RPCMethod::BeaconBlockHeadersByRange.into();
+        let mut events: Vec<RPCEvent> = headers
+            .into_iter()
+            .map(|header| RPCEvent::Response {
+                id,
+                method_id,
+                result: RPCResponse::BeaconBlockHeadersByRange(header),
+            })
+            .collect();
+        events.push(RPCEvent::StreamTermination { id, method_id });
+
+        events
+            .into_iter()
+            .map(round_trip)
+            .filter_map(|event| match event {
+                RPCEvent::Response {
+                    result: RPCResponse::BeaconBlockHeadersByRange(header),
+                    ..
+                } => Some(header),
+                RPCEvent::StreamTermination { .. } => None,
+                _ => panic!("decoded incorrect event"),
+            })
+            .collect()
+    }
+
+    fn header_at_slot(slot: u64) -> BeaconBlockHeader {
+        let mut block = BeaconBlock::empty(&MainnetEthSpec::default_spec());
+        block.slot = Slot::new(slot);
+        block.block_header()
+    }
+
+    #[test]
+    fn headers_by_range_empty_range() {
+        let request = BeaconBlockHeadersByRangeRequest {
+            start_slot: Slot::new(5),
+            count: 0,
+        };
+        assert!(RPCRequest::BeaconBlockHeadersByRange(request.clone())
+            .validate()
+            .is_ok());
+
+        let headers = stream_headers(RequestId::from(1), vec![]);
+        assert!(request.pair_with_slots(headers).is_empty());
+
+        // a non-empty range for which the responder has no headers
+        let request = BeaconBlockHeadersByRangeRequest {
+            start_slot: Slot::new(5),
+            count: 3,
+        };
+        let paired = request.pair_with_slots(stream_headers(RequestId::from(2), vec![]));
+        assert_eq!(paired.len(), 3);
+        assert!(paired.iter().all(|(_slot, header)| header.is_none()));
+    }
+
+    #[test]
+    fn headers_by_range_skipped_slots() {
+        let request = BeaconBlockHeadersByRangeRequest {
+            start_slot: Slot::new(10),
+            count: 5,
+        };
+        // slots 11 and 13 are skipped
+        let headers = stream_headers(
+            RequestId::from(3),
+            vec![header_at_slot(10), header_at_slot(12), header_at_slot(14)],
+        );
+
+        let paired = request.pair_with_slots(headers);
+        let slots: Vec<u64> = paired.iter().map(|(slot, _)| slot.as_u64()).collect();
+        assert_eq!(slots, vec![10, 11, 12, 13, 14]);
+
+        let present: Vec<bool> = paired.iter().map(|(_, header)| header.is_some()).collect();
+        assert_eq!(present, vec![true, false, true, false, true]);
+        for (slot, header) in paired {
+            if let Some(header) = header {
+                assert_eq!(header.slot, slot);
+            }
+        }
+    }
+
+    #[test]
+    fn headers_by_range_count_cap() {
+        let request = |count| {
+            RPCRequest::BeaconBlockHeadersByRange(BeaconBlockHeadersByRangeRequest {
+                start_slot: Slot::new(0),
+                count,
+            })
+        };
+        assert!(request(MAX_REQUEST_BLOCKS).validate().is_ok());
+        assert!(request(MAX_REQUEST_BLOCKS + 1).validate().is_err());
+    }
+
+    #[test]
+    fn bodies_by_root_subset_known() {
+        let body = BeaconBlock::empty(&MainnetEthSpec::default_spec()).body;
+        let request = BeaconBlockBodiesByRootRequest {
+            body_roots: (1..=4).map(Hash256::from_low_u64_be).collect(),
+        };
+        let id = RequestId::from(12);
+        let method_id: u16 = RPCMethod::BeaconBlockBodiesByRoot.into();
+
+        // the responder only knows the bodies at positions 1 and 3
+        let mut events: Vec<RPCEvent> = vec![1, 3]
+            .into_iter()
+            .map(|index: usize| RPCEvent::Response {
+                id,
+                method_id,
+                result: RPCResponse::BeaconBlockBodiesByRoot(Box::new(BeaconBlockBodyByRoot {
+                    index: index as u64,
+                    body_root: request.body_roots[index],
+                    body: body.clone(),
+                })),
+            })
+            .collect();
+        events.push(RPCEvent::StreamTermination { id, method_id });
+
+        let responses: Vec<BeaconBlockBodyByRoot> = events
+            .into_iter()
+            .map(round_trip)
+            .filter_map(|event| {
+                assert_eq!(event.id(), id);
+                match event {
+                    RPCEvent::Response {
+                        result: RPCResponse::BeaconBlockBodiesByRoot(response),
+                        ..
+                    } => Some(*response),
+                    RPCEvent::StreamTermination { .. } => None,
+                    _ => panic!("decoded incorrect event"),
+                }
+            })
+            .collect();
+
+        assert_eq!(responses.len(), 2);
+        for response in &responses {
+            assert_eq!(
+                request.body_roots[response.index as usize],
+                response.body_root
+            );
+        }
+        assert_eq!(
+            request.missing_roots(&responses),
+            vec![request.body_roots[0], request.body_roots[2]]
+        );
+    }
+}
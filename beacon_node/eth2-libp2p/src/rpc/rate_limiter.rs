@@ -0,0 +1,397 @@
+//! Per-peer, per-method rate limiting of inbound RPC requests.
+
+use super::methods::RPCMethod;
+use libp2p::PeerId;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The number of rate limited requests after which a peer should be banned.
+const MAX_RATE_LIMIT_OFFENCES: u32 = 10;
+
+/// The allowance of a peer for a single RPC method.
+///
+/// A peer may burst up to `max_requests` requests, which are replenished evenly over `period`.
+/// Responses to the peer draw from a budget of `max_response_bytes` that is replenished in the same
+/// way. Requests are refused while either allowance is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_requests: u32,
+    pub max_response_bytes: u64,
+    pub period: Duration,
+}
+
+impl Quota {
+    /// The time it takes to replenish a single request.
+    fn request_cost(&self) -> Duration {
+        if self.max_requests == 0 {
+            // never allow a request
+            return self.period + Duration::from_secs(1);
+        }
+        self.period / self.max_requests
+    }
+
+    /// The time it takes to replenish `bytes` of the response budget.
+    fn response_cost(&self, bytes: u64) -> Duration {
+        if self.max_response_bytes == 0 {
+            return self.period;
+        }
+        let nanos =
+            self.period.as_nanos() * u128::from(bytes) / u128::from(self.max_response_bytes);
+        Duration::from_nanos(nanos.min(u128::from(u64::max_value())) as u64)
+    }
+}
+
+/// The RPC quotas applied to each peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RPCQuotas {
+    pub blocks_by_range: Quota,
+    pub blocks_by_root: Quota,
+    pub block_headers_by_range: Quota,
+    pub block_bodies_by_root: Quota,
+    /// The quota of all other methods.
+    pub other: Quota,
+}
+
+impl RPCQuotas {
+    /// Returns the quota of the method `method_id`.
+    pub fn quota(&self, method_id: u16) -> Quota {
+        match RPCMethod::from(method_id) {
+            RPCMethod::BeaconBlocksByRange => self.blocks_by_range,
+            RPCMethod::BeaconBlocksByRoot => self.blocks_by_root,
+            RPCMethod::BeaconBlockHeadersByRange => self.block_headers_by_range,
+            RPCMethod::BeaconBlockBodiesByRoot => self.block_bodies_by_root,
+            _ => self.other,
+        }
+    }
+}
+
+impl Default for RPCQuotas {
+    fn default() -> Self {
+        let period = Duration::from_secs(10);
+        RPCQuotas {
+            blocks_by_range: Quota {
+                max_requests: 10,
+                max_response_bytes: 50_000_000,
+                period,
+            },
+            blocks_by_root: Quota {
+                max_requests: 20,
+                max_response_bytes: 20_000_000,
+                period,
+            },
+            block_headers_by_range: Quota {
+                max_requests: 10,
+                max_response_bytes: 5_000_000,
+                period,
+            },
+            block_bodies_by_root: Quota {
+                max_requests: 20,
+                max_response_bytes: 20_000_000,
+                period,
+            },
+            other: Quota {
+                max_requests: 50,
+                max_response_bytes: 10_000_000,
+                period,
+            },
+        }
+    }
+}
+
+/// The result of checking an inbound request against the rate limiter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitResult {
+    /// The request is within the peer's quota.
+    Allowed,
+    /// The request exceeds the peer's quota and should be refused.
+    Limited,
+    /// The request exceeds the peer's quota and the peer has repeatedly done so.
+    Ban,
+}
+
+/// A token bucket, expressed as the time at which the bucket is full again.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    full_at: Instant,
+}
+
+impl Bucket {
+    /// Returns `true` if the bucket has any allowance left at `now`.
+    fn has_allowance(&self, now: Instant, period: Duration) -> bool {
+        self.full_at < now + period
+    }
+
+    /// Takes `cost` from the bucket if it has enough allowance left at `now`.
+    fn try_take(&mut self, now: Instant, cost: Duration, period: Duration) -> bool {
+        let full_at = self.full_at.max(now) + cost;
+        if full_at > now + period {
+            return false;
+        }
+        self.full_at = full_at;
+        true
+    }
+
+    /// Takes `cost` from the bucket, regardless of the allowance left.
+    fn take(&mut self, now: Instant, cost: Duration) {
+        self.full_at = self.full_at.max(now) + cost;
+    }
+}
+
+/// The allowance of a peer for a single RPC method.
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    requests: Bucket,
+    response_bytes: Bucket,
+}
+
+/// Tracks the requests and response bytes of each peer against the configured quotas.
+pub struct RateLimiter {
+    quotas: RPCQuotas,
+    limits: HashMap<(PeerId, u16), Limit>,
+    /// The number of refused requests of each peer.
+    offences: HashMap<PeerId, u32>,
+}
+
+impl RateLimiter {
+    pub fn new(quotas: RPCQuotas) -> Self {
+        RateLimiter {
+            quotas,
+            limits: HashMap::new(),
+            offences: HashMap::new(),
+        }
+    }
+
+    /// Checks a request of `method_id` received from `peer_id` at `now`, taking it from the peer's
+    /// allowance if it is allowed.
+    pub fn allows(&mut self, peer_id: &PeerId, method_id: u16, now: Instant) -> RateLimitResult {
+        let quota = self.quotas.quota(method_id);
+        let limit = self
+            .limits
+            .entry((peer_id.clone(), method_id))
+            .or_insert(Limit {
+                requests: Bucket { full_at: now },
+                response_bytes: Bucket { full_at: now },
+            });
+
+        if limit.response_bytes.has_allowance(now, quota.period)
+            && limit
+                .requests
+                .try_take(now, quota.request_cost(), quota.period)
+        {
+            return RateLimitResult::Allowed;
+        }
+
+        let offences = self.offences.entry(peer_id.clone()).or_insert(0);
+        *offences = offences.saturating_add(1);
+        if *offences >= MAX_RATE_LIMIT_OFFENCES {
+            RateLimitResult::Ban
+        } else {
+            RateLimitResult::Limited
+        }
+    }
+
+    /// Records `bytes` sent to `peer_id` in response to a request of `method_id` at `now`.
+    pub fn record_response(&mut self, peer_id: &PeerId, method_id: u16, bytes: u64, now: Instant) {
+        let quota = self.quotas.quota(method_id);
+        if let Some(limit) = self.limits.get_mut(&(peer_id.clone(), method_id)) {
+            limit.response_bytes.take(now, quota.response_cost(bytes));
+        }
+    }
+
+    /// Forgets the allowances that have fully replenished at `now`.
+    pub fn prune(&mut self, now: Instant) {
+        self.limits.retain(|_key, limit| {
+            limit.requests.full_at > now || limit.response_bytes.full_at > now
+        });
+
+        // peers that have stayed within their quotas are forgiven past offences
+        let limits = &self.limits;
+        self.offences.retain(|peer_id, _offences| {
+            limits
+                .keys()
+                .any(|(limited_peer, _method_id)| limited_peer == peer_id)
+        });
+    }
+
+    /// Forgets all state of a peer.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.limits
+            .retain(|(limited_peer, _method_id), _limit| limited_peer != peer_id);
+        self.offences.remove(peer_id);
+    }
+
+    /// Returns the number of allowances being tracked.
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.limits.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_secs(10);
+
+    fn quotas() -> RPCQuotas {
+        let quota = |max_requests| Quota {
+            max_requests,
+            max_response_bytes: 1_000,
+            period: PERIOD,
+        };
+        RPCQuotas {
+            blocks_by_range: quota(2),
+            blocks_by_root: quota(3),
+            block_headers_by_range: quota(4),
+            block_bodies_by_root: quota(5),
+            other: quota(6),
+        }
+    }
+
+    #[test]
+    fn each_quota_is_enforced() {
+        let methods: Vec<u16> = vec![
+            RPCMethod::BeaconBlocksByRange.into(),
+            RPCMethod::BeaconBlocksByRoot.into(),
+            RPCMethod::BeaconBlockHeadersByRange.into(),
+            RPCMethod::BeaconBlockBodiesByRoot.into(),
+            RPCMethod::Hello.into(),
+        ];
+        let now = Instant::now();
+
+        for method_id in methods {
+            let mut limiter = RateLimiter::new(quotas());
+            let peer_id = PeerId::random();
+            let max_requests = limiter.quotas.quota(method_id).max_requests;
+
+            for _ in 0..max_requests {
+                assert_eq!(
+                    limiter.allows(&peer_id, method_id, now),
+                    RateLimitResult::Allowed
+                );
+            }
+            assert_eq!(
+                limiter.allows(&peer_id, method_id, now),
+                RateLimitResult::Limited
+            );
+        }
+    }
+
+    #[test]
+    fn quota_replenishes() {
+        let mut limiter = RateLimiter::new(quotas());
+        let peer_id = PeerId::random();
+        let method_id = RPCMethod::BeaconBlocksByRange.into();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, now),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, now),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, now),
+            RateLimitResult::Limited
+        );
+
+        // one request is replenished after half the period
+        let later = now + PERIOD / 2;
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, later),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, later),
+            RateLimitResult::Limited
+        );
+
+        // the full quota is replenished after the period
+        let later = later + PERIOD;
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, later),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, later),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, later),
+            RateLimitResult::Limited
+        );
+    }
+
+    #[test]
+    fn response_bytes_are_limited() {
+        let mut limiter = RateLimiter::new(quotas());
+        let peer_id = PeerId::random();
+        let method_id = RPCMethod::BeaconBlocksByRoot.into();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, now),
+            RateLimitResult::Allowed
+        );
+        limiter.record_response(&peer_id, method_id, 1_000, now);
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, now),
+            RateLimitResult::Limited
+        );
+
+        assert_eq!(
+            limiter.allows(&peer_id, method_id, now + PERIOD / 2),
+            RateLimitResult::Allowed
+        );
+    }
+
+    #[test]
+    fn repeat_offenders_are_banned() {
+        let mut limiter = RateLimiter::new(quotas());
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+        let method_id = RPCMethod::BeaconBlocksByRange.into();
+        let now = Instant::now();
+
+        let results: Vec<RateLimitResult> = (0..2 + MAX_RATE_LIMIT_OFFENCES)
+            .map(|_| limiter.allows(&peer_id, method_id, now))
+            .collect();
+        assert_eq!(results.last(), Some(&RateLimitResult::Ban));
+        assert_eq!(
+            results
+                .iter()
+                .filter(|result| **result == RateLimitResult::Limited)
+                .count() as u32,
+            MAX_RATE_LIMIT_OFFENCES - 1
+        );
+
+        // other peers are unaffected
+        assert_eq!(
+            limiter.allows(&other_peer_id, method_id, now),
+            RateLimitResult::Allowed
+        );
+    }
+
+    #[test]
+    fn state_is_not_leaked() {
+        let mut limiter = RateLimiter::new(quotas());
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+        let now = Instant::now();
+
+        limiter.allows(&peer_id, RPCMethod::BeaconBlocksByRange.into(), now);
+        limiter.allows(&peer_id, RPCMethod::Hello.into(), now);
+        limiter.allows(&other_peer_id, RPCMethod::Hello.into(), now);
+        assert_eq!(limiter.tracked(), 3);
+
+        limiter.remove_peer(&peer_id);
+        assert_eq!(limiter.tracked(), 1);
+
+        limiter.prune(now + PERIOD);
+        assert_eq!(limiter.tracked(), 0);
+    }
+}
@@ -1,10 +1,10 @@
-use crate::behaviour::{Behaviour, BehaviourEvent, PubsubMessage};
+use crate::behaviour::{Behaviour, BehaviourEvent, GossipBlockInfo, PeerSummary, PubsubMessage};
+use crate::discovery::DialErrorKind;
 use crate::error;
+use crate::inbound_limit::InboundRateLimited;
 use crate::multiaddr::Protocol;
 use crate::rpc::RPCEvent;
-use crate::NetworkConfig;
-use crate::{TopicBuilder, TopicHash};
-use crate::{BEACON_ATTESTATION_TOPIC, BEACON_PUBSUB_TOPIC};
+use crate::{MessageId, NetworkConfig, TopicHash};
 use futures::prelude::*;
 use futures::Stream;
 use libp2p::core::{
@@ -12,17 +12,19 @@ use libp2p::core::{
     multiaddr::Multiaddr,
     muxing::StreamMuxerBox,
     nodes::Substream,
-    transport::boxed::Boxed,
+    transport::{boxed::Boxed, MemoryTransport},
     upgrade::{InboundUpgradeExt, OutboundUpgradeExt},
 };
-use libp2p::{core, secio, PeerId, Swarm, Transport};
+use libp2p::{core, mplex, secio, PeerId, Swarm, Transport};
 use slog::{debug, info, trace, warn};
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{Error, ErrorKind};
 use std::time::Duration;
+use types::Slot;
 
-type Libp2pStream = Boxed<(PeerId, StreamMuxerBox), Error>;
+/// A transport yielding authenticated, multiplexed connections, as a `Service` runs over.
+pub type Libp2pTransport = Boxed<(PeerId, StreamMuxerBox), Error>;
 type Libp2pBehaviour = Behaviour<Substream<StreamMuxerBox>>;
 
 const NETWORK_KEY_FILENAME: &str = "key";
@@ -31,7 +33,7 @@ const NETWORK_KEY_FILENAME: &str = "key";
 pub struct Service {
     /// The libp2p Swarm handler.
     //TODO: Make this private
-    pub swarm: Swarm<Libp2pStream, Libp2pBehaviour>,
+    pub swarm: Swarm<Libp2pTransport, Libp2pBehaviour>,
     /// This node's PeerId.
     _local_peer_id: PeerId,
     /// The libp2p logger handle.
@@ -39,7 +41,34 @@ pub struct Service {
 }
 
 impl Service {
+    /// Builds the service over TCP, listening on the configured address and port.
     pub fn new(config: NetworkConfig, log: slog::Logger) -> error::Result<Self> {
+        let listen_multiaddr = {
+            let mut m = Multiaddr::from(config.listen_address);
+            m.push(Protocol::Tcp(config.libp2p_port));
+            m
+        };
+        Self::with_transport(config, log, build_transport, listen_multiaddr)
+    }
+
+    /// Builds the service over the transport `build_transport` returns for our key, listening on
+    /// `listen_multiaddr`. Inbound connections beyond the configured `inbound_rate_limit` are
+    /// dropped by the transport before they are upgraded.
+    ///
+    /// Tests can connect services in one process, without binding ports, with
+    /// `build_memory_transport`, listening on a `/memory/<port>` address. Alternative networks
+    /// can supply any transport which authenticates the remote peer and multiplexes the
+    /// connection, such as `build_transport` over another base transport than TCP, listening on
+    /// an address that transport understands.
+    pub fn with_transport<F>(
+        config: NetworkConfig,
+        log: slog::Logger,
+        build_transport: F,
+        listen_multiaddr: Multiaddr,
+    ) -> error::Result<Self>
+    where
+        F: FnOnce(Keypair) -> Libp2pTransport,
+    {
         debug!(log, "Network-libp2p Service starting");
 
         // load the private key from CLI flag, disk or generate a new one
@@ -49,20 +78,23 @@ impl Service {
         info!(log, "Local peer id: {:?}", local_peer_id);
 
         let mut swarm = {
-            // Set up the transport - tcp/ws with secio and mplex/yamux
-            let transport = build_transport(local_private_key.clone());
+            let transport = InboundRateLimited::new(
+                build_transport(local_private_key.clone()),
+                config.inbound_rate_limit,
+                log.clone(),
+            )
+            .boxed();
             // Lighthouse network behaviour
-            let behaviour = Behaviour::new(&local_private_key, &config, &log)?;
+            let behaviour = Behaviour::new(&local_private_key, &config, &log)
+                .map_err(|e| format!("Could not build network behaviour: {}", e))?;
+            // Network metrics are gathered with the default registry.
+            if let Err(e) = behaviour.metrics.register(prometheus::default_registry()) {
+                warn!(log, "Could not register network metrics: {:?}", e);
+            }
             Swarm::new(transport, behaviour, local_peer_id.clone())
         };
 
         // listen on the specified address
-        let listen_multiaddr = {
-            let mut m = Multiaddr::from(config.listen_address);
-            m.push(Protocol::Tcp(config.libp2p_port));
-            m
-        };
-
         match Swarm::listen_on(&mut swarm, listen_multiaddr.clone()) {
             Ok(_) => {
                 let mut log_address = listen_multiaddr;
@@ -75,26 +107,6 @@ impl Service {
             ),
         };
 
-        // subscribe to default gossipsub topics
-        let mut topics = vec![];
-        //TODO: Handle multiple shard attestations. For now we simply use a separate topic for
-        //attestations
-        topics.push(BEACON_ATTESTATION_TOPIC.to_string());
-        topics.push(BEACON_PUBSUB_TOPIC.to_string());
-        topics.append(&mut config.topics.clone());
-
-        let mut subscribed_topics = vec![];
-        for topic in topics {
-            let t = TopicBuilder::new(topic.clone()).build();
-            if swarm.subscribe(t) {
-                trace!(log, "Subscribed to topic: {:?}", topic);
-                subscribed_topics.push(topic);
-            } else {
-                warn!(log, "Could not subscribe to topic: {:?}", topic)
-            }
-        }
-        info!(log, "Subscribed to topics: {:?}", subscribed_topics);
-
         Ok(Service {
             _local_peer_id: local_peer_id,
             swarm,
@@ -116,14 +128,18 @@ impl Stream for Service {
                 Ok(Async::Ready(Some(event))) => match event {
                     // TODO: Stub here for debugging
                     BehaviourEvent::GossipMessage {
+                        id,
                         source,
                         topics,
+                        block,
                         message,
                     } => {
                         trace!(self.log, "Pubsub message received: {:?}", message);
                         return Ok(Async::Ready(Some(Libp2pEvent::PubsubMessage {
+                            id,
                             source,
                             topics,
+                            block,
                             message,
                         })));
                     }
@@ -133,6 +149,65 @@ impl Stream for Service {
                     BehaviourEvent::PeerDialed(peer_id) => {
                         return Ok(Async::Ready(Some(Libp2pEvent::PeerDialed(peer_id))));
                     }
+                    BehaviourEvent::PeerDiscovered(peer_id) => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::PeerDiscovered(peer_id))));
+                    }
+                    BehaviourEvent::BanPeer(peer_id) => {
+                        debug!(self.log, "Banning peer"; "peer" => format!("{:?}", peer_id));
+                        Swarm::ban_peer_id(&mut self.swarm, peer_id);
+                    }
+                    BehaviourEvent::UnbanPeer(peer_id) => {
+                        debug!(self.log, "Unbanning peer"; "peer" => format!("{:?}", peer_id));
+                        Swarm::unban_peer_id(&mut self.swarm, peer_id);
+                    }
+                    BehaviourEvent::DisconnectPeer(peer_id) => {
+                        debug!(self.log, "Disconnecting peer"; "peer" => format!("{:?}", peer_id));
+                        // banning closes the connection, lifting the ban allows future ones
+                        Swarm::ban_peer_id(&mut self.swarm, peer_id.clone());
+                        Swarm::unban_peer_id(&mut self.swarm, peer_id);
+                    }
+                    BehaviourEvent::MeshChanged {
+                        peer_id,
+                        topic,
+                        grafted,
+                    } => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::MeshChanged {
+                            peer_id,
+                            topic,
+                            grafted,
+                        })));
+                    }
+                    BehaviourEvent::PeerSummary(summary) => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::PeerSummary(summary))));
+                    }
+                    BehaviourEvent::LowPeerCount(count) => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::LowPeerCount(count))));
+                    }
+                    BehaviourEvent::RecoveredPeerCount(count) => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::RecoveredPeerCount(count))));
+                    }
+                    BehaviourEvent::DialFailed { addr, kind } => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::DialFailed { addr, kind })));
+                    }
+                    BehaviourEvent::SubnetPeersFound(subnet_id, count) => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::SubnetPeersFound(
+                            subnet_id, count,
+                        ))));
+                    }
+                    BehaviourEvent::SubnetQueryTimedOut(subnet_id, count) => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::SubnetQueryTimedOut(
+                            subnet_id, count,
+                        ))));
+                    }
+                    BehaviourEvent::ShutdownComplete => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::ShutdownComplete)));
+                    }
+                    BehaviourEvent::RangeSyncFailed { start_slot, count } => {
+                        return Ok(Async::Ready(Some(Libp2pEvent::RangeSyncFailed {
+                            start_slot,
+                            count,
+                        })));
+                    }
                 },
                 Ok(Async::Ready(None)) => unreachable!("Swarm stream shouldn't end"),
                 Ok(Async::NotReady) => break,
@@ -145,7 +220,9 @@ impl Stream for Service {
 
 /// The implementation supports TCP/IP, WebSockets over TCP/IP, secio as the encryption layer, and
 /// mplex or yamux as the multiplexing layer.
-fn build_transport(local_private_key: Keypair) -> Boxed<(PeerId, StreamMuxerBox), Error> {
+/// Builds the transport `Service::new` runs over: TCP, with DNS resolution, secured with secio and
+/// multiplexed with yamux or mplex.
+pub fn build_transport(local_private_key: Keypair) -> Libp2pTransport {
     // TODO: The Wire protocol currently doesn't specify encryption and this will need to be customised
     // in the future.
     let transport = libp2p::tcp::TcpConfig::new();
@@ -176,18 +253,63 @@ fn build_transport(local_private_key: Keypair) -> Boxed<(PeerId, StreamMuxerBox)
         .boxed()
 }
 
+/// Builds a transport connecting services within this process, secured with secio and multiplexed
+/// with mplex. Services listen on `/memory/<port>` addresses, where the port is any non-zero
+/// number not listened on by another service of the process.
+pub fn build_memory_transport(local_private_key: Keypair) -> Libp2pTransport {
+    MemoryTransport::default()
+        .with_upgrade(secio::SecioConfig::new(local_private_key))
+        .and_then(move |out, endpoint| {
+            let peer_id = out.remote_key.into_peer_id();
+            core::upgrade::apply(out.stream, mplex::MplexConfig::new(), endpoint)
+                .map(move |muxer| (peer_id, StreamMuxerBox::new(muxer)))
+        })
+        .map_err(|err| Error::new(ErrorKind::Other, err))
+        .boxed()
+}
+
 /// Events that can be obtained from polling the Libp2p Service.
 pub enum Libp2pEvent {
     /// An RPC response request has been received on the swarm.
     RPC(PeerId, RPCEvent),
     /// Initiated the connection to a new peer.
     PeerDialed(PeerId),
-    /// Received pubsub message.
+    /// Discovery found a peer, which will be dialed if more peers are needed.
+    PeerDiscovered(PeerId),
+    /// Received pubsub message. Its `id` is reported to `Behaviour::report_gossip_validation` once
+    /// the message is validated. The slot and parent of a block are given in `block`.
     PubsubMessage {
+        id: MessageId,
         source: PeerId,
         topics: Vec<TopicHash>,
+        block: Option<GossipBlockInfo>,
         message: Box<PubsubMessage>,
     },
+    /// A peer has joined, or left, our mesh of a topic.
+    MeshChanged {
+        peer_id: PeerId,
+        topic: TopicHash,
+        grafted: bool,
+    },
+    /// A periodic summary of our peers.
+    PeerSummary(PeerSummary),
+    /// The number of connected peers, given, has fallen below the configured minimum.
+    LowPeerCount(usize),
+    /// The number of connected peers, given, has recovered to the configured minimum.
+    RecoveredPeerCount(usize),
+    /// Our peers have been said goodbye to, the service can be dropped.
+    ShutdownComplete,
+    /// A blocks by range request failed on every peer it was retried from.
+    RangeSyncFailed { start_slot: Slot, count: u64 },
+    /// Dialing an address failed.
+    DialFailed {
+        addr: Multiaddr,
+        kind: DialErrorKind,
+    },
+    /// A search found the given number of peers on an attestation subnet, enough.
+    SubnetPeersFound(u64, usize),
+    /// A search gave up, having found too few peers on an attestation subnet.
+    SubnetQueryTimedOut(u64, usize),
 }
 
 /// Loads a private key from disk. If this fails, a new key is
@@ -237,3 +359,128 @@ fn load_private_key(config: &NetworkConfig, log: &slog::Logger) -> Keypair {
     }
     local_private_key
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::NodeRole;
+    use futures::future;
+    use slog::o;
+    use std::time::Instant;
+    use tempfile::{tempdir, TempDir};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_timer::Delay;
+    use types::Hash256;
+
+    /// Builds a service listening on the memory transport `port`, and the directory it stores its
+    /// network files in.
+    fn build_service(port: u64) -> (Service, TempDir) {
+        let network_dir = tempdir().expect("should create network directory");
+        let mut config = NetworkConfig::default();
+        config.network_dir = network_dir.path().to_path_buf();
+        config.network_id = Some(1);
+        config.genesis_root = Some(Hash256::zero());
+        config.role = NodeRole::BootstrapOnly;
+        config.discovery_port = 0;
+        let log = slog::Logger::root(slog::Discard, o!());
+        let address = Protocol::Memory(port).into();
+        let service = Service::with_transport(config, log, build_memory_transport, address)
+            .expect("should build service");
+        (service, network_dir)
+    }
+
+    #[test]
+    fn services_connect_over_memory_transport() {
+        // beyond the ports the behaviour tests of the same binary count up from one
+        let port = 1 << 32;
+        let (first, _first_dir) = build_service(port);
+        let (second, _second_dir) = build_service(port + 1);
+        let mut services = [first, second];
+        let address = Protocol::Memory(port).into();
+        Swarm::dial_addr(&mut services[1].swarm, address).expect("should dial");
+
+        let mut runtime = Runtime::new().expect("should build runtime");
+        let mut deadline = Delay::new(Instant::now() + Duration::from_secs(10));
+        let connected = runtime.block_on(future::poll_fn(|| -> Poll<(), ()> {
+            for service in services.iter_mut() {
+                while let Ok(Async::Ready(Some(_))) = service.poll() {}
+            }
+            let peers: Vec<Vec<PeerId>> = services
+                .iter()
+                .map(|service| {
+                    service
+                        .swarm
+                        .peer_info_snapshot()
+                        .into_iter()
+                        .map(|info| info.peer_id)
+                        .collect()
+                })
+                .collect();
+            let local_peer_ids: Vec<PeerId> = services
+                .iter()
+                .map(|service| Swarm::local_peer_id(&service.swarm).clone())
+                .collect();
+            if peers[0] == vec![local_peer_ids[1].clone()]
+                && peers[1] == vec![local_peer_ids[0].clone()]
+            {
+                return Ok(Async::Ready(()));
+            }
+            match deadline.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Err(()),
+            }
+        }));
+        connected.expect("services should connect before the timeout");
+    }
+
+    #[test]
+    #[ignore] // needs multicast on the local network, see `mdns_peers_dialed_until_expired`
+    fn services_discover_each_other_over_mdns() {
+        let (mut services, _network_dirs): (Vec<Service>, Vec<TempDir>) = (0..2)
+            .map(|_| {
+                let network_dir = tempdir().expect("should create network directory");
+                let mut config = NetworkConfig::default();
+                config.network_dir = network_dir.path().to_path_buf();
+                config.network_id = Some(1);
+                config.genesis_root = Some(Hash256::zero());
+                config.libp2p_port = 0;
+                config.discovery_port = 0;
+                config.enable_mdns = true;
+                let log = slog::Logger::root(slog::Discard, o!());
+                let service = Service::new(config, log).expect("should build service");
+                (service, network_dir)
+            })
+            .unzip();
+        let local_peer_ids: Vec<PeerId> = services
+            .iter()
+            .map(|service| Swarm::local_peer_id(&service.swarm).clone())
+            .collect();
+
+        let mut runtime = Runtime::new().expect("should build runtime");
+        let mut deadline = Delay::new(Instant::now() + Duration::from_secs(30));
+        let discovered = runtime.block_on(future::poll_fn(|| -> Poll<(), ()> {
+            for service in services.iter_mut() {
+                while let Ok(Async::Ready(Some(_))) = service.poll() {}
+            }
+            let found_each_other =
+                services
+                    .iter()
+                    .zip(local_peer_ids.iter().rev())
+                    .all(|(service, other)| {
+                        service
+                            .swarm
+                            .peer_info_snapshot()
+                            .iter()
+                            .any(|info| &info.peer_id == other && info.discovered_via_mdns)
+                    });
+            if found_each_other {
+                return Ok(Async::Ready(()));
+            }
+            match deadline.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Err(()),
+            }
+        }));
+        discovered.expect("services should discover each other before the timeout");
+    }
+}
@@ -5,14 +5,16 @@ use beacon_chain::{BeaconChain, BeaconChainTypes};
 use crossbeam_channel::{unbounded as channel, Sender};
 use eth2_libp2p::{
     behaviour::PubsubMessage,
-    rpc::{methods::GoodbyeReason, RPCRequest, RPCResponse, RequestId},
-    PeerId, RPCEvent,
+    rpc::{
+        methods::GoodbyeReason, RPCError, RPCErrorCode, RPCMethod, RPCRequest, RPCResponse,
+        RequestId,
+    },
+    GossipBlockInfo, MessageId, NetworkSender, PeerId, RPCEvent, RPCResponseQuality,
 };
 use futures::future;
-use slog::{debug, warn};
-use std::collections::HashMap;
+use slog::{debug, trace, warn};
 use std::sync::Arc;
-use std::time::Instant;
+use types::Hash256;
 
 /// Timeout for RPC requests.
 // const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
@@ -27,6 +29,10 @@ pub struct MessageHandler<T: BeaconChainTypes> {
     sync: SimpleSync<T>,
     /// The context required to send messages to, and process messages from peers.
     network_context: NetworkContext,
+    /// Instructs the network behaviour directly.
+    network_sender: NetworkSender,
+    /// The head of the status last given to the network behaviour.
+    local_head: Option<Hash256>,
     /// The `MessageHandler` logger.
     log: slog::Logger,
 }
@@ -40,8 +46,14 @@ pub enum HandlerMessage {
     PeerDisconnected(PeerId),
     /// An RPC response/request has been received.
     RPC(PeerId, RPCEvent),
-    /// A gossip message has been received.
-    PubsubMessage(PeerId, Box<PubsubMessage>),
+    /// A gossip message has been received, with its id and, for a block, where it sits in the
+    /// chain.
+    PubsubMessage(
+        MessageId,
+        PeerId,
+        Option<GossipBlockInfo>,
+        Box<PubsubMessage>,
+    ),
 }
 
 impl<T: BeaconChainTypes + 'static> MessageHandler<T> {
@@ -49,6 +61,7 @@ impl<T: BeaconChainTypes + 'static> MessageHandler<T> {
     pub fn spawn(
         beacon_chain: Arc<BeaconChain<T>>,
         network_send: crossbeam_channel::Sender<NetworkMessage>,
+        network_sender: NetworkSender,
         executor: &tokio::runtime::TaskExecutor,
         log: slog::Logger,
     ) -> error::Result<Sender<HandlerMessage>> {
@@ -64,8 +77,11 @@ impl<T: BeaconChainTypes + 'static> MessageHandler<T> {
             _chain: beacon_chain.clone(),
             sync,
             network_context: NetworkContext::new(network_send, log.clone()),
+            network_sender,
+            local_head: None,
             log: log.clone(),
         };
+        handler.update_local_status();
 
         // spawn handler task
         // TODO: Handle manual termination of thread
@@ -92,12 +108,31 @@ impl<T: BeaconChainTypes + 'static> MessageHandler<T> {
                 self.handle_rpc_message(peer_id, rpc_event);
             }
             // we have received an RPC message request/response
-            HandlerMessage::PubsubMessage(peer_id, gossip) => {
-                self.handle_gossip(peer_id, *gossip);
+            HandlerMessage::PubsubMessage(id, peer_id, block, gossip) => {
+                self.handle_gossip(peer_id, block, *gossip);
+                // the message has been processed, completing its validation
+                if let Err(e) = self.network_sender.report_validation(id) {
+                    warn!(self.log, "Could not report gossip validation"; "error" => format!("{:?}", e));
+                }
             }
             //TODO: Handle all messages
             _ => {}
         }
+        // messages may have moved our head
+        self.update_local_status();
+    }
+
+    /// Gives our status to the network behaviour, to send to our peers and check theirs against,
+    /// if our head has changed since it was last given.
+    fn update_local_status(&mut self) {
+        let status = self.sync.generate_hello();
+        if self.local_head == Some(status.best_root) {
+            return;
+        }
+        self.local_head = Some(status.best_root);
+        if let Err(e) = self.network_sender.update_local_status(status) {
+            warn!(self.log, "Could not update local status"; "error" => format!("{:?}", e));
+        }
     }
 
     /* RPC - Related functionality */
@@ -108,6 +143,12 @@ impl<T: BeaconChainTypes + 'static> MessageHandler<T> {
             RPCEvent::Request { id, body, .. // TODO: Clean up RPC Message types, have a cleaner type by this point.
             } => self.handle_rpc_request(peer_id, id, body),
             RPCEvent::Response { id, result, .. } => self.handle_rpc_response(peer_id, id, result),
+            RPCEvent::StreamTermination { id, .. } => self.handle_stream_termination(peer_id, id),
+            RPCEvent::Error {
+                id,
+                method_id,
+                error,
+            } => self.handle_rpc_error(peer_id, id, method_id, error),
         }
     }
 
@@ -144,28 +185,59 @@ impl<T: BeaconChainTypes + 'static> MessageHandler<T> {
                 // We do not implement this endpoint, it is not required and will only likely be
                 // useful for light-client support in later phases.
                 warn!(self.log, "BeaconChainState RPC call is not supported.");
+                self.network_context.send_rpc_error(
+                    peer_id,
+                    request_id,
+                    RPCMethod::BeaconChainState.into(),
+                    RPCErrorCode::ResourceUnavailable,
+                    Some("BeaconChainState is not supported".to_string()),
+                );
+            }
+            RPCRequest::BeaconBlocksByRange(request) => {
+                self.sync.on_beacon_blocks_by_range_request(
+                    peer_id,
+                    request_id,
+                    request,
+                    &mut self.network_context,
+                )
+            }
+            RPCRequest::BeaconBlocksByRoot(request) => self.sync.on_beacon_blocks_by_root_request(
+                peer_id,
+                request_id,
+                request,
+                &mut self.network_context,
+            ),
+            RPCRequest::BeaconBlockHeadersByRange(request) => {
+                self.sync.on_beacon_block_headers_by_range_request(
+                    peer_id,
+                    request_id,
+                    request,
+                    &mut self.network_context,
+                )
+            }
+            RPCRequest::BeaconBlockBodiesByRoot(request) => {
+                self.sync.on_beacon_block_bodies_by_root_request(
+                    peer_id,
+                    request_id,
+                    request,
+                    &mut self.network_context,
+                )
             }
+            // Metadata and ping requests are answered by the network behaviour and never reach us.
+            RPCRequest::MetaData | RPCRequest::Ping(_) => {}
         }
     }
 
     /// An RPC response has been received from the network.
-    // we match on id and ignore responses past the timeout.
+    ///
+    /// The RPC behaviour only passes on responses to requests that are still outstanding.
     fn handle_rpc_response(&mut self, peer_id: PeerId, id: RequestId, response: RPCResponse) {
-        // if response id is not related to a request, ignore (likely RPC timeout)
-        if self
-            .network_context
-            .outstanding_outgoing_request_ids
-            .remove(&(peer_id.clone(), id))
-            .is_none()
-        {
-            warn!(
-                self.log,
-                "Unknown ResponseId for incoming RPCRequest";
-                "peer" => format!("{:?}", peer_id),
-                "request_id" => format!("{:?}", id)
-            );
-            return;
-        }
+        trace!(
+            self.log,
+            "RPC response received";
+            "peer" => format!("{:?}", peer_id),
+            "request_id" => format!("{:?}", id)
+        );
 
         match response {
             RPCResponse::Hello(hello_message) => {
@@ -201,21 +273,96 @@ impl<T: BeaconChainTypes + 'static> MessageHandler<T> {
                 // beacon state RPC request.
                 warn!(self.log, "BeaconChainState RPC call is not supported.");
             }
+            RPCResponse::BeaconBlocksByRange(block) => {
+                self.sync
+                    .on_beacon_blocks_by_range_response(peer_id, *block);
+            }
+            RPCResponse::BeaconBlocksByRoot(block) => {
+                self.sync.on_beacon_blocks_by_root_response(peer_id, *block);
+            }
+            RPCResponse::BeaconBlockHeadersByRange(header) => {
+                self.sync.on_beacon_block_headers_by_range_response(
+                    peer_id,
+                    header,
+                    &mut self.network_context,
+                );
+            }
+            RPCResponse::BeaconBlockBodiesByRoot(response) => {
+                self.sync
+                    .on_beacon_block_bodies_by_root_response(peer_id, *response);
+            }
+            // Metadata and ping responses are handled by the network behaviour and never reach us.
+            RPCResponse::MetaData(_) | RPCResponse::Ping(_) => {}
         };
     }
 
+    /// A stream of RPC responses has been completed by the remote.
+    fn handle_stream_termination(&mut self, peer_id: PeerId, id: RequestId) {
+        trace!(
+            self.log,
+            "RPC stream terminated";
+            "peer" => format!("{:?}", peer_id),
+            "request_id" => format!("{:?}", id)
+        );
+
+        self.sync
+            .on_stream_termination(peer_id, &mut self.network_context);
+    }
+
+    /// One of our RPC requests was rejected by the remote or timed out.
+    fn handle_rpc_error(
+        &mut self,
+        peer_id: PeerId,
+        id: RequestId,
+        method_id: u16,
+        error: RPCError,
+    ) {
+        warn!(
+            self.log,
+            "RPC request failed";
+            "peer" => format!("{:?}", peer_id),
+            "request_id" => format!("{:?}", id),
+            "error" => error.message()
+        );
+
+        if error == RPCError::Timeout {
+            self.sync.on_rpc_timeout(
+                peer_id,
+                RPCMethod::from(method_id),
+                &mut self.network_context,
+            );
+        }
+    }
+
     /// Handle RPC messages
-    fn handle_gossip(&mut self, peer_id: PeerId, gossip_message: PubsubMessage) {
+    fn handle_gossip(
+        &mut self,
+        peer_id: PeerId,
+        block: Option<GossipBlockInfo>,
+        gossip_message: PubsubMessage,
+    ) {
         match gossip_message {
             PubsubMessage::Block(message) => {
                 let _should_foward_on =
                     self.sync
-                        .on_block_gossip(peer_id, message, &mut self.network_context);
+                        .on_block_gossip(peer_id, message, block, &mut self.network_context);
             }
             PubsubMessage::Attestation(message) => {
                 self.sync
                     .on_attestation_gossip(peer_id, message, &mut self.network_context)
             }
+            PubsubMessage::VoluntaryExit(message) => self.sync.on_voluntary_exit_gossip(message),
+            PubsubMessage::ProposerSlashing(message) => {
+                self.sync.on_proposer_slashing_gossip(message)
+            }
+            PubsubMessage::AttesterSlashing(message) => {
+                self.sync.on_attester_slashing_gossip(message)
+            }
+            // gossipsub relays light client updates, the beacon chain has no use for them
+            PubsubMessage::LightClientFinalityUpdate(_)
+            | PubsubMessage::LightClientOptimisticUpdate(_) => {
+                trace!(self.log, "Received light client update"; "peer" => format!("{:?}", peer_id));
+            }
         }
     }
 }
@@ -223,22 +370,13 @@ impl<T: BeaconChainTypes + 'static> MessageHandler<T> {
 pub struct NetworkContext {
     /// The network channel to relay messages to the Network service.
     network_send: crossbeam_channel::Sender<NetworkMessage>,
-    /// A mapping of peers and the RPC id we have sent an RPC request to.
-    outstanding_outgoing_request_ids: HashMap<(PeerId, RequestId), Instant>,
-    /// Stores the next `RequestId` we should include on an outgoing `RPCRequest` to a `PeerId`.
-    outgoing_request_ids: HashMap<PeerId, RequestId>,
     /// The `MessageHandler` logger.
     log: slog::Logger,
 }
 
 impl NetworkContext {
     pub fn new(network_send: crossbeam_channel::Sender<NetworkMessage>, log: slog::Logger) -> Self {
-        Self {
-            network_send,
-            outstanding_outgoing_request_ids: HashMap::new(),
-            outgoing_request_ids: HashMap::new(),
-            log,
-        }
+        Self { network_send, log }
     }
 
     pub fn disconnect(&mut self, peer_id: PeerId, reason: GoodbyeReason) {
@@ -246,22 +384,46 @@ impl NetworkContext {
         // TODO: disconnect peers.
     }
 
-    pub fn send_rpc_request(&mut self, peer_id: PeerId, rpc_request: RPCRequest) {
-        let id = self.generate_request_id(&peer_id);
-
-        self.outstanding_outgoing_request_ids
-            .insert((peer_id.clone(), id), Instant::now());
+    /// Scores `peer_id` by the usefulness of its response to one of our requests.
+    pub fn report_rpc_outcome(&mut self, peer_id: PeerId, quality: RPCResponseQuality) {
+        self.network_send
+            .send(NetworkMessage::ReportRpcOutcome(peer_id, quality))
+            .unwrap_or_else(|_| {
+                warn!(
+                    self.log,
+                    "Could not send RPC outcome to the network service"
+                )
+            });
+    }
 
+    pub fn send_rpc_request(&mut self, peer_id: PeerId, rpc_request: RPCRequest) {
         self.send_rpc_event(
             peer_id,
             RPCEvent::Request {
-                id,
+                // the request id is assigned by the RPC behaviour
+                id: RequestId::default(),
                 method_id: rpc_request.method_id(),
                 body: rpc_request,
             },
         );
     }
 
+    /// Signals the end of a streamed response to the request `request_id`.
+    pub fn send_stream_termination(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        method_id: u16,
+    ) {
+        self.send_rpc_event(
+            peer_id,
+            RPCEvent::StreamTermination {
+                id: request_id,
+                method_id,
+            },
+        );
+    }
+
     pub fn send_rpc_response(
         &mut self,
         peer_id: PeerId,
@@ -278,6 +440,25 @@ impl NetworkContext {
         );
     }
 
+    /// Fails the request `request_id` with an error in place of a response.
+    pub fn send_rpc_error(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        method_id: u16,
+        code: RPCErrorCode,
+        message: Option<String>,
+    ) {
+        self.send_rpc_event(
+            peer_id,
+            RPCEvent::Error {
+                id: request_id,
+                method_id,
+                error: RPCError::new(code, message),
+            },
+        );
+    }
+
     fn send_rpc_event(&self, peer_id: PeerId, rpc_event: RPCEvent) {
         self.send(peer_id, OutgoingMessage::RPC(rpc_event))
     }
@@ -293,15 +474,4 @@ impl NetworkContext {
             });
         //
     }
-
-    /// Returns the next `RequestId` for sending an `RPCRequest` to the `peer_id`.
-    fn generate_request_id(&mut self, peer_id: &PeerId) -> RequestId {
-        let next_id = self
-            .outgoing_request_ids
-            .entry(peer_id.clone())
-            .and_modify(RequestId::increment)
-            .or_insert_with(|| RequestId::from(1));
-
-        next_id.previous()
-    }
 }
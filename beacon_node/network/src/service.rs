@@ -6,11 +6,11 @@ use crossbeam_channel::{unbounded as channel, Sender, TryRecvError};
 use eth2_libp2p::Service as LibP2PService;
 use eth2_libp2p::Topic;
 use eth2_libp2p::{Libp2pEvent, PeerId};
-use eth2_libp2p::{PubsubMessage, RPCEvent};
+use eth2_libp2p::{PubsubMessage, RPCEvent, RPCResponseQuality};
 use futures::prelude::*;
 use futures::sync::oneshot;
 use futures::Stream;
-use slog::{debug, info, o, trace};
+use slog::{debug, info, o, trace, warn};
 use std::marker::PhantomData;
 use std::sync::Arc;
 use tokio::runtime::TaskExecutor;
@@ -33,19 +33,27 @@ impl<T: BeaconChainTypes + 'static> Service<T> {
     ) -> error::Result<(Arc<Self>, Sender<NetworkMessage>)> {
         // build the network channel
         let (network_send, network_recv) = channel::<NetworkMessage>();
+        // our status is of the chain we follow, unless configured otherwise
+        let mut config = config.clone();
+        config.network_id.get_or_insert(beacon_chain.spec.chain_id);
+        config
+            .genesis_root
+            .get_or_insert(beacon_chain.genesis_block_root());
+
+        // build the libp2p service, which the message handler instructs
+        let libp2p_log = log.new(o!("Service" => "Libp2p"));
+        let libp2p_service = LibP2PService::new(config, libp2p_log)?;
+
         // launch message handler thread
         let message_handler_log = log.new(o!("Service" => "MessageHandler"));
         let message_handler_send = MessageHandler::spawn(
             beacon_chain,
             network_send.clone(),
+            libp2p_service.swarm.network_sender(),
             executor,
             message_handler_log,
         )?;
 
-        // launch libp2p service
-        let libp2p_log = log.new(o!("Service" => "Libp2p"));
-        let libp2p_service = LibP2PService::new(config.clone(), libp2p_log)?;
-
         // TODO: Spawn thread to handle libp2p messages and pass to message handler thread.
         let libp2p_exit = spawn_service(
             libp2p_service,
@@ -89,10 +97,9 @@ fn spawn_service(
             libp2p_service,
             network_recv,
             message_handler_send,
+            exit_rx,
             log.clone(),
         )
-        // allow for manual termination
-        .select(exit_rx.then(|_| Ok(())))
         .then(move |_| {
             info!(log.clone(), "Network service shutdown");
             Ok(())
@@ -102,14 +109,28 @@ fn spawn_service(
     Ok(network_exit)
 }
 
+/// Runs the libp2p service until it fails, or until it has said goodbye to its peers once
+/// `exit_rx` fires or its sender is dropped.
 //TODO: Potentially handle channel errors
 fn network_service(
     mut libp2p_service: LibP2PService,
     network_recv: crossbeam_channel::Receiver<NetworkMessage>,
     message_handler_send: crossbeam_channel::Sender<HandlerMessage>,
+    mut exit_rx: oneshot::Receiver<()>,
     log: slog::Logger,
 ) -> impl futures::Future<Item = (), Error = eth2_libp2p::error::Error> {
     futures::future::poll_fn(move || -> Result<_, eth2_libp2p::error::Error> {
+        // allow for manual termination, which is graceful
+        if !libp2p_service.swarm.is_shutting_down() {
+            match exit_rx.poll() {
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(())) | Err(_) => {
+                    info!(log, "Saying goodbye to network peers");
+                    libp2p_service.swarm.shutdown();
+                }
+            }
+        }
+
         // poll the swarm
         loop {
             match libp2p_service.poll() {
@@ -126,15 +147,76 @@ fn network_service(
                             .send(HandlerMessage::PeerDialed(peer_id))
                             .map_err(|_| "failed to send rpc to handler")?;
                     }
+                    Libp2pEvent::PeerDiscovered(peer_id) => {
+                        trace!(log, "Peer discovered: {:?}", peer_id);
+                    }
                     Libp2pEvent::PubsubMessage {
-                        source, message, ..
+                        id,
+                        source,
+                        block,
+                        message,
+                        ..
                     } => {
                         //TODO: Decide if we need to propagate the topic upwards. (Potentially for
                         //attestations)
                         message_handler_send
-                            .send(HandlerMessage::PubsubMessage(source, message))
+                            .send(HandlerMessage::PubsubMessage(id, source, block, message))
                             .map_err(|_| " failed to send pubsub message to handler")?;
                     }
+                    Libp2pEvent::MeshChanged {
+                        peer_id,
+                        topic,
+                        grafted,
+                    } => {
+                        debug!(log, "Mesh changed";
+                            "peer" => format!("{:?}", peer_id),
+                            "topic" => format!("{:?}", topic),
+                            "grafted" => grafted
+                        );
+                    }
+                    Libp2pEvent::PeerSummary(summary) => {
+                        debug!(log, "Peer summary";
+                            "connected_peers" => summary.connected_peers,
+                            "clients" => format!("{:?}", summary.clients),
+                            "average_rtt" => format!("{:?}", summary.average_rtt),
+                            "topic_peers" => format!("{:?}", summary.topic_peers),
+                            "secs_since_peer_search" => format!("{:?}", summary.last_peer_search.map(|at| at.elapsed().as_secs()))
+                        );
+                    }
+                    Libp2pEvent::LowPeerCount(count) => {
+                        warn!(log, "Low peer count"; "connected_peers" => count);
+                    }
+                    Libp2pEvent::RecoveredPeerCount(count) => {
+                        info!(log, "Peer count recovered"; "connected_peers" => count);
+                    }
+                    Libp2pEvent::RangeSyncFailed { start_slot, count } => {
+                        warn!(log, "Blocks by range request failed on every peer tried";
+                            "start_slot" => start_slot.as_u64(),
+                            "count" => count
+                        );
+                    }
+                    Libp2pEvent::DialFailed { addr, kind } => {
+                        debug!(log, "Failed to dial address";
+                            "address" => format!("{}", addr),
+                            "kind" => format!("{:?}", kind)
+                        );
+                    }
+                    Libp2pEvent::SubnetPeersFound(subnet_id, count) => {
+                        debug!(log, "Found subnet peers";
+                            "subnet_id" => subnet_id,
+                            "count" => count
+                        );
+                    }
+                    Libp2pEvent::SubnetQueryTimedOut(subnet_id, count) => {
+                        warn!(log, "Could not find enough subnet peers";
+                            "subnet_id" => subnet_id,
+                            "count" => count
+                        );
+                    }
+                    Libp2pEvent::ShutdownComplete => {
+                        debug!(log, "Network peers said goodbye to");
+                        return Ok(Async::Ready(()));
+                    }
                 },
                 Ok(Async::Ready(None)) => unreachable!("Stream never ends"),
                 Ok(Async::NotReady) => break,
@@ -161,7 +243,16 @@ fn network_service(
                 }
                 Ok(NetworkMessage::Publish { topics, message }) => {
                     debug!(log, "Sending pubsub message on topics {:?}", topics);
-                    libp2p_service.swarm.publish(topics, *message);
+                    if let Err(e) = libp2p_service.swarm.publish(topics, *message) {
+                        warn!(log, "Could not publish message"; "error" => format!("{:?}", e));
+                    }
+                }
+                Ok(NetworkMessage::ReportRpcOutcome(peer_id, quality)) => {
+                    trace!(log, "Reporting RPC outcome";
+                        "peer" => format!("{:?}", peer_id),
+                        "quality" => format!("{:?}", quality)
+                    );
+                    libp2p_service.swarm.report_rpc_outcome(peer_id, quality);
                 }
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => {
@@ -186,6 +277,8 @@ pub enum NetworkMessage {
         topics: Vec<Topic>,
         message: Box<PubsubMessage>,
     },
+    /// Score a peer by the usefulness of its response to one of our RPC requests.
+    ReportRpcOutcome(PeerId, RPCResponseQuality),
 }
 
 /// Type of outgoing messages that can be sent through the network service.
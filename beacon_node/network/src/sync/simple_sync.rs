@@ -2,15 +2,16 @@ use super::import_queue::ImportQueue;
 use crate::message_handler::NetworkContext;
 use beacon_chain::{BeaconChain, BeaconChainTypes, BlockProcessingOutcome};
 use eth2_libp2p::rpc::methods::*;
-use eth2_libp2p::rpc::{RPCRequest, RPCResponse, RequestId};
-use eth2_libp2p::PeerId;
+use eth2_libp2p::rpc::{RPCMethod, RPCRequest, RPCResponse, RequestId};
+use eth2_libp2p::{GossipBlockInfo, PeerId, RPCResponseQuality};
 use slog::{debug, error, info, o, trace, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 use store::Store;
 use types::{
-    Attestation, BeaconBlock, BeaconBlockBody, BeaconBlockHeader, Epoch, EthSpec, Hash256, Slot,
+    Attestation, AttesterSlashing, BeaconBlock, BeaconBlockBody, BeaconBlockHeader, Epoch, EthSpec,
+    Hash256, ProposerSlashing, Slot, VoluntaryExit,
 };
 
 /// The number of slots that we can import blocks ahead of us, before going into full Sync mode.
@@ -320,6 +321,7 @@ impl<T: BeaconChainTypes> SimpleSync<T> {
                 "Peer returned empty block roots response";
                 "peer_id" => format!("{:?}", peer_id)
             );
+            network.report_rpc_outcome(peer_id, RPCResponseQuality::Empty);
             return;
         }
 
@@ -330,8 +332,10 @@ impl<T: BeaconChainTypes> SimpleSync<T> {
                 "Peer returned block roots response with bad slot ordering";
                 "peer_id" => format!("{:?}", peer_id)
             );
+            network.report_rpc_outcome(peer_id, RPCResponseQuality::WrongRange);
             return;
         }
+        network.report_rpc_outcome(peer_id.clone(), RPCResponseQuality::Useful);
 
         let new_roots = self
             .import_queue
@@ -429,8 +433,10 @@ impl<T: BeaconChainTypes> SimpleSync<T> {
                 self.log,
                 "Peer returned empty block headers response. PeerId: {:?}", peer_id
             );
+            network.report_rpc_outcome(peer_id, RPCResponseQuality::Empty);
             return;
         }
+        network.report_rpc_outcome(peer_id.clone(), RPCResponseQuality::Useful);
 
         // Enqueue the headers, obtaining a list of the roots of the headers which were newly added
         // to the queue.
@@ -499,6 +505,13 @@ impl<T: BeaconChainTypes> SimpleSync<T> {
             "count" => res.block_bodies.len(),
         );
 
+        let quality = if res.block_bodies.is_empty() {
+            RPCResponseQuality::Empty
+        } else {
+            RPCResponseQuality::Useful
+        };
+        network.report_rpc_outcome(peer_id.clone(), quality);
+
         self.import_queue
             .enqueue_bodies(res.block_bodies, peer_id.clone());
 
@@ -509,17 +522,320 @@ impl<T: BeaconChainTypes> SimpleSync<T> {
         self.process_import_queue(network);
     }
 
+    /// Handle a `BeaconBlocksByRange` request from the peer.
+    ///
+    /// Streams each known block in the range back to the peer, followed by a stream termination.
+    pub fn on_beacon_blocks_by_range_request(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        req: BeaconBlocksByRangeRequest,
+        network: &mut NetworkContext,
+    ) {
+        debug!(
+            self.log,
+            "BlocksByRangeRequest";
+            "peer" => format!("{:?}", peer_id),
+            "count" => req.count,
+            "start_slot" => req.start_slot,
+            "step" => req.step,
+        );
+
+        let mut roots: Vec<Hash256> = self
+            .chain
+            .rev_iter_block_roots(req.start_slot + req.count)
+            .skip(1)
+            .take(req.count as usize)
+            .filter(|(_root, slot)| (*slot - req.start_slot).as_u64() % req.step == 0)
+            .map(|(root, _slot)| root)
+            .collect();
+
+        roots.reverse();
+        roots.dedup();
+
+        let mut returned = 0;
+        for root in roots {
+            if let Ok(Some(block)) = self.chain.store.get::<BeaconBlock>(&root) {
+                returned += 1;
+                network.send_rpc_response(
+                    peer_id.clone(),
+                    request_id,
+                    RPCResponse::BeaconBlocksByRange(Box::new(block)),
+                );
+            }
+        }
+
+        debug!(
+            self.log,
+            "BlocksByRangeResponse";
+            "peer" => format!("{:?}", peer_id),
+            "requested" => req.count,
+            "returned" => returned,
+        );
+
+        network.send_stream_termination(peer_id, request_id, RPCMethod::BeaconBlocksByRange.into());
+    }
+
+    /// Handle a single block of a `BeaconBlocksByRange` response from the peer.
+    ///
+    /// The block is queued and imported once the stream has been terminated.
+    pub fn on_beacon_blocks_by_range_response(&mut self, peer_id: PeerId, block: BeaconBlock) {
+        trace!(
+            self.log,
+            "BlocksByRangeResponse";
+            "peer" => format!("{:?}", peer_id),
+            "slot" => block.slot,
+        );
+
+        self.import_queue.enqueue_full_blocks(vec![block], peer_id);
+    }
+
+    /// Handle a `BeaconBlocksByRoot` request from the peer.
+    ///
+    /// Streams each known block back to the peer, followed by a stream termination. Unknown roots
+    /// are skipped.
+    pub fn on_beacon_blocks_by_root_request(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        req: BeaconBlocksByRootRequest,
+        network: &mut NetworkContext,
+    ) {
+        let mut returned = 0;
+        for root in &req.block_roots {
+            if let Ok(Some(block)) = self.chain.store.get::<BeaconBlock>(root) {
+                returned += 1;
+                network.send_rpc_response(
+                    peer_id.clone(),
+                    request_id,
+                    RPCResponse::BeaconBlocksByRoot(Box::new(block)),
+                );
+            } else {
+                debug!(
+                    self.log,
+                    "Peer requested unknown block";
+                    "peer" => format!("{:?}", peer_id),
+                    "request_root" => format!("{:}", root),
+                );
+            }
+        }
+
+        debug!(
+            self.log,
+            "BlocksByRootRequest";
+            "peer" => format!("{:?}", peer_id),
+            "requested" => req.block_roots.len(),
+            "returned" => returned,
+        );
+
+        network.send_stream_termination(peer_id, request_id, RPCMethod::BeaconBlocksByRoot.into());
+    }
+
+    /// Handle a single block of a `BeaconBlocksByRoot` response from the peer.
+    ///
+    /// The block is queued and imported once the stream has been terminated.
+    pub fn on_beacon_blocks_by_root_response(&mut self, peer_id: PeerId, block: BeaconBlock) {
+        trace!(
+            self.log,
+            "BlocksByRootResponse";
+            "peer" => format!("{:?}", peer_id),
+            "slot" => block.slot,
+        );
+
+        self.import_queue.enqueue_full_blocks(vec![block], peer_id);
+    }
+
+    /// Handle a `BeaconBlockHeadersByRange` request from the peer.
+    ///
+    /// Streams the header of each known block in the range back to the peer, followed by a stream
+    /// termination.
+    pub fn on_beacon_block_headers_by_range_request(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        req: BeaconBlockHeadersByRangeRequest,
+        network: &mut NetworkContext,
+    ) {
+        let mut roots: Vec<Hash256> = self
+            .chain
+            .rev_iter_block_roots(req.start_slot + req.count)
+            .skip(1)
+            .take(req.count as usize)
+            .map(|(root, _slot)| root)
+            .collect();
+
+        roots.reverse();
+        roots.dedup();
+
+        let mut returned = 0;
+        for root in roots {
+            if let Ok(Some(block)) = self.chain.store.get::<BeaconBlock>(&root) {
+                returned += 1;
+                network.send_rpc_response(
+                    peer_id.clone(),
+                    request_id,
+                    RPCResponse::BeaconBlockHeadersByRange(block.block_header()),
+                );
+            }
+        }
+
+        debug!(
+            self.log,
+            "BlockHeadersByRangeRequest";
+            "peer" => format!("{:?}", peer_id),
+            "start_slot" => req.start_slot,
+            "requested" => req.count,
+            "returned" => returned,
+        );
+
+        network.send_stream_termination(
+            peer_id,
+            request_id,
+            RPCMethod::BeaconBlockHeadersByRange.into(),
+        );
+    }
+
+    /// Handle a single header of a `BeaconBlockHeadersByRange` response from the peer.
+    ///
+    /// The header is queued and the corresponding body is requested if it is new.
+    pub fn on_beacon_block_headers_by_range_response(
+        &mut self,
+        peer_id: PeerId,
+        header: BeaconBlockHeader,
+        network: &mut NetworkContext,
+    ) {
+        let block_roots = self
+            .import_queue
+            .enqueue_headers(vec![header], peer_id.clone());
+
+        if !block_roots.is_empty() {
+            self.request_block_bodies(peer_id, BeaconBlockBodiesRequest { block_roots }, network);
+        }
+    }
+
+    /// Handle a `BeaconBlockBodiesByRoot` request from the peer.
+    ///
+    /// Streams each known body back to the peer, tagged with its position in the request,
+    /// followed by a stream termination. Unknown roots are skipped.
+    pub fn on_beacon_block_bodies_by_root_request(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        req: BeaconBlockBodiesByRootRequest,
+        network: &mut NetworkContext,
+    ) {
+        let mut returned = 0;
+        for (index, body_root) in req.body_roots.iter().enumerate() {
+            match self.chain.get_block_by_body_root(body_root) {
+                Ok(Some(block)) => {
+                    returned += 1;
+                    network.send_rpc_response(
+                        peer_id.clone(),
+                        request_id,
+                        RPCResponse::BeaconBlockBodiesByRoot(Box::new(BeaconBlockBodyByRoot {
+                            index: index as u64,
+                            body_root: *body_root,
+                            body: block.body,
+                        })),
+                    );
+                }
+                _ => trace!(
+                    self.log,
+                    "Peer requested unknown block body";
+                    "peer" => format!("{:?}", peer_id),
+                    "body_root" => format!("{}", body_root),
+                ),
+            }
+        }
+
+        debug!(
+            self.log,
+            "BlockBodiesByRootRequest";
+            "peer" => format!("{:?}", peer_id),
+            "requested" => req.body_roots.len(),
+            "returned" => returned,
+        );
+
+        network.send_stream_termination(
+            peer_id,
+            request_id,
+            RPCMethod::BeaconBlockBodiesByRoot.into(),
+        );
+    }
+
+    /// Handle a single body of a `BeaconBlockBodiesByRoot` response from the peer.
+    ///
+    /// The body is queued and imported once the stream has been terminated.
+    pub fn on_beacon_block_bodies_by_root_response(
+        &mut self,
+        peer_id: PeerId,
+        res: BeaconBlockBodyByRoot,
+    ) {
+        trace!(
+            self.log,
+            "BlockBodiesByRootResponse";
+            "peer" => format!("{:?}", peer_id),
+            "index" => res.index,
+        );
+
+        self.import_queue.enqueue_bodies(vec![res.body], peer_id);
+    }
+
+    /// Handle one of our requests to the peer timing out, lowering the peer's RPC score.
+    ///
+    /// Timed out `BeaconBlocksByRange` requests are scored by the network behaviour itself.
+    pub fn on_rpc_timeout(
+        &mut self,
+        peer_id: PeerId,
+        method: RPCMethod,
+        network: &mut NetworkContext,
+    ) {
+        if method != RPCMethod::BeaconBlocksByRange {
+            network.report_rpc_outcome(peer_id, RPCResponseQuality::TimedOut);
+        }
+    }
+
+    /// Handle the termination of a streamed response from the peer.
+    pub fn on_stream_termination(&mut self, peer_id: PeerId, network: &mut NetworkContext) {
+        trace!(self.log, "StreamTermination"; "peer" => format!("{:?}", peer_id));
+
+        // Clear out old entries
+        self.import_queue.remove_stale();
+
+        // Import blocks, if possible.
+        self.process_import_queue(network);
+    }
+
     /// Process a gossip message declaring a new block.
     ///
     /// Attempts to apply to block to the beacon chain. May queue the block for later processing.
+    /// A block whose parent, by the `info` gossiped with it, is itself queued is queued without
+    /// an attempt to apply it.
     ///
     /// Returns a `bool` which, if `true`, indicates we should forward the block to our peers.
     pub fn on_block_gossip(
         &mut self,
         peer_id: PeerId,
         block: BeaconBlock,
+        info: Option<GossipBlockInfo>,
         network: &mut NetworkContext,
     ) -> bool {
+        if let Some(info) = info {
+            if self.import_queue.contains_block_root(info.parent_root) {
+                self.import_queue.remove_stale();
+                self.import_queue
+                    .enqueue_full_blocks(vec![block], peer_id.clone());
+                trace!(
+                    self.log,
+                    "NewGossipBlock";
+                    "peer" => format!("{:?}", peer_id),
+                    "slot" => info.slot.as_u64(),
+                    "parent_queued" => true,
+                );
+                return SHOULD_FORWARD_GOSSIP_BLOCK;
+            }
+        }
+
         if let Some(outcome) =
             self.process_block(peer_id.clone(), block.clone(), network, &"gossip")
         {
@@ -539,16 +855,12 @@ impl<T: BeaconChainTypes> SimpleSync<T> {
                     );
 
                     // Unless the parent is in the queue, request the parent block from the peer.
-                    //
-                    // It is likely that this is duplicate work, given we already send a hello
-                    // request. However, I believe there are some edge-cases where the hello
-                    // message doesn't suffice, so we perform this request as well.
                     if !self.import_queue.contains_block_root(parent) {
-                        // Send a hello to learn of the clients best slot so we can then sync the required
-                        // parent(s).
                         network.send_rpc_request(
                             peer_id.clone(),
-                            RPCRequest::Hello(hello_message(&self.chain)),
+                            RPCRequest::BeaconBlocksByRoot(BeaconBlocksByRootRequest {
+                                block_roots: vec![parent],
+                            }),
                         );
                     }
 
@@ -592,6 +904,36 @@ impl<T: BeaconChainTypes> SimpleSync<T> {
         }
     }
 
+    /// Queues a voluntary exit received over gossip for inclusion in a block.
+    pub fn on_voluntary_exit_gossip(&mut self, msg: VoluntaryExit) {
+        match self.chain.process_voluntary_exit(msg) {
+            Ok(()) => info!(self.log, "ImportedVoluntaryExit"; "source" => "gossip"),
+            Err(e) => {
+                warn!(self.log, "InvalidVoluntaryExit"; "source" => "gossip", "error" => format!("{:?}", e))
+            }
+        }
+    }
+
+    /// Queues a proposer slashing received over gossip for inclusion in a block.
+    pub fn on_proposer_slashing_gossip(&mut self, msg: ProposerSlashing) {
+        match self.chain.process_proposer_slashing(msg) {
+            Ok(()) => info!(self.log, "ImportedProposerSlashing"; "source" => "gossip"),
+            Err(e) => {
+                warn!(self.log, "InvalidProposerSlashing"; "source" => "gossip", "error" => format!("{:?}", e))
+            }
+        }
+    }
+
+    /// Queues an attester slashing received over gossip for inclusion in a block.
+    pub fn on_attester_slashing_gossip(&mut self, msg: AttesterSlashing) {
+        match self.chain.process_attester_slashing(msg) {
+            Ok(()) => info!(self.log, "ImportedAttesterSlashing"; "source" => "gossip"),
+            Err(e) => {
+                warn!(self.log, "InvalidAttesterSlashing"; "source" => "gossip", "error" => format!("{:?}", e))
+            }
+        }
+    }
+
     /// Iterate through the `import_queue` and process any complete blocks.
     ///
     /// If a block is successfully processed it is removed from the queue, otherwise it remains in
@@ -818,6 +1160,7 @@ fn hello_message<T: BeaconChainTypes>(beacon_chain: &BeaconChain<T>) -> HelloMes
 
     HelloMessage {
         network_id: spec.chain_id,
+        genesis_root: beacon_chain.genesis_block_root(),
         latest_finalized_root: state.finalized_root,
         latest_finalized_epoch: state.finalized_epoch,
         best_root: beacon_chain.head().beacon_block_root,
@@ -40,7 +40,13 @@ fn main() {
         .arg(
             Arg::with_name("maxpeers")
                 .long("maxpeers")
-                .help("The maximum number of peers (default 10).")
+                .help("The maximum number of peers (default 15).")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target-peers")
+                .long("target-peers")
+                .help("The number of peers to seek connections to (default 10).")
                 .takes_value(true),
         )
         .arg(
@@ -51,6 +57,40 @@ fn main() {
                 .help("One or more comma-delimited base64-encoded ENR's to bootstrap the p2p network.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("libp2p-addresses")
+                .long("libp2p-addresses")
+                .value_name("MULTIADDR")
+                .help("One or more comma-delimited multiaddrs of boot nodes to dial, for nodes without a known ENR.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trusted-peers")
+                .long("trusted-peers")
+                .value_name("MULTIADDR")
+                .help("One or more comma-delimited multiaddrs of peers to stay connected to, exempt from peer limits and bans.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connection-idle-timeout")
+                .long("connection-idle-timeout")
+                .value_name("SECONDS")
+                .help("Disconnect peers which send no gossip or RPCs, beyond keep-alives, for this many seconds.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-rpc-per-peer")
+                .long("max-rpc-per-peer")
+                .value_name("COUNT")
+                .help("The maximum number of RPC requests awaiting a response from a peer at once. Further requests wait for one to complete.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("enable-mdns")
+                .long("enable-mdns")
+                .help("Discover peers on the local network with mDNS. For local devnets only.")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("port")
                 .long("port")
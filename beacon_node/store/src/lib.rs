@@ -76,6 +76,7 @@ pub trait Store: Sync + Send + Sized {
 /// A unique column identifier.
 pub enum DBColumn {
     BeaconBlock,
+    BeaconBlockBodyRoot,
     BeaconState,
     BeaconChain,
 }
@@ -85,6 +86,7 @@ impl<'a> Into<&'a str> for DBColumn {
     fn into(self) -> &'a str {
         match self {
             DBColumn::BeaconBlock => &"blk",
+            DBColumn::BeaconBlockBodyRoot => &"bbr",
             DBColumn::BeaconState => &"ste",
             DBColumn::BeaconChain => &"bch",
         }
@@ -400,6 +400,14 @@ pub fn decode_list_of_variable_length_items<T: Decode>(
         });
     }
 
+    // The first offset bounds the number of items, which must fit in `bytes`. This is checked
+    // before allocating for the items.
+    if next_variable_byte > bytes.len() {
+        return Err(DecodeError::OutOfBoundsByte {
+            i: next_variable_byte,
+        });
+    }
+
     let num_items = next_variable_byte / BYTES_PER_LENGTH_OFFSET;
 
     // The fixed-length section must be a clean multiple of `BYTES_PER_LENGTH_OFFSET`.
@@ -459,6 +467,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn out_of_bounds_first_offset() {
+        let mut bytes = vec![0; 12];
+        bytes[..4].copy_from_slice(&0xffff_fffc_u32.to_le_bytes());
+
+        assert_eq!(
+            <Vec<Vec<u8>>>::from_ssz_bytes(&bytes),
+            Err(DecodeError::OutOfBoundsByte { i: 0xffff_fffc })
+        );
+    }
+
     #[test]
     fn invalid_bool() {
         assert_eq!(